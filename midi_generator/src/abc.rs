@@ -0,0 +1,309 @@
+// ABC export
+// Writes the notes of a file in ABC notation, as a tune with one voice per track and more where its notes overlap
+
+use crate::midicsv::parse_paths;
+use crate::notation::{read_score, spell, Element, Part, Score, DIVISIONS};
+
+/// Letters the sharps and flats of key signatures fall on, in the order they are added
+const SHARP_ORDER: [char; 7] = ['F', 'C', 'G', 'D', 'A', 'E', 'B'];
+const FLAT_ORDER: [char; 7] = ['B', 'E', 'A', 'D', 'G', 'C', 'F'];
+
+/// Returns the ABC name of a key, e.g. "Eb" or "F#m"
+fn key_name(sharps: i8, minor: bool) -> String {
+    const MAJOR: [&str; 15] = ["Cb", "Gb", "Db", "Ab", "Eb", "Bb", "F", "C", "G", "D", "A", "E", "B", "F#", "C#"];
+    const MINOR: [&str; 15] = ["Ab", "Eb", "Bb", "F", "C", "G", "D", "A", "E", "B", "F#", "C#", "G#", "D#", "A#"];
+
+    let index = (sharps.clamp(-7, 7) + 7) as usize;
+    if minor { format!("{}m", MINOR[index]) } else { String::from(MAJOR[index]) }
+}
+
+/// Returns the alteration a key signature gives a letter
+fn key_alter(sharps: i8, step: char) -> i8 {
+    if sharps > 0 && SHARP_ORDER[..sharps as usize].contains(&step) {
+        1
+    }
+    else if sharps < 0 && FLAT_ORDER[..(-sharps) as usize].contains(&step) {
+        -1
+    }
+    else {
+        0
+    }
+}
+
+fn gcd(a: u32, b: u32) -> u32 {
+    if b == 0 { a } else { gcd(b, a % b) }
+}
+
+/// Writes a duration as a multiple of the unit note length, leaving out a multiple of 1
+fn length(duration: u32, unit: u32) -> String {
+    let divisor = gcd(duration, unit);
+    match (duration / divisor, unit / divisor) {
+        (1, 1) => String::new(),
+        (numerator, 1) => numerator.to_string(),
+        (1, denominator) => format!("/{}", denominator),
+        (numerator, denominator) => format!("{}/{}", numerator, denominator),
+    }
+}
+
+/// Writes the measures of a voice, keeping track of the accidentals in force within each bar
+struct VoiceWriter {
+    unit: u32, // the unit note length, in divisions
+    key: i8,
+    accidentals: Vec<(char, i32, i8)>, // letter, octave and alteration of the accidentals written so far in the bar
+}
+
+impl VoiceWriter {
+    /// Writes a pitch, with an accidental where the key and the bar so far don't already give it
+    fn pitch(&mut self, pitch: u8) -> String {
+        let (step, alter, octave) = spell(pitch, self.key);
+        let in_force = self.accidentals.iter().rev()
+            .find(|(letter, in_octave, _)| *letter == step && *in_octave == octave)
+            .map_or(key_alter(self.key, step), |accidental| accidental.2);
+
+        let mut abc = String::new();
+        if alter != in_force {
+            abc += match alter {
+                1 => "^",
+                -1 => "_",
+                _ => "=",
+            };
+            self.accidentals.push((step, octave, alter));
+        }
+
+        // C is middle C, c the octave above, with a comma for each octave lower and an apostrophe for each higher
+        if octave >= 5 {
+            abc.push(step.to_ascii_lowercase());
+            abc += &"'".repeat((octave - 5) as usize);
+        }
+        else {
+            abc.push(step);
+            abc += &",".repeat((4 - octave).max(0) as usize);
+        }
+        abc
+    }
+
+    fn element(&mut self, element: &Element) -> String {
+        let mut abc = match element.pitches.as_slice() {
+            [] => String::from("z"),
+            [pitch] => self.pitch(*pitch),
+            pitches => format!("[{}]", pitches.iter().map(|pitch| self.pitch(*pitch)).collect::<String>()),
+        };
+        abc += &length(element.duration, self.unit);
+        if element.tie_start {
+            abc.push('-');
+        }
+        abc
+    }
+
+    /// Writes the body of one voice of a part, four measures to a line
+    ///
+    /// Measures where a voice rests throughout are written as Z in the first voice of the part, and left invisible as X
+    /// in the others.
+    fn voice(&mut self, part: &Part, voice: usize) -> String {
+        let mut lines = Vec::new();
+        let mut line = String::new();
+
+        for (index, measure) in part.measures.iter().enumerate() {
+            if index > 0 {
+                if let Some((numerator, denominator)) = measure.time {
+                    line += &format!("[M:{}/{}] ", numerator, denominator);
+                }
+                if let Some((sharps, minor)) = measure.key {
+                    line += &format!("[K:{}] ", key_name(sharps, minor));
+                }
+            }
+            if let Some((sharps, _)) = measure.key {
+                self.key = sharps;
+            }
+            self.accidentals.clear();
+
+            let elements: Vec<String> = measure.voices[voice].iter().map(|element| self.element(element)).collect();
+            line += &if !elements.is_empty() { elements.join(" ") } else if voice == 0 { String::from("Z") } else { String::from("X") };
+
+            if index + 1 == part.measures.len() {
+                line += " |]";
+                lines.push(std::mem::take(&mut line));
+            }
+            else if index % 4 == 3 {
+                line += " |";
+                lines.push(std::mem::take(&mut line));
+            }
+            else {
+                line += " | ";
+            }
+        }
+
+        lines.join("\n") + "\n"
+    }
+}
+
+/// Keeps header text on one line, escaping the % that would start a comment
+fn header_text(text: &str) -> String {
+    text.chars().filter(|c| !c.is_control()).collect::<String>().replace('%', "\\%")
+}
+
+/// Writes a score as an ABC tune
+///
+/// The M, Q and K header fields come from the time signature, tempo and key at the start of the score, and durations
+/// are written as multiples of the unit note length, given as the denominator of its fraction of a whole note.
+/// Scores of more than one part, or with overlapping notes, have a voice for each voice of each part, and the voices
+/// of a part share a staff.
+pub fn to_abc(score: &Score, unit_length: u32) -> String {
+    let first = &score.parts[0].measures[0];
+    let (numerator, denominator) = first.time.unwrap_or((4, 4));
+    let (sharps, minor) = first.key.unwrap_or((0, false));
+
+    let mut abc = format!("X:1\nT:{}\nM:{}/{}\nL:1/{}\nQ:1/4={}\n",
+        header_text(score.title.as_deref().unwrap_or("")),
+        numerator,
+        denominator,
+        unit_length,
+        score.bpm.round());
+
+    // voices after the first of a part are named after its track and their number, e.g. 3-2
+    let voice_ids = |part: &Part| -> Vec<String> {
+        let count = part.measures.first().map_or(1, |measure| measure.voices.len());
+        (0..count).map(|index| if index == 0 { part.track.to_string() } else { format!("{}-{}", part.track, index + 1) }).collect()
+    };
+
+    let overlapping = score.parts.iter().any(|part| voice_ids(part).len() > 1);
+    let multiple_voices = score.parts.len() > 1 || overlapping;
+    if overlapping {
+        let staves: Vec<String> = score.parts.iter().map(|part| {
+            let ids = voice_ids(part);
+            if ids.len() > 1 { format!("({})", ids.join(" ")) } else { ids.join(" ") }
+        }).collect();
+        abc += &format!("%%score {}\n", staves.join(" "));
+    }
+    if multiple_voices {
+        for part in &score.parts {
+            let clef = if part.bass_clef { " clef=bass" } else { "" };
+            for (index, id) in voice_ids(part).iter().enumerate() {
+                if index == 0 {
+                    abc += &format!("V:{} name=\"{}\"{}\n", id, header_text(&part.name).replace('"', "'"), clef);
+                }
+                else {
+                    abc += &format!("V:{}{}\n", id, clef);
+                }
+            }
+        }
+    }
+    abc += &format!("K:{}{}\n", key_name(sharps, minor), if !multiple_voices && score.parts[0].bass_clef { " clef=bass" } else { "" });
+
+    for part in &score.parts {
+        for (index, id) in voice_ids(part).iter().enumerate() {
+            if multiple_voices {
+                abc += &format!("V:{}\n", id);
+            }
+            let mut writer = VoiceWriter { unit: 4 * DIVISIONS / unit_length, key: 0, accidentals: Vec::new() };
+            abc += &writer.voice(part, index);
+        }
+    }
+
+    abc
+}
+
+pub const USAGE: &str = "\
+usage: midi_generator abc [options] <file>
+
+writes the notes of a MIDI file as an ABC tune with one voice per track, and more on the same staff where
+its notes overlap, quantized to 32nd notes
+
+options:
+  -o, --output <file>    write the tune to <file> (default <file>.abc)
+  -L, --unit <1/n>       unit note length the durations are written in, one of 1/1 to 1/32 (default 1/8)";
+
+/// Runs the abc command, writing the notes of a MIDI file as an ABC tune
+pub fn run(args: Vec<String>) -> Result<(), String> {
+    let mut unit_length = 8;
+    let mut rest = Vec::new();
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-L" | "--unit" => {
+                let unit = args.next().ok_or(format!("{} needs a note length", arg))?;
+                unit_length = unit.strip_prefix("1/").and_then(|denominator| denominator.parse().ok())
+                    .filter(|denominator: &u32| denominator.is_power_of_two() && *denominator <= 4 * DIVISIONS)
+                    .ok_or(format!("invalid unit note length: {}\n{}", unit, USAGE))?;
+            },
+            _ => rest.push(arg),
+        }
+    }
+
+    let (input, output) = parse_paths(rest, "abc", USAGE)?;
+    let score = read_score(&input)?;
+    std::fs::write(&output, to_abc(&score, unit_length)).map_err(|error| format!("could not write {}: {}", output, error))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::notation::Measure;
+
+    fn element(pitches: Vec<u8>, duration: u32, tie_start: bool) -> Element {
+        Element { pitches, duration, tie_start, tie_stop: false, lyric: None }
+    }
+
+    #[test]
+    fn accidentals_follow_the_key_and_the_bar() {
+        let mut writer = VoiceWriter { unit: 4, key: -2, accidentals: Vec::new() }; // B flat major
+        assert_eq!(writer.pitch(70), "B"); // B flat is in the key
+        assert_eq!(writer.pitch(71), "=B");
+        assert_eq!(writer.pitch(71), "B"); // the natural holds for the rest of the bar
+        assert_eq!(writer.pitch(83), "=b"); // but not in other octaves
+        assert_eq!(writer.pitch(49), "_D,");
+        assert_eq!(writer.pitch(96), "c''");
+
+        assert_eq!(length(4, 4), "");
+        assert_eq!(length(12, 4), "3");
+        assert_eq!(length(2, 4), "/2");
+        assert_eq!(length(6, 4), "3/2");
+        assert_eq!(key_name(-3, true), "Cm");
+        assert_eq!(key_name(4, false), "E");
+    }
+
+    #[test]
+    fn score_is_written_as_a_tune() {
+        let measure = |time, elements| Measure { time, key: None, tempo: None, length: 16, voices: vec![elements] };
+        let part = |track, bass_clef| Part {
+            track,
+            name: format!("Part {}", track),
+            bass_clef,
+            measures: vec![
+                Measure { key: Some((1, false)), ..measure(Some((2, 4)), vec![element(vec![66], 8, false), element(vec![62, 66], 8, true)]) },
+                measure(None, vec![element(vec![62, 66], 12, false), element(vec![], 4, false)]),
+                measure(Some((3, 8)), vec![]),
+            ],
+            truncated: false,
+            cut_short: false,
+        };
+
+        let solo = to_abc(&Score { title: Some(String::from("Reel 100%")), bpm: 132.0, parts: vec![part(1, false)] }, 8);
+        assert_eq!(solo, "X:1\nT:Reel 100\\%\nM:2/4\nL:1/8\nQ:1/4=132\nK:G\nF2 [DF]2- | [DF]3 z | [M:3/8] Z |]\n");
+
+        let duet = to_abc(&Score { title: None, bpm: 120.0, parts: vec![part(1, false), part(3, true)] }, 16);
+        assert!(duet.contains("V:1 name=\"Part 1\"\nV:3 name=\"Part 3\" clef=bass\nK:G\nV:1\nF4 [DF]4- |"));
+        assert!(duet.contains("V:3\nF4"));
+    }
+
+    #[test]
+    fn overlapping_notes_are_written_as_voices_on_one_staff() {
+        let measure = |voices| Measure { time: Some((4, 4)), key: Some((0, false)), tempo: None, length: 32, voices };
+        let part = Part {
+            track: 2,
+            name: String::from("Piano"),
+            bass_clef: false,
+            measures: vec![
+                measure(vec![vec![element(vec![72], 8, false), element(vec![74], 24, false)], vec![element(vec![48], 32, false)]]),
+                Measure { time: None, key: None, ..measure(vec![vec![element(vec![72], 32, false)], vec![]]) },
+            ],
+            truncated: false,
+            cut_short: false,
+        };
+
+        let abc = to_abc(&Score { title: None, bpm: 120.0, parts: vec![part] }, 8);
+        assert!(abc.contains("%%score (2 2-2)\nV:2 name=\"Piano\"\nV:2-2\nK:C\n"));
+        assert!(abc.contains("V:2\nc2 d6 | c8 |]\nV:2-2\nC,8 | X |]\n"));
+    }
+}
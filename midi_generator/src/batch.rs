@@ -0,0 +1,340 @@
+// Batch generation
+// Writes a whole corpus of files into a directory, spread across worker threads
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use crate::{generate_file, manifest, parse_args, GeneratedFile, GeneratorOptions};
+
+/// How many times a file over the size limit is generated again from a new seed before giving up
+const MAX_ATTEMPTS: u32 = 100;
+
+/// Settings of a batch run
+struct Batch {
+    output: String, // directory the files are written into
+    count: u64, // number of files to write
+    jobs: usize, // number of worker threads
+    seed: u64, // master seed the seed of every file is derived from
+    name: String, // naming scheme, see USAGE
+    max_file_size: Option<usize>, // files bigger than this are generated again from another seed
+    max_total_size: Option<u64>, // end the corpus at the first file that brings its size to this many bytes
+    manifest: bool, // write manifest.json describing every file
+    options: GeneratorOptions,
+}
+
+/// Derives the seed of one file from the master seed, by the SplitMix64 mixing function
+///
+/// Each file's seed depends only on the master seed and the file's index, so the files don't depend on
+/// which worker happens to make them.
+fn file_seed(master: u64, index: u64) -> u64 {
+    let mut z = master.wrapping_add(index.wrapping_add(1).wrapping_mul(0x9E37_79B9_7F4A_7C15));
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+/// Fills in the naming scheme for one file
+fn file_name(scheme: &str, index: u64, width: usize, seed: u64, file: &GeneratedFile) -> String {
+    scheme
+        .replace("{index}", &format!("{:0width$}", index, width = width))
+        .replace("{seed}", &seed.to_string())
+        .replace("{format}", &file.header.format.to_string())
+        .replace("{ntracks}", &file.header.ntracks.to_string())
+}
+
+/// Generates the file at `index` of the batch, trying new seeds while it is over the size limit
+fn generate_within_limit(batch: &Batch, index: u64) -> Result<(u64, GeneratedFile), String> {
+    let mut seed = file_seed(batch.seed, index);
+
+    for _ in 0..MAX_ATTEMPTS {
+        let file = generate_file(seed, &batch.options);
+        if batch.max_file_size.is_none_or(|max| file.bytes.len() <= max) {
+            return Ok((seed, file));
+        }
+        seed = file_seed(seed, 0);
+    }
+
+    Err(format!("could not generate file {} within the size limit in {} attempts", index, MAX_ATTEMPTS))
+}
+
+/// Writes one file of the batch along with its fault record, returning its size and its manifest
+fn write_file(batch: &Batch, index: u64, width: usize, seed: u64, file: GeneratedFile) -> Result<(u64, String), String> {
+    let name = file_name(&batch.name, index, width, seed, &file);
+    let path = format!("{}/{}", batch.output, name);
+
+    std::fs::write(&path, &file.bytes).map_err(|error| format!("could not write {}: {}", path, error))?;
+    if !file.faults.is_empty() {
+        let record: String = file.faults.iter().map(|fault| format!("{}\n", fault.name())).collect();
+        std::fs::write(format!("{}.faults", path), record).map_err(|error| format!("could not write {}.faults: {}", path, error))?;
+    }
+
+    let manifest = if batch.manifest { manifest::manifest(&name, seed, &file.header, &file.tracks, &file.faults, &file.bytes) } else { String::new() };
+    Ok((file.bytes.len() as u64, manifest))
+}
+
+/// Where the corpus ends under a size budget
+///
+/// Files are counted against the budget in order of index, so the corpus ends at the same file however the
+/// workers are scheduled. A file generated before all the files ahead of it waits here until it is known
+/// whether it comes before the end.
+#[derive(Default)]
+struct Budget {
+    waiting: BTreeMap<u64, (u64, GeneratedFile)>, // seed and file by index
+    counted: u64, // files before this index have been counted
+    total: u64, // size of the counted files
+    last: Option<u64>, // index of the last file of the corpus, once the budget is spent
+}
+
+impl Budget {
+    /// Takes in a generated file and returns the files that are now known to be part of the corpus
+    fn add(&mut self, max: u64, index: u64, seed: u64, file: GeneratedFile) -> Vec<(u64, u64, GeneratedFile)> {
+        self.waiting.insert(index, (seed, file));
+        let mut ready = Vec::new();
+
+        while self.last.is_none() {
+            let Some((seed, file)) = self.waiting.remove(&self.counted) else { break };
+            self.total += file.bytes.len() as u64;
+            if self.total >= max {
+                self.last = Some(self.counted);
+            }
+            ready.push((self.counted, seed, file));
+            self.counted += 1;
+        }
+
+        if self.last.is_some() {
+            self.waiting.clear(); // past the end of the corpus
+        }
+        ready
+    }
+}
+
+/// Runs a batch on `batch.jobs` worker threads, returning the manifests of the files written in order of index,
+/// along with their total size
+///
+/// Workers take the next index until every file is written, the size budget is spent or one of them fails.
+fn run_batch(batch: &Batch) -> Result<(Vec<String>, u64), String> {
+    let width = (batch.count.saturating_sub(1)).to_string().len().max(6);
+    let next = AtomicU64::new(0);
+    let total = AtomicU64::new(0);
+    let done = AtomicU64::new(0);
+    let budget: Mutex<Budget> = Mutex::new(Budget::default());
+    let results: Mutex<Vec<(u64, String)>> = Mutex::new(Vec::new());
+    let failure: Mutex<Option<String>> = Mutex::new(None);
+    let step = (batch.count / 100).max(1);
+
+    std::thread::scope(|scope| {
+        for _ in 0..batch.jobs.max(1) {
+            scope.spawn(|| loop {
+                if failure.lock().unwrap().is_some() {
+                    return;
+                }
+
+                let index = next.fetch_add(1, Ordering::SeqCst);
+                if index >= batch.count || budget.lock().unwrap().last.is_some_and(|last| index > last) {
+                    return;
+                }
+
+                let ready = generate_within_limit(batch, index).map(|(seed, file)| match batch.max_total_size {
+                    Some(max) => budget.lock().unwrap().add(max, index, seed, file),
+                    None => vec![(index, seed, file)],
+                });
+                let written = ready.and_then(|ready| ready.into_iter().try_for_each(|(index, seed, file)| {
+                    let (size, manifest) = write_file(batch, index, width, seed, file)?;
+                    total.fetch_add(size, Ordering::SeqCst);
+                    results.lock().unwrap().push((index, manifest));
+
+                    let finished = done.fetch_add(1, Ordering::SeqCst) + 1;
+                    if finished.is_multiple_of(step) || finished == batch.count {
+                        eprint!("\r{}/{} files", finished, batch.count);
+                    }
+                    Ok(())
+                }));
+
+                if let Err(error) = written {
+                    failure.lock().unwrap().get_or_insert(error);
+                }
+            });
+        }
+    });
+    eprintln!();
+
+    if let Some(error) = failure.into_inner().unwrap() {
+        return Err(error);
+    }
+
+    let mut results = results.into_inner().unwrap();
+    results.sort_by_key(|(index, _)| *index);
+    Ok((results.into_iter().map(|(_, manifest)| manifest).collect(), total.into_inner()))
+}
+
+pub const USAGE: &str = "\
+usage: midi_generator batch [options] [generator options]
+
+writes a corpus of files into a directory using several worker threads; each file's seed comes from
+the master seed and the file's index, so the same master seed always gives the same corpus
+
+options:
+  -o, --output <dir>       write the files into <dir> (default corpus)
+  -n, --count <n>          number of files to write (default 100)
+  -j, --jobs <n>           number of worker threads (default the number of processors)
+  --seed <n>               master seed (default random)
+  --name <scheme>          file names, filling in {index}, {seed}, {format} and {ntracks}
+                           (default file_{index}.mid), must contain {index} or {seed}
+  --max-file-size <bytes>  generate files bigger than <bytes> again from another seed
+  --max-total-size <bytes> end the corpus at the first file, in order of index, that brings it to <bytes>
+  --manifest               describe every file in <dir>/manifest.json
+
+generator options are any of the options of midi_generator itself, e.g. --lyrics or --corrupt all,
+except --render and --print-duration";
+
+/// Parses a number given on the command line
+fn parse_number<T: std::str::FromStr>(arg: &str, value: Option<String>) -> Result<T, String> {
+    let value = value.ok_or(format!("{} needs a number", arg))?;
+    value.parse().map_err(|_| format!("invalid number for {}: {}", arg, value))
+}
+
+/// Runs the batch command, writing a corpus of generated files into a directory
+pub fn run(args: Vec<String>) -> Result<(), String> {
+    let mut batch = Batch {
+        output: String::from("corpus"),
+        count: 100,
+        jobs: std::thread::available_parallelism().map_or(1, |jobs| jobs.get()),
+        seed: rand::random(),
+        name: String::from("file_{index}.mid"),
+        max_file_size: None,
+        max_total_size: None,
+        manifest: false,
+        options: GeneratorOptions::default(),
+    };
+    let mut generator_args = Vec::new();
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" | "--output" => batch.output = args.next().ok_or(format!("{} needs a directory", arg))?,
+            "-n" | "--count" => batch.count = parse_number(&arg, args.next())?,
+            "-j" | "--jobs" => batch.jobs = parse_number(&arg, args.next())?,
+            "--seed" => batch.seed = parse_number(&arg, args.next())?,
+            "--name" => batch.name = args.next().ok_or(format!("{} needs a naming scheme", arg))?,
+            "--max-file-size" => batch.max_file_size = Some(parse_number(&arg, args.next())?),
+            "--max-total-size" => batch.max_total_size = Some(parse_number(&arg, args.next())?),
+            "--manifest" => batch.manifest = true,
+            _ => generator_args.push(arg),
+        }
+    }
+
+    let generator = parse_args(generator_args.into_iter()).map_err(|error| format!("{}\n{}", error, USAGE))?;
+    if generator.render {
+        return Err(format!("--render can't be used with batch, render files one at a time with midi_generator render\n{}", USAGE));
+    }
+    if generator.print_duration {
+        return Err(format!("--print-duration can't be used with batch, --manifest records the duration of every file\n{}", USAGE));
+    }
+    batch.options = generator.options;
+    if !batch.name.contains("{index}") && !batch.name.contains("{seed}") {
+        return Err(format!("the naming scheme must contain {{index}} or {{seed}}\n{}", USAGE));
+    }
+
+    std::fs::create_dir_all(&batch.output).map_err(|error| format!("could not create {}: {}", batch.output, error))?;
+    let (manifests, total) = run_batch(&batch)?;
+
+    if batch.manifest {
+        let path = format!("{}/manifest.json", batch.output);
+        let entries: Vec<&str> = manifests.iter().map(|manifest| manifest.trim_end()).collect();
+        std::fs::write(&path, format!("[\n{}\n]\n", entries.join(",\n"))).map_err(|error| format!("could not write {}: {}", path, error))?;
+    }
+    println!("wrote {} files ({} bytes) to {} from master seed {}", manifests.len(), total, batch.output, batch.seed);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn batch_into(output: &std::path::Path, jobs: usize) -> Batch {
+        Batch {
+            output: output.to_string_lossy().into_owned(),
+            count: 24,
+            jobs,
+            seed: 1234,
+            name: String::from("f{index}_{format}.mid"),
+            max_file_size: Some(4_000),
+            max_total_size: None,
+            manifest: true,
+            options: GeneratorOptions { provenance: true, ..Default::default() },
+        }
+    }
+
+    fn read_dir(path: &std::path::Path) -> Vec<(String, Vec<u8>)> {
+        let mut files: Vec<(String, Vec<u8>)> = std::fs::read_dir(path).unwrap()
+            .map(|entry| entry.unwrap().path())
+            .map(|file| (file.file_name().unwrap().to_string_lossy().into_owned(), std::fs::read(&file).unwrap()))
+            .collect();
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn corpus_does_not_depend_on_scheduling() {
+        let base = std::env::temp_dir().join(format!("midi_generator_batch_{}", std::process::id()));
+        let (one, four) = (base.join("one"), base.join("four"));
+        std::fs::create_dir_all(&one).unwrap();
+        std::fs::create_dir_all(&four).unwrap();
+
+        let (manifests, _) = run_batch(&batch_into(&one, 1)).unwrap();
+        run_batch(&batch_into(&four, 4)).unwrap();
+
+        let files = read_dir(&one);
+        assert_eq!(manifests.len(), 24);
+        assert_eq!(files.len(), 24);
+        assert_eq!(files, read_dir(&four));
+        assert!(files.iter().all(|(name, bytes)| name.starts_with('f') && bytes.len() <= 4_000));
+
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn size_budget_ends_the_corpus_at_the_same_file() {
+        let base = std::env::temp_dir().join(format!("midi_generator_budget_{}", std::process::id()));
+        let (one, eight) = (base.join("one"), base.join("eight"));
+        std::fs::create_dir_all(&one).unwrap();
+        std::fs::create_dir_all(&eight).unwrap();
+
+        let budgeted = |output: &std::path::Path, jobs| Batch { max_total_size: Some(20_000), ..batch_into(output, jobs) };
+        let (manifests, total) = run_batch(&budgeted(&one, 1)).unwrap();
+        run_batch(&budgeted(&eight, 8)).unwrap();
+
+        let files = read_dir(&one);
+        assert_eq!(files, read_dir(&eight));
+        assert_eq!(files.len(), manifests.len());
+        assert!(files.len() < 24);
+        assert!(files.iter().enumerate().all(|(index, (name, _))| name[1..7].parse::<usize>().unwrap() == index)); // no gaps
+
+        let sizes: Vec<u64> = files.iter().map(|(_, bytes)| bytes.len() as u64).collect();
+        assert_eq!(sizes.iter().sum::<u64>(), total);
+        assert!(total >= 20_000 && total - sizes.last().unwrap() < 20_000); // the last file is the one that spends it
+
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn file_seeds_differ_by_index_and_master() {
+        let seeds: Vec<u64> = (0..1_000).map(|index| file_seed(7, index)).collect();
+        let mut unique = seeds.clone();
+        unique.sort_unstable();
+        unique.dedup();
+
+        assert_eq!(unique.len(), seeds.len());
+        assert_ne!(file_seed(7, 0), file_seed(8, 0));
+    }
+
+    #[test]
+    fn options_the_batch_cannot_honour_are_rejected() {
+        let args = |args: &[&str]| args.iter().map(|arg| String::from(*arg)).collect::<Vec<String>>();
+
+        assert!(run(args(&["-o", "/nonexistent/corpus", "--render"])).unwrap_err().starts_with("--render can't be used with batch"));
+        assert!(run(args(&["-o", "/nonexistent/corpus", "--print-duration"])).unwrap_err().starts_with("--print-duration can't be used"));
+    }
+}
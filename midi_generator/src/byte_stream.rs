@@ -0,0 +1,126 @@
+// Byte stream generation
+// Drives the generator with decisions read from a byte string instead of a random number generator,
+// so that coverage-guided fuzzers can steer which files get built
+
+use rand::RngCore;
+
+use crate::{encode_midi_file, generate_midi_file, GeneratorOptions, MThd, MTrk};
+
+/// A source of "random" numbers that reads them from a byte string
+///
+/// Every pick_random, Uniform and WeightedIndex choice of the generator asks for a number, which is
+/// read from the next bytes of the input. Once the input runs out every number reads as zero.
+/// Uniform sampling always accepts zero, so the generator finishes on any input, however short.
+pub struct ByteStream<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> ByteStream<'a> {
+    pub fn new(data: &'a [u8]) -> ByteStream<'a> {
+        ByteStream {
+            data,
+            pos: 0,
+        }
+    }
+}
+
+impl RngCore for ByteStream<'_> {
+    fn next_u32(&mut self) -> u32 {
+        let mut bytes = [0; 4];
+        self.fill_bytes(&mut bytes);
+        u32::from_le_bytes(bytes)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        let mut bytes = [0; 8];
+        self.fill_bytes(&mut bytes);
+        u64::from_le_bytes(bytes)
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        let rest = &self.data[self.pos..];
+        let count = rest.len().min(dest.len());
+
+        dest[..count].copy_from_slice(&rest[..count]);
+        dest[count..].iter_mut().for_each(|byte| *byte = 0); // past the end of the input
+        self.pos += count;
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+/// Generates the header and every track of a MIDI file from the decisions held in `data`
+///
+/// The same input and options always give the same file, and every input gives a structurally valid one.
+pub fn generate_from_bytes(data: &[u8], options: &GeneratorOptions) -> (MThd, Vec<MTrk>) {
+    generate_midi_file(&mut ByteStream::new(data), options)
+}
+
+pub const USAGE: &str = "\
+usage: midi_generator from-bytes [options] <input file>
+
+options:
+  -o, --output <file>    write the generated file to <file> (default output.mid)
+
+the bytes of <input file> are read as the decisions of the generator, e.g. a fuzzer test case";
+
+/// Runs the from-bytes command, generating a MIDI file from the bytes of an input file
+pub fn run(args: Vec<String>) -> Result<(), String> {
+    let mut output = String::from("output.mid");
+    let mut input = None;
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" | "--output" => output = args.next().ok_or(format!("{} needs a file name", arg))?,
+            _ if arg.starts_with('-') => return Err(format!("unknown argument: {}\n{}", arg, USAGE)),
+            _ if input.is_none() => input = Some(arg),
+            _ => return Err(format!("only one input file can be given\n{}", USAGE)),
+        }
+    }
+
+    let input = input.ok_or(format!("no input file given\n{}", USAGE))?;
+    let data = std::fs::read(&input).map_err(|error| format!("could not read {}: {}", input, error))?;
+
+    let (header, tracks) = generate_from_bytes(&data, &GeneratorOptions::default());
+    std::fs::write(&output, encode_midi_file(&header, &tracks)).map_err(|error| format!("could not write {}: {}", output, error))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reader::parse_midi_file;
+
+    fn generate_bytes(data: &[u8]) -> Vec<u8> {
+        let (header, tracks) = generate_from_bytes(data, &GeneratorOptions::default());
+        encode_midi_file(&header, &tracks)
+    }
+
+    #[test]
+    fn any_input_gives_a_valid_file() {
+        let mut inputs = vec![Vec::new(), vec![0xFF; 64], vec![0x00; 3]];
+        for _ in 0..50 {
+            let mut input = vec![0; (rand::random::<u16>() % 2_048) as usize];
+            rand::thread_rng().fill_bytes(&mut input);
+            inputs.push(input);
+        }
+
+        for input in inputs {
+            let (header, tracks) = parse_midi_file(&generate_bytes(&input)).unwrap();
+            assert_eq!(header.ntracks as usize, tracks.len());
+            assert!(tracks.iter().all(|track| track.data.last().unwrap().1.data == [0xFF, 0x2F, 0x00]));
+        }
+    }
+
+    #[test]
+    fn same_input_gives_same_file() {
+        let input: Vec<u8> = (0..1_000).map(|i| (i * 37 % 251) as u8).collect();
+
+        assert_eq!(generate_bytes(&input), generate_bytes(&input));
+        assert_ne!(generate_bytes(&input), generate_bytes(&input[1..]));
+    }
+}
@@ -0,0 +1,172 @@
+// Boundary-value catalog
+// Writes one small file for each edge case of the format that random generation is unlikely to reach
+
+use crate::{encode_midi_file, DeltaTime, Event, MThd, MTrk};
+
+/// Names of the text-type Meta events, by type
+const TEXT_EVENTS: [(u8, &str); 9] = [
+    (0x01, "text"),
+    (0x02, "copyright"),
+    (0x03, "track-name"),
+    (0x04, "instrument-name"),
+    (0x05, "lyric"),
+    (0x06, "marker"),
+    (0x07, "cue-point"),
+    (0x08, "program-name"),
+    (0x09, "device-name"),
+];
+
+/// The SMPTE frame rates tickdiv can hold
+const SMPTE_FPS: [u8; 4] = [24, 25, 29, 30];
+
+/// A header with the given fields and the standard chunklen
+fn header(format: u16, ntracks: u16, tickdiv: u16) -> MThd {
+    MThd {
+        identifier: [b'M', b'T', b'h', b'd'],
+        chunklen: 6,
+        format,
+        ntracks,
+        tickdiv,
+    }
+}
+
+/// A track holding the given events, all at delta time 0 unless given, closed by an EndOfTrack event
+fn track(events: Vec<(DeltaTime, Event)>) -> MTrk {
+    let mut data = events;
+    data.push((DeltaTime::zero(), Event { data: vec![0xFF, 0x2F, 0x00] }));
+    MTrk::new(data)
+}
+
+/// A single note, played for the given number of ticks
+fn note(length: u32) -> Vec<(DeltaTime, Event)> {
+    vec![
+        (DeltaTime::zero(), Event::new_note_on(0, 60, 100)),
+        (DeltaTime::from_ticks(length), Event::new_note_off(0, 60)),
+    ]
+}
+
+/// A format 0 file with a single track of the given events, at 96 ticks per quarter note
+fn single_track(events: Vec<(DeltaTime, Event)>) -> (MThd, Vec<MTrk>) {
+    (header(0, 1, 96), vec![track(events)])
+}
+
+/// Returns every edge case with a file name describing it
+///
+/// The catalog covers:
+/// * Delta times of 0 and 0x0FFFFFFF, the largest a 4 byte variable-length quantity holds
+/// * Tempos of 1 and 0xFFFFFF microseconds per quarter note
+/// * Metrical tickdiv of 1 and 0x7FFF ticks per quarter note
+/// * Every SMPTE frame rate with every sub-frame resolution
+/// * ntracks of 0 and 65535
+/// * Key signatures of 7 flats and 7 sharps, major and minor
+/// * A track with only EndOfTrack in it and a track with no events at all
+/// * Every text-type Meta event with no text
+/// * Pitch bend at its lowest, centre and highest values
+pub fn edge_cases() -> Vec<(String, MThd, Vec<MTrk>)> {
+    let mut cases = Vec::new();
+    let mut add = |name: String, (header, tracks): (MThd, Vec<MTrk>)| cases.push((name, header, tracks));
+
+    add(String::from("delta-time-0"), single_track(note(0)));
+    add(String::from("delta-time-0x0fffffff"), single_track(note(0x0FFF_FFFF)));
+
+    add(String::from("tempo-1"), single_track([vec![(DeltaTime::zero(), Event::new_tempo(1))], note(96)].concat()));
+    add(String::from("tempo-0xffffff"), single_track([vec![(DeltaTime::zero(), Event::new_tempo(0xFF_FFFF))], note(96)].concat()));
+
+    add(String::from("ppqn-1"), (header(0, 1, 1), vec![track(note(1))]));
+    add(String::from("ppqn-32767"), (header(0, 1, 0x7FFF), vec![track(note(0x7FFF))]));
+
+    for fps in SMPTE_FPS.iter().copied() {
+        for subframes in 0..=255u8 {
+            let tickdiv = ((fps as i8).wrapping_neg() as u8 as u16) << 8 | subframes as u16;
+            add(format!("smpte-{}fps-{}-subframes", fps, subframes), (header(0, 1, tickdiv), vec![track(note(subframes as u32))]));
+        }
+    }
+
+    add(String::from("ntracks-0"), (header(1, 0, 96), Vec::new()));
+    add(String::from("ntracks-65535"), (header(1, 0xFFFF, 96), (0..0xFFFF).map(|_| track(Vec::new())).collect()));
+
+    for (sf, key) in [(-7i8, "7-flats"), (7, "7-sharps")] {
+        for (mi, mode) in [(0, "major"), (1, "minor")] {
+            let signature = Event { data: vec![0xFF, 0x59, 0x02, sf as u8, mi] };
+            add(format!("key-signature-{}-{}", key, mode), single_track([vec![(DeltaTime::zero(), signature)], note(96)].concat()));
+        }
+    }
+
+    add(String::from("empty-track"), single_track(Vec::new()));
+    add(String::from("track-without-events"), (header(0, 1, 96), vec![MTrk::new(Vec::new())]));
+
+    for (meta_type, name) in TEXT_EVENTS.iter().copied() {
+        add(format!("empty-{}", name), single_track(vec![(DeltaTime::zero(), Event::new_text_event(meta_type, b""))]));
+    }
+
+    for value in [0u16, 0x2000, 0x3FFF] {
+        let bend = Event { data: vec![0xE0, (value & 0x7F) as u8, (value >> 7) as u8] };
+        add(format!("pitch-bend-0x{:04x}", value), single_track([vec![(DeltaTime::zero(), bend)], note(96)].concat()));
+    }
+
+    cases
+}
+
+pub const USAGE: &str = "\
+usage: midi_generator catalog [options]
+
+writes one small file for each edge case of the format, named after it
+
+options:
+  -o, --output <dir>     write the files into <dir> (default catalog)";
+
+/// Runs the catalog command, writing every edge case into a directory
+pub fn run(args: Vec<String>) -> Result<(), String> {
+    let mut output = String::from("catalog");
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" | "--output" => output = args.next().ok_or(format!("{} needs a directory", arg))?,
+            _ => return Err(format!("unknown argument: {}\n{}", arg, USAGE)),
+        }
+    }
+
+    std::fs::create_dir_all(&output).map_err(|error| format!("could not create {}: {}", output, error))?;
+
+    let cases = edge_cases();
+    for (name, header, tracks) in &cases {
+        let path = format!("{}/{}.mid", output, name);
+        std::fs::write(&path, encode_midi_file(header, tracks)).map_err(|error| format!("could not write {}: {}", path, error))?;
+    }
+    println!("{} files written to {}", cases.len(), output);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reader::parse_midi_file;
+
+    #[test]
+    fn every_case_is_a_readable_file_with_a_unique_name() {
+        let cases = edge_cases();
+        let mut names: Vec<&String> = cases.iter().map(|(name, _, _)| name).collect();
+        names.sort();
+        names.dedup();
+        assert_eq!(names.len(), cases.len());
+
+        for (name, header, tracks) in &cases {
+            let (read_header, read_tracks) = parse_midi_file(&encode_midi_file(header, tracks)).unwrap();
+            assert_eq!(read_header.ntracks as usize, read_tracks.len(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn extremes_are_encoded_as_specified() {
+        let cases = edge_cases();
+        let find = |name: &str| cases.iter().find(|(case, _, _)| case == name).unwrap();
+
+        assert_eq!(find("delta-time-0x0fffffff").2[0].data[1].0.data, vec![0xFF, 0xFF, 0xFF, 0x7F]);
+        assert_eq!(find("tempo-0xffffff").2[0].data[0].1.data, vec![0xFF, 0x51, 0x03, 0xFF, 0xFF, 0xFF]);
+        assert_eq!(find("smpte-29fps-80-subframes").1.tickdiv, 0xE350);
+        assert_eq!(find("pitch-bend-0x3fff").2[0].data[0].1.data, vec![0xE0, 0x7F, 0x7F]);
+        assert_eq!(cases.iter().filter(|(name, _, _)| name.starts_with("smpte-")).count(), 4 * 256);
+    }
+}
@@ -0,0 +1,115 @@
+// Foreign chunks
+// Adds chunks of unknown type and padding past the end of the MThd data, which readers are required to skip
+
+use rand::RngCore;
+use rand_distr::{Distribution, Uniform};
+
+use crate::encode_vlq;
+
+const ID_CHARACTERS: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789 _-";
+
+/// Makes up a four-character chunk type that is neither MThd nor MTrk
+fn random_identifier(rng: &mut dyn RngCore) -> [u8; 4] {
+    let characters = Uniform::from(0..ID_CHARACTERS.len());
+
+    loop {
+        let mut identifier = [0; 4];
+        identifier.iter_mut().for_each(|c| *c = ID_CHARACTERS[characters.sample(rng)]);
+        if &identifier != b"MThd" && &identifier != b"MTrk" {
+            return identifier;
+        }
+    }
+}
+
+/// Returns between 0 and `max` random bytes
+fn random_bytes(rng: &mut dyn RngCore, max: u32) -> Vec<u8> {
+    let length = Uniform::from(0..=max).sample(rng);
+    (0..length).map(|_| Uniform::from(0..=255).sample(rng)).collect()
+}
+
+/// Returns a whole chunk with a random type and payload
+///
+/// Some payloads look like track data, a delta time and a Meta event, to tempt readers that only check for MTrk loosely.
+fn random_chunk(rng: &mut dyn RngCore) -> Vec<u8> {
+    let payload = if Uniform::from(0..4).sample(rng) == 0 {
+        let text = random_bytes(rng, 32);
+        let mut payload = vec![0x00, 0xFF, 0x01];
+        payload.extend(encode_vlq(text.len() as u32));
+        payload.extend(text);
+        payload
+    }
+    else {
+        random_bytes(rng, 256)
+    };
+
+    let mut chunk = random_identifier(rng).to_vec();
+    chunk.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    chunk.extend(payload);
+    chunk
+}
+
+/// Inserts 1..5 foreign chunks into an encoded file, and half of the time pads the MThd data past its 6 bytes
+///
+/// The chunks go at random chunk boundaries: ahead of the first MTrk, between two of them or after the last.
+/// `chunk_lengths` are the sizes of the chunks following MThd in `bytes`, as written. If the file was cut short
+/// nothing goes after its last chunk, so that the chunk being cut stays the last one.
+pub fn insert_foreign_chunks(rng: &mut dyn RngCore, mut bytes: Vec<u8>, chunk_lengths: &[usize]) -> Vec<u8> {
+    let mut boundaries = vec![14];
+    for length in chunk_lengths {
+        boundaries.push(boundaries.last().unwrap() + length);
+    }
+    boundaries.retain(|&boundary| boundary <= bytes.len()); // a chunk cut short has no end to go after
+
+    let mut insertions: Vec<usize> = (0..Uniform::from(1..6).sample(rng))
+        .map(|_| boundaries[Uniform::from(0..boundaries.len()).sample(rng)])
+        .collect();
+    insertions.sort_unstable_by(|a, b| b.cmp(a)); // from the back, so earlier boundaries stay where they are
+
+    for boundary in insertions {
+        let chunk = random_chunk(rng);
+        bytes.splice(boundary..boundary, chunk);
+    }
+
+    if Uniform::from(0..2).sample(rng) == 0 {
+        let padding = random_bytes(rng, 31);
+        let trailing = if padding.is_empty() { vec![0] } else { padding };
+        bytes[4..8].copy_from_slice(&(6 + trailing.len() as u32).to_be_bytes());
+        bytes.splice(14..14, trailing);
+    }
+
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{encode_midi_file, generate_midi_file, reader, GeneratorOptions};
+
+    #[test]
+    fn readers_that_skip_foreign_chunks_see_the_same_file() {
+        let rng = &mut rand::thread_rng();
+
+        for _ in 0..20 {
+            let (header, tracks) = generate_midi_file(rng, &GeneratorOptions::default());
+            let bytes = encode_midi_file(&header, &tracks);
+            let lengths: Vec<usize> = tracks.iter().map(|track| track.to_bytes().len()).collect();
+
+            let with_chunks = insert_foreign_chunks(rng, bytes.clone(), &lengths);
+            assert!(with_chunks.len() > bytes.len());
+
+            let (read_header, read_tracks) = reader::parse_midi_file(&with_chunks).unwrap();
+            assert_eq!(encode_midi_file(&read_header, &read_tracks), bytes);
+        }
+    }
+
+    #[test]
+    fn identifiers_are_never_known_chunk_types() {
+        let rng = &mut rand::thread_rng();
+
+        for _ in 0..1_000 {
+            let identifier = random_identifier(rng);
+            assert!(identifier.iter().all(|c| ID_CHARACTERS.contains(c)));
+            assert!(&identifier != b"MThd" && &identifier != b"MTrk");
+        }
+    }
+}
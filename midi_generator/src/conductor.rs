@@ -0,0 +1,182 @@
+// Conductor track
+// Shapes the Tempo events of the global tempo track into sections joined by tempo gestures:
+// gradual ramps, sudden changes at section boundaries and fermata-like holds
+
+use rand::RngCore;
+use rand_distr::{Distribution, Uniform};
+
+use crate::{Event, GeneratorOptions};
+
+/// Tempo range used when none is given, matching 100000..5000000 microseconds per quarter note
+pub const DEFAULT_BPM_RANGE: (f64, f64) = (12.0, 600.0);
+
+const TICKS_PER_QUARTER: u32 = 96; // the metrical tickdiv MThd::new uses
+const RAMP_STEP: u32 = TICKS_PER_QUARTER / 8; // ramps move the tempo every 32nd note
+
+#[derive(Debug, Copy, Clone)]
+/// Enum defining how the tempo of a section leads into the next section
+///
+/// Use TempoGesture::pick_random(rng) to randomly choose a TempoGesture with uniform distribution
+enum TempoGesture {
+    Steady, // no tempo change
+    Sudden, // jump to a new tempo at the end of the section
+    Accelerando, // ramp up to a faster tempo over the section
+    Ritardando, // ramp down to a slower tempo over the section
+    Fermata, // hold the last beat of the section at a much slower tempo, then carry on
+}
+
+impl TempoGesture {
+    /// Returns a random TempoGesture using a Uniform distribution
+    fn pick_random(rng: &mut dyn RngCore) -> TempoGesture {
+        let temp = Uniform::from(0..5).sample(rng) as u32;
+        match temp {
+            0 => TempoGesture::Steady,
+            1 => TempoGesture::Sudden,
+            2 => TempoGesture::Accelerando,
+            3 => TempoGesture::Ritardando,
+            4 => TempoGesture::Fermata,
+            _ => panic!("Error when picking random TempoGesture. Number out of bounds.")
+        }
+    }
+}
+
+/// Converts beats per minute to microseconds per quarter note, clamped to what fits in a Tempo event
+pub fn bpm_to_tempo(bpm: f64) -> u32 {
+    (60_000_000.0 / bpm).round().clamp(1.0, 0xFF_FFFF as f64) as u32
+}
+
+/// Returns the BPM range of the options, or the default range if none is set
+fn bpm_range(options: &GeneratorOptions) -> (f64, f64) {
+    options.bpm_range.unwrap_or(DEFAULT_BPM_RANGE)
+}
+
+/// Picks a random tempo in microseconds per quarter note, within the BPM range of the options
+pub fn random_tempo(rng: &mut dyn RngCore, options: &GeneratorOptions) -> u32 {
+    let (lower, upper) = bpm_range(options);
+
+    Uniform::from(bpm_to_tempo(upper)..=bpm_to_tempo(lower)).sample(rng)
+}
+
+/// Picks a random tempo in beats per minute, within the BPM range of the options
+fn random_bpm(rng: &mut dyn RngCore, options: &GeneratorOptions) -> f64 {
+    let (lower, upper) = bpm_range(options);
+
+    (60_000_000.0 / random_tempo(rng, options) as f64).clamp(lower, upper)
+}
+
+/// Parses a BPM range given on the command line as "<min>-<max>", e.g. "60-180"
+pub fn parse_bpm_range(text: &str) -> Result<(f64, f64), String> {
+    let error = || format!("invalid BPM range: {}", text);
+
+    let (lower, upper) = text.split_once('-').ok_or_else(error)?;
+    let lower: f64 = lower.trim().parse().map_err(|_| error())?;
+    let upper: f64 = upper.trim().parse().map_err(|_| error())?;
+
+    if !(lower > 0.0 && lower <= upper && upper.is_finite()) {
+        return Err(error());
+    }
+
+    Ok((lower, upper))
+}
+
+/// Returns a dense series of Tempo events moving linearly from `from` to `to` beats per minute
+///
+/// The first event comes one step after `start` and the last lands on `start + length` at exactly `to`.
+fn ramp(from: f64, to: f64, start: u32, length: u32) -> Vec<(u32, Event)> {
+    let nsteps = (length / RAMP_STEP).max(1);
+
+    (1..=nsteps).map(|step| {
+        let bpm = from + (to - from) * step as f64 / nsteps as f64;
+        (start + length * step / nsteps, Event::new_tempo(bpm_to_tempo(bpm)))
+    }).collect()
+}
+
+/// Generates the Tempo events of a conductor track, stamped with absolute ticks
+///
+/// The track is split into 2..8 sections of 4..32 quarter notes, each ending with a random TempoGesture.
+/// The first event is always the opening Tempo at tick 0.
+///
+/// Returns the events along with the tick the last section ends on.
+pub fn generate_tempo_changes(rng: &mut dyn RngCore, options: &GeneratorOptions) -> (Vec<(u32, Event)>, u32) {
+    let (lower, upper) = bpm_range(options);
+
+    let mut bpm = random_bpm(rng, options);
+    let mut events = vec![(0, Event::new_tempo(bpm_to_tempo(bpm)))];
+    let mut tick = 0;
+
+    let nsections = Uniform::from(2..9).sample(rng);
+    for _ in 0..nsections {
+        let length = Uniform::from(4..33).sample(rng) * TICKS_PER_QUARTER;
+
+        match TempoGesture::pick_random(rng) {
+            TempoGesture::Steady => (),
+            TempoGesture::Sudden => {
+                bpm = random_bpm(rng, options);
+                events.push((tick + length, Event::new_tempo(bpm_to_tempo(bpm))));
+            },
+            TempoGesture::Accelerando => {
+                let target = Uniform::from(bpm..=upper).sample(rng);
+                events.extend(ramp(bpm, target, tick, length));
+                bpm = target;
+            },
+            TempoGesture::Ritardando => {
+                let target = Uniform::from(lower..=bpm).sample(rng);
+                events.extend(ramp(bpm, target, tick, length));
+                bpm = target;
+            },
+            TempoGesture::Fermata => {
+                events.push((tick + length - TICKS_PER_QUARTER, Event::new_tempo(bpm_to_tempo((bpm / 4.0).max(lower)))));
+                events.push((tick + length, Event::new_tempo(bpm_to_tempo(bpm))));
+            },
+        }
+
+        tick += length;
+    }
+
+    (events, tick)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tempo_of(event: &Event) -> u32 {
+        ((event.data[3] as u32) << 16) | ((event.data[4] as u32) << 8) | event.data[5] as u32
+    }
+
+    #[test]
+    fn ramps_move_steadily_to_target() {
+        let events = ramp(60.0, 120.0, 96, 4 * TICKS_PER_QUARTER);
+        let tempos: Vec<u32> = events.iter().map(|(_, event)| tempo_of(event)).collect();
+
+        assert_eq!(events.len(), (4 * TICKS_PER_QUARTER / RAMP_STEP) as usize);
+        assert!(tempos.windows(2).all(|pair| pair[1] < pair[0]));
+        assert_eq!(*tempos.last().unwrap(), 500_000);
+        assert_eq!(events.last().unwrap().0, 96 + 4 * TICKS_PER_QUARTER);
+    }
+
+    #[test]
+    fn tempo_changes_stay_in_bpm_range() {
+        let rng = &mut rand::thread_rng();
+        let options = GeneratorOptions { bpm_range: Some((60.0, 180.0)), ..Default::default() };
+
+        for _ in 0..100 {
+            let (events, length) = generate_tempo_changes(rng, &options);
+
+            assert_eq!(events[0].0, 0);
+            for (tick, event) in &events {
+                assert!(*tick <= length);
+                assert!((bpm_to_tempo(180.0)..=bpm_to_tempo(60.0)).contains(&tempo_of(event)));
+            }
+        }
+    }
+
+    #[test]
+    fn bpm_range_parses_and_validates() {
+        assert_eq!(parse_bpm_range("60-180"), Ok((60.0, 180.0)));
+        assert_eq!(parse_bpm_range("72.5-72.5"), Ok((72.5, 72.5)));
+        assert!(parse_bpm_range("180-60").is_err());
+        assert!(parse_bpm_range("0-60").is_err());
+        assert!(parse_bpm_range("fast").is_err());
+    }
+}
@@ -0,0 +1,253 @@
+// Malformed file generation
+// Injects named classes of faults into a generated file so that MIDI readers can be fuzzed with broken input
+
+use rand::RngCore;
+use rand_distr::{Distribution, Uniform};
+
+use crate::{encode_midi_file, encode_vlq, DeltaTime, Event, MThd, MTrk};
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+/// Enum defining every class of fault that can be injected into a file
+pub enum Fault {
+    TruncatedChunk, // the file ends part way through the last chunk
+    ChunkLenMismatch, // an MTrk chunklen disagrees with the size of its data
+    MissingEndOfTrack, // a track doesn't finish with an EndOfTrack event
+    HighBitData, // a channel event data byte has bit 7 set
+    LongVlq, // a delta time is padded out past the 4 bytes the spec allows
+    OrphanedRunningStatus, // channel event data bytes with no status byte to run on from
+    UnterminatedSysEx, // a SysEx event whose data doesn't end with F7
+    WrongNtracks, // the ntracks field of MThd doesn't match the number of MTrk chunks
+}
+
+impl Fault {
+    /// Every fault class, in the order they are applied
+    pub const ALL: [Fault; 8] = [
+        Fault::MissingEndOfTrack,
+        Fault::HighBitData,
+        Fault::LongVlq,
+        Fault::OrphanedRunningStatus,
+        Fault::UnterminatedSysEx,
+        Fault::ChunkLenMismatch,
+        Fault::WrongNtracks,
+        Fault::TruncatedChunk,
+    ];
+
+    /// Returns the name of the fault as used on the command line and in fault records
+    pub fn name(&self) -> &'static str {
+        match self {
+            Fault::TruncatedChunk => "truncated-chunk",
+            Fault::ChunkLenMismatch => "chunklen-mismatch",
+            Fault::MissingEndOfTrack => "missing-end-of-track",
+            Fault::HighBitData => "high-bit-data",
+            Fault::LongVlq => "long-vlq",
+            Fault::OrphanedRunningStatus => "orphaned-running-status",
+            Fault::UnterminatedSysEx => "unterminated-sysex",
+            Fault::WrongNtracks => "wrong-ntracks",
+        }
+    }
+
+    /// Parses the name of a fault
+    pub fn from_name(name: &str) -> Option<Fault> {
+        Fault::ALL.iter().copied().find(|fault| fault.name() == name)
+    }
+}
+
+/// Parses the list of faults given on the command line, either "all" or comma separated fault names
+pub fn parse_faults(text: &str) -> Result<Vec<Fault>, String> {
+    if text == "all" {
+        return Ok(Fault::ALL.to_vec());
+    }
+
+    text.split(',')
+        .map(|name| Fault::from_name(name.trim()).ok_or(format!("unknown fault: {}", name)))
+        .collect()
+}
+
+/// Picks a random index into a collection of the given (non-zero) length
+fn random_index(rng: &mut dyn RngCore, len: usize) -> usize {
+    Uniform::from(0..len).sample(rng)
+}
+
+/// Applies a fault that works on the events of a track, returning false if no track had anywhere to put it
+fn inject_event_fault(rng: &mut dyn RngCore, tracks: &mut [MTrk], fault: Fault) -> bool {
+    // start from a random track and move on to the next one if the fault has nowhere to go
+    let first = random_index(rng, tracks.len());
+    for offset in 0..tracks.len() {
+        let index = (first + offset) % tracks.len();
+        let mut data = std::mem::take(&mut tracks[index].data);
+
+        let applied = match fault {
+            Fault::MissingEndOfTrack => {
+                let ends_track = data.last().is_some_and(|(_, event)| event.data == [0xFF, 0x2F, 0x00]);
+                if ends_track {
+                    data.pop();
+                }
+                ends_track
+            },
+            Fault::HighBitData => {
+                let channel_events: Vec<usize> = (0..data.len())
+                    .filter(|&i| (0x80..0xF0).contains(&data[i].1.data[0]) && data[i].1.data.len() > 1)
+                    .collect();
+                if !channel_events.is_empty() {
+                    let event = &mut data[channel_events[random_index(rng, channel_events.len())]].1;
+                    let byte = 1 + random_index(rng, event.data.len() - 1);
+                    event.data[byte] |= 0x80;
+                }
+                !channel_events.is_empty()
+            },
+            Fault::LongVlq => {
+                if !data.is_empty() {
+                    // leading 0x80 bytes add nothing to the value, only to the length
+                    let pair = random_index(rng, data.len());
+                    let delta_time = &mut data[pair].0;
+                    let value = encode_vlq(delta_time.ticks());
+                    let padding = Uniform::from(5..9).sample(rng) - value.len();
+                    delta_time.data = vec![0x80; padding];
+                    delta_time.data.extend(value);
+                }
+                !data.is_empty()
+            },
+            Fault::OrphanedRunningStatus => {
+                // running status is cancelled by Meta and SysEx events, so data bytes right after one have no status to use
+                let positions: Vec<usize> = (0..data.len().max(1))
+                    .filter(|&i| i == 0 || data[i - 1].1.data[0] >= 0xF0)
+                    .collect();
+                let position = positions[random_index(rng, positions.len())];
+                let orphan = Event {
+                    data: vec![Uniform::from(0..128).sample(rng) as u8, Uniform::from(0..128).sample(rng) as u8],
+                };
+                data.insert(position, (DeltaTime::zero(), orphan));
+                true
+            },
+            Fault::UnterminatedSysEx => {
+                let length = Uniform::from(1..64).sample(rng);
+                let mut sysex = vec![0xF0];
+                sysex.extend(encode_vlq(length));
+                for _ in 0..length {
+                    sysex.push(Uniform::from(0..128).sample(rng) as u8); // never F7, which would terminate it
+                }
+                let position = random_index(rng, data.len().max(1)); // ahead of the last event, to keep EndOfTrack last
+                data.insert(position, (DeltaTime::zero(), Event { data: sysex }));
+                true
+            },
+            _ => panic!("Error when injecting fault. {} is not an event fault.", fault.name())
+        };
+
+        tracks[index] = MTrk::new(data);
+        if applied {
+            return true;
+        }
+    }
+
+    false
+}
+
+/// Makes a generated file malformed and returns its bytes along with the faults that were injected
+///
+/// Each of the given faults is injected with a probability of one half, and at least one always is.
+/// Faults that have nowhere to go in this file (e.g. HighBitData with no channel events) are left out of the record.
+pub fn corrupt(rng: &mut dyn RngCore, header: &mut MThd, tracks: &mut [MTrk], faults: &[Fault]) -> (Vec<u8>, Vec<Fault>) {
+    let mut chosen: Vec<Fault> = faults.iter().copied().filter(|_| Uniform::from(0..2).sample(rng) == 0).collect();
+    if chosen.is_empty() && !faults.is_empty() {
+        chosen.push(faults[random_index(rng, faults.len())]);
+    }
+
+    let mut injected = Vec::new();
+
+    for fault in Fault::ALL.iter().copied().filter(|fault| chosen.contains(fault)) {
+        let applied = match fault {
+            Fault::ChunkLenMismatch => {
+                let track = &mut tracks[random_index(rng, tracks.len())];
+                let error = Uniform::from(1..17).sample(rng);
+                track.chunklen = if track.chunklen > error && Uniform::from(0..2).sample(rng) == 0 {
+                    track.chunklen - error
+                }
+                else {
+                    track.chunklen + error
+                };
+                true
+            },
+            Fault::WrongNtracks => {
+                let actual = tracks.len() as u16;
+                let wrong: Vec<u16> = [0, actual.wrapping_sub(1), actual + 1, actual + Uniform::from(2..10).sample(rng), 0xFFFF]
+                    .iter().copied().filter(|&n| n != actual).collect();
+                header.ntracks = wrong[random_index(rng, wrong.len())];
+                true
+            },
+            Fault::TruncatedChunk => true, // cut from the bytes below, once everything else is in place
+            _ => inject_event_fault(rng, tracks, fault),
+        };
+
+        if applied {
+            injected.push(fault);
+        }
+    }
+
+    let mut bytes = encode_midi_file(header, tracks);
+
+    if injected.contains(&Fault::TruncatedChunk) {
+        let last_chunk = tracks.last().map_or(14, |track| track.to_bytes().len());
+        let start = bytes.len() - last_chunk;
+        bytes.truncate(start + 1 + random_index(rng, last_chunk - 1)); // keep at least the first byte of the chunk
+    }
+
+    (bytes, injected)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{generate_midi_file, GeneratorOptions};
+
+    fn corrupt_with(fault: Fault) -> (MThd, Vec<MTrk>, Vec<u8>) {
+        let rng = &mut rand::thread_rng();
+        let (mut header, mut tracks) = generate_midi_file(rng, &GeneratorOptions::default());
+        let (bytes, injected) = corrupt(rng, &mut header, &mut tracks, &[fault]);
+        assert_eq!(injected, vec![fault]);
+        (header, tracks, bytes)
+    }
+
+    #[test]
+    fn each_fault_leaves_its_mark() {
+        for _ in 0..20 {
+            let (header, tracks, bytes) = corrupt_with(Fault::WrongNtracks);
+            assert_ne!(header.ntracks as usize, tracks.len());
+            assert_eq!(bytes.len(), encode_midi_file(&header, &tracks).len());
+
+            let (header, tracks, bytes) = corrupt_with(Fault::TruncatedChunk);
+            assert!(bytes.len() < encode_midi_file(&header, &tracks).len());
+
+            let (_, tracks, _) = corrupt_with(Fault::MissingEndOfTrack);
+            assert!(tracks.iter().any(|track| track.data.last().is_none_or(|(_, event)| event.data != [0xFF, 0x2F, 0x00])));
+
+            let (_, tracks, _) = corrupt_with(Fault::LongVlq);
+            assert!(tracks.iter().any(|track| track.data.iter().any(|(delta_time, _)| delta_time.data.len() > 4)));
+
+            let (_, tracks, _) = corrupt_with(Fault::UnterminatedSysEx);
+            assert!(tracks.iter().any(|track| track.data.iter().any(|(_, event)| event.data[0] == 0xF0 && *event.data.last().unwrap() != 0xF7)));
+
+            let (_, tracks, _) = corrupt_with(Fault::OrphanedRunningStatus);
+            assert!(tracks.iter().any(|track| track.data.iter().any(|(_, event)| event.data[0] < 0x80)));
+
+            let (_, tracks, _) = corrupt_with(Fault::ChunkLenMismatch);
+            assert!(tracks.iter().any(|track| track.chunklen as usize != track.to_bytes().len() - 8));
+        }
+    }
+
+    #[test]
+    fn long_vlq_keeps_its_value() {
+        let rng = &mut rand::thread_rng();
+        let mut tracks = vec![MTrk::new(vec![(DeltaTime::from_ticks(300), Event::new_note_on(0, 60, 100))])];
+
+        assert!(inject_event_fault(rng, &mut tracks, Fault::LongVlq));
+        assert!(tracks[0].data[0].0.data.len() > 4);
+        assert_eq!(tracks[0].data[0].0.ticks(), 300);
+    }
+
+    #[test]
+    fn fault_lists_parse() {
+        assert_eq!(parse_faults("all").unwrap().len(), 8);
+        assert_eq!(parse_faults("long-vlq, wrong-ntracks"), Ok(vec![Fault::LongVlq, Fault::WrongNtracks]));
+        assert!(parse_faults("long-vlq,bogus").is_err());
+    }
+}
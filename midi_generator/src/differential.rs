@@ -0,0 +1,307 @@
+// Differential testing
+// Feeds generated files to several local programs that dump MIDI events as text, and saves every file
+// on which their normalized outputs disagree with each other or with the events the generator wrote
+
+use std::time::Duration;
+
+use crate::{encode_midi_file, generate_midi_file, process, GeneratorOptions, MTrk};
+
+/// How a program handled one file
+#[derive(Debug, Clone, PartialEq)]
+struct Outcome {
+    status: String, // how the program finished, e.g. "exit 0" or "timed out"
+    lines: Vec<String>, // normalized output
+}
+
+/// Normalizes the text a program printed, so that differences in layout don't count as disagreements
+///
+/// Each line is split into tokens at whitespace and commas, then tokens are lowercased and lose any 0x prefix.
+/// Blank lines are left out.
+fn normalize(output: &[u8]) -> Vec<String> {
+    String::from_utf8_lossy(output)
+        .lines()
+        .map(|line| {
+            line.split(|c: char| c.is_whitespace() || c == ',')
+                .filter(|token| !token.is_empty())
+                .map(|token| {
+                    let token = token.to_lowercase();
+                    token.strip_prefix("0x").map(String::from).unwrap_or(token)
+                })
+                .collect::<Vec<String>>()
+                .join(" ")
+        })
+        .filter(|line| !line.is_empty())
+        .collect()
+}
+
+/// Lists every event the generator wrote, one per line as "<track> <tick> <event bytes>"
+///
+/// Track numbers count from 0, ticks are absolute and in decimal, and the event bytes are in hex with running status
+/// filled in, e.g. "1 480 90 3c 64". Programs that print the same are checked against this list.
+fn ground_truth(tracks: &[MTrk]) -> Vec<String> {
+    tracks.iter().enumerate().flat_map(|(index, track)| {
+        track.timed_events().into_iter().map(move |(tick, event)| {
+            let bytes: Vec<String> = event.data.iter().map(|byte| format!("{:02x}", byte)).collect();
+            format!("{} {} {}", index, tick, bytes.join(" "))
+        })
+    }).collect()
+}
+
+/// Whether every line of normalized output reads as an event in the form of the ground truth
+///
+/// Output that is empty, or in any other form, comes from a program that can't be checked against the generator.
+fn lists_events(lines: &[String]) -> bool {
+    !lines.is_empty() && lines.iter().all(|line| {
+        let tokens: Vec<&str> = line.split(' ').collect();
+        tokens.len() >= 3
+            && tokens[..2].iter().all(|token| token.parse::<u64>().is_ok())
+            && tokens[2..].iter().all(|token| token.len() == 2 && u8::from_str_radix(token, 16).is_ok())
+    })
+}
+
+/// Describes the first line where `actual` differs from `expected`, if it does
+fn first_difference(expected: &[String], actual: &[String]) -> Option<String> {
+    let line = (0..expected.len().max(actual.len())).find(|&i| expected.get(i) != actual.get(i))?;
+    let show = |text: Option<&String>| text.map_or(String::from("<end of output>"), |text| format!("\"{}\"", text));
+
+    Some(format!("at line {}: expected {}, got {}", line + 1, show(expected.get(line)), show(actual.get(line))))
+}
+
+/// Compares the outcomes of the programs with each other and, if given, with the generator's events
+///
+/// Only programs whose output lists events in the form of the ground truth are checked against it.
+/// Returns a report if anything disagrees, or None if every program printed the same.
+fn compare(commands: &[String], outcomes: &[Outcome], truth: Option<&[String]>) -> Option<String> {
+    let agree = outcomes.iter().all(|outcome| *outcome == outcomes[0]);
+    let match_truth = truth.is_none_or(|truth| {
+        outcomes.iter().filter(|outcome| lists_events(&outcome.lines)).all(|outcome| outcome.status == "exit 0" && outcome.lines == truth)
+    });
+    if agree && match_truth {
+        return None;
+    }
+
+    let mut report = String::new();
+    if let Some(truth) = truth {
+        report += &format!("the generator wrote {} events\n", truth.len());
+    }
+
+    for (index, (command, outcome)) in commands.iter().zip(outcomes).enumerate() {
+        report += &format!("\nprogram {}: {}\n  {}, {} lines of output\n", index + 1, command, outcome.status, outcome.lines.len());
+
+        if let Some(truth) = truth {
+            match first_difference(truth, &outcome.lines) {
+                _ if !lists_events(&outcome.lines) => report += "  doesn't list events as <track> <tick> <bytes>, so isn't checked against them\n",
+                Some(difference) => report += &format!("  differs from the generator's events {}\n", difference),
+                None => report += "  matches the generator's events\n",
+            }
+        }
+        if index > 0 {
+            match first_difference(&outcomes[0].lines, &outcome.lines) {
+                Some(difference) => report += &format!("  differs from program 1 {}\n", difference),
+                None if outcome.status != outcomes[0].status => report += "  prints the same as program 1 but finishes differently\n",
+                None => report += "  matches program 1\n",
+            }
+        }
+    }
+
+    Some(report)
+}
+
+/// Runs a program on a file and normalizes what it printed
+fn run_program(command: &[String], path: &str, timeout: Duration) -> Result<Outcome, String> {
+    let outcome = match process::run_with_timeout(&process::command_for(command, path), timeout)? {
+        Some(run) => Outcome {
+            status: run.status.code().map_or(String::from("killed by a signal"), |code| format!("exit {}", code)),
+            lines: normalize(&run.stdout),
+        },
+        None => Outcome {
+            status: String::from("timed out"),
+            lines: Vec::new(),
+        },
+    };
+
+    Ok(outcome)
+}
+
+/// Splits a program command into its arguments at whitespace, the way a shell would quote them
+///
+/// Single quotes keep everything up to the next one as it is. Within double quotes and outside quotes a backslash
+/// keeps the character after it, so paths with spaces can be given as "my tool" or my\ tool.
+fn split_command(command: &str) -> Result<Vec<String>, String> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut chars = command.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => words.extend(word.take()),
+            '\'' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => word.push(c),
+                        None => return Err(format!("unterminated ' in program command: {}", command)),
+                    }
+                }
+            },
+            '"' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => word.extend(chars.next()),
+                        Some(c) => word.push(c),
+                        None => return Err(format!("unterminated \" in program command: {}", command)),
+                    }
+                }
+            },
+            '\\' => word.get_or_insert_with(String::new).extend(chars.next()),
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+
+    words.extend(word);
+    Ok(words)
+}
+
+pub const USAGE: &str = "\
+usage: midi_generator differential [options] -p <command> [-p <command>]...
+
+each generated file is given to every <command>, named by a {} argument or else added as the last
+argument, and files where the programs' normalized outputs disagree are saved with a report
+
+programs whose every line of output is an event as <track> <tick> <event bytes in hex>, e.g.
+\"1 480 90 3c 64\", are also checked against the events the generator wrote; other programs are only
+compared with each other
+
+options:
+  -p, --program <command>  a program to compare, with its arguments separated by spaces; quote an argument
+                           with spaces in it as in a shell, e.g. -p \"'/opt/my tools/dump' --hex\"
+  -o, --output <dir>       save disagreeing files and their reports in <dir> (default disagreements)
+  -n, --count <n>          number of files to generate (default 100)
+  --timeout <seconds>      stop a program after <seconds> (default 10)
+  --no-ground-truth        only compare the programs with each other";
+
+/// Runs the differential command, comparing several programs on a batch of generated files
+pub fn run(args: Vec<String>) -> Result<(), String> {
+    let mut programs: Vec<String> = Vec::new();
+    let mut output = String::from("disagreements");
+    let mut count: u32 = 100;
+    let mut timeout = 10.0;
+    let mut check_truth = true;
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-p" | "--program" => programs.push(args.next().ok_or(format!("{} needs a command", arg))?),
+            "-o" | "--output" => output = args.next().ok_or(format!("{} needs a directory", arg))?,
+            "-n" | "--count" => {
+                let n = args.next().ok_or(format!("{} needs a number", arg))?;
+                count = n.parse().map_err(|_| format!("invalid count: {}", n))?;
+            },
+            "--timeout" => {
+                let seconds = args.next().ok_or(format!("{} needs a number of seconds", arg))?;
+                timeout = seconds.parse().ok().filter(|seconds: &f64| *seconds > 0.0).ok_or(format!("invalid timeout: {}", seconds))?;
+            },
+            "--no-ground-truth" => check_truth = false,
+            _ => return Err(format!("unknown argument: {}\n{}", arg, USAGE)),
+        }
+    }
+
+    if programs.is_empty() || (!check_truth && programs.len() < 2) {
+        return Err(format!("not enough programs to compare\n{}", USAGE));
+    }
+    let commands = programs.iter().map(|program| split_command(program)).collect::<Result<Vec<Vec<String>>, String>>()?;
+    if commands.iter().any(Vec::is_empty) {
+        return Err(String::from("empty program command"));
+    }
+
+    std::fs::create_dir_all(&output).map_err(|error| format!("could not create {}: {}", output, error))?;
+    let current = format!("{}/current.mid", output);
+    let timeout = Duration::from_secs_f64(timeout);
+    let rng = &mut rand::thread_rng();
+    let mut disagreements = 0;
+
+    for index in 0..count {
+        let (header, tracks) = generate_midi_file(rng, &GeneratorOptions::default());
+        let bytes = encode_midi_file(&header, &tracks);
+        std::fs::write(&current, &bytes).map_err(|error| format!("could not write {}: {}", current, error))?;
+
+        let outcomes = commands.iter().map(|command| run_program(command, &current, timeout)).collect::<Result<Vec<Outcome>, String>>()?;
+        let truth = ground_truth(&tracks);
+
+        if let Some(report) = compare(&programs, &outcomes, if check_truth { Some(&truth) } else { None }) {
+            let path = format!("{}/file_{:06}.mid", output, index);
+            std::fs::write(&path, &bytes).map_err(|error| format!("could not write {}: {}", path, error))?;
+            std::fs::write(format!("{}.report", path), report).map_err(|error| format!("could not write {}.report: {}", path, error))?;
+            disagreements += 1;
+        }
+    }
+
+    let _ = std::fs::remove_file(&current);
+    println!("{} files, {} with disagreements, saved in {}", count, disagreements, output);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{to_delta_times, Event};
+
+    fn outcome(status: &str, lines: &[&str]) -> Outcome {
+        Outcome {
+            status: String::from(status),
+            lines: lines.iter().map(|line| String::from(*line)).collect(),
+        }
+    }
+
+    #[test]
+    fn layout_differences_are_normalized() {
+        assert_eq!(normalize(b"  1\t480  0x90, 0x3C,0x64\r\n\n0 0 FF 2F 00\n"), vec!["1 480 90 3c 64", "0 0 ff 2f 00"]);
+    }
+
+    #[test]
+    fn program_commands_are_split_like_a_shell() {
+        assert_eq!(split_command("  dump  --hex {} ").unwrap(), ["dump", "--hex", "{}"]);
+        assert_eq!(split_command(r#"'/opt/my tools/dump' "a \"b\" c" my\ file ''"#).unwrap(), ["/opt/my tools/dump", "a \"b\" c", "my file", ""]);
+        assert!(split_command("dump 'unclosed").is_err());
+        assert!(split_command("   ").unwrap().is_empty());
+    }
+
+    #[test]
+    fn ground_truth_lists_events_at_absolute_ticks() {
+        let track = MTrk::new(to_delta_times(vec![(0, Event::new_note_on(1, 60, 100)), (480, Event::new_note_off(1, 60))]));
+
+        assert_eq!(ground_truth(&[track]), vec!["0 0 91 3c 64", "0 480 81 3c 40"]);
+    }
+
+    #[test]
+    fn disagreements_are_reported() {
+        let commands = vec![String::from("a"), String::from("b")];
+        let truth = vec![String::from("0 0 ff 2f 00")];
+
+        assert_eq!(compare(&commands, &[outcome("exit 0", &["0 0 ff 2f 00"]), outcome("exit 0", &["0 0 ff 2f 00"])], Some(&truth)), None);
+
+        let report = compare(&commands, &[outcome("exit 0", &["0 0 ff 2f 00"]), outcome("exit 0", &["0 1 ff 2f 00"])], Some(&truth)).unwrap();
+        assert!(report.contains("differs from program 1 at line 1: expected \"0 0 ff 2f 00\", got \"0 1 ff 2f 00\""));
+
+        let report = compare(&commands, &[outcome("exit 0", &["x"]), outcome("exit 1", &["x"])], None).unwrap();
+        assert!(report.contains("finishes differently"));
+    }
+
+    #[test]
+    fn programs_in_other_formats_are_not_checked_against_the_generator() {
+        let commands = vec![String::from("a"), String::from("b")];
+        let truth = vec![String::from("0 0 ff 2f 00")];
+        let own_format = outcome("exit 0", &["track 0: end of track"]);
+
+        assert!(lists_events(&truth) && !lists_events(&own_format.lines));
+        assert_eq!(compare(&commands, &[own_format.clone(), own_format.clone()], Some(&truth)), None);
+
+        let report = compare(&commands, &[own_format, outcome("exit 0", &["0 0 ff 2f"])], Some(&truth)).unwrap();
+        assert!(report.contains("program 1: a\n  exit 0, 1 lines of output\n  doesn't list events"));
+        assert!(report.contains("differs from the generator's events at line 1"));
+    }
+}
@@ -0,0 +1,652 @@
+// JSON model
+// Writes the whole MThd/MTrk/Event model of a file as JSON with a typed field for every parameter, and reads it back
+// into the same bytes
+
+use std::convert::{TryFrom, TryInto};
+
+use crate::manifest::json_string;
+use crate::midicsv::{length_bytes, padded_vlq, parse_paths, split_length};
+use crate::{encode_midi_file, encode_vlq, reader, DeltaTime, Event, MIDIEvent, MThd, MTrk, MetaEvent};
+
+/// A parsed JSON value
+#[derive(Debug, Clone, PartialEq)]
+enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    /// Returns the value of a field of an object
+    fn get(&self, name: &str) -> Option<&Json> {
+        match self {
+            Json::Object(fields) => fields.iter().find(|(key, _)| key == name).map(|(_, value)| value),
+            _ => None,
+        }
+    }
+}
+
+/// Reads JSON text into Json values
+struct Parser<'a> {
+    text: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    /// Parses a whole JSON document
+    fn parse(text: &'a str) -> Result<Json, String> {
+        let mut parser = Parser { text: text.as_bytes(), pos: 0 };
+        let value = parser.value()?;
+
+        parser.skip_whitespace();
+        if parser.pos != parser.text.len() {
+            return Err(format!("unexpected text after the document at byte {}", parser.pos));
+        }
+        Ok(value)
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.text.get(self.pos).is_some_and(|byte| byte.is_ascii_whitespace()) {
+            self.pos += 1;
+        }
+    }
+
+    /// Skips whitespace and consumes `expected` if it comes next
+    fn eat(&mut self, expected: u8) -> bool {
+        self.skip_whitespace();
+        if self.text.get(self.pos) == Some(&expected) {
+            self.pos += 1;
+            true
+        }
+        else {
+            false
+        }
+    }
+
+    fn expect(&mut self, expected: u8) -> Result<(), String> {
+        if self.eat(expected) {
+            Ok(())
+        }
+        else {
+            Err(format!("expected '{}' at byte {}", expected as char, self.pos))
+        }
+    }
+
+    fn value(&mut self) -> Result<Json, String> {
+        self.skip_whitespace();
+
+        match self.text.get(self.pos) {
+            Some(b'{') => {
+                self.pos += 1;
+                let mut fields = Vec::new();
+                if !self.eat(b'}') {
+                    loop {
+                        self.skip_whitespace();
+                        let key = self.string()?;
+                        self.expect(b':')?;
+                        fields.push((key, self.value()?));
+                        if self.eat(b'}') {
+                            break;
+                        }
+                        self.expect(b',')?;
+                    }
+                }
+                Ok(Json::Object(fields))
+            },
+            Some(b'[') => {
+                self.pos += 1;
+                let mut values = Vec::new();
+                if !self.eat(b']') {
+                    loop {
+                        values.push(self.value()?);
+                        if self.eat(b']') {
+                            break;
+                        }
+                        self.expect(b',')?;
+                    }
+                }
+                Ok(Json::Array(values))
+            },
+            Some(b'"') => Ok(Json::String(self.string()?)),
+            Some(b't') => self.literal("true", Json::Bool(true)),
+            Some(b'f') => self.literal("false", Json::Bool(false)),
+            Some(b'n') => self.literal("null", Json::Null),
+            Some(byte) if *byte == b'-' || byte.is_ascii_digit() => self.number(),
+            _ => Err(format!("expected a value at byte {}", self.pos)),
+        }
+    }
+
+    fn literal(&mut self, word: &str, value: Json) -> Result<Json, String> {
+        if self.text[self.pos..].starts_with(word.as_bytes()) {
+            self.pos += word.len();
+            Ok(value)
+        }
+        else {
+            Err(format!("expected a value at byte {}", self.pos))
+        }
+    }
+
+    fn number(&mut self) -> Result<Json, String> {
+        let start = self.pos;
+        while self.text.get(self.pos).is_some_and(|byte| byte.is_ascii_digit() || b"+-.eE".contains(byte)) {
+            self.pos += 1;
+        }
+
+        let number = std::str::from_utf8(&self.text[start..self.pos]).unwrap();
+        number.parse().map(Json::Number).map_err(|_| format!("invalid number at byte {}: {}", start, number))
+    }
+
+    /// Reads four hex digits of a \u escape
+    fn hex4(&mut self) -> Result<u32, String> {
+        let digits = self.text.get(self.pos..self.pos + 4).and_then(|digits| std::str::from_utf8(digits).ok());
+        let value = digits.and_then(|digits| u32::from_str_radix(digits, 16).ok()).ok_or(format!("invalid \\u escape at byte {}", self.pos))?;
+        self.pos += 4;
+        Ok(value)
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        if self.text.get(self.pos) != Some(&b'"') {
+            return Err(format!("expected a string at byte {}", self.pos));
+        }
+        self.pos += 1;
+
+        let mut string = Vec::new();
+        loop {
+            let byte = *self.text.get(self.pos).ok_or("unterminated string")?;
+            self.pos += 1;
+
+            match byte {
+                b'"' => break,
+                b'\\' => {
+                    let escape = *self.text.get(self.pos).ok_or("unterminated string")?;
+                    self.pos += 1;
+                    let c = match escape {
+                        b'"' => '"',
+                        b'\\' => '\\',
+                        b'/' => '/',
+                        b'b' => '\u{8}',
+                        b'f' => '\u{c}',
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'u' => {
+                            let mut code = self.hex4()?;
+                            if (0xD800..0xDC00).contains(&code) && self.text[self.pos..].starts_with(b"\\u") {
+                                self.pos += 2;
+                                let low = self.hex4()?;
+                                if !(0xDC00..0xE000).contains(&low) {
+                                    return Err(format!("invalid \\u escape before byte {}: a high surrogate needs a low one after it", self.pos));
+                                }
+                                code = 0x10000 + ((code - 0xD800) << 10) + (low - 0xDC00);
+                            }
+                            char::from_u32(code).ok_or(format!("invalid \\u escape before byte {}", self.pos))?
+                        },
+                        _ => return Err(format!("invalid escape at byte {}", self.pos - 1)),
+                    };
+                    string.extend(c.to_string().as_bytes());
+                },
+                _ => string.push(byte),
+            }
+        }
+
+        // the text came from a &str, and escapes only add whole characters
+        Ok(String::from_utf8(string).unwrap())
+    }
+}
+
+/// Names of the parameters following the status byte of each kind of channel event
+fn channel_parameters(event: MIDIEvent) -> &'static [&'static str] {
+    match event {
+        MIDIEvent::NoteOff | MIDIEvent::NoteOn => &["note", "velocity"],
+        MIDIEvent::PolyphonicPressure => &["note", "pressure"],
+        MIDIEvent::Controller => &["controller", "value"],
+        MIDIEvent::ProgramChange => &["program"],
+        MIDIEvent::ChannelPressure => &["pressure"],
+        MIDIEvent::PitchBend => &["value"],
+    }
+}
+
+/// Returns the status byte of the channel event with the given name, on channel 0
+fn status_of(name: &str) -> Option<u8> {
+    (0x80..=0xE0).step_by(0x10).find(|status| MIDIEvent::from_status(*status).is_some_and(|event| format!("{:?}", event) == name))
+}
+
+/// Returns the type byte of the Meta event with the given name
+fn meta_type_of(name: &str) -> Option<u8> {
+    (0x00..=0x7F).find(|meta_type| MetaEvent::from_type(*meta_type).is_some_and(|event| format!("{:?}", event) == name))
+}
+
+/// Meta events holding text, whose data is written as a string
+fn is_text(meta_type: u8) -> bool {
+    (0x01..=0x0F).contains(&meta_type)
+}
+
+/// Writes bytes as a JSON array of numbers
+fn byte_array(bytes: &[u8]) -> String {
+    let numbers: Vec<String> = bytes.iter().map(|byte| byte.to_string()).collect();
+    format!("[{}]", numbers.join(", "))
+}
+
+/// Writes bytes as a JSON string with one character per byte, for chunk identifiers
+fn byte_string(bytes: &[u8]) -> String {
+    json_string(&bytes.iter().map(|&byte| byte as char).collect::<String>())
+}
+
+/// Returns the typed fields of an event, after its delta time
+///
+/// Events are named after their MIDIEvent and MetaEvent variants. Meta events with no variant are "Meta", SysEx events
+/// are "SysEx" and "SysExEscape", and bytes that don't make up a well-formed event are "Data" with the raw bytes.
+/// Text is written as a string where it is valid UTF-8, and as an array of bytes under "data" otherwise.
+fn event_fields(event: &Event) -> Vec<(&'static str, String)> {
+    let data = &event.data;
+    let payload_field = |payload: &[u8], text: bool| match std::str::from_utf8(payload) {
+        Ok(string) if text => ("text", json_string(string)),
+        _ => ("data", byte_array(payload)),
+    };
+
+    let mut fields = Vec::new();
+    match data[0] {
+        0x80..=0xEF => {
+            let kind = MIDIEvent::from_status(data[0]).unwrap();
+            fields.push(("type", json_string(&format!("{:?}", kind))));
+            fields.push(("channel", (data[0] & 0x0F).to_string()));
+
+            if let MIDIEvent::PitchBend = kind {
+                let value = data.get(1).copied().unwrap_or(0) as u16 | (data.get(2).copied().unwrap_or(0) as u16) << 7;
+                fields.push(("value", value.to_string()));
+            }
+            else {
+                for (name, value) in channel_parameters(kind).iter().zip(&data[1..]) {
+                    fields.push((name, value.to_string()));
+                }
+            }
+        },
+        0xF0 | 0xF7 => {
+            fields.push(("type", json_string(if data[0] == 0xF0 { "SysEx" } else { "SysExEscape" })));
+            fields.push(payload_field(split_length(event, 1).unwrap_or(&[]), false));
+        },
+        0xFF if data.len() > 2 => {
+            let meta_type = data[1];
+            let payload = split_length(event, 2).unwrap_or(&[]);
+
+            match (MetaEvent::from_type(meta_type), payload) {
+                (Some(MetaEvent::MIDIChannelPrefix), [channel]) => {
+                    fields.push(("type", json_string("MIDIChannelPrefix")));
+                    fields.push(("channel", channel.to_string()));
+                },
+                (Some(MetaEvent::MIDIPort), [port]) => {
+                    fields.push(("type", json_string("MIDIPort")));
+                    fields.push(("port", port.to_string()));
+                },
+                (Some(MetaEvent::EndOfTrack), []) => fields.push(("type", json_string("EndOfTrack"))),
+                (Some(MetaEvent::Tempo), [a, b, c]) => {
+                    fields.push(("type", json_string("Tempo")));
+                    fields.push(("tempo", ((*a as u32) << 16 | (*b as u32) << 8 | *c as u32).to_string()));
+                },
+                (Some(MetaEvent::TimeSignature), [numerator, denominator, clocks, notated]) => {
+                    fields.push(("type", json_string("TimeSignature")));
+                    fields.push(("numerator", numerator.to_string()));
+                    fields.push(("denominator", denominator.to_string()));
+                    fields.push(("clocks_per_click", clocks.to_string()));
+                    fields.push(("notated_32nds_per_quarter", notated.to_string()));
+                },
+                (Some(MetaEvent::KeySignature), [key, mode]) if *mode <= 1 => {
+                    fields.push(("type", json_string("KeySignature")));
+                    fields.push(("key", (*key as i8).to_string()));
+                    fields.push(("minor", (*mode == 1).to_string()));
+                },
+                (Some(kind), _) if is_text(meta_type) => {
+                    fields.push(("type", json_string(&format!("{:?}", kind))));
+                    fields.push(payload_field(payload, true));
+                },
+                _ => {
+                    fields.push(("type", json_string("Meta")));
+                    fields.push(("meta_type", meta_type.to_string()));
+                    fields.push(payload_field(payload, is_text(meta_type)));
+                },
+            }
+        },
+        _ => (),
+    }
+
+    let length_start = match data[0] {
+        0xF0 | 0xF7 => Some(1),
+        0xFF => Some(2),
+        _ => None,
+    };
+    if let Some(payload) = length_start.and_then(|start| split_length(event, start)) {
+        let nbytes = length_bytes(event, length_start.unwrap()).unwrap();
+        if nbytes > encode_vlq(payload.len() as u32).len() {
+            fields.push(("length_bytes", nbytes.to_string()));
+        }
+    }
+
+    fields
+}
+
+/// Writes a <DeltaTime, Event> pair as a JSON object on one line
+///
+/// Delta times are written in ticks, along with "delta_bytes" when they are padded past their shortest encoding.
+/// Anything the typed fields can't reproduce byte for byte is written as raw bytes instead.
+fn event_json(delta_time: &DeltaTime, event: &Event) -> String {
+    let ticks = delta_time.ticks();
+    let delta = if padded_vlq(ticks, delta_time.data.len()) == delta_time.data {
+        let mut delta = format!("\"delta\": {}", ticks);
+        if delta_time.data.len() > encode_vlq(ticks).len() {
+            delta += &format!(", \"delta_bytes\": {}", delta_time.data.len());
+        }
+        delta
+    }
+    else {
+        format!("\"delta_data\": {}", byte_array(&delta_time.data))
+    };
+
+    let typed: Vec<String> = event_fields(event).iter().map(|(name, value)| format!("\"{}\": {}", name, value)).collect();
+    let line = format!("{{ {}, {} }}", delta, typed.join(", "));
+
+    let rebuilt = Parser::parse(&line).and_then(|json| event_from_json(&json));
+    if rebuilt.is_ok_and(|(_, rebuilt)| rebuilt.data == event.data) {
+        line
+    }
+    else {
+        format!("{{ {}, \"type\": \"Data\", \"data\": {} }}", delta, byte_array(&event.data))
+    }
+}
+
+/// Writes the model of a file as a JSON document
+///
+/// Chunk identifiers and lengths are only written where they differ from what a well-formed file would have, so
+/// that events can be edited without working the lengths out again.
+pub fn to_json(header: &MThd, tracks: &[MTrk]) -> String {
+    let mut header_fields = Vec::new();
+    if header.identifier != *b"MThd" {
+        header_fields.push(format!("\"identifier\": {}", byte_string(&header.identifier)));
+    }
+    if header.chunklen != 6 {
+        header_fields.push(format!("\"chunklen\": {}", header.chunklen));
+    }
+    header_fields.push(format!("\"format\": {}, \"ntracks\": {}, \"tickdiv\": {}", header.format, header.ntracks, header.tickdiv));
+
+    let tracks_json: Vec<String> = tracks.iter().map(|track| {
+        let mut track_json = String::from("    {\n");
+        if track.identifier != *b"MTrk" {
+            track_json += &format!("      \"identifier\": {},\n", byte_string(&track.identifier));
+        }
+        if track.chunklen != MTrk::new(track.data.clone()).chunklen {
+            track_json += &format!("      \"chunklen\": {},\n", track.chunklen);
+        }
+
+        let events: Vec<String> = track.data.iter().map(|(delta_time, event)| format!("        {}", event_json(delta_time, event))).collect();
+        track_json += &format!("      \"events\": [\n{}\n      ]\n    }}", events.join(",\n"));
+        track_json
+    }).collect();
+
+    format!("{{\n  \"header\": {{ {} }},\n  \"tracks\": [\n{}\n  ]\n}}\n", header_fields.join(", "), tracks_json.join(",\n"))
+}
+
+/// Returns a field of an object as an integer, checking it lies within min..=max
+fn integer(object: &Json, name: &str, min: i64, max: i64) -> Result<i64, String> {
+    match object.get(name) {
+        Some(Json::Number(number)) if number.fract() == 0.0 && *number >= min as f64 && *number <= max as f64 => Ok(*number as i64),
+        Some(_) => Err(format!("\"{}\" must be a whole number in {}..={}", name, min, max)),
+        None => Err(format!("missing \"{}\"", name)),
+    }
+}
+
+/// Returns a field of an object as an integer, or None where the object doesn't have it
+fn optional_integer(object: &Json, name: &str, max: i64) -> Result<Option<i64>, String> {
+    object.get(name).map(|_| integer(object, name, 0, max)).transpose()
+}
+
+/// Returns a field of an object holding an array of bytes
+fn bytes(object: &Json, name: &str) -> Result<Vec<u8>, String> {
+    match object.get(name) {
+        Some(Json::Array(values)) => values.iter().map(|value| match value {
+            Json::Number(number) if number.fract() == 0.0 && (0.0..=255.0).contains(number) => Ok(*number as u8),
+            _ => Err(format!("\"{}\" must only hold bytes, 0..=255", name)),
+        }).collect(),
+        Some(_) => Err(format!("\"{}\" must be an array of bytes", name)),
+        None => Err(format!("missing \"{}\"", name)),
+    }
+}
+
+/// Returns a field of an object holding a string with one character per byte
+fn chars_as_bytes(object: &Json, name: &str) -> Result<Option<Vec<u8>>, String> {
+    match object.get(name) {
+        Some(Json::String(string)) => string.chars()
+            .map(|c| u8::try_from(c as u32).map_err(|_| format!("\"{}\" can only hold characters up to U+00FF", name)))
+            .collect::<Result<Vec<u8>, String>>()
+            .map(Some),
+        Some(_) => Err(format!("\"{}\" must be a string", name)),
+        None => Ok(None),
+    }
+}
+
+/// Returns the data of a SysEx or Meta event, given either as "text" or as "data"
+fn payload(object: &Json) -> Result<Vec<u8>, String> {
+    match object.get("text") {
+        Some(Json::String(text)) => Ok(text.as_bytes().to_vec()),
+        Some(_) => Err(String::from("\"text\" must be a string")),
+        None => bytes(object, "data"),
+    }
+}
+
+/// Reads a <DeltaTime, Event> pair back from its JSON object
+fn event_from_json(object: &Json) -> Result<(DeltaTime, Event), String> {
+    let delta_time = match object.get("delta_data") {
+        Some(_) => DeltaTime { data: bytes(object, "delta_data")? },
+        None => {
+            let ticks = integer(object, "delta", 0, u32::MAX as i64)? as u32;
+            DeltaTime { data: padded_vlq(ticks, optional_integer(object, "delta_bytes", 16)?.unwrap_or(0) as usize) }
+        },
+    };
+    let length_bytes = optional_integer(object, "length_bytes", 16)?.unwrap_or(0) as usize;
+
+    let name = match object.get("type") {
+        Some(Json::String(name)) => name.as_str(),
+        _ => return Err(String::from("missing \"type\"")),
+    };
+    let with_length = |start: Vec<u8>, payload: Vec<u8>| {
+        let mut data = start;
+        data.extend(padded_vlq(payload.len() as u32, length_bytes));
+        data.extend(payload);
+        Event { data }
+    };
+
+    let event = if let Some(status) = status_of(name) {
+        let mut data = vec![status | integer(object, "channel", 0, 15)? as u8];
+        let kind = MIDIEvent::from_status(status).unwrap();
+
+        if let MIDIEvent::PitchBend = kind {
+            let value = integer(object, "value", 0, 0x3FFF)? as u16;
+            data.extend([(value & 0x7F) as u8, (value >> 7) as u8]);
+        }
+        else {
+            for parameter in channel_parameters(kind) {
+                data.push(integer(object, parameter, 0, 127)? as u8);
+            }
+        }
+        Event { data }
+    }
+    else {
+        match name {
+            "SysEx" => with_length(vec![0xF0], payload(object)?),
+            "SysExEscape" => with_length(vec![0xF7], payload(object)?),
+            "Data" => Event { data: bytes(object, "data")? },
+            "Meta" => with_length(vec![0xFF, integer(object, "meta_type", 0, 255)? as u8], payload(object)?),
+            "MIDIChannelPrefix" => with_length(vec![0xFF, 0x20], vec![integer(object, "channel", 0, 255)? as u8]),
+            "MIDIPort" => with_length(vec![0xFF, 0x21], vec![integer(object, "port", 0, 255)? as u8]),
+            "EndOfTrack" => with_length(vec![0xFF, 0x2F], vec![]),
+            "Tempo" => with_length(vec![0xFF, 0x51], (integer(object, "tempo", 0, 0xFF_FFFF)? as u32).to_be_bytes()[1..].to_vec()),
+            "TimeSignature" => with_length(vec![0xFF, 0x58], vec![
+                integer(object, "numerator", 0, 255)? as u8,
+                integer(object, "denominator", 0, 255)? as u8,
+                integer(object, "clocks_per_click", 0, 255)? as u8,
+                integer(object, "notated_32nds_per_quarter", 0, 255)? as u8,
+            ]),
+            "KeySignature" => {
+                let minor = match object.get("minor") {
+                    Some(Json::Bool(minor)) => *minor,
+                    _ => return Err(String::from("\"minor\" must be true or false")),
+                };
+                with_length(vec![0xFF, 0x59], vec![integer(object, "key", -128, 127)? as i8 as u8, minor as u8])
+            },
+            _ => match meta_type_of(name) {
+                Some(meta_type) if is_text(meta_type) => with_length(vec![0xFF, meta_type], payload(object)?),
+                _ => return Err(format!("unknown event type {}", name)),
+            },
+        }
+    };
+
+    Ok((delta_time, event))
+}
+
+/// Reads the model of a file back from a JSON document
+///
+/// Missing identifiers and chunk lengths are filled in with those of a well-formed file.
+pub fn from_json(text: &str) -> Result<(MThd, Vec<MTrk>), String> {
+    let document = Parser::parse(text)?;
+    let header = document.get("header").ok_or("missing \"header\"")?;
+
+    let header = MThd {
+        identifier: chars_as_bytes(header, "identifier")?.map_or(Ok(*b"MThd"), |identifier| identifier.try_into())
+            .map_err(|_| "the header identifier must be 4 bytes")?,
+        chunklen: optional_integer(header, "chunklen", u32::MAX as i64)?.map_or(6, |chunklen| chunklen as u32),
+        format: integer(header, "format", 0, 0xFFFF).map_err(|error| format!("header: {}", error))? as u16,
+        ntracks: integer(header, "ntracks", 0, 0xFFFF).map_err(|error| format!("header: {}", error))? as u16,
+        tickdiv: integer(header, "tickdiv", 0, 0xFFFF).map_err(|error| format!("header: {}", error))? as u16,
+    };
+
+    let tracks = match document.get("tracks") {
+        Some(Json::Array(tracks)) => tracks,
+        _ => return Err(String::from("missing \"tracks\" array")),
+    };
+    let tracks = tracks.iter().enumerate().map(|(index, track)| {
+        let error = |message: String| format!("track {}: {}", index, message);
+        let events = match track.get("events") {
+            Some(Json::Array(events)) => events,
+            _ => return Err(error(String::from("missing \"events\" array"))),
+        };
+
+        let data = events.iter().enumerate()
+            .map(|(number, event)| event_from_json(event).map_err(|message| error(format!("event {}: {}", number, message))))
+            .collect::<Result<Vec<(DeltaTime, Event)>, String>>()?;
+
+        let mut track_chunk = MTrk::new(data);
+        if let Some(identifier) = chars_as_bytes(track, "identifier").map_err(error)? {
+            track_chunk.identifier = identifier.try_into().map_err(|_| error(String::from("the identifier must be 4 bytes")))?;
+        }
+        if let Some(chunklen) = optional_integer(track, "chunklen", u32::MAX as i64).map_err(error)? {
+            track_chunk.chunklen = chunklen as u32;
+        }
+        Ok(track_chunk)
+    }).collect::<Result<Vec<MTrk>, String>>()?;
+
+    Ok((header, tracks))
+}
+
+pub const TO_JSON_USAGE: &str = "\
+usage: midi_generator to-json [options] <file>
+
+writes the model of a MIDI file as JSON, with a typed field for every event parameter
+
+options:
+  -o, --output <file>    write the JSON to <file> (default <file>.model.json)";
+
+pub const FROM_JSON_USAGE: &str = "\
+usage: midi_generator from-json [options] <file>
+
+turns the JSON model of a file back into a MIDI file
+
+options:
+  -o, --output <file>    write the MIDI file to <file> (default <file>.mid)";
+
+/// Runs the to-json command, writing the model of a MIDI file as JSON
+pub fn run_to_json(args: Vec<String>) -> Result<(), String> {
+    let (input, output) = parse_paths(args, "model.json", TO_JSON_USAGE)?;
+    let (header, tracks) = reader::read_midi_file(&input)?;
+
+    std::fs::write(&output, to_json(&header, &tracks)).map_err(|error| format!("could not write {}: {}", output, error))
+}
+
+/// Runs the from-json command, turning the JSON model of a file into a MIDI file
+pub fn run_from_json(args: Vec<String>) -> Result<(), String> {
+    let (input, output) = parse_paths(args, "mid", FROM_JSON_USAGE)?;
+    let text = std::fs::read_to_string(&input).map_err(|error| format!("could not read {}: {}", input, error))?;
+
+    let (header, tracks) = from_json(&text).map_err(|error| format!("{}: {}", input, error))?;
+    std::fs::write(&output, encode_midi_file(&header, &tracks)).map_err(|error| format!("could not write {}: {}", output, error))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::corrupt::Fault;
+    use crate::{generate_file, GeneratorOptions};
+
+    #[test]
+    fn generated_files_round_trip() {
+        let options = [
+            GeneratorOptions { lyrics: true, provenance: true, ..Default::default() },
+            GeneratorOptions { karaoke: true, text_mode: Some(crate::TextMode::Latin1), ..Default::default() },
+            GeneratorOptions { faults: Fault::ALL.to_vec(), ..Default::default() },
+        ];
+
+        for options in &options {
+            for seed in 0..10 {
+                let file = generate_file(seed, options);
+                let (header, tracks) = from_json(&to_json(&file.header, &file.tracks)).unwrap();
+                assert_eq!(encode_midi_file(&header, &tracks), encode_midi_file(&file.header, &file.tracks));
+            }
+        }
+    }
+
+    #[test]
+    fn events_have_typed_fields() {
+        let header = MThd { identifier: *b"MThd", chunklen: 6, format: 0, ntracks: 1, tickdiv: 96 };
+        let tracks = vec![MTrk::new(vec![
+            (DeltaTime::zero(), Event::new_tempo(500_000)),
+            (DeltaTime { data: vec![0x80, 0x60] }, Event::new_note_on(3, 60, 100)),
+            (DeltaTime::from_ticks(200), Event { data: vec![0xE3, 0x00, 0x40] }),
+            (DeltaTime::zero(), Event { data: vec![0xFF, 0x59, 0x02, 0xFD, 0x01] }),
+            (DeltaTime::zero(), Event::new_text_event(0x05, "la \"la\"".as_bytes())),
+            (DeltaTime::zero(), Event { data: vec![0xFF, 0x2F, 0x00] }),
+        ])];
+
+        let json = to_json(&header, &tracks);
+        assert!(json.contains("\"header\": { \"format\": 0, \"ntracks\": 1, \"tickdiv\": 96 }"));
+        assert!(json.contains("{ \"delta\": 0, \"type\": \"Tempo\", \"tempo\": 500000 }"));
+        assert!(json.contains("{ \"delta\": 96, \"delta_bytes\": 2, \"type\": \"NoteOn\", \"channel\": 3, \"note\": 60, \"velocity\": 100 }"));
+        assert!(json.contains("{ \"delta\": 200, \"type\": \"PitchBend\", \"channel\": 3, \"value\": 8192 }"));
+        assert!(json.contains("{ \"delta\": 0, \"type\": \"KeySignature\", \"key\": -3, \"minor\": true }"));
+        assert!(json.contains("{ \"delta\": 0, \"type\": \"Lyric\", \"text\": \"la \\\"la\\\"\" }"));
+        assert!(json.contains("{ \"delta\": 0, \"type\": \"EndOfTrack\" }"));
+    }
+
+    #[test]
+    fn hand_written_json_is_read() {
+        let text = r#"{ "header": { "format": 0, "ntracks": 1, "tickdiv": 480 },
+            "tracks": [ { "events": [
+                { "delta": 0, "type": "Copyright", "meta_type": 2 },
+                { "delta": 0, "type": "Meta", "meta_type": 2, "text": "© 2024 🎵" },
+                { "delta": 10, "type": "SysEx", "data": [126, 127, 247], "length_bytes": 2 },
+                { "delta": 0, "type": "EndOfTrack" } ] } ] }"#;
+
+        assert!(from_json(text).unwrap_err().contains("unknown event type Copyright"));
+        let (header, tracks) = from_json(&text.replacen(r#"{ "delta": 0, "type": "Copyright", "meta_type": 2 },"#, "", 1)).unwrap();
+
+        assert_eq!(header.tickdiv, 480);
+        assert_eq!(tracks[0].data[0].1.data, [&[0xFF, 0x02, 0x0C][..], "© 2024 🎵".as_bytes()].concat());
+        assert_eq!(tracks[0].data[1].1.data, vec![0xF0, 0x80, 0x03, 0x7E, 0x7F, 0xF7]);
+        assert_eq!(tracks[0].chunklen, 16 + 7 + 4);
+
+        let with_text = |text: &str| from_json(&r#"{ "header": { "format": 0, "ntracks": 1, "tickdiv": 480 }, "tracks": [ { "events": [
+            { "delta": 0, "type": "Lyric", "text": "TEXT" } ] } ] }"#.replace("TEXT", text));
+        assert_eq!(with_text(r"\ud83c\udfb5").unwrap().1[0].data[0].1.data, [&[0xFF, 0x05, 0x04][..], "🎵".as_bytes()].concat());
+        assert!(with_text(r"\uD800\u0041").unwrap_err().contains("invalid \\u escape"));
+        assert!(with_text(r"\uDC00").unwrap_err().contains("invalid \\u escape"));
+    }
+}
@@ -0,0 +1,322 @@
+// LilyPond export
+// Writes the notes of a file as a LilyPond score, with a staff per track and the lyrics sung on it beneath
+
+use crate::midicsv::parse_paths;
+use crate::notation::{note_value, read_score, spell, Element, Part, Score, DIVISIONS, MAX_VOICES};
+
+/// Escapes text for a LilyPond string, leaving out control characters
+fn string(text: &str) -> String {
+    let mut escaped = String::from("\"");
+    for c in text.chars().filter(|c| !c.is_control()) {
+        if c == '"' || c == '\\' {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped + "\""
+}
+
+/// Returns the LilyPond name of a key, e.g. "\key es \major"
+fn key_name(sharps: i8, minor: bool) -> String {
+    const MAJOR: [&str; 15] = ["ces", "ges", "des", "as", "es", "bes", "f", "c", "g", "d", "a", "e", "b", "fis", "cis"];
+    const MINOR: [&str; 15] = ["as", "es", "bes", "f", "c", "g", "d", "a", "e", "b", "fis", "cis", "gis", "dis", "ais"];
+
+    let index = (sharps.clamp(-7, 7) + 7) as usize;
+    if minor { format!("\\key {} \\minor", MINOR[index]) } else { format!("\\key {} \\major", MAJOR[index]) }
+}
+
+/// Writes a pitch in absolute octaves, where c is the C below middle C
+fn pitch(pitch: u8, key: i8) -> String {
+    let (step, alter, octave) = spell(pitch, key);
+    let mut name = String::from(step.to_ascii_lowercase());
+    name += match (step, alter) {
+        (_, 1) => "is",
+        ('E', -1) | ('A', -1) => "s",
+        (_, -1) => "es",
+        _ => "",
+    };
+
+    let marks = octave - 3;
+    name += &if marks >= 0 { "'".repeat(marks as usize) } else { ",".repeat((-marks) as usize) };
+    name
+}
+
+fn element(element: &Element, key: i8) -> String {
+    let mut ly = match element.pitches.as_slice() {
+        [] => String::from("r"),
+        [single] => pitch(*single, key),
+        pitches => format!("<{}>", pitches.iter().map(|chord_pitch| pitch(*chord_pitch, key)).collect::<Vec<String>>().join(" ")),
+    };
+
+    let (value, dots) = note_value(element.duration);
+    ly += &value.to_string();
+    ly += &".".repeat(dots as usize);
+    if element.tie_start {
+        ly.push('~');
+    }
+    ly
+}
+
+/// Writes a Lyric as a syllable, joined to the next by a hyphen where it ends in one
+///
+/// Notes without a Lyric are skipped over, so the syllables stay under the notes they are sung on.
+fn syllable(lyric: Option<&str>) -> String {
+    let text = lyric.unwrap_or("").trim_end();
+    let (text, hyphen) = match text.strip_suffix('-') {
+        Some(text) => (text, true),
+        None => (text, false),
+    };
+    let text: String = text.chars().filter(|c| !c.is_whitespace() && !c.is_control()).collect();
+
+    match (text.is_empty(), hyphen) {
+        (true, _) => String::from("\\skip 1"),
+        (false, false) => string(&text),
+        (false, true) => format!("{} --", string(&text)),
+    }
+}
+
+/// Commands that turn the stems of each voice of a staff its own way, when it has more than one
+const VOICE_STYLES: [&str; MAX_VOICES] = ["\\voiceOne", "\\voiceTwo", "\\voiceThree", "\\voiceFour"];
+
+/// Writes the measures of one voice of a part, a line per measure, collecting the syllables sung on its notes
+///
+/// The first voice carries the key, time and tempo marks, while the others only keep track of them and fill measures
+/// where they rest throughout with spacers.
+fn voice(part: &Part, index: usize, marks_tempo: bool, syllables: &mut Vec<String>) -> String {
+    let first = index == 0;
+    let (mut key, mut time) = (0, (4, 4));
+    let mut shortened = false; // the last measure was ended early by a change, so its length needs setting back
+    let mut ly = String::new();
+
+    for measure in &part.measures {
+        let mut line = Vec::new();
+        if let Some((sharps, minor)) = measure.key {
+            key = sharps;
+            if first {
+                line.push(key_name(sharps, minor));
+            }
+        }
+        if let Some((numerator, denominator)) = measure.time {
+            time = (numerator, denominator);
+            if first {
+                line.push(format!("\\time {}/{}", numerator, denominator));
+            }
+        }
+        // the first voice sets the length of measures for the whole staff
+        if first && measure.length != time.0 * 4 * DIVISIONS / time.1 {
+            line.push(format!("\\set Timing.measureLength = #(ly:make-moment {}/{})", measure.length, 4 * DIVISIONS));
+            shortened = true;
+        }
+        else if first && shortened {
+            if measure.time.is_none() {
+                line.push(format!("\\set Timing.measureLength = #(ly:make-moment {}/{})", time.0, time.1));
+            }
+            shortened = false;
+        }
+        if let Some(tempo) = measure.tempo.filter(|_| marks_tempo && first) {
+            line.push(format!("\\tempo 4 = {}", tempo.round().max(1.0)));
+        }
+
+        let elements = &measure.voices[index];
+        if elements.is_empty() {
+            line.push(format!("{}{}*{}", if first { "R" } else { "s" }, time.1, time.0));
+        }
+        for note in elements {
+            line.push(element(note, key));
+            if first && !note.pitches.is_empty() && !note.tie_stop {
+                syllables.push(syllable(note.lyric.as_deref()));
+            }
+        }
+
+        ly += &format!("        {} |\n", line.join(" "));
+    }
+    ly
+}
+
+/// Writes the measures of a part as a staff with a named voice for each of its voices, followed by the lyrics of
+/// the first if it has any
+fn staff(part: &Part, marks_tempo: bool) -> String {
+    let name = format!("track{}", part.track);
+    let count = part.measures.first().map_or(1, |measure| measure.voices.len());
+    let (open, close) = if count > 1 { ("<<", ">>") } else { ("{", "}") };
+    let mut ly = format!("    \\new Staff \\with {{ instrumentName = {} }} {}\n", string(&part.name), open);
+
+    let mut syllables = Vec::new();
+    for (index, style) in VOICE_STYLES.iter().enumerate().take(count) {
+        let voice_name = if index == 0 { name.clone() } else { format!("{}-{}", name, index + 1) };
+        ly += &format!("      \\new Voice = \"{}\" {{\n", voice_name);
+        if count > 1 {
+            ly += &format!("        {}\n", style);
+        }
+        if index == 0 {
+            ly += &format!("        \\clef {}\n", if part.bass_clef { "bass" } else { "treble" });
+        }
+
+        ly += &voice(part, index, marks_tempo, &mut syllables);
+        if index == 0 {
+            ly += "        \\bar \"|.\"\n";
+        }
+        ly += "      }\n";
+    }
+    ly += &format!("    {}\n", close);
+
+    if part.measures.iter().flat_map(|measure| &measure.voices[0]).any(|note| note.lyric.is_some()) {
+        ly += &format!("    \\new Lyrics \\lyricsto \"{}\" {{\n      {}\n    }}\n", name, syllables.join(" "));
+    }
+    ly
+}
+
+/// Writes a score as a LilyPond file
+///
+/// Each staff keeps its own time, so parts with different time signatures stay in line. Tempo marks are written
+/// above the first staff only.
+pub fn to_lilypond(score: &Score) -> String {
+    let mut ly = String::from("\\version \"2.24.0\"\n\n\\header {\n");
+    if let Some(title) = &score.title {
+        ly += &format!("  title = {}\n", string(title));
+    }
+    ly += "  tagline = ##f\n}\n\n\\score {\n  <<\n";
+
+    for (index, part) in score.parts.iter().enumerate() {
+        ly += &staff(part, index == 0);
+    }
+
+    ly += "  >>
+  \\layout {
+    \\context {
+      \\Score
+      \\remove Timing_translator
+      \\remove Default_bar_line_engraver
+    }
+    \\context {
+      \\Staff
+      \\consists Timing_translator
+      \\consists Default_bar_line_engraver
+    }
+  }
+}
+";
+    ly
+}
+
+pub const USAGE: &str = "\
+usage: midi_generator lilypond [options] <file>
+
+writes the notes of a MIDI file as a LilyPond score with one staff per track and the lyrics sung on it, quantized to 32nd notes
+
+options:
+  -o, --output <file>    write the score to <file> (default <file>.ly)";
+
+/// Runs the lilypond command, writing the notes of a MIDI file as a LilyPond score
+pub fn run(args: Vec<String>) -> Result<(), String> {
+    let (input, output) = parse_paths(args, "ly", USAGE)?;
+    let score = read_score(&input)?;
+    std::fs::write(&output, to_lilypond(&score)).map_err(|error| format!("could not write {}: {}", output, error))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::notation::Measure;
+
+    fn note(pitches: Vec<u8>, duration: u32, tie_start: bool, tie_stop: bool, lyric: Option<&str>) -> Element {
+        Element { pitches, duration, tie_start, tie_stop, lyric: lyric.map(String::from) }
+    }
+
+    #[test]
+    fn pitches_and_syllables_are_written_in_lilypond_syntax() {
+        assert_eq!(pitch(60, 0), "c'");
+        assert_eq!(pitch(48, 0), "c");
+        assert_eq!(pitch(63, -3), "es'");
+        assert_eq!(pitch(56, -1), "as");
+        assert_eq!(pitch(70, -2), "bes'");
+        assert_eq!(pitch(30, 2), "fis,,");
+        assert_eq!(key_name(-3, true), "\\key c \\minor");
+        assert_eq!(key_name(6, false), "\\key fis \\major");
+
+        assert_eq!(syllable(Some("Hal-")), "\"Hal\" --");
+        assert_eq!(syllable(Some("lo\r")), "\"lo\"");
+        assert_eq!(syllable(Some("say \"ah\"\n")), "\"say\\\"ah\\\"\"");
+        assert_eq!(syllable(Some(" ")), "\\skip 1");
+        assert_eq!(syllable(None), "\\skip 1");
+    }
+
+    #[test]
+    fn score_is_written_with_a_staff_per_part() {
+        let part = |track, bass_clef| Part {
+            track,
+            name: format!("Part \"{}\"", track),
+            bass_clef,
+            measures: vec![
+                Measure {
+                    time: Some((3, 4)),
+                    key: Some((-1, false)),
+                    tempo: Some(100.4),
+                    length: 24,
+                    voices: vec![vec![
+                        note(vec![], 8, false, false, None),
+                        note(vec![70], 4, false, false, Some("Hal-")),
+                        note(vec![65, 69], 12, true, false, Some("lo ")),
+                    ]],
+                },
+                Measure { time: Some((6, 8)), key: None, tempo: Some(80.0), length: 24, voices: vec![vec![]] },
+                Measure {
+                    time: None,
+                    key: None,
+                    tempo: None,
+                    length: 24,
+                    voices: vec![vec![note(vec![65, 69], 8, false, true, None), note(vec![72], 16, false, false, None)]],
+                },
+            ],
+            truncated: false,
+            cut_short: false,
+        };
+
+        let ly = to_lilypond(&Score { title: Some(String::from("Song")), bpm: 100.4, parts: vec![part(1, false), part(2, true)] });
+        assert!(ly.starts_with("\\version \"2.24.0\"\n\n\\header {\n  title = \"Song\"\n"));
+        assert!(ly.contains("\\new Staff \\with { instrumentName = \"Part \\\"1\\\"\" } {\n      \\new Voice = \"track1\" {\n        \\clef treble\n"));
+        assert!(ly.contains("        \\key f \\major \\time 3/4 \\tempo 4 = 100 r4 bes'8 <f' a'>4.~ |\n        \\time 6/8 \\tempo 4 = 80 R8*6 |\n        <f' a'>4 c''2 |\n        \\bar \"|.\"\n"));
+        assert!(ly.contains("\\new Lyrics \\lyricsto \"track1\" {\n      \"Hal\" -- \"lo\" \\skip 1\n    }"));
+        assert!(ly.contains("\\clef bass\n        \\key f \\major \\time 3/4 r4"));
+        assert_eq!(ly.matches("\\tempo").count(), 2);
+        assert_eq!(ly.matches("{").count(), ly.matches("}").count());
+    }
+
+    #[test]
+    fn measures_ended_early_set_their_length() {
+        let measure = |key, length, duration| Measure { time: None, key, tempo: None, length, voices: vec![vec![note(vec![60], duration, false, false, None)]] };
+        let part = Part {
+            track: 1,
+            name: String::from("Piano"),
+            bass_clef: false,
+            measures: vec![measure(Some((0, false)), 16, 16), measure(Some((2, false)), 32, 32), measure(None, 32, 32)],
+            truncated: false,
+            cut_short: false,
+        };
+
+        let ly = to_lilypond(&Score { title: None, bpm: 120.0, parts: vec![part] });
+        assert!(ly.contains("        \\key c \\major \\set Timing.measureLength = #(ly:make-moment 16/32) c'2 |\n"));
+        assert!(ly.contains("        \\key d \\major \\set Timing.measureLength = #(ly:make-moment 4/4) c'1 |\n        c'1 |\n"));
+    }
+
+    #[test]
+    fn overlapping_voices_share_a_staff() {
+        let measure = |time, voices| Measure { time, key: None, tempo: None, length: 32, voices };
+        let part = Part {
+            track: 2,
+            name: String::from("Piano"),
+            bass_clef: false,
+            measures: vec![
+                measure(Some((4, 4)), vec![vec![note(vec![72], 8, false, false, Some("la")), note(vec![], 24, false, false, None)], vec![note(vec![48], 32, false, false, None)]]),
+                measure(None, vec![vec![note(vec![74], 32, false, false, None)], vec![]]),
+            ],
+            truncated: false,
+            cut_short: false,
+        };
+
+        let ly = to_lilypond(&Score { title: None, bpm: 120.0, parts: vec![part] });
+        assert!(ly.contains("\\new Staff \\with { instrumentName = \"Piano\" } <<\n      \\new Voice = \"track2\" {\n        \\voiceOne\n        \\clef treble\n"));
+        assert!(ly.contains("        \\bar \"|.\"\n      }\n      \\new Voice = \"track2-2\" {\n        \\voiceTwo\n        c1 |\n        s4*4 |\n      }\n    >>\n"));
+        assert!(ly.contains("\\lyricsto \"track2\" {\n      \"la\" \\skip 1\n"));
+    }
+}
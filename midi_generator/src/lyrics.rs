@@ -0,0 +1,302 @@
+// Lyric generation
+// Builds verses out of made-up syllables and sings them on a melody, one syllable per note,
+// either as Lyric events next to the notes or as a .kar karaoke file
+
+use rand::RngCore;
+use rand_distr::{Distribution, Uniform};
+
+use crate::{create_delta_time, to_delta_times, ChannelAssignment, DeltaTime, Event, GeneratorOptions, MTrk, MetaEvent};
+
+const ONSETS: [&str; 24] = ["b", "c", "d", "f", "g", "h", "j", "k", "l", "m", "n", "p", "r", "s", "t", "v", "w", "y", "z", "ch", "sh", "th", "st", "tr"];
+const NUCLEI: [&str; 9] = ["a", "e", "i", "o", "u", "ay", "ee", "oo", "ou"];
+const CODAS: [&str; 8] = ["", "", "", "n", "r", "s", "t", "ng"];
+
+// Note lengths in ticks, from an eighth to a half note at the common 96 ppqn
+const DURATIONS: [u32; 4] = [48, 96, 144, 192];
+
+#[derive(Debug, Clone)]
+/// A single sung syllable and where it falls in the words, lines and paragraphs of the verse
+struct Syllable {
+    text: String,
+    word_start: bool,
+    word_end: bool,
+    line_start: bool,
+    line_end: bool,
+    paragraph_start: bool,
+    paragraph_end: bool,
+}
+
+impl Syllable {
+    /// Returns the text of a Lyric event for this syllable
+    ///
+    /// Follows the RP-017/RP-026 conventions: a hyphen joins syllables of the same word,
+    /// a space ends a word, a carriage return ends a line and a line feed ends a paragraph.
+    fn lyric_text(&self) -> String {
+        let mut text = self.text.clone();
+
+        if !self.word_end {
+            text.push('-');
+        }
+        else if self.paragraph_end {
+            text.push('\n');
+        }
+        else if self.line_end {
+            text.push('\r');
+        }
+        else {
+            text.push(' ');
+        }
+
+        text
+    }
+
+    /// Returns the text of a .kar Text event for this syllable
+    ///
+    /// Karaoke players start a new line at a leading '/' and clear the screen for a new paragraph at a leading '\',
+    /// words are separated by a leading space and syllables of the same word are written without a separator.
+    fn karaoke_text(&self) -> String {
+        let prefix = if self.paragraph_start {
+            "\\"
+        }
+        else if self.line_start {
+            "/"
+        }
+        else if self.word_start {
+            " "
+        }
+        else {
+            ""
+        };
+
+        format!("{}{}", prefix, self.text)
+    }
+}
+
+#[derive(Debug)]
+/// A note of the melody and the syllable sung on it
+struct SungNote {
+    syllable: Syllable,
+    tick: u32, // absolute time of the NoteOn
+    duration: u32, // ticks until the NoteOff
+    note: u8,
+    velocity: u8,
+}
+
+/// Makes up a syllable from a random onset, vowel sound and ending
+pub fn generate_syllable(rng: &mut dyn RngCore) -> String {
+    format!("{}{}{}",
+        ONSETS[Uniform::from(0..ONSETS.len()).sample(rng)],
+        NUCLEI[Uniform::from(0..NUCLEI.len()).sample(rng)],
+        CODAS[Uniform::from(0..CODAS.len()).sample(rng)])
+}
+
+/// Generates a verse of 1..3 paragraphs, each of 2..4 lines of 3..7 words of 1..3 syllables
+fn generate_verse(rng: &mut dyn RngCore) -> Vec<Syllable> {
+    let mut verse = Vec::new();
+
+    let nparagraphs = Uniform::from(1..4).sample(rng);
+    for paragraph in 0..nparagraphs {
+        let nlines = Uniform::from(2..5).sample(rng);
+        for line in 0..nlines {
+            let nwords = Uniform::from(3..8).sample(rng);
+            for word in 0..nwords {
+                let nsyllables = Uniform::from(1..4).sample(rng);
+                for syllable in 0..nsyllables {
+                    let word_start = syllable == 0;
+                    let word_end = syllable == nsyllables - 1;
+                    let line_start = word_start && word == 0;
+                    let line_end = word_end && word == nwords - 1;
+
+                    verse.push(Syllable {
+                        text: generate_syllable(rng),
+                        word_start,
+                        word_end,
+                        line_start,
+                        line_end,
+                        paragraph_start: line_start && line == 0,
+                        paragraph_end: line_end && line == nlines - 1 && paragraph != nparagraphs - 1,
+                    });
+                }
+            }
+        }
+    }
+
+    verse
+}
+
+/// Sets a verse to a melody, one note per syllable
+///
+/// The melody is a random walk around middle C, with a rest before every new line.
+fn generate_song(rng: &mut dyn RngCore) -> Vec<SungNote> {
+    let durations = Uniform::from(0..DURATIONS.len());
+
+    let mut song = Vec::new();
+    let mut tick = 0;
+    let mut note: i32 = 60;
+
+    for syllable in generate_verse(rng) {
+        if syllable.line_start && tick > 0 {
+            tick += DURATIONS[durations.sample(rng)];
+        }
+
+        note = (note + Uniform::from(-4..5).sample(rng)).clamp(48, 84);
+        let duration = DURATIONS[durations.sample(rng)];
+
+        song.push(SungNote {
+            syllable,
+            tick,
+            duration,
+            note: note as u8,
+            velocity: Uniform::from(40..128).sample(rng) as u8, // a velocity of 0 would be read as a NoteOff
+        });
+
+        tick += duration;
+    }
+
+    song
+}
+
+/// Returns the NoteOn/NoteOff pairs of the melody, stamped with absolute ticks
+///
+/// If `lyrics` is set, each NoteOn is preceded by a Lyric event at the same tick.
+/// Notes of the song never overlap, so the events come out in order of tick.
+fn melody_events(song: &[SungNote], assignment: &ChannelAssignment, lyrics: bool) -> Vec<(u32, Event)> {
+    let mut events = Vec::new();
+
+    for sung in song {
+        if lyrics {
+            events.push((sung.tick, Event::new_text_event(0x05, sung.syllable.lyric_text().as_bytes())));
+        }
+        events.push((sung.tick, Event::new_note_on(assignment.channel, sung.note, sung.velocity)));
+        events.push((sung.tick + sung.duration, Event::new_note_off(assignment.channel, sung.note)));
+    }
+
+    events
+}
+
+/// Appends an EndOfTrack event after a random delta time
+fn end_track(rng: &mut dyn RngCore, mut data: Vec<(DeltaTime, Event)>, options: &GeneratorOptions) -> MTrk {
+    data.push((create_delta_time(rng), Event::new_meta_event(rng, MetaEvent::EndOfTrack, &ChannelAssignment::default(), options)));
+    MTrk::new(data)
+}
+
+/// Generates a melody track with a Lyric event on every NoteOn
+///
+/// # Arguments
+///
+/// * `assignment` - The channel the melody is played on
+/// * `with_timing` - If true, the track starts with the mandatory Tempo, Time Signature and Key Signature events, as in format 0 and 2 files
+pub fn new_lyric_track(rng: &mut dyn RngCore, assignment: &ChannelAssignment, with_timing: bool, options: &GeneratorOptions) -> MTrk {
+    let mut data = Vec::new();
+
+    if with_timing {
+        for event in Event::generate_mandatory_meta_events(rng, options) {
+            data.push((DeltaTime::zero(), event));
+        }
+    }
+    data.extend(to_delta_times(melody_events(&generate_song(rng), assignment, true)));
+
+    end_track(rng, data, options)
+}
+
+/// Generates the tracks of a .kar karaoke file
+///
+/// The layout follows the common .kar conventions:
+///
+/// * Track 0 is the global tempo track, opening with the "@KMIDI KARAOKE FILE" Text event
+/// * Track 1 is the "Words" track, with the @L language and @T title headers followed by one Text event per syllable
+/// * Track 2 is the melody, each NoteOn on the same tick as its syllable in the Words track
+/// * Any further tracks are random note tracks
+pub fn new_karaoke_tracks(rng: &mut dyn RngCore, ntracks: u16, options: &GeneratorOptions) -> Vec<MTrk> {
+    let song = generate_song(rng);
+    let assignment = ChannelAssignment::for_track(rng, 0, options.spread_channels);
+    let mut tracks = Vec::new();
+
+    let mut tempo = MTrk::new_global_tempo(rng, options).data;
+    tempo.insert(0, (DeltaTime::zero(), Event::new_text_event(0x01, b"@KMIDI KARAOKE FILE")));
+    tracks.push(MTrk::new(tempo));
+
+    let title: Vec<String> = (0..Uniform::from(1..4).sample(rng)).map(|_| generate_syllable(rng)).collect();
+    let mut words = vec![
+        (0, Event::new_text_event(0x03, b"Words")),
+        (0, Event::new_text_event(0x01, b"@LENGL")),
+        (0, Event::new_text_event(0x01, format!("@T{}", title.join(" ")).as_bytes())),
+        (0, Event::new_text_event(0x01, concat!("@Tmidi_generator ", env!("CARGO_PKG_VERSION")).as_bytes())),
+    ];
+    for sung in &song {
+        words.push((sung.tick, Event::new_text_event(0x01, sung.syllable.karaoke_text().as_bytes())));
+    }
+    tracks.push(end_track(rng, to_delta_times(words), options));
+
+    let mut melody = vec![(0, Event::new_text_event(0x03, b"Melody"))];
+    melody.extend(melody_events(&song, &assignment, false));
+    tracks.push(end_track(rng, to_delta_times(melody), options));
+
+    for index in 3..ntracks {
+        let assignment = ChannelAssignment::for_track(rng, index - 2, options.spread_channels);
+        tracks.push(MTrk::new_track_format_1(rng, &assignment, options));
+    }
+
+    tracks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns the absolute tick of every event in a track matching `filter`
+    fn ticks_of(track: &MTrk, filter: fn(&Event) -> bool) -> Vec<u32> {
+        track.timed_events().into_iter()
+            .filter(|(_, event)| filter(event))
+            .map(|(tick, _)| tick)
+            .collect()
+    }
+
+    fn is_note_on(event: &Event) -> bool {
+        event.data[0] & 0xF0 == 0x90
+    }
+
+    #[test]
+    fn lyrics_land_on_note_ons() {
+        let rng = &mut rand::thread_rng();
+        for _ in 0..20 {
+            let track = new_lyric_track(rng, &ChannelAssignment { channel: 3, port: 0 }, false, &GeneratorOptions::default());
+
+            let lyrics = ticks_of(&track, |event| event.data[..2] == [0xFF, 0x05]);
+            assert!(!lyrics.is_empty());
+            assert_eq!(lyrics, ticks_of(&track, is_note_on));
+        }
+    }
+
+    #[test]
+    fn karaoke_words_line_up_with_melody() {
+        let rng = &mut rand::thread_rng();
+        let tracks = new_karaoke_tracks(rng, 3, &GeneratorOptions::default());
+
+        assert_eq!(tracks.len(), 3);
+        assert_eq!(&tracks[0].data[0].1.data[3..], b"@KMIDI KARAOKE FILE");
+
+        // skip the track name and the @L/@T headers, every other Text event is a syllable
+        let words: Vec<u32> = ticks_of(&tracks[1], |event| event.data[..2] == [0xFF, 0x01]).split_off(3);
+        assert_eq!(words, ticks_of(&tracks[2], is_note_on));
+    }
+
+    #[test]
+    fn lyric_text_marks_hyphens_and_line_breaks() {
+        let syllable = Syllable {
+            text: String::from("la"),
+            word_start: true,
+            word_end: false,
+            line_start: true,
+            line_end: false,
+            paragraph_start: false,
+            paragraph_end: false,
+        };
+        assert_eq!(syllable.lyric_text(), "la-");
+        assert_eq!(syllable.karaoke_text(), "/la");
+
+        let syllable = Syllable { word_end: true, line_end: true, paragraph_start: true, ..syllable };
+        assert_eq!(syllable.lyric_text(), "la\r");
+        assert_eq!(syllable.karaoke_text(), "\\la");
+    }
+}
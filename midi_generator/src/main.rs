@@ -1,15 +1,45 @@
 extern crate rand;
 extern crate rand_distr;
 
-use rand::Rng;
+mod abc;
+mod batch;
+mod byte_stream;
+mod catalog;
+mod chunks;
+mod conductor;
+mod corrupt;
+mod differential;
+mod json;
+mod lilypond;
+mod lyrics;
+mod manifest;
+mod midicsv;
+mod musicxml;
+mod mutate;
+mod notation;
+mod piano_roll;
+mod process;
+mod provenance;
+mod reader;
+mod reduce;
+mod riff;
+mod sf2;
+mod sha256;
+mod synth;
+mod timing;
+mod ump;
+mod wav;
+
 use rand::distributions::WeightedIndex;
+use rand::rngs::StdRng;
+use rand::{RngCore, SeedableRng};
 use rand_distr::{Distribution, Uniform};
 
 #[derive(Debug, Copy, Clone)]
 /// Enum defining all MIDIEvents
 /// 
 /// Used with match to create different events
-/// Use MIDIEvent::pick_random() to randomly choose a MIDIEvent with uniform distribution
+/// Use MIDIEvent::pick_random(rng) to randomly choose a MIDIEvent with uniform distribution
 enum MIDIEvent {
     NoteOff,
     NoteOn,
@@ -22,9 +52,8 @@ enum MIDIEvent {
 
 impl MIDIEvent {
     /// Returns a random MDIIEvent using a Uniform distribution
-    fn pick_random() -> MIDIEvent {
-        let mut rng = rand::thread_rng();
-        let temp = Uniform::from(0..7).sample(&mut rng) as u32;
+    fn pick_random(rng: &mut dyn RngCore) -> MIDIEvent {
+        let temp = Uniform::from(0..7).sample(rng) as u32;
         match temp {
             0 => MIDIEvent::NoteOff,
             1 => MIDIEvent::NoteOn,
@@ -36,13 +65,27 @@ impl MIDIEvent {
             _ => panic!("Error when picking random MIDIEvent. Number out of bounds.")
         }
     }
+
+    /// Returns the MIDIEvent a channel event status byte stands for, whatever its channel
+    fn from_status(status: u8) -> Option<MIDIEvent> {
+        match status & 0xF0 {
+            0x80 => Some(MIDIEvent::NoteOff),
+            0x90 => Some(MIDIEvent::NoteOn),
+            0xA0 => Some(MIDIEvent::PolyphonicPressure),
+            0xB0 => Some(MIDIEvent::Controller),
+            0xC0 => Some(MIDIEvent::ProgramChange),
+            0xD0 => Some(MIDIEvent::ChannelPressure),
+            0xE0 => Some(MIDIEvent::PitchBend),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug, Copy, Clone)]
 /// Enum defining all MetaEvents
 /// 
 /// Used with match to create different events
-/// Use MetaEvent::pick_random() to randomly choose a MetaEvent with uniform distribution
+/// Use MetaEvent::pick_random(rng) to randomly choose a MetaEvent with uniform distribution
 enum MetaEvent {
     Text,
     SequenceORTrackName,
@@ -69,9 +112,8 @@ impl MetaEvent {
     /// * `upper` - A u32 representing the upper bound of the random number generation, maximum value of 13
     /// 
     /// To pick between timing events, Lower: 8 and Upper: 13
-    fn pick_random(lower: u32, upper: u32) -> MetaEvent {
-        let mut rng = rand::thread_rng();
-        let temp = Uniform::from(lower..upper).sample(&mut rng) as u32;
+    fn pick_random(rng: &mut dyn RngCore, lower: u32, upper: u32) -> MetaEvent {
+        let temp = Uniform::from(lower..upper).sample(rng);
         match temp {
             0 => MetaEvent::Text,
             1 => MetaEvent::SequenceORTrackName,
@@ -89,9 +131,29 @@ impl MetaEvent {
             _ => panic!("Error when picking random MetaEvent. Number out of bounds.")
         }
     }
+
+    /// Returns the MetaEvent with the given type byte, the one following 0xFF
+    fn from_type(meta_type: u8) -> Option<MetaEvent> {
+        match meta_type {
+            0x01 => Some(MetaEvent::Text),
+            0x03 => Some(MetaEvent::SequenceORTrackName),
+            0x04 => Some(MetaEvent::InstrumentName),
+            0x05 => Some(MetaEvent::Lyric),
+            0x06 => Some(MetaEvent::Marker),
+            0x07 => Some(MetaEvent::CuePoint),
+            0x08 => Some(MetaEvent::ProgramName),
+            0x20 => Some(MetaEvent::MIDIChannelPrefix),
+            0x21 => Some(MetaEvent::MIDIPort),
+            0x2F => Some(MetaEvent::EndOfTrack),
+            0x51 => Some(MetaEvent::Tempo),
+            0x58 => Some(MetaEvent::TimeSignature),
+            0x59 => Some(MetaEvent::KeySignature),
+            _ => None,
+        }
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct MThd {
     //identifier: String,
     identifier: [u8; 4],
@@ -106,16 +168,15 @@ impl MThd {
     /// Create a new MThd chunk to serve as the header of the MIDI file
     /// 
     /// Randomly choosese format, ntracks, and tickdiv with uniform distribution and common values
-    fn new() -> MThd {
-        let mut rng = rand::thread_rng();
+    fn new(rng: &mut dyn RngCore) -> MThd {
         let uniform = Uniform::from(0..3);
 
-        let fmt = uniform.sample(&mut rng) as u16;
+        let fmt = uniform.sample(rng) as u16;
         
         let ntrk = match fmt {
             0 => 1,// format 0 can only contain 1 MTrk chunk
-            1 => Uniform::from(2..26).sample(&mut rng) as u16, // 2 or more MTrk chunks, played simultaneously, let's set an arbitrary limit of 25
-            2 => Uniform::from(1..26).sample(&mut rng) as u16, // 1 or more MTrk chunks, played independently
+            1 => Uniform::from(2..26).sample(rng) as u16, // 2 or more MTrk chunks, played simultaneously, let's set an arbitrary limit of 25
+            2 => Uniform::from(1..26).sample(rng) as u16, // 1 or more MTrk chunks, played independently
             _ => panic!("Error found when generating MThd chunk. Invalid ntracks")
         };
 
@@ -144,27 +205,27 @@ impl MThd {
         A timing resolution of 1 ms can be achieved by specifying 25 fps and 40 sub-frames, which would be encoded in hex as  E7 28.
         */
 
-        let timecode = Uniform::from(0..2).sample(&mut rng) as u16; // get a 0 or 1 for bit 15
+        let timecode = Uniform::from(0..2).sample(rng) as u16; // get a 0 or 1 for bit 15
         let mut tckdv: u16 = timecode << 15;
 
         let tckdv_extra_bits: u16 = match timecode {
             0 => 96, // common value
             1 => {
-                let mut temp: u16 = match Uniform::from(0..4).sample(&mut rng) as u8 { // this gets us our fps
-                    0 => 0xE8 as u16,
-                    1 => 0xE7 as u16,
-                    2 => 0xE3 as u16,
-                    3 => 0xE2 as u16,
+                let mut temp: u16 = match Uniform::from(0..4).sample(rng) as u8 { // this gets us our fps
+                    0 => 0xE8,
+                    1 => 0xE7,
+                    2 => 0xE3,
+                    3 => 0xE2,
                     _ => panic!("Error found when generating MThd chunk. Invalid fps in tickdiv.")
                 };
-                temp = temp << 8; /* set up bits 8 - 15 and shift */
+                temp <<= 8; /* set up bits 8 - 15 and shift */
                 // temp = temp | (1 << 15); /* because we had to move bit 0 over by 8, bit 7 may have overwritten bit 15 with a 0, let's do this for safety */
-                temp = temp | match Uniform::from(0..5).sample(&mut rng) as u8 { /* set up our sub-frame resolution using the typical values */
-                    0 => 4 as u16,
-                    1 => 8 as u16,
-                    2 => 10 as u16,
-                    3 => 80 as u16,
-                    4 => 100 as u16,
+                temp |= match Uniform::from(0..5).sample(rng) as u8 { /* set up our sub-frame resolution using the typical values */
+                    0 => 4,
+                    1 => 8,
+                    2 => 10,
+                    3 => 80,
+                    4 => 100,
                     _ => panic!("Error found when generating MThd chunk. Invalid sub-frame resolution in tickdiv.")
                 };
                 temp
@@ -172,37 +233,100 @@ impl MThd {
             _ => panic!("Error found when generating MThd chunk. Invalid timecode in tickdiv.")
         };
 
-        tckdv = tckdv | tckdv_extra_bits;
+        tckdv |= tckdv_extra_bits;
 
         MThd {
-            identifier: ['M' as u8, 'T' as u8, 'h' as u8,'d' as u8],
+            identifier: [b'M', b'T', b'h',b'd'],
             chunklen: 6, // MIDI currently only supports chunklen 6
             format: fmt,
             ntracks: ntrk,
             tickdiv: tckdv,
         }
     }
+
+    /// Returns the bytes of the chunk as they appear in a MIDI file
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+
+        bytes.extend_from_slice(&self.identifier);
+        bytes.extend_from_slice(&self.chunklen.to_be_bytes());
+        bytes.extend_from_slice(&self.format.to_be_bytes());
+        bytes.extend_from_slice(&self.ntracks.to_be_bytes());
+        bytes.extend_from_slice(&self.tickdiv.to_be_bytes());
+
+        bytes
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 /// This is just a wrapper around a Vec<u8>
 struct DeltaTime {
     data: Vec<u8>,
 }
 
-fn create_delta_time() -> DeltaTime {
-    let mut delta_time = Vec::new();
+impl DeltaTime {
+    /// A delta time of 0 ticks, used for events that happen at the same time as the previous event
+    fn zero() -> DeltaTime {
+        DeltaTime {
+            data: vec![0x00],
+        }
+    }
 
-    let mut rng = rand::thread_rng();
+    /// Encodes a number of ticks as a delta time
+    fn from_ticks(ticks: u32) -> DeltaTime {
+        DeltaTime {
+            data: encode_vlq(ticks),
+        }
+    }
+
+    /// Decodes the number of ticks held in the delta time
+    /// 
+    /// Bytes beyond the 4 the MIDI spec allows are still decoded, with the excess high bits shifted out
+    fn ticks(&self) -> u32 {
+        self.data.iter().fold(0, |ticks, byte| (ticks << 7) | (byte & 0x7F) as u32)
+    }
+}
+
+/// Encodes a value as a MIDI variable-length quantity
+/// 
+/// Seven bits are stored per byte, most significant group first, with bit 7 set on every byte but the last.
+/// Values above 0x0FFFFFFF need more than the 4 bytes the MIDI spec allows, and are still encoded rather than truncated.
+fn encode_vlq(value: u32) -> Vec<u8> {
+    let mut bytes = vec![(value & 0x7F) as u8];
+    let mut rest = value >> 7;
+
+    while rest > 0 {
+        bytes.insert(0, (rest & 0x7F) as u8 | 0x80);
+        rest >>= 7;
+    }
+
+    bytes
+}
+
+/// Turns events stamped with absolute tick times into <DeltaTime, Event> pairs
+/// 
+/// The events must already be in order of tick
+fn to_delta_times(events: Vec<(u32, Event)>) -> Vec<(DeltaTime, Event)> {
+    let mut previous = 0;
+
+    events.into_iter().map(|(tick, event)| {
+        let delta_time = DeltaTime::from_ticks(tick - previous);
+        previous = tick;
+        (delta_time, event)
+    }).collect()
+}
+
+fn create_delta_time(rng: &mut dyn RngCore) -> DeltaTime {
+    let mut delta_time = Vec::new();
 
     let choices = [1, 2, 3, 4];
     let weights = [80, 12, 6, 2];
-    let dist = WeightedIndex::new(&weights).unwrap();
+    let dist = WeightedIndex::new(weights).unwrap();
 
-    let nbytes = choices[dist.sample(&mut rng)];
+    let nbytes = choices[dist.sample(rng)];
 
     // loosely generating weights to ensure that fewer bytes are more common
-    // let nbytes: u8 = match Uniform::from(0..20).sample(&mut rng) as u8 {
+    // let nbytes: u8 = match Uniform::from(0..20).sample(rng) as u8 {
     //     0 | 1 | 2 | 3 | 4 | 5 | 6 | 7 | 8 | 9 | 10 | 11 | 12 | 13 => 1,
     //     14 | 15 | 16 => 2,
     //     17 | 18 => 3,
@@ -212,23 +336,23 @@ fn create_delta_time() -> DeltaTime {
     
     match nbytes {
         1 => {
-            delta_time.push(Uniform::from(0..128).sample(&mut rng) as u8);
+            delta_time.push(Uniform::from(0..128).sample(rng) as u8);
         },
         2 => {
-            delta_time.push(Uniform::from(0..128).sample(&mut rng) as u8 | 0x80);
-            delta_time.push(Uniform::from(0..128).sample(&mut rng) as u8);
+            delta_time.push(Uniform::from(0..128).sample(rng) as u8 | 0x80);
+            delta_time.push(Uniform::from(0..128).sample(rng) as u8);
         },
         3 => {
-            delta_time.push(Uniform::from(0..128).sample(&mut rng) as u8 | 0x80);
-            delta_time.push(Uniform::from(0..128).sample(&mut rng) as u8 | 0x80);
-            delta_time.push(Uniform::from(0..128).sample(&mut rng) as u8);
+            delta_time.push(Uniform::from(0..128).sample(rng) as u8 | 0x80);
+            delta_time.push(Uniform::from(0..128).sample(rng) as u8 | 0x80);
+            delta_time.push(Uniform::from(0..128).sample(rng) as u8);
 
         },
         4 => {
-            delta_time.push(Uniform::from(0..128).sample(&mut rng) as u8 | 0x80);
-            delta_time.push(Uniform::from(0..128).sample(&mut rng) as u8 | 0x80);
-            delta_time.push(Uniform::from(0..128).sample(&mut rng) as u8 | 0x80);
-            delta_time.push(Uniform::from(0..128).sample(&mut rng) as u8);
+            delta_time.push(Uniform::from(0..128).sample(rng) as u8 | 0x80);
+            delta_time.push(Uniform::from(0..128).sample(rng) as u8 | 0x80);
+            delta_time.push(Uniform::from(0..128).sample(rng) as u8 | 0x80);
+            delta_time.push(Uniform::from(0..128).sample(rng) as u8);
         },
         _ => panic!("Error when generating delta time. nbytes out of range.")
     }
@@ -238,7 +362,7 @@ fn create_delta_time() -> DeltaTime {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 /// This is just a wrapper around a Vec<u8>
 struct Event {
     data: Vec<u8>, // Events have variable sizes, so we'll make a vector of bytes
@@ -246,75 +370,77 @@ struct Event {
 
 impl Event {
 
-    fn new_midi_event(event: MIDIEvent) -> Event {
+    /// Creates a MIDI channel event of the given kind with random data bytes
+    /// 
+    /// The channel nibble of the status byte always comes from `assignment`, so every channel message in a track stays on the track's channel
+    fn new_midi_event(rng: &mut dyn RngCore, event: MIDIEvent, assignment: &ChannelAssignment) -> Event {
         let mut event_bytes: Vec<u8> = Vec::new();
         
-        let mut rng = rand::thread_rng();
 
         match event {
             MIDIEvent::NoteOff => {
                 let mut status_byte: u8 = 0x80;
-                status_byte = status_byte | (Uniform::from(0..16).sample(&mut rng) as u8);
+                status_byte |= assignment.channel;
                 event_bytes.push(status_byte);
 
-                let note: u8 = Uniform::from(0..128).sample(&mut rng) as u8;
-                let velocity: u8 = Uniform::from(0..128).sample(&mut rng) as u8; // defaults to 64 in absence of velocity sensors?
+                let note: u8 = Uniform::from(0..128).sample(rng) as u8;
+                let velocity: u8 = Uniform::from(0..128).sample(rng) as u8; // defaults to 64 in absence of velocity sensors?
                 event_bytes.push(note);
                 event_bytes.push(velocity);
             },
             MIDIEvent::NoteOn => {
                 let mut status_byte: u8 = 0x90;
-                status_byte = status_byte | (Uniform::from(0..16).sample(&mut rng) as u8);
+                status_byte |= assignment.channel;
                 event_bytes.push(status_byte);
 
-                let note: u8 = Uniform::from(0..128).sample(&mut rng) as u8;
-                let velocity: u8 = Uniform::from(0..128).sample(&mut rng) as u8; // defaults to 64 in absence of velocity sensors?
+                let note: u8 = Uniform::from(0..128).sample(rng) as u8;
+                let velocity: u8 = Uniform::from(0..128).sample(rng) as u8; // defaults to 64 in absence of velocity sensors?
                 event_bytes.push(note);
                 event_bytes.push(velocity);
             },
             MIDIEvent::PolyphonicPressure => {
                 let mut status_byte: u8 = 0xA0;
-                status_byte = status_byte | (Uniform::from(0..16).sample(&mut rng) as u8);
+                status_byte |= assignment.channel;
                 event_bytes.push(status_byte);
 
-                let note: u8 = Uniform::from(0..128).sample(&mut rng) as u8;
-                let pressure: u8 = Uniform::from(0..128).sample(&mut rng) as u8; // amount of note aftertouch
+                let note: u8 = Uniform::from(0..128).sample(rng) as u8;
+                let pressure: u8 = Uniform::from(0..128).sample(rng) as u8; // amount of note aftertouch
                 event_bytes.push(note);
                 event_bytes.push(pressure);
             },
             MIDIEvent::Controller => {
                 let mut status_byte: u8 = 0xB0;
-                status_byte = status_byte | (Uniform::from(0..16).sample(&mut rng) as u8);
+                status_byte |= assignment.channel;
                 event_bytes.push(status_byte);
 
-                let controller: u8 = Uniform::from(0..128).sample(&mut rng) as u8;
-                let value: u8 = Uniform::from(0..128).sample(&mut rng) as u8;
+                let controller: u8 = Uniform::from(0..128).sample(rng) as u8;
+                let value: u8 = Uniform::from(0..128).sample(rng) as u8;
                 event_bytes.push(controller);
                 event_bytes.push(value);
             },
             MIDIEvent::ProgramChange => {
                 let mut status_byte: u8 = 0xC0;
-                status_byte = status_byte | (Uniform::from(0..16).sample(&mut rng) as u8);
+                status_byte |= assignment.channel;
                 event_bytes.push(status_byte);
 
-                let program: u8 = Uniform::from(0..128).sample(&mut rng) as u8;
+                let program: u8 = Uniform::from(0..128).sample(rng) as u8;
                 event_bytes.push(program);
             },
             MIDIEvent::ChannelPressure => {
                 let mut status_byte: u8 = 0xD0;
-                status_byte = status_byte | (Uniform::from(0..16).sample(&mut rng) as u8);
+                status_byte |= assignment.channel;
                 event_bytes.push(status_byte);
 
-                let pressure: u8 = Uniform::from(0..128).sample(&mut rng) as u8;
+                let pressure: u8 = Uniform::from(0..128).sample(rng) as u8;
                 event_bytes.push(pressure);
             },
             MIDIEvent::PitchBend => {
                 let mut status_byte: u8 = 0xE0;
-                status_byte = status_byte | (Uniform::from(0..16).sample(&mut rng) as u8);
+                status_byte |= assignment.channel;
                 event_bytes.push(status_byte);
 
-                let lsb: u8 = Uniform::from(0..128).sample(&mut rng) as u8;
-                let msb: u8 = Uniform::from(0..128).sample(&mut rng) as u8;
+                let lsb: u8 = Uniform::from(0..128).sample(rng) as u8;
+                let msb: u8 = Uniform::from(0..128).sample(rng) as u8;
                 event_bytes.push(lsb);
                 event_bytes.push(msb);
             },
@@ -325,63 +451,55 @@ impl Event {
         }
     }
 
-    fn new_meta_event(event: MetaEvent) -> Event {
+    /// Creates a Meta event of the given kind with random data bytes
+    /// 
+    /// MIDIChannelPrefix and MIDIPort events take their values from `assignment` rather than picking them at random.
+    /// Text-type events get text of a random length, written in `options.text_mode` if one is set.
+    fn new_meta_event(rng: &mut dyn RngCore, event: MetaEvent, assignment: &ChannelAssignment, options: &GeneratorOptions) -> Event {
         
         let mut event_bytes: Vec<u8> = Vec::new();
         event_bytes.push(0xFF); // Status byte 0xFF holds for all Meta Events
 
-        let mut rng = rand::thread_rng();
-
         match event {
             MetaEvent::Text => {
                 event_bytes.push(0x01);
-                let length = Uniform::from(1..50).sample(&mut rng) as u8;
-                event_bytes.push(length);
-                for byte in generate_random_characters(length as u32) {
-                    event_bytes.push(byte);
-                }
+                let text = generate_random_text(rng, options);
+                event_bytes.extend(encode_vlq(text.len() as u32));
+                event_bytes.extend(text);
             },
             MetaEvent::SequenceORTrackName => { // Optional, if in first track of format 0 or 1, gives Sequence Name. Gives Track Name otherwise.
                 event_bytes.push(0x03);
-                let length = Uniform::from(1..50).sample(&mut rng) as u8;
-                event_bytes.push(length);
-                for byte in generate_random_characters(length as u32) {
-                    event_bytes.push(byte);
-                }
+                let text = generate_random_text(rng, options);
+                event_bytes.extend(encode_vlq(text.len() as u32));
+                event_bytes.extend(text);
             },
             MetaEvent::InstrumentName => {
                 event_bytes.push(0x04);
-                let length = Uniform::from(1..50).sample(&mut rng) as u8;
-                event_bytes.push(length);
-                for byte in generate_random_characters(length as u32) {
-                    event_bytes.push(byte);
-                }
+                let text = generate_random_text(rng, options);
+                event_bytes.extend(encode_vlq(text.len() as u32));
+                event_bytes.extend(text);
             },
             MetaEvent::Lyric => {
                 event_bytes.push(0x05);
-                let length = Uniform::from(1..50).sample(&mut rng) as u8;
-                event_bytes.push(length);
-                for byte in generate_random_characters(length as u32) {
-                    event_bytes.push(byte);
-                }
+                let text = generate_random_text(rng, options);
+                event_bytes.extend(encode_vlq(text.len() as u32));
+                event_bytes.extend(text);
             },
             MetaEvent::ProgramName => {
                 event_bytes.push(0x08);
-                let length = Uniform::from(1..50).sample(&mut rng) as u8;
-                event_bytes.push(length);
-                for byte in generate_random_characters(length as u32) {
-                    event_bytes.push(byte);
-                }
+                let text = generate_random_text(rng, options);
+                event_bytes.extend(encode_vlq(text.len() as u32));
+                event_bytes.extend(text);
             },
             MetaEvent::MIDIChannelPrefix => {
                 event_bytes.push(0x20);
                 event_bytes.push(0x01);
-                event_bytes.push(Uniform::from(0..16).sample(&mut rng) as u8); // cc byte, specifying MIDI channel 0-15
+                event_bytes.push(assignment.channel); // cc byte, specifying MIDI channel 0-15
             },
             MetaEvent::MIDIPort => {
                 event_bytes.push(0x21);
                 event_bytes.push(0x01);
-                event_bytes.push(Uniform::from(0..128).sample(&mut rng) as u8); // pp byte, specifying MIDI port 0-127
+                event_bytes.push(assignment.port); // pp byte, specifying MIDI port 0-127
             },
             MetaEvent::EndOfTrack => { // Mandatory as the last event in each MTrk chunk, only one occurrence per track
                 event_bytes.push(0x2F);
@@ -389,27 +507,23 @@ impl Event {
             },
             MetaEvent::Marker => { // Format 1, only in first MTrk chunk
                 event_bytes.push(0x06);
-                let length = Uniform::from(1..50).sample(&mut rng) as u8;
-                event_bytes.push(length);
-                for byte in generate_random_characters(length as u32) {
-                    event_bytes.push(byte);
-                }
+                let text = generate_random_text(rng, options);
+                event_bytes.extend(encode_vlq(text.len() as u32));
+                event_bytes.extend(text);
             },
             MetaEvent::CuePoint => { // Format 1, only in first MTrk chunk
                 event_bytes.push(0x07);
-                let length = Uniform::from(1..50).sample(&mut rng) as u8;
-                event_bytes.push(length);
-                for byte in generate_random_characters(length as u32) {
-                    event_bytes.push(byte);
-                }
+                let text = generate_random_text(rng, options);
+                event_bytes.extend(encode_vlq(text.len() as u32));
+                event_bytes.extend(text);
             },
             MetaEvent::Tempo => { // Format 1, only in first MTrk chunk, Mandatory
                 event_bytes.push(0x51);
                 event_bytes.push(0x03);
 
                 // Need a 24-bit value for number of microseconds per quarter note
-                // set an arbitrary range from 100000..5000000
-                let tt_bytes = Uniform::from(100_000..5_000_000).sample(&mut rng) as u32;
+                // within the BPM range of the options, 100000..5000000 by default
+                let tt_bytes = conductor::random_tempo(rng, options);
                 
                 event_bytes.push(((tt_bytes & 0xFF0000) >> 16) as u8);
                 event_bytes.push(((tt_bytes & 0x00FF00) >> 8) as u8);
@@ -420,13 +534,13 @@ impl Event {
                 event_bytes.push(0x04);
 
                 // nn byte specifies the numerator of the time signature
-                let nn: u8 = Uniform::from(1..33).sample(&mut rng) as u8;
+                let nn: u8 = Uniform::from(1..33).sample(rng) as u8;
                 // dd byte specifies the denominator of the time signature as a negative power of 2 (i.e., 2 is quarter note, 3 is eighth-note, etc.)
-                let dd: u8 = Uniform::from(0..7).sample(&mut rng) as u8;
+                let dd: u8 = Uniform::from(0..7).sample(rng) as u8;
                 // cc byte specifies the number of MIDI clocks between metronome clicks
-                let cc: u8 = Uniform::from(1..65).sample(&mut rng) as u8;
+                let cc: u8 = Uniform::from(1..65).sample(rng) as u8;
                 // bb byte specifies the number of notated 32nd notes in a MIDI quarter-note (24 MIDI Clocks). The usual value is 8, though some sequencers allow user to specify
-                let bb: u8 = 0x08 as u8;
+                let bb: u8 = 0x08;
 
                 event_bytes.push(nn);
                 event_bytes.push(dd);
@@ -438,9 +552,9 @@ impl Event {
                 event_bytes.push(0x02);
 
                 // sf byte specifies the number of flats or sharps in the key signature, possible values from -7 to +7, inclusive
-                let sf: i8 = Uniform::from(-7..8).sample(&mut rng) as i8;
+                let sf: i8 = Uniform::from(-7..8).sample(rng) as i8;
                 // mi byte specifies major (0) or minor (1) key
-                let mi: u8 = Uniform::from(0..2).sample(&mut rng) as u8;
+                let mi: u8 = Uniform::from(0..2).sample(rng) as u8;
 
                 event_bytes.push(sf as u8); // cast to u8 will distort the value if we print it, but the bytes are the same
                 event_bytes.push(mi);
@@ -452,12 +566,48 @@ impl Event {
         }
     }
 
-    fn generate_mandatory_meta_events() -> Vec<Event> {
+    /// Creates a NoteOn event with the given note and velocity
+    fn new_note_on(channel: u8, note: u8, velocity: u8) -> Event {
+        Event {
+            data: vec![0x90 | channel, note, velocity],
+        }
+    }
+
+    /// Creates a NoteOff event with the given note and a release velocity of 64
+    fn new_note_off(channel: u8, note: u8) -> Event {
+        Event {
+            data: vec![0x80 | channel, note, 64],
+        }
+    }
+
+    /// Creates a text-type Meta event (Text, Lyric, Marker, etc.) holding `text`
+    /// 
+    /// # Arguments
+    /// 
+    /// * `meta_type` - The type byte following 0xFF, e.g. 0x01 for Text or 0x05 for Lyric
+    /// * `text` - The bytes of the text
+    fn new_text_event(meta_type: u8, text: &[u8]) -> Event {
+        let mut event_bytes: Vec<u8> = vec![0xFF, meta_type];
+        event_bytes.extend(encode_vlq(text.len() as u32));
+        event_bytes.extend_from_slice(text);
+
+        Event {
+            data: event_bytes,
+        }
+    }
+
+    /// Creates a Tempo event setting the given number of microseconds per quarter note
+    fn new_tempo(tt: u32) -> Event {
+        Event {
+            data: vec![0xFF, 0x51, 0x03, ((tt & 0xFF0000) >> 16) as u8, ((tt & 0x00FF00) >> 8) as u8, (tt & 0x0000FF) as u8],
+        }
+    }
+
+    /// Generates the Tempo, Time Signature and Key Signature events every tempo map needs, in that order
+    fn generate_mandatory_meta_events(rng: &mut dyn RngCore, options: &GeneratorOptions) -> Vec<Event> {
         
         let mut events: Vec<Event> = Vec::new();
 
-        let mut rng = rand::thread_rng();
-
         let mut tempo_bytes: Vec<u8> = Vec::new();
         let mut time_signature_bytes: Vec<u8> = Vec::new();
         let mut key_signature_bytes: Vec<u8> = Vec::new();
@@ -469,8 +619,8 @@ impl Event {
         tempo_bytes.push(0x03);
 
         // Need a 24-bit value for number of microseconds per quarter note
-        // set an arbitrary range from 100000..5000000
-        let tt_bytes = Uniform::from(100_000..5_000_000).sample(&mut rng) as u32;
+        // within the BPM range of the options, 100000..5000000 by default
+        let tt_bytes = conductor::random_tempo(rng, options);
         
         tempo_bytes.push(((tt_bytes & 0xFF0000) >> 16) as u8);
         tempo_bytes.push(((tt_bytes & 0x00FF00) >> 8) as u8);
@@ -488,13 +638,13 @@ impl Event {
         time_signature_bytes.push(0x04);
 
         // nn byte specifies the numerator of the time signature
-        let nn: u8 = Uniform::from(1..33).sample(&mut rng) as u8;
+        let nn: u8 = Uniform::from(1..33).sample(rng) as u8;
         // dd byte specifies the denominator of the time signature as a negative power of 2 (i.e., 2 is quarter note, 3 is eighth-note, etc.)
-        let dd: u8 = Uniform::from(0..7).sample(&mut rng) as u8;
+        let dd: u8 = Uniform::from(0..7).sample(rng) as u8;
         // cc byte specifies the number of MIDI clocks between metronome clicks
-        let cc: u8 = Uniform::from(1..65).sample(&mut rng) as u8;
+        let cc: u8 = Uniform::from(1..65).sample(rng) as u8;
         // bb byte specifies the number of notated 32nd notes in a MIDI quarter-note (24 MIDI Clocks). The usual value is 8, though some sequencers allow user to specify
-        let bb: u8 = 0x08 as u8;
+        let bb: u8 = 0x08;
 
         time_signature_bytes.push(nn);
         time_signature_bytes.push(dd);
@@ -512,9 +662,9 @@ impl Event {
         key_signature_bytes.push(0x02);
 
         // sf byte specifies the number of flats or sharps in the key signature, possible values from -7 to +7, inclusive
-        let sf: i8 = Uniform::from(-7..8).sample(&mut rng) as i8;
+        let sf: i8 = Uniform::from(-7..8).sample(rng) as i8;
         // mi byte specifies major (0) or minor (1) key
-        let mi: u8 = Uniform::from(0..2).sample(&mut rng) as u8;
+        let mi: u8 = Uniform::from(0..2).sample(rng) as u8;
 
         key_signature_bytes.push(sf as u8); // cast to u8 will distort the value if we print it, but the bytes are the same
         key_signature_bytes.push(mi);
@@ -536,7 +686,7 @@ impl Event {
 // Track chunk
 // A single track chunk will contain a sequence of delta-time / event pairs for chunklen bytes
 // The different event types, MidiEvent, SysExEvent, and MetaEvent can all be used in a single track chunk
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct MTrk {
     identifier: [u8; 4],
     chunklen: u32, // big-endian
@@ -545,27 +695,37 @@ struct MTrk {
 }
 
 impl MTrk {
-    fn new() -> MTrk {
-        let mut rng = rand::thread_rng();
-        let uniform = Uniform::from(0..3);
-
-        let fmt = uniform.sample(&mut rng) as u16;
+    /// Wraps a list of <DeltaTime, Event> pairs in an MTrk chunk, computing chunklen from the data
+    fn new(data: Vec<(DeltaTime, Event)>) -> MTrk {
+        let chunklen = data.iter()
+            .map(|(delta_time, event)| (delta_time.data.len() + event.data.len()) as u32)
+            .sum();
 
         MTrk {
-            identifier: ['M' as u8, 'T' as u8, 'r' as u8, 'k' as u8],
-            chunklen: 3,
-            data: Vec::new(),
+            identifier: [b'M', b'T', b'r', b'k'],
+            chunklen,
+            data,
         }
     }
 
-    fn new_track_format_0() -> MTrk {
-        todo!();
+    /// Generates a random track for format 0 files.
+    /// The single track of a format 0 file holds the timing events as well as the note data.
+    fn new_track_format_0(rng: &mut dyn RngCore, assignment: &ChannelAssignment, options: &GeneratorOptions) -> MTrk {
+        let mut data = Vec::new();
+
+        for event in Event::generate_mandatory_meta_events(rng, options) {
+            data.push((DeltaTime::zero(), event));
+        }
+        data.extend(generate_track_events(rng, assignment, options));
+
+        MTrk::new(data)
     }
 
     /// Generates a random Global Tempo Track Chunk for use in format 1 files.
     /// A global tempo track contains all timing related events and no note data.
     /// 
-    /// This will generate a random number of timing events from 1..100
+    /// This will generate a series of tempo gestures (see conductor::generate_tempo_changes)
+    /// and a random number of other timing events from 1..100
     /// 
     /// Timing events are the following Meta events:
     /// 
@@ -575,64 +735,569 @@ impl MTrk {
     /// * SMPTE Offset
     /// * Time Signature
     /// * Key Signature
-    fn new_global_tempo() -> MTrk {
-        let mut rng = rand::thread_rng();
+    fn new_global_tempo(rng: &mut dyn RngCore, options: &GeneratorOptions) -> MTrk {
+        let assignment = ChannelAssignment::default(); // timing events carry no channel
         
+        // Tempo changes come from the conductor as a series of gestures, the other timing events are scattered over the same span
+        let (mut events, length) = conductor::generate_tempo_changes(rng, options);
+
+        for event in Event::generate_mandatory_meta_events(rng, options).into_iter().skip(1) { // the conductor supplies the opening Tempo
+            events.push((0, event));
+        }
+
+        let timing_events = [MetaEvent::Marker, MetaEvent::CuePoint, MetaEvent::TimeSignature, MetaEvent::KeySignature];
+        let nevents = Uniform::from(1..100).sample(rng);
+        for _ in 0..nevents {
+            let event = timing_events[Uniform::from(0..timing_events.len()).sample(rng)];
+            events.push((Uniform::from(0..=length).sample(rng), Event::new_meta_event(rng, event, &assignment, options)));
+        }
+
+        events.sort_by_key(|(tick, _)| *tick); // stable, so the opening events stay first
+
         // Generate <DeltaTime, Event> pairs
-        
+        let mut data = to_delta_times(events);
+        data.push((create_delta_time(rng), Event::new_meta_event(rng, MetaEvent::EndOfTrack, &assignment, options)));
 
-        MTrk {
-            identifier: ['M' as u8, 'T' as u8, 'r' as u8, 'k' as u8],
-            chunklen: 3,
-            data: Vec::new(),
+        MTrk::new(data)
+    }
+
+    /// Generates a random note track for format 1 files.
+    /// Timing events are left to the global tempo track, so this only holds channel events and non-timing Meta events.
+    fn new_track_format_1(rng: &mut dyn RngCore, assignment: &ChannelAssignment, options: &GeneratorOptions) -> MTrk {
+        MTrk::new(generate_track_events(rng, assignment, options))
+    }
+
+    /// Generates a random track for format 2 files.
+    /// Tracks in a format 2 file are independent, so each one carries its own tempo map just like a format 0 track.
+    fn new_track_format_2(rng: &mut dyn RngCore, assignment: &ChannelAssignment, options: &GeneratorOptions) -> MTrk {
+        MTrk::new_track_format_0(rng, assignment, options)
+    }
+
+    /// Returns each event of the track with the absolute tick it happens on
+    fn timed_events(&self) -> Vec<(u32, &Event)> {
+        let mut tick: u32 = 0;
+
+        self.data.iter().map(|(delta_time, event)| {
+            tick = tick.saturating_add(delta_time.ticks());
+            (tick, event)
+        }).collect()
+    }
+
+    /// Returns the tick of the last event in the track, which is how long the track lasts
+    fn length(&self) -> u32 {
+        self.timed_events().last().map_or(0, |(tick, _)| *tick)
+    }
+
+    /// Returns the bytes of the chunk as they appear in a MIDI file
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+
+        bytes.extend_from_slice(&self.identifier);
+        bytes.extend_from_slice(&self.chunklen.to_be_bytes());
+        for (delta_time, event) in &self.data {
+            bytes.extend_from_slice(&delta_time.data);
+            bytes.extend_from_slice(&event.data);
         }
 
+        bytes
     }
+}
 
-    fn new_track_format_1() -> MTrk {
-        todo!();
+/// Generates the channel and non-timing Meta events making up the body of a track, finishing with an EndOfTrack event
+/// 
+/// This will generate a random number of events from 1..100, roughly four channel events to every Meta event.
+/// All channel events are sent on the channel in `assignment`.
+fn generate_track_events(rng: &mut dyn RngCore, assignment: &ChannelAssignment, options: &GeneratorOptions) -> Vec<(DeltaTime, Event)> {
+    let mut data = Vec::new();
+
+    // Announce the track's channel and port up front so that players routing by port see it before any channel events
+    if options.spread_channels {
+        data.push((DeltaTime::zero(), Event::new_meta_event(rng, MetaEvent::MIDIChannelPrefix, assignment, options)));
+        data.push((DeltaTime::zero(), Event::new_meta_event(rng, MetaEvent::MIDIPort, assignment, options)));
     }
 
-    fn new_track_format_2() -> MTrk {
-        todo!();
+    let kinds = WeightedIndex::new([80, 20]).unwrap(); // channel event, Meta event
+    let nevents = Uniform::from(1..100).sample(rng);
+    for _ in 0..nevents {
+        let event = match kinds.sample(rng) {
+            0 => {
+                let event = MIDIEvent::pick_random(rng);
+                Event::new_midi_event(rng, event, assignment)
+            },
+            _ => {
+                let event = MetaEvent::pick_random(rng, 0, 7); // stop short of EndOfTrack and the timing events
+                Event::new_meta_event(rng, event, assignment, options)
+            },
+        };
+        data.push((create_delta_time(rng), event));
     }
+
+    data.push((create_delta_time(rng), Event::new_meta_event(rng, MetaEvent::EndOfTrack, assignment, options)));
+
+    data
 }
 
-/// Generate n number of ASCII characters in range 32..127 (inclusive), returning as a Vec<u8>
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+/// The MIDI channel and port that a track sends its channel events on
 /// 
-/// # Arguments
+/// MIDIChannelPrefix and MIDIPort Meta events in the track are generated from the same values
+struct ChannelAssignment {
+    channel: u8, // 0-15, the low nibble of every channel event status byte
+    port: u8, // 0-127
+}
+
+impl ChannelAssignment {
+    /// Picks the channel and port for the note track at `index`, counting from 0
+    /// 
+    /// # Arguments
+    /// 
+    /// * `index` - The position of the track among the note tracks of the file
+    /// * `spread` - If true, tracks take channels 0-15 in order and every further 16 tracks move onto the next port,
+    ///   so no two tracks share a channel on the same port. If false, a random channel on port 0 is picked.
+    fn for_track(rng: &mut dyn RngCore, index: u16, spread: bool) -> ChannelAssignment {
+        if spread {
+            ChannelAssignment {
+                channel: (index % 16) as u8,
+                port: ((index / 16) % 128) as u8,
+            }
+        }
+        else {
+            ChannelAssignment {
+                channel: Uniform::from(0..16).sample(rng) as u8,
+                port: 0,
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+/// Options controlling the content of generated files, set from the command line
+struct GeneratorOptions {
+    spread_channels: bool, // deal tracks out across channels and ports instead of picking channels at random
+    lyrics: bool, // make the first note track a melody with a Lyric event on every note
+    karaoke: bool, // lay the file out as a .kar karaoke file, implies a format 1 file
+    text_mode: Option<TextMode>, // kind of text in text-type Meta events, None picks one at random for each event
+    bpm_range: Option<(f64, f64)>, // lowest and highest tempo in beats per minute, None uses conductor::DEFAULT_BPM_RANGE
+    faults: Vec<corrupt::Fault>, // fault classes to inject, empty for a well-formed file
+    foreign_chunks: bool, // add chunks of unknown type and pad out the MThd data
+    rmid: bool, // wrap the file in a RIFF RMID container
+    provenance: bool, // start the first track with a Sequencer-Specific event recording how the file was made
+}
+
+/// Returns the bytes of a complete MIDI file made of the header followed by each track chunk
+fn encode_midi_file(header: &MThd, tracks: &[MTrk]) -> Vec<u8> {
+    let mut bytes = header.to_bytes();
+
+    for track in tracks {
+        bytes.extend(track.to_bytes());
+    }
+
+    bytes
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+/// Enum defining the kinds of text written into text-type Meta events
+/// 
+/// Use TextMode::pick_random(rng) to randomly choose a TextMode, favouring plain ASCII
+enum TextMode {
+    Ascii, // printable ASCII, 32..127
+    Utf8, // characters from several scripts, encoded as UTF-8
+    Latin1, // printable ISO-8859-1, including the 0xA0..0xFF range
+    Words, // pseudo-words separated by spaces
+    Empty, // zero-length text
+}
+
+impl TextMode {
+    /// Returns a random TextMode using a weighted distribution
+    fn pick_random(rng: &mut dyn RngCore) -> TextMode {
+        let choices = [TextMode::Ascii, TextMode::Utf8, TextMode::Latin1, TextMode::Words, TextMode::Empty];
+        let weights = [50, 15, 15, 15, 5];
+        let dist = WeightedIndex::new(weights).unwrap();
+
+        choices[dist.sample(rng)]
+    }
+
+    /// Returns the name of the TextMode as given on the command line
+    fn name(&self) -> &'static str {
+        match self {
+            TextMode::Ascii => "ascii",
+            TextMode::Utf8 => "utf8",
+            TextMode::Latin1 => "latin1",
+            TextMode::Words => "words",
+            TextMode::Empty => "empty",
+        }
+    }
+
+    /// Parses the name of a TextMode as given on the command line
+    fn from_name(name: &str) -> Option<TextMode> {
+        match name {
+            "ascii" => Some(TextMode::Ascii),
+            "utf8" => Some(TextMode::Utf8),
+            "latin1" => Some(TextMode::Latin1),
+            "words" => Some(TextMode::Words),
+            "empty" => Some(TextMode::Empty),
+            _ => None,
+        }
+    }
+}
+
+/// Generates the text of a text-type Meta event
 /// 
-/// * `n` - The number of characters to generate
-fn generate_random_characters(n: u32) -> Vec<u8> {
-    let mut rng = rand::thread_rng();
-    let uniform = Uniform::from(32..128);
+/// Most texts are short, but some run into the thousands of characters so that lengths need multi-byte VLQs.
+/// The kind of text comes from `options.text_mode`, or is picked at random for each event if that is unset.
+fn generate_random_text(rng: &mut dyn RngCore, options: &GeneratorOptions) -> Vec<u8> {
+    let lengths = [(1, 50), (50, 128), (128, 1_000), (1_000, 20_000)];
+    let weights = [80, 10, 8, 2];
+    let dist = WeightedIndex::new(weights).unwrap();
+    let (lower, upper) = lengths[dist.sample(rng)];
+
+    let mode = options.text_mode.unwrap_or_else(|| TextMode::pick_random(rng));
+    let length = Uniform::from(lower..upper).sample(rng);
+    generate_random_characters(rng, length, mode)
+}
 
+/// Generate n number of characters of the given kind, returning their encoded bytes as a Vec<u8>
+/// 
+/// # Arguments
+/// 
+/// * `n` - The number of characters to generate. UTF-8 characters can take up to 4 bytes each, so the result may be longer than n.
+/// * `mode` - The kind of text to generate. TextMode::Empty ignores n and always returns no bytes.
+fn generate_random_characters(rng: &mut dyn RngCore, n: u32, mode: TextMode) -> Vec<u8> {
     let mut chars = Vec::new();
 
-    for _ in 0..n {
-        chars.push(uniform.sample(&mut rng) as u8);
+    match mode {
+        TextMode::Ascii => {
+            let uniform = Uniform::from(32..128);
+            for _ in 0..n {
+                chars.push(uniform.sample(rng) as u8);
+            }
+        },
+        TextMode::Utf8 => {
+            // Latin-1 Supplement, Greek, Cyrillic, Hebrew, Hiragana, CJK and emoji, to cover every UTF-8 sequence length
+            let blocks = [(0x20, 0x7F), (0xA0, 0x100), (0x391, 0x3CA), (0x410, 0x450), (0x5D0, 0x5EB), (0x3041, 0x3097), (0x4E00, 0x9FA6), (0x1F600, 0x1F650)];
+            let block = Uniform::from(0..blocks.len());
+            for _ in 0..n {
+                let (lower, upper) = blocks[block.sample(rng)];
+                let c = std::char::from_u32(Uniform::from(lower..upper).sample(rng)).unwrap();
+                chars.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+            }
+        },
+        TextMode::Latin1 => {
+            let range = Uniform::from(0..(95 + 96)); // 95 printable ASCII characters and 96 from 0xA0..0xFF
+            for _ in 0..n {
+                let index = range.sample(rng) as u8;
+                chars.push(if index < 95 { 32 + index } else { 0xA0 + (index - 95) });
+            }
+        },
+        TextMode::Words => {
+            while (chars.len() as u32) < n {
+                if !chars.is_empty() {
+                    chars.push(b' ');
+                }
+                for _ in 0..Uniform::from(1..4).sample(rng) {
+                    chars.extend_from_slice(lyrics::generate_syllable(rng).as_bytes());
+                }
+            }
+            chars.truncate(n as usize);
+        },
+        TextMode::Empty => (),
     }
 
     chars
 }
 
-fn main() {
-    let header = MThd::new();
+/// Command line arguments for a generator run
+struct Args {
+    output: String, // path of the MIDI file to write
+    options: GeneratorOptions,
+    print_duration: bool, // print how long the generated file plays
+    seed: Option<u64>, // seed for the generator, None picks one at random
+    manifest: bool, // write a JSON manifest describing the file next to it
+    render: bool, // render the file to audio next to it
+}
+
+const USAGE: &str = "\
+usage: midi_generator [options]
+       midi_generator <command> [arguments]
+
+commands:
+  mutate                 write mutants of a corpus of existing MIDI files, see midi_generator mutate --help
+  from-bytes             generate a file from the bytes of a fuzzer test case instead of at random
+  reduce                 shrink a file that makes a program fail, see midi_generator reduce --help
+  differential           compare how several programs read the same generated files,
+                         see midi_generator differential --help
+  catalog                write one small file for each edge case of the format
+  regenerate             rebuild a generated file from the provenance recorded in it
+  batch                  write a corpus of files into a directory, see midi_generator batch --help
+  to-csv                 write a MIDI file as midicsv text, one line per event
+  from-csv               turn midicsv text back into a MIDI file
+  to-json                write the model of a MIDI file as JSON with typed event fields
+  from-json              turn the JSON model of a file back into a MIDI file
+  musicxml               write the notes of a MIDI file as a MusicXML score
+  abc                    write the notes of a MIDI file as an ABC tune
+  lilypond               write the notes and lyrics of a MIDI file as a LilyPond score
+  render                 play a MIDI file through a built-in synthesizer into a WAV file,
+                         see midi_generator render --help
+  to-ump                 write a MIDI file as a stream of MIDI 2.0 Universal MIDI Packets
+  to-clip                write a MIDI file as a MIDI 2.0 Clip File (SMF2CLIP)
+  from-clip              turn a MIDI 2.0 Clip File back into a MIDI file
+  piano-roll             draw a MIDI file as an SVG piano roll with controller, pitch bend and tempo lanes
+
+options:
+  -o, --output <file>    write the generated file to <file> (default output.mid)
+  --print-duration       print how long the generated file plays, in seconds
+                         (and as SMPTE timecode for timecode files)
+  --seed <n>             start the generator from seed <n>, so the same seed and options give the same file
+  --manifest             describe the file in a JSON manifest written to <file>.json
+  --render               play the file through the built-in synthesizer into <file>.wav
+  --spread-channels      give each track its own channel, moving to the next port after 16 tracks
+  --lyrics               sing the first note track with a Lyric event on every note
+  --karaoke              lay the file out as a .kar karaoke file
+  --text-mode <mode>     text in text-type Meta events: ascii, utf8, latin1, words or empty
+  --bpm <min>-<max>      keep tempos between <min> and <max> beats per minute (default 12-600)
+  --corrupt <faults>     make the file malformed with faults picked from a comma separated list, or all:
+                         truncated-chunk, chunklen-mismatch, missing-end-of-track, high-bit-data,
+                         long-vlq, orphaned-running-status, unterminated-sysex, wrong-ntracks
+                         the injected faults are listed one per line in <file>.faults
+  --foreign-chunks       add chunks of unknown type around the MTrk chunks and pad out the MThd chunk
+  --rmid                 wrap the file in a RIFF RMID container, with an INFO list giving the title and
+                         copyright from the first track
+  --no-provenance        leave out the Sequencer-Specific event recording the version, seed and options
+                         that midi_generator regenerate rebuilds the file from";
+
+/// Parses the command line arguments, not including the program name
+fn parse_args<I: Iterator<Item = String>>(mut args: I) -> Result<Args, String> {
+    let mut parsed = Args {
+        output: String::from("output.mid"),
+        options: GeneratorOptions { provenance: true, ..Default::default() },
+        print_duration: false,
+        seed: None,
+        manifest: false,
+        render: false,
+    };
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" | "--output" => {
+                parsed.output = args.next().ok_or(format!("{} needs a file name", arg))?;
+            },
+            "--spread-channels" => parsed.options.spread_channels = true,
+            "--print-duration" => parsed.print_duration = true,
+            "--seed" => {
+                let seed = args.next().ok_or(format!("{} needs a number", arg))?;
+                parsed.seed = Some(seed.parse().map_err(|_| format!("invalid seed: {}", seed))?);
+            },
+            "--manifest" => parsed.manifest = true,
+            "--render" => parsed.render = true,
+            "--lyrics" => parsed.options.lyrics = true,
+            "--karaoke" => parsed.options.karaoke = true,
+            "--foreign-chunks" => parsed.options.foreign_chunks = true,
+            "--rmid" => parsed.options.rmid = true,
+            "--no-provenance" => parsed.options.provenance = false,
+            "--bpm" => {
+                let range = args.next().ok_or(format!("{} needs a range", arg))?;
+                parsed.options.bpm_range = Some(conductor::parse_bpm_range(&range)?);
+            },
+            "--corrupt" => {
+                let faults = args.next().ok_or(format!("{} needs a list of faults", arg))?;
+                parsed.options.faults = corrupt::parse_faults(&faults)?;
+            },
+            "--text-mode" => {
+                let name = args.next().ok_or(format!("{} needs a mode", arg))?;
+                parsed.options.text_mode = Some(TextMode::from_name(&name).ok_or(format!("unknown text mode: {}", name))?);
+            },
+            _ => return Err(format!("unknown argument: {}", arg)),
+        }
+    }
+
+    Ok(parsed)
+}
+
+/// Generates the header and every track of a random MIDI file
+fn generate_midi_file(rng: &mut dyn RngCore, options: &GeneratorOptions) -> (MThd, Vec<MTrk>) {
+    let mut header = MThd::new(rng);
     let mut tracks = Vec::new();
 
+    if options.karaoke { // .kar files are format 1 with a tempo track, a words track and a melody track
+        header.format = 1;
+        header.ntracks = header.ntracks.max(3);
+        let tracks = lyrics::new_karaoke_tracks(rng, header.ntracks, options);
+        return (header, tracks);
+    }
+
     // Generate MTrk chunks depending on format
     if header.format == 0 { // need a single MTrk chunk containing any valid event
-        tracks.push(MTrk::new_track_format_0());
+        let assignment = ChannelAssignment::for_track(rng, 0, options.spread_channels);
+        tracks.push(MTrk::new_track_format_0(rng, &assignment, options));
     }
     else if header.format == 1 { // first MTrk chunk is a global tempo chunk, second and subsequent are the actual note data
-        tracks.push(MTrk::new_global_tempo());
-        for _ in 1..header.ntracks {
-            tracks.push(MTrk::new_track_format_1());
+        tracks.push(MTrk::new_global_tempo(rng, options));
+        for index in 1..header.ntracks {
+            let assignment = ChannelAssignment::for_track(rng, index - 1, options.spread_channels);
+            tracks.push(MTrk::new_track_format_1(rng, &assignment, options));
         }        
     } 
     else { // each track is separate and can contain any type of event, each track may have its own tempo map
-        for _ in 0..header.ntracks {
-            tracks.push(MTrk::new_track_format_2());
+        for index in 0..header.ntracks {
+            let assignment = ChannelAssignment::for_track(rng, index, options.spread_channels);
+            tracks.push(MTrk::new_track_format_2(rng, &assignment, options));
+        }
+    }
+
+    if options.lyrics { // swap the first note track for a sung melody
+        let first_note_track = if header.format == 1 { 1 } else { 0 };
+        let assignment = ChannelAssignment::for_track(rng, 0, options.spread_channels);
+        tracks[first_note_track] = lyrics::new_lyric_track(rng, &assignment, header.format != 1, options);
+    }
+
+    (header, tracks)
+}
+
+/// A generated file, as written
+struct GeneratedFile {
+    header: MThd,
+    tracks: Vec<MTrk>, // after any faults were injected
+    bytes: Vec<u8>, // including any foreign chunks
+    faults: Vec<corrupt::Fault>, // the faults that were injected
+}
+
+/// Generates a whole file from a seed, with its provenance, faults and foreign chunks as the options ask
+///
+/// The same seed and options always give the same bytes.
+fn generate_file(seed: u64, options: &GeneratorOptions) -> GeneratedFile {
+    let rng = &mut StdRng::seed_from_u64(seed);
+    let (mut header, mut tracks) = generate_midi_file(rng, options);
+
+    if options.provenance {
+        let position = if options.karaoke { 1 } else { 0 }; // keep "@KMIDI KARAOKE FILE" first
+        let mut data = tracks[0].data.clone();
+        data.insert(position, (DeltaTime::zero(), provenance::provenance_event(seed, options)));
+        tracks[0] = MTrk::new(data);
+    }
+
+    let (bytes, faults) = if options.faults.is_empty() {
+        (encode_midi_file(&header, &tracks), Vec::new())
+    }
+    else {
+        corrupt::corrupt(rng, &mut header, &mut tracks, &options.faults)
+    };
+
+    let bytes = if options.foreign_chunks {
+        let lengths: Vec<usize> = tracks.iter().map(|track| track.to_bytes().len()).collect();
+        chunks::insert_foreign_chunks(rng, bytes, &lengths)
+    }
+    else {
+        bytes
+    };
+
+    let bytes = if options.rmid { riff::wrap_rmid(&bytes, &riff::rmid_info(&tracks)) } else { bytes };
+
+    GeneratedFile {
+        header,
+        tracks,
+        bytes,
+        faults,
+    }
+}
+
+/// A command that can be given as the first argument, run with the arguments that follow it
+struct Command {
+    name: &'static str,
+    run: fn(Vec<String>) -> Result<(), String>,
+    usage: &'static str,
+}
+
+const COMMANDS: [Command; 19] = [
+    Command { name: "mutate", run: mutate::run, usage: mutate::USAGE },
+    Command { name: "from-bytes", run: byte_stream::run, usage: byte_stream::USAGE },
+    Command { name: "reduce", run: reduce::run, usage: reduce::USAGE },
+    Command { name: "differential", run: differential::run, usage: differential::USAGE },
+    Command { name: "catalog", run: catalog::run, usage: catalog::USAGE },
+    Command { name: "regenerate", run: provenance::run, usage: provenance::USAGE },
+    Command { name: "batch", run: batch::run, usage: batch::USAGE },
+    Command { name: "to-csv", run: midicsv::run_to_csv, usage: midicsv::TO_CSV_USAGE },
+    Command { name: "from-csv", run: midicsv::run_from_csv, usage: midicsv::FROM_CSV_USAGE },
+    Command { name: "to-json", run: json::run_to_json, usage: json::TO_JSON_USAGE },
+    Command { name: "from-json", run: json::run_from_json, usage: json::FROM_JSON_USAGE },
+    Command { name: "musicxml", run: musicxml::run, usage: musicxml::USAGE },
+    Command { name: "abc", run: abc::run, usage: abc::USAGE },
+    Command { name: "lilypond", run: lilypond::run, usage: lilypond::USAGE },
+    Command { name: "render", run: synth::run, usage: synth::USAGE },
+    Command { name: "to-ump", run: ump::run_to_ump, usage: ump::TO_UMP_USAGE },
+    Command { name: "to-clip", run: ump::run_to_clip, usage: ump::TO_CLIP_USAGE },
+    Command { name: "from-clip", run: ump::run_from_clip, usage: ump::FROM_CLIP_USAGE },
+    Command { name: "piano-roll", run: piano_roll::run, usage: piano_roll::USAGE },
+];
+
+fn main() {
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+
+    if let Some(command) = COMMANDS.iter().find(|command| args.first().map(String::as_str) == Some(command.name)) {
+        let command_args = args.split_off(1);
+        if command_args.iter().any(|arg| arg == "-h" || arg == "--help") {
+            println!("{}", command.usage);
+            return;
+        }
+        if let Err(message) = (command.run)(command_args) {
+            eprintln!("{}", message);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let args = match parse_args(args.into_iter()) {
+        Ok(args) => args,
+        Err(message) => {
+            eprintln!("{}\n{}", message, USAGE);
+            std::process::exit(2);
+        }
+    };
+
+    let seed = args.seed.unwrap_or_else(rand::random);
+    let GeneratedFile { header, tracks, bytes, faults } = generate_file(seed, &args.options);
+
+    if let Err(error) = std::fs::write(&args.output, &bytes) {
+        eprintln!("could not write {}: {}", args.output, error);
+        std::process::exit(1);
+    }
+
+    if !faults.is_empty() {
+        let record: String = faults.iter().map(|fault| format!("{}\n", fault.name())).collect();
+        let path = format!("{}.faults", args.output);
+        if let Err(error) = std::fs::write(&path, record) {
+            eprintln!("could not write {}: {}", path, error);
+            std::process::exit(1);
+        }
+    }
+
+    if args.manifest {
+        let path = format!("{}.json", args.output);
+        if let Err(error) = std::fs::write(&path, manifest::manifest(&args.output, seed, &header, &tracks, &faults, &bytes)) {
+            eprintln!("could not write {}: {}", path, error);
+            std::process::exit(1);
+        }
+    }
+
+    if args.render {
+        let path = format!("{}.wav", args.output);
+        let options = synth::RenderOptions::default();
+        let (frames, truncated) = synth::render(&header, &tracks, &options);
+        if truncated {
+            eprintln!("warning: the render was cut off after {} seconds", options.max_seconds);
+        }
+        let written = wav::encode_wav(&frames, options.sample_rate, wav::SampleFormat::Int16)
+            .and_then(|bytes| std::fs::write(&path, bytes).map_err(|error| format!("could not write {}: {}", path, error)));
+        if let Err(error) = written {
+            eprintln!("{}", error);
+            std::process::exit(1);
+        }
+    }
+
+    if args.print_duration {
+        let seconds = timing::duration(&header, &tracks);
+        match timing::TickDiv::from_tickdiv(header.tickdiv) {
+            timing::TickDiv::Timecode { fps, .. } => println!("{:.3} ({})", seconds, timing::timecode(seconds, fps)),
+            timing::TickDiv::Metrical { .. } => println!("{:.3}", seconds),
         }
     }
 }
@@ -643,7 +1308,8 @@ mod tests {
 
     #[test]
     fn mthd_size_is_valid() {
-        let header = MThd::new();
+        let rng = &mut rand::thread_rng();
+        let header = MThd::new(rng);
         assert_eq!(
             std::mem::size_of_val(&header.identifier) +
             std::mem::size_of_val(&header.chunklen) +
@@ -654,13 +1320,14 @@ mod tests {
 
     #[test]
     fn mthd_is_valid() {
+        let rng = &mut rand::thread_rng();
 
         // relying on randomness for a test is bad
         // should be making custom headers to test these things
         // or, better, should be using a seeded random number generator to get predictable results
         // but, because this new function does rely on randomness, we will just loop and make a bunch of them
         for _ in 0..100 {
-            let header = MThd::new();
+            let header = MThd::new(rng);
 
             assert_eq!(header.identifier[0] as char, 'M');
             assert_eq!(header.identifier[1] as char, 'T');
@@ -702,5 +1369,71 @@ mod tests {
         }
     }
 
+    #[test]
+    fn track_events_stay_on_assigned_channel() {
+        let rng = &mut rand::thread_rng();
+        let assignment = ChannelAssignment { channel: 5, port: 3 };
+        let options = GeneratorOptions { spread_channels: true, ..Default::default() };
+
+        for _ in 0..100 {
+            let track = MTrk::new_track_format_1(rng, &assignment, &options);
+
+            for (_, event) in &track.data {
+                match event.data[0] {
+                    0x80..=0xEF => assert_eq!(event.data[0] & 0x0F, 5),
+                    0xFF if event.data[1] == 0x20 => assert_eq!(event.data[3], 5),
+                    0xFF if event.data[1] == 0x21 => assert_eq!(event.data[3], 3),
+                    _ => (),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn spread_assignment_moves_to_next_port_after_16_tracks() {
+        let rng = &mut rand::thread_rng();
+        assert_eq!(ChannelAssignment::for_track(rng, 0, true), ChannelAssignment { channel: 0, port: 0 });
+        assert_eq!(ChannelAssignment::for_track(rng, 15, true), ChannelAssignment { channel: 15, port: 0 });
+        assert_eq!(ChannelAssignment::for_track(rng, 17, true), ChannelAssignment { channel: 1, port: 1 });
+    }
+
+    #[test]
+    fn mtrk_chunklen_matches_data() {
+        let rng = &mut rand::thread_rng();
+        let track = MTrk::new_global_tempo(rng, &GeneratorOptions::default());
+        let bytes = track.to_bytes();
+
+        assert_eq!(bytes.len() as u32, track.chunklen + 8);
+        assert_eq!(&bytes[bytes.len() - 3..], &[0xFF, 0x2F, 0x00]);
+    }
+
+    #[test]
+    fn vlq_encoding_matches_spec_examples() {
+        assert_eq!(encode_vlq(0), vec![0x00]);
+        assert_eq!(encode_vlq(0x7F), vec![0x7F]);
+        assert_eq!(encode_vlq(0x80), vec![0x81, 0x00]);
+        assert_eq!(encode_vlq(0x2000), vec![0xC0, 0x00]);
+        assert_eq!(encode_vlq(0x1FFFFF), vec![0xFF, 0xFF, 0x7F]);
+        assert_eq!(encode_vlq(0x0FFFFFFF), vec![0xFF, 0xFF, 0xFF, 0x7F]);
+    }
+
+    #[test]
+    fn long_text_event_uses_vlq_length() {
+        let event = Event::new_text_event(0x01, &[b'a'; 200]);
+
+        assert_eq!(&event.data[..4], &[0xFF, 0x01, 0x81, 0x48]);
+        assert_eq!(event.data.len(), 4 + 200);
+    }
 
-}
\ No newline at end of file
+    #[test]
+    fn text_modes_produce_their_encodings() {
+        let rng = &mut rand::thread_rng();
+        for _ in 0..100 {
+            assert!(generate_random_characters(rng, 40, TextMode::Ascii).iter().all(|&b| (32..128).contains(&b)));
+            assert!(generate_random_characters(rng, 40, TextMode::Latin1).iter().all(|&b| (32..127).contains(&b) || b >= 0xA0));
+            assert!(String::from_utf8(generate_random_characters(rng, 40, TextMode::Utf8)).is_ok());
+            assert_eq!(generate_random_characters(rng, 40, TextMode::Words).len(), 40);
+            assert!(generate_random_characters(rng, 40, TextMode::Empty).is_empty());
+        }
+    }
+}
@@ -0,0 +1,136 @@
+// Manifests
+// Describes a generated file as JSON, so it can be triaged without a MIDI tool
+
+use std::collections::BTreeMap;
+
+use crate::corrupt::Fault;
+use crate::{sha256, timing, Event, MIDIEvent, MThd, MTrk, MetaEvent};
+
+/// Returns the name of the kind of an event, used as its key in the event counts
+///
+/// Channel events and Meta events are named after their MIDIEvent and MetaEvent variants. Meta events the generator
+/// has no variant for are named by their type byte, e.g. "Meta02", and bytes that aren't an event are "Data".
+fn kind_of(event: &Event) -> String {
+    match event.data[0] {
+        0x80..=0xEF => format!("{:?}", MIDIEvent::from_status(event.data[0]).unwrap()),
+        0xFF => match event.data.get(1).copied() {
+            Some(meta_type) => MetaEvent::from_type(meta_type).map_or(format!("Meta{:02X}", meta_type), |meta| format!("{:?}", meta)),
+            None => String::from("Data"),
+        },
+        0xF0 => String::from("SysEx"),
+        0xF7 => String::from("SysExEscape"),
+        _ => String::from("Data"),
+    }
+}
+
+/// Counts the events of a track by kind, in order of name
+fn count_events(track: &MTrk) -> BTreeMap<String, u32> {
+    let mut counts = BTreeMap::new();
+    for (_, event) in &track.data {
+        *counts.entry(kind_of(event)).or_insert(0) += 1;
+    }
+    counts
+}
+
+/// Quotes a string for JSON
+pub fn json_string(text: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => quoted += "\\\"",
+            '\\' => quoted += "\\\\",
+            '\n' => quoted += "\\n",
+            '\r' => quoted += "\\r",
+            '\t' => quoted += "\\t",
+            c if (c as u32) < 0x20 => quoted += &format!("\\u{:04x}", c as u32),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Returns the JSON manifest of a generated file
+///
+/// # Arguments
+///
+/// * `file` - The name the file was written under
+/// * `seed` - The seed the generator was started from
+/// * `header`, `tracks` - The file as generated, after any faults were injected
+/// * `faults` - The faults that were injected, if any
+/// * `bytes` - The bytes written to the file, which the SHA-256 hash is taken of
+pub fn manifest(file: &str, seed: u64, header: &MThd, tracks: &[MTrk], faults: &[Fault], bytes: &[u8]) -> String {
+    let duration = timing::duration(header, tracks);
+
+    let tracks_json: Vec<String> = tracks.iter().map(|track| {
+        let counts: Vec<String> = count_events(track).iter().map(|(kind, count)| format!("{}: {}", json_string(kind), count)).collect();
+        format!("    {{ \"events\": {}, \"kinds\": {{ {} }} }}", track.data.len(), counts.join(", "))
+    }).collect();
+    let faults_json: Vec<String> = faults.iter().map(|fault| json_string(fault.name())).collect();
+
+    format!("{{
+  \"file\": {},
+  \"seed\": {},
+  \"format\": {},
+  \"ntracks\": {},
+  \"tickdiv\": {},
+  \"track_count\": {},
+  \"tracks\": [
+{}
+  ],
+  \"duration\": {},
+  \"faults\": [{}],
+  \"sha256\": \"{}\"
+}}
+",
+        json_string(file),
+        seed,
+        header.format,
+        header.ntracks,
+        header.tickdiv,
+        tracks.len(),
+        tracks_json.join(",\n"),
+        if duration.is_finite() { format!("{:.3}", duration) } else { String::from("null") },
+        faults_json.join(", "),
+        sha256::hex_digest(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{encode_midi_file, to_delta_times};
+
+    #[test]
+    fn events_are_counted_by_kind() {
+        let track = MTrk::new(to_delta_times(vec![
+            (0, Event::new_tempo(500_000)),
+            (0, Event::new_note_on(3, 60, 100)),
+            (96, Event::new_note_off(3, 60)),
+            (96, Event::new_note_on(3, 62, 100)),
+            (96, Event::new_text_event(0x02, b"(c)")),
+            (96, Event { data: vec![0xFF, 0x2F, 0x00] }),
+        ]));
+
+        let counts = count_events(&track);
+        assert_eq!(counts.get("NoteOn"), Some(&2));
+        assert_eq!(counts.get("NoteOff"), Some(&1));
+        assert_eq!(counts.get("Tempo"), Some(&1));
+        assert_eq!(counts.get("Meta02"), Some(&1));
+        assert_eq!(counts.get("EndOfTrack"), Some(&1));
+    }
+
+    #[test]
+    fn manifest_records_the_file() {
+        let header = MThd { identifier: [b'M', b'T', b'h', b'd'], chunklen: 6, format: 0, ntracks: 1, tickdiv: 96 };
+        let tracks = vec![MTrk::new(to_delta_times(vec![(192, Event { data: vec![0xFF, 0x2F, 0x00] })]))];
+        let bytes = encode_midi_file(&header, &tracks);
+
+        let json = manifest("a \"b\".mid", 42, &header, &tracks, &[Fault::LongVlq], &bytes);
+        assert!(json.contains("\"file\": \"a \\\"b\\\".mid\""));
+        assert!(json.contains("\"seed\": 42"));
+        assert!(json.contains("{ \"events\": 1, \"kinds\": { \"EndOfTrack\": 1 } }"));
+        assert!(json.contains("\"duration\": 1.000"));
+        assert!(json.contains("\"faults\": [\"long-vlq\"]"));
+        assert!(json.contains(&format!("\"sha256\": \"{}\"", sha256::hex_digest(&bytes))));
+    }
+}
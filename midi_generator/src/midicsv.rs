@@ -0,0 +1,509 @@
+// midicsv text format
+// Writes a file as one line of text per event in the layout of the midicsv tool, and reads such text back,
+// so that files can be edited by hand and turned back into MIDI
+
+use crate::{encode_midi_file, encode_vlq, reader, DeltaTime, Event, MThd, MTrk};
+
+/// Comment recording that the next event's delta time is padded to this many bytes
+const DELTA_BYTES: &str = "# delta-bytes ";
+/// Comment recording that the next event's length is padded to this many bytes
+const LENGTH_BYTES: &str = "# length-bytes ";
+
+/// Names of the text-type Meta events midicsv knows, by type
+const TEXT_RECORDS: [(u8, &str); 7] = [
+    (0x01, "Text_t"),
+    (0x02, "Copyright_t"),
+    (0x03, "Title_t"),
+    (0x04, "Instrument_name_t"),
+    (0x05, "Lyric_t"),
+    (0x06, "Marker_t"),
+    (0x07, "Cue_point_t"),
+];
+
+/// Quotes text for a midicsv string field
+///
+/// Printable ASCII is written as it is, with quotes doubled and backslashes escaped; every other byte is
+/// written as a backslash and three octal digits, so any bytes survive the trip through text.
+fn quote(text: &[u8]) -> String {
+    let mut quoted = String::from("\"");
+    for &byte in text {
+        match byte {
+            b'"' => quoted += "\"\"",
+            b'\\' => quoted += "\\\\",
+            0x20..=0x7E => quoted.push(byte as char),
+            _ => quoted += &format!("\\{:03o}", byte),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Turns a quoted midicsv string field back into bytes
+fn unquote(field: &str) -> Result<Vec<u8>, String> {
+    let inner = field.strip_prefix('"').and_then(|field| field.strip_suffix('"')).ok_or(format!("expected a quoted string, found {}", field))?;
+    let bytes = inner.as_bytes();
+    let mut text = Vec::new();
+    let mut pos = 0;
+
+    while pos < bytes.len() {
+        match bytes[pos] {
+            b'"' => { // a doubled quote
+                text.push(b'"');
+                pos += 2;
+            },
+            b'\\' if bytes.get(pos + 1) == Some(&b'\\') => {
+                text.push(b'\\');
+                pos += 2;
+            },
+            b'\\' => {
+                let digits = inner.get(pos + 1..pos + 4).ok_or(format!("bad escape in {}", field))?;
+                text.push(u8::from_str_radix(digits, 8).map_err(|_| format!("bad escape in {}", field))?);
+                pos += 4;
+            },
+            byte => {
+                text.push(byte);
+                pos += 1;
+            },
+        }
+    }
+
+    Ok(text)
+}
+
+/// Lists bytes as a midicsv length followed by the bytes in decimal
+fn byte_fields(bytes: &[u8]) -> String {
+    let mut fields = bytes.len().to_string();
+    for byte in bytes {
+        fields += &format!(", {}", byte);
+    }
+    fields
+}
+
+/// Returns the number of bytes of the variable-length quantity starting at `start` in an event
+pub fn length_bytes(event: &Event, start: usize) -> Option<usize> {
+    Some(event.data.get(start..)?.iter().position(|byte| byte & 0x80 == 0)? + 1)
+}
+
+/// Returns the data that follows the variable-length quantity starting at `start` in an event
+pub fn split_length(event: &Event, start: usize) -> Option<&[u8]> {
+    event.data.get(start + length_bytes(event, start)?..)
+}
+
+/// Encodes a value as a variable-length quantity of at least `nbytes` bytes, padding it with leading 0x80 bytes
+pub fn padded_vlq(value: u32, nbytes: usize) -> Vec<u8> {
+    let encoded = encode_vlq(value);
+    let mut padded = vec![0x80; nbytes.saturating_sub(encoded.len())];
+    padded.extend(encoded);
+    padded
+}
+
+/// Returns the record type and fields of an event, as they follow the track and tick of its line
+///
+/// Meta events whose length doesn't match their type are written as Unknown_meta_event, which keeps every byte.
+fn event_record(event: &Event) -> Result<String, String> {
+    let data = &event.data;
+    let channel = data[0] & 0x0F;
+
+    let record = match data[0] & 0xF0 {
+        0x80 if data.len() == 3 => format!("Note_off_c, {}, {}, {}", channel, data[1], data[2]),
+        0x90 if data.len() == 3 => format!("Note_on_c, {}, {}, {}", channel, data[1], data[2]),
+        0xA0 if data.len() == 3 => format!("Poly_aftertouch_c, {}, {}, {}", channel, data[1], data[2]),
+        0xB0 if data.len() == 3 => format!("Control_c, {}, {}, {}", channel, data[1], data[2]),
+        0xC0 if data.len() == 2 => format!("Program_c, {}, {}", channel, data[1]),
+        0xD0 if data.len() == 2 => format!("Channel_aftertouch_c, {}, {}", channel, data[1]),
+        0xE0 if data.len() == 3 => format!("Pitch_bend_c, {}, {}", channel, data[1] as u16 | (data[2] as u16) << 7),
+        0xF0 if data[0] == 0xF0 || data[0] == 0xF7 => {
+            let payload = split_length(event, 1).ok_or("SysEx event without a length")?;
+            let name = if data[0] == 0xF0 { "System_exclusive" } else { "System_exclusive_packet" };
+            format!("{}, {}", name, byte_fields(payload))
+        },
+        0xF0 if data[0] == 0xFF && data.len() > 2 => {
+            let meta_type = data[1];
+            let payload = split_length(event, 2).ok_or("Meta event without a length")?;
+
+            match (meta_type, payload.len()) {
+                (0x00, 2) => format!("Sequence_number, {}", (payload[0] as u16) << 8 | payload[1] as u16),
+                (0x01..=0x07, _) => {
+                    let name = TEXT_RECORDS.iter().find(|(text_type, _)| *text_type == meta_type).unwrap().1;
+                    format!("{}, {}", name, quote(payload))
+                },
+                (0x20, 1) => format!("Channel_prefix, {}", payload[0]),
+                (0x21, 1) => format!("MIDI_port, {}", payload[0]),
+                (0x2F, 0) => String::from("End_track"),
+                (0x51, 3) => format!("Tempo, {}", (payload[0] as u32) << 16 | (payload[1] as u32) << 8 | payload[2] as u32),
+                (0x54, 5) => format!("SMPTE_offset, {}, {}, {}, {}, {}", payload[0], payload[1], payload[2], payload[3], payload[4]),
+                (0x58, 4) => format!("Time_signature, {}, {}, {}, {}", payload[0], payload[1], payload[2], payload[3]),
+                (0x59, 2) if payload[1] <= 1 => {
+                    format!("Key_signature, {}, \"{}\"", payload[0] as i8, if payload[1] == 0 { "major" } else { "minor" })
+                },
+                (0x7F, _) => format!("Sequencer_specific, {}", byte_fields(payload)),
+                _ => format!("Unknown_meta_event, {}, {}", meta_type, byte_fields(payload)),
+            }
+        },
+        _ => return Err(format!("event {:02X?} has no midicsv form", data)),
+    };
+
+    Ok(record)
+}
+
+/// Writes a file as midicsv text
+///
+/// Tracks are numbered from 1, with track 0 holding the Header and End_of_file records, and every event
+/// is stamped with its absolute tick. The tickdiv is written as its 16 bit value.
+///
+/// Delta times and lengths that aren't in their shortest encoding can't be told apart in midicsv, so they are
+/// recorded in comment lines before their event, which midicsv skips and `from_csv` reads back.
+pub fn to_csv(header: &MThd, tracks: &[MTrk]) -> Result<String, String> {
+    let mut csv = format!("0, 0, Header, {}, {}, {}\n", header.format, header.ntracks, header.tickdiv);
+
+    for (index, track) in tracks.iter().enumerate() {
+        let number = index + 1;
+        let mut tick: u32 = 0;
+        csv += &format!("{}, 0, Start_track\n", number);
+
+        for (delta_time, event) in &track.data {
+            tick = tick.saturating_add(delta_time.ticks());
+            let record = event_record(event)?;
+
+            let delta_bytes = if delta_time.data == encode_vlq(delta_time.ticks()) { 0 } else { delta_time.data.len() };
+            let length_start = match event.data[0] {
+                0xF0 | 0xF7 => Some(1),
+                0xFF => Some(2),
+                _ => None,
+            };
+            let nbytes = length_start.and_then(|start| length_bytes(event, start)).unwrap_or(0);
+            let padded_length = length_start.and_then(|start| split_length(event, start))
+                .is_some_and(|payload| nbytes > encode_vlq(payload.len() as u32).len());
+
+            let fields = split_fields(&record);
+            let rebuilt = parse_event(&fields[0], &fields[1..], nbytes);
+            if padded_vlq(delta_time.ticks(), delta_bytes) != delta_time.data || rebuilt.map(|rebuilt| rebuilt.data) != Ok(event.data.clone()) {
+                return Err(format!("event {:02X?} at tick {} of track {} can't be written without losing bytes", event.data, tick, number));
+            }
+
+            if delta_bytes > 0 {
+                csv += &format!("{}{}\n", DELTA_BYTES, delta_bytes);
+            }
+            if padded_length {
+                csv += &format!("{}{}\n", LENGTH_BYTES, nbytes);
+            }
+            csv += &format!("{}, {}, {}\n", number, tick, record);
+        }
+    }
+
+    csv += "0, 0, End_of_file\n";
+    Ok(csv)
+}
+
+/// Splits a midicsv line into its fields, keeping quoted strings whole
+fn split_fields(line: &str) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+
+    for c in line.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted; // a doubled quote toggles twice, and stays in the field
+                field.push(c);
+            },
+            ',' if !quoted => fields.push(std::mem::take(&mut field).trim().to_string()),
+            _ => field.push(c),
+        }
+    }
+    fields.push(field.trim().to_string());
+
+    fields
+}
+
+/// Parses the number in a field, checking it fits the given range
+fn number(fields: &[String], index: usize, min: i64, max: i64) -> Result<i64, String> {
+    let field = fields.get(index).ok_or(format!("missing field {}", index + 1))?;
+    let value: i64 = field.parse().map_err(|_| format!("field {} is not a number: {}", index + 1, field))?;
+
+    if value < min || value > max {
+        return Err(format!("field {} is out of range {}..{}: {}", index + 1, min, max, value));
+    }
+    Ok(value)
+}
+
+/// Reads a length field followed by that many byte fields
+fn byte_list(fields: &[String], index: usize) -> Result<Vec<u8>, String> {
+    let length = number(fields, index, 0, 0x0FFF_FFFF)? as usize;
+    if fields.len() != index + 1 + length {
+        return Err(format!("expected {} bytes, found {}", length, fields.len() - index - 1));
+    }
+
+    (0..length).map(|i| number(fields, index + 1 + i, 0, 255).map(|byte| byte as u8)).collect()
+}
+
+/// Builds a Meta event from its type and data, with its length padded to at least `length_bytes` bytes
+fn meta(meta_type: u8, payload: &[u8], length_bytes: usize) -> Event {
+    let mut data = vec![0xFF, meta_type];
+    data.extend(padded_vlq(payload.len() as u32, length_bytes));
+    data.extend_from_slice(payload);
+    Event { data }
+}
+
+/// Builds the event of a midicsv record, given the fields that follow the track and tick
+///
+/// The lengths of SysEx and Meta events are padded to at least `length_bytes` bytes.
+fn parse_event(record: &str, fields: &[String], length_bytes: usize) -> Result<Event, String> {
+    let channel_event = |status: u8, nfields: usize| -> Result<Event, String> {
+        let mut data = vec![status | number(fields, 0, 0, 15)? as u8];
+        for i in 1..nfields {
+            data.push(number(fields, i, 0, 127)? as u8);
+        }
+        Ok(Event { data })
+    };
+
+    let event = match record {
+        "Note_off_c" => channel_event(0x80, 3)?,
+        "Note_on_c" => channel_event(0x90, 3)?,
+        "Poly_aftertouch_c" => channel_event(0xA0, 3)?,
+        "Control_c" => channel_event(0xB0, 3)?,
+        "Program_c" => channel_event(0xC0, 2)?,
+        "Channel_aftertouch_c" => channel_event(0xD0, 2)?,
+        "Pitch_bend_c" => {
+            let value = number(fields, 1, 0, 0x3FFF)? as u16;
+            Event { data: vec![0xE0 | number(fields, 0, 0, 15)? as u8, (value & 0x7F) as u8, (value >> 7) as u8] }
+        },
+        "System_exclusive" | "System_exclusive_packet" => {
+            let mut data = vec![if record == "System_exclusive" { 0xF0 } else { 0xF7 }];
+            let payload = byte_list(fields, 0)?;
+            data.extend(padded_vlq(payload.len() as u32, length_bytes));
+            data.extend(payload);
+            Event { data }
+        },
+        "Sequence_number" => {
+            let value = number(fields, 0, 0, 0xFFFF)? as u16;
+            meta(0x00, &value.to_be_bytes(), length_bytes)
+        },
+        "Channel_prefix" => meta(0x20, &[number(fields, 0, 0, 255)? as u8], length_bytes),
+        "MIDI_port" => meta(0x21, &[number(fields, 0, 0, 255)? as u8], length_bytes),
+        "End_track" => meta(0x2F, &[], length_bytes),
+        "Tempo" => meta(0x51, &(number(fields, 0, 0, 0xFF_FFFF)? as u32).to_be_bytes()[1..], length_bytes),
+        "SMPTE_offset" => {
+            let payload = (0..5).map(|i| number(fields, i, 0, 255).map(|byte| byte as u8)).collect::<Result<Vec<u8>, String>>()?;
+            meta(0x54, &payload, length_bytes)
+        },
+        "Time_signature" => {
+            let payload = (0..4).map(|i| number(fields, i, 0, 255).map(|byte| byte as u8)).collect::<Result<Vec<u8>, String>>()?;
+            meta(0x58, &payload, length_bytes)
+        },
+        "Key_signature" => {
+            let key = number(fields, 0, -128, 127)? as i8 as u8;
+            let mode = match unquote(fields.get(1).ok_or("missing field 2")?)?.to_ascii_lowercase().as_slice() {
+                b"major" => 0,
+                b"minor" => 1,
+                _ => return Err(String::from("key signature mode must be \"major\" or \"minor\"")),
+            };
+            meta(0x59, &[key, mode], length_bytes)
+        },
+        "Sequencer_specific" => meta(0x7F, &byte_list(fields, 0)?, length_bytes),
+        "Unknown_meta_event" => meta(number(fields, 0, 0, 255)? as u8, &byte_list(fields, 1)?, length_bytes),
+        _ => match TEXT_RECORDS.iter().find(|(_, name)| *name == record) {
+            Some((meta_type, _)) => meta(*meta_type, &unquote(fields.first().ok_or("missing field 1")?)?, length_bytes),
+            None => return Err(format!("unknown record type {}", record)),
+        },
+    };
+
+    Ok(event)
+}
+
+/// Reads midicsv text back into a file
+///
+/// Blank lines and lines starting with # or ; are skipped, apart from the comments `to_csv` writes to record padding.
+/// Events are given in order of tick within each track, and delta times and lengths are otherwise written with their
+/// shortest encoding.
+pub fn from_csv(text: &str) -> Result<(MThd, Vec<MTrk>), String> {
+    let mut header = None;
+    let mut tracks: Vec<MTrk> = Vec::new();
+    let mut current: Option<(u32, Vec<(DeltaTime, Event)>)> = None; // number of the open track and its events so far
+    let mut last = 0; // tick of the last event in the open track
+    let mut finished = false;
+    let (mut delta_bytes, mut length_bytes) = (0, 0); // padding of the next event
+
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        let error = |message: String| format!("line {}: {}", index + 1, message);
+
+        if let Some(nbytes) = line.strip_prefix(DELTA_BYTES) {
+            delta_bytes = nbytes.parse().map_err(|_| error(format!("bad delta-bytes comment: {}", line)))?;
+        }
+        else if let Some(nbytes) = line.strip_prefix(LENGTH_BYTES) {
+            length_bytes = nbytes.parse().map_err(|_| error(format!("bad length-bytes comment: {}", line)))?;
+        }
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+        if finished {
+            return Err(error(String::from("text after End_of_file")));
+        }
+
+        let fields = split_fields(line);
+        let track = number(&fields, 0, 0, 0xFFFF).map_err(error)? as u32;
+        let tick = number(&fields, 1, 0, u32::MAX as i64).map_err(error)? as u32;
+        let record = fields.get(2).ok_or_else(|| error(String::from("missing record type")))?.as_str();
+
+        match record {
+            "Header" => {
+                header = Some(MThd {
+                    identifier: [b'M', b'T', b'h', b'd'],
+                    chunklen: 6,
+                    format: number(&fields, 3, 0, 0xFFFF).map_err(error)? as u16,
+                    ntracks: number(&fields, 4, 0, 0xFFFF).map_err(error)? as u16,
+                    tickdiv: number(&fields, 5, 0, 0xFFFF).map_err(error)? as u16,
+                });
+            },
+            "Start_track" | "End_of_file" => {
+                if let Some((_, events)) = current.take() {
+                    tracks.push(MTrk::new(events));
+                }
+                if record == "Start_track" {
+                    current = Some((track, Vec::new()));
+                    last = 0;
+                }
+                else {
+                    finished = true;
+                }
+            },
+            _ => {
+                let (number, events) = current.as_mut().ok_or_else(|| error(String::from("event outside of a track")))?;
+                if track != *number {
+                    return Err(error(format!("event for track {} inside track {}", track, number)));
+                }
+                if tick < last {
+                    return Err(error(String::from("ticks go backwards")));
+                }
+
+                let delta_time = DeltaTime { data: padded_vlq(tick - last, delta_bytes) };
+                events.push((delta_time, parse_event(record, &fields[3..], length_bytes).map_err(error)?));
+                last = tick;
+                (delta_bytes, length_bytes) = (0, 0);
+            },
+        }
+    }
+
+    if !finished {
+        return Err(String::from("the text has no End_of_file record"));
+    }
+    let header = header.ok_or("the text has no Header record")?;
+    Ok((header, tracks))
+}
+
+pub const TO_CSV_USAGE: &str = "\
+usage: midi_generator to-csv [options] <file>
+
+writes a MIDI file as midicsv text, one line per event
+
+options:
+  -o, --output <file>    write the text to <file> (default <file>.csv)";
+
+pub const FROM_CSV_USAGE: &str = "\
+usage: midi_generator from-csv [options] <file>
+
+turns midicsv text back into a MIDI file
+
+options:
+  -o, --output <file>    write the MIDI file to <file> (default <file>.mid)";
+
+/// Parses the arguments of commands converting one file into another, such as to-csv and from-csv, returning the input file and the output file
+pub fn parse_paths(args: Vec<String>, extension: &str, usage: &str) -> Result<(String, String), String> {
+    let mut output = None;
+    let mut input = None;
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" | "--output" => output = Some(args.next().ok_or(format!("{} needs a file name", arg))?),
+            _ if arg.starts_with('-') => return Err(format!("unknown argument: {}\n{}", arg, usage)),
+            _ if input.is_none() => input = Some(arg),
+            _ => return Err(format!("only one file can be given\n{}", usage)),
+        }
+    }
+
+    let input = input.ok_or(format!("no file given\n{}", usage))?;
+    let output = output.unwrap_or(format!("{}.{}", input, extension));
+    Ok((input, output))
+}
+
+/// Runs the to-csv command, writing a MIDI file as midicsv text
+pub fn run_to_csv(args: Vec<String>) -> Result<(), String> {
+    let (input, output) = parse_paths(args, "csv", TO_CSV_USAGE)?;
+    let (header, tracks) = reader::read_midi_file(&input)?;
+
+    let csv = to_csv(&header, &tracks).map_err(|error| format!("{}: {}", input, error))?;
+    std::fs::write(&output, csv).map_err(|error| format!("could not write {}: {}", output, error))
+}
+
+/// Runs the from-csv command, turning midicsv text into a MIDI file
+pub fn run_from_csv(args: Vec<String>) -> Result<(), String> {
+    let (input, output) = parse_paths(args, "mid", FROM_CSV_USAGE)?;
+    let text = std::fs::read_to_string(&input).map_err(|error| format!("could not read {}: {}", input, error))?;
+
+    let (header, tracks) = from_csv(&text).map_err(|error| format!("{}: {}", input, error))?;
+    std::fs::write(&output, encode_midi_file(&header, &tracks)).map_err(|error| format!("could not write {}: {}", output, error))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{generate_midi_file, GeneratorOptions};
+
+    #[test]
+    fn generated_files_round_trip() {
+        let rng = &mut rand::thread_rng();
+
+        for _ in 0..20 {
+            let (header, tracks) = generate_midi_file(rng, &GeneratorOptions::default());
+            let bytes = encode_midi_file(&header, &tracks);
+
+            let (read_header, read_tracks) = from_csv(&to_csv(&header, &tracks).unwrap()).unwrap();
+            assert_eq!(encode_midi_file(&read_header, &read_tracks), bytes);
+        }
+    }
+
+    #[test]
+    fn midicsv_layout_is_read() {
+        let text = "\
+0, 0, Header, 1, 1, 480
+1, 0, Start_track
+1, 0, Title_t, \"Say \"\"hi\"\", caf\\351 \\\\o/\"
+1, 0, Key_signature, -3, \"minor\"
+1, 0, System_exclusive, 3, 126, 127, 247
+1, 240, Note_on_c, 9, 36, 100
+1, 480, Pitch_bend_c, 9, 8192
+1, 480, End_track
+0, 0, End_of_file
+";
+        let (header, tracks) = from_csv(text).unwrap();
+
+        assert_eq!((header.format, header.ntracks, header.tickdiv), (1, 1, 480));
+        assert_eq!(tracks[0].data[0].1.data, [&[0xFF, 0x03, 18][..], b"Say \"hi\", caf\xE9 \\o/"].concat());
+        assert_eq!(tracks[0].data[1].1.data, vec![0xFF, 0x59, 0x02, 0xFD, 0x01]);
+        assert_eq!(tracks[0].data[2].1.data, vec![0xF0, 0x03, 0x7E, 0x7F, 0xF7]);
+        assert_eq!(tracks[0].data[3].0.ticks(), 240);
+        assert_eq!(tracks[0].data[4].1.data, vec![0xE9, 0x00, 0x40]);
+        assert_eq!(to_csv(&header, &tracks).unwrap(), text);
+    }
+
+    #[test]
+    fn odd_encodings_keep_every_byte() {
+        let event = meta(0x51, &[0x07, 0xA1], 0); // a Tempo event two bytes long
+        assert_eq!(event_record(&event).unwrap(), "Unknown_meta_event, 81, 2, 7, 161");
+        assert_eq!(parse_event("Unknown_meta_event", &split_fields("81, 2, 7, 161"), 0).unwrap().data, event.data);
+
+        let header = MThd { identifier: [b'M', b'T', b'h', b'd'], chunklen: 6, format: 0, ntracks: 1, tickdiv: 96 };
+        let tracks = vec![MTrk::new(vec![
+            (DeltaTime { data: vec![0x80, 0x80, 0x05] }, Event { data: vec![0xFF, 0x01, 0x80, 0x00] }),
+            (DeltaTime { data: vec![0x00] }, Event { data: vec![0xFF, 0x2F, 0x00] }),
+        ])];
+        let csv = to_csv(&header, &tracks).unwrap();
+        assert!(csv.contains("# delta-bytes 3\n# length-bytes 2\n1, 5, Text_t, \"\"\n"));
+
+        let (read_header, read_tracks) = from_csv(&csv).unwrap();
+        assert_eq!(encode_midi_file(&read_header, &read_tracks), encode_midi_file(&header, &tracks));
+        assert!(from_csv("0, 0, Header, 0, 1, 96\n1, 0, Start_track\n1, 5, End_track\n1, 4, End_track\n0, 0, End_of_file\n").is_err());
+    }
+}
@@ -0,0 +1,238 @@
+// MusicXML export
+// Writes the notes of a file as a MusicXML 4.0 partwise score, so it can be opened in notation editors
+
+use crate::midicsv::parse_paths;
+use crate::notation::{note_value, read_score, spell, Element, Part, Score, DIVISIONS};
+
+/// Escapes text for XML, leaving out the control characters XML can't hold
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::new();
+    for c in text.chars() {
+        match c {
+            '&' => escaped += "&amp;",
+            '<' => escaped += "&lt;",
+            '>' => escaped += "&gt;",
+            '"' => escaped += "&quot;",
+            c if c < ' ' && c != '\t' && c != '\n' => (),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Names MusicXML gives note values, by the denominator of their fraction of a whole note
+fn type_name(value: u32) -> &'static str {
+    match value {
+        1 => "whole",
+        2 => "half",
+        4 => "quarter",
+        8 => "eighth",
+        16 => "16th",
+        _ => "32nd",
+    }
+}
+
+/// Writes a note, chord or rest of a voice, counting from 1, as <note> elements, one per pitch
+fn element_xml(element: &Element, key: i8, voice: usize) -> String {
+    let (value, dots) = note_value(element.duration);
+    let mut xml = String::new();
+
+    let pitches: Vec<Option<u8>> = if element.pitches.is_empty() { vec![None] } else { element.pitches.iter().copied().map(Some).collect() };
+    for (index, pitch) in pitches.iter().enumerate() {
+        xml += "      <note>\n";
+        if index > 0 {
+            xml += "        <chord/>\n";
+        }
+
+        match pitch {
+            Some(pitch) => {
+                let (step, alter, octave) = spell(*pitch, key);
+                xml += &format!("        <pitch><step>{}</step>", step);
+                if alter != 0 {
+                    xml += &format!("<alter>{}</alter>", alter);
+                }
+                xml += &format!("<octave>{}</octave></pitch>\n", octave);
+            },
+            None => xml += "        <rest/>\n",
+        }
+
+        xml += &format!("        <duration>{}</duration>\n", element.duration);
+        if element.tie_stop {
+            xml += "        <tie type=\"stop\"/>\n";
+        }
+        if element.tie_start {
+            xml += "        <tie type=\"start\"/>\n";
+        }
+        xml += &format!("        <voice>{}</voice>\n        <type>{}</type>\n", voice, type_name(value));
+        for _ in 0..dots {
+            xml += "        <dot/>\n";
+        }
+
+        if element.tie_start || element.tie_stop {
+            xml += "        <notations>";
+            if element.tie_stop {
+                xml += "<tied type=\"stop\"/>";
+            }
+            if element.tie_start {
+                xml += "<tied type=\"start\"/>";
+            }
+            xml += "</notations>\n";
+        }
+        xml += "      </note>\n";
+    }
+
+    xml
+}
+
+/// Writes the measures of a part as a <part> element, with the tempo of the score at its start
+fn part_xml(part: &Part, id: usize, bpm: f64) -> String {
+    let mut xml = format!("  <part id=\"P{}\">\n", id);
+    let mut key = 0;
+
+    for (index, measure) in part.measures.iter().enumerate() {
+        xml += &format!("    <measure number=\"{}\">\n", index + 1);
+
+        if index == 0 || measure.time.is_some() || measure.key.is_some() {
+            xml += "      <attributes>\n";
+            if index == 0 {
+                xml += &format!("        <divisions>{}</divisions>\n", DIVISIONS);
+            }
+            if let Some((sharps, minor)) = measure.key {
+                key = sharps;
+                xml += &format!("        <key><fifths>{}</fifths><mode>{}</mode></key>\n", sharps, if minor { "minor" } else { "major" });
+            }
+            if let Some((numerator, denominator)) = measure.time {
+                xml += &format!("        <time><beats>{}</beats><beat-type>{}</beat-type></time>\n", numerator, denominator);
+            }
+            if index == 0 {
+                let (sign, line) = if part.bass_clef { ("F", 4) } else { ("G", 2) };
+                xml += &format!("        <clef><sign>{}</sign><line>{}</line></clef>\n", sign, line);
+            }
+            xml += "      </attributes>\n";
+        }
+        if index == 0 {
+            xml += &format!("      <direction placement=\"above\">\n        <direction-type><metronome><beat-unit>quarter</beat-unit><per-minute>{}</per-minute></metronome></direction-type>\n        <sound tempo=\"{}\"/>\n      </direction>\n", bpm.round(), bpm.round());
+        }
+
+        // every voice after the first goes back to the start of the measure, and is left out where it rests throughout
+        for (index, elements) in measure.voices.iter().enumerate() {
+            if index > 0 {
+                if elements.is_empty() {
+                    continue;
+                }
+                xml += &format!("      <backup><duration>{}</duration></backup>\n", measure.length);
+            }
+            if elements.is_empty() {
+                xml += &format!("      <note>\n        <rest measure=\"yes\"/>\n        <duration>{}</duration>\n        <voice>1</voice>\n      </note>\n", measure.length);
+            }
+            for element in elements {
+                xml += &element_xml(element, key, index + 1);
+            }
+        }
+        xml += "    </measure>\n";
+    }
+
+    xml += "  </part>\n";
+    xml
+}
+
+/// Writes a score as a MusicXML partwise document
+pub fn to_musicxml(score: &Score) -> String {
+    let mut xml = String::from("\
+<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"no\"?>
+<!DOCTYPE score-partwise PUBLIC \"-//Recordare//DTD MusicXML 4.0 Partwise//EN\" \"http://www.musicxml.org/dtds/partwise.dtd\">
+<score-partwise version=\"4.0\">
+");
+    if let Some(title) = &score.title {
+        xml += &format!("  <movement-title>{}</movement-title>\n", escape(title));
+    }
+    xml += "\
+  <identification>
+    <encoding><software>midi_generator</software></encoding>
+  </identification>
+  <part-list>
+";
+    for (index, part) in score.parts.iter().enumerate() {
+        xml += &format!("    <score-part id=\"P{}\"><part-name>{}</part-name></score-part>\n", index + 1, escape(&part.name));
+    }
+    xml += "  </part-list>\n";
+
+    for (index, part) in score.parts.iter().enumerate() {
+        xml += &part_xml(part, index + 1, score.bpm);
+    }
+    xml += "</score-partwise>\n";
+    xml
+}
+
+pub const USAGE: &str = "\
+usage: midi_generator musicxml [options] <file>
+
+writes the notes of a MIDI file as a MusicXML score with one part per track, quantized to 32nd notes
+
+options:
+  -o, --output <file>    write the score to <file> (default <file>.musicxml)";
+
+/// Runs the musicxml command, writing the notes of a MIDI file as a MusicXML score
+pub fn run(args: Vec<String>) -> Result<(), String> {
+    let (input, output) = parse_paths(args, "musicxml", USAGE)?;
+    let score = read_score(&input)?;
+    std::fs::write(&output, to_musicxml(&score)).map_err(|error| format!("could not write {}: {}", output, error))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::notation::Measure;
+
+    #[test]
+    fn score_is_written_as_partwise_xml() {
+        let part = Part {
+            track: 1,
+            name: String::from("Piano & <Voice>"),
+            bass_clef: true,
+            measures: vec![Measure {
+                time: Some((2, 4)),
+                key: Some((-3, true)),
+                tempo: Some(90.4),
+                length: 16,
+                voices: vec![
+                    vec![
+                        Element { pitches: vec![48, 51], duration: 12, tie_start: true, tie_stop: false, lyric: None },
+                        Element { pitches: vec![48, 51], duration: 2, tie_start: false, tie_stop: true, lyric: None },
+                        Element { pitches: vec![], duration: 2, tie_start: false, tie_stop: false, lyric: None },
+                    ],
+                    vec![Element { pitches: vec![36], duration: 16, tie_start: false, tie_stop: false, lyric: None }],
+                ],
+            }, Measure { time: None, key: None, tempo: None, length: 16, voices: vec![vec![], vec![]] }],
+            truncated: false,
+            cut_short: false,
+        };
+
+        let xml = to_musicxml(&Score { title: Some(String::from("Étude")), bpm: 90.4, parts: vec![part] });
+        assert!(xml.contains("<movement-title>Étude</movement-title>"));
+        assert!(xml.contains("<sound tempo=\"90\"/>"));
+        assert!(xml.contains("<score-part id=\"P1\"><part-name>Piano &amp; &lt;Voice&gt;</part-name></score-part>"));
+        assert!(xml.contains("<key><fifths>-3</fifths><mode>minor</mode></key>"));
+        assert!(xml.contains("<time><beats>2</beats><beat-type>4</beat-type></time>"));
+        assert!(xml.contains("<clef><sign>F</sign><line>4</line></clef>"));
+        assert!(xml.contains("<chord/>\n        <pitch><step>E</step><alter>-1</alter><octave>3</octave></pitch>"));
+        assert!(xml.contains("<type>quarter</type>\n        <dot/>"));
+        assert_eq!(xml.matches("<tied type=\"start\"/>").count(), 2);
+        assert!(xml.contains("<rest/>\n        <duration>2</duration>"));
+        assert!(xml.contains("<measure number=\"2\">\n      <note>\n        <rest measure=\"yes\"/>\n        <duration>16</duration>"));
+        assert!(xml.contains("<voice>1</voice>\n        <type>16th</type>\n      </note>\n      <backup><duration>16</duration></backup>\n      <note>\n        <pitch><step>C</step><octave>2</octave></pitch>"));
+        assert_eq!((xml.matches("<backup>").count(), xml.matches("<voice>2</voice>").count()), (1, 1)); // the second voice rests in measure 2
+    }
+
+    #[test]
+    fn generated_files_export() {
+        let rng = &mut rand::thread_rng();
+        let options = crate::GeneratorOptions { lyrics: true, ..Default::default() };
+        let (header, tracks) = crate::generate_midi_file(rng, &options);
+
+        let score = crate::notation::score(&header, &tracks);
+        let xml = to_musicxml(&score);
+        assert_eq!(xml.matches("<part id=").count(), score.parts.len());
+        assert_eq!(xml.matches("<measure ").count(), score.parts.iter().map(|part| part.measures.len()).sum::<usize>());
+    }
+}
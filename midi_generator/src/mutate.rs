@@ -0,0 +1,344 @@
+// Mutation engine
+// Parses a seed corpus of real MIDI files and applies structured mutations to the MThd/MTrk/Event model,
+// so that mutants stay close enough to the format to get past a reader's first checks
+
+use rand::RngCore;
+use rand_distr::{Distribution, Uniform};
+
+use crate::{encode_midi_file, reader, DeltaTime, Event, MThd, MTrk};
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+/// Enum defining all mutations
+///
+/// Use Mutation::pick_random(rng) to randomly choose a Mutation with uniform distribution
+pub enum Mutation {
+    SwapEvents, // two events of a track trade places, their delta times stay put
+    DuplicateRange, // a run of events is copied to somewhere else in the same track
+    DeleteRange, // a run of events is removed
+    ChangeStatus, // a channel event gets a different message type or channel
+    TweakDeltaTime, // a delta time is nudged, scaled, zeroed or maxed out
+    SpliceTrack, // a track is replaced by, or joined by, a track from another file
+    FlipHeaderField, // format, ntracks or tickdiv of MThd gets a new value
+}
+
+impl Mutation {
+    /// Returns a random Mutation using a Uniform distribution
+    fn pick_random(rng: &mut dyn RngCore) -> Mutation {
+        let temp = Uniform::from(0..7).sample(rng) as u32;
+        match temp {
+            0 => Mutation::SwapEvents,
+            1 => Mutation::DuplicateRange,
+            2 => Mutation::DeleteRange,
+            3 => Mutation::ChangeStatus,
+            4 => Mutation::TweakDeltaTime,
+            5 => Mutation::SpliceTrack,
+            6 => Mutation::FlipHeaderField,
+            _ => panic!("Error when picking random Mutation. Number out of bounds.")
+        }
+    }
+
+    /// Returns the name of the mutation as written to mutation records
+    pub fn name(&self) -> &'static str {
+        match self {
+            Mutation::SwapEvents => "swap-events",
+            Mutation::DuplicateRange => "duplicate-range",
+            Mutation::DeleteRange => "delete-range",
+            Mutation::ChangeStatus => "change-status",
+            Mutation::TweakDeltaTime => "tweak-delta-time",
+            Mutation::SpliceTrack => "splice-track",
+            Mutation::FlipHeaderField => "flip-header-field",
+        }
+    }
+}
+
+/// Picks a random index into a collection of the given (non-zero) length
+fn random_index(rng: &mut dyn RngCore, len: usize) -> usize {
+    Uniform::from(0..len).sample(rng)
+}
+
+/// Picks a random range of at least one element within a collection of the given (non-zero) length
+fn random_range(rng: &mut dyn RngCore, len: usize) -> std::ops::Range<usize> {
+    let start = random_index(rng, len);
+    start..start + 1 + random_index(rng, len - start)
+}
+
+/// Returns the data of a random track with at least `min_events` events, if there is one
+fn random_track<'a>(rng: &mut dyn RngCore, tracks: &'a mut [MTrk], min_events: usize) -> Option<&'a mut Vec<(DeltaTime, Event)>> {
+    let candidates: Vec<usize> = (0..tracks.len()).filter(|&i| tracks[i].data.len() >= min_events).collect();
+    if candidates.is_empty() {
+        return None;
+    }
+
+    Some(&mut tracks[candidates[random_index(rng, candidates.len())]].data)
+}
+
+/// Applies a single mutation, returning false if the file had nothing it could be applied to
+///
+/// # Arguments
+///
+/// * `donors` - Tracks from other files of the corpus, used by SpliceTrack
+pub fn apply(rng: &mut dyn RngCore, mutation: Mutation, header: &mut MThd, tracks: &mut Vec<MTrk>, donors: &[MTrk]) -> bool {
+    let applied = match mutation {
+        Mutation::SwapEvents => match random_track(rng, tracks, 2) {
+            Some(data) => {
+                let a = random_index(rng, data.len());
+                let b = (a + 1 + random_index(rng, data.len() - 1)) % data.len(); // any index but a
+                let event = data[a].1.clone();
+                data[a].1 = std::mem::replace(&mut data[b].1, event);
+                true
+            },
+            None => false,
+        },
+        Mutation::DuplicateRange => match random_track(rng, tracks, 1) {
+            Some(data) => {
+                let range = random_range(rng, data.len());
+                let copy: Vec<(DeltaTime, Event)> = data[range].to_vec();
+                let position = random_index(rng, data.len() + 1);
+                data.splice(position..position, copy);
+                true
+            },
+            None => false,
+        },
+        Mutation::DeleteRange => match random_track(rng, tracks, 1) {
+            Some(data) => {
+                data.drain(random_range(rng, data.len()));
+                true
+            },
+            None => false,
+        },
+        Mutation::ChangeStatus => {
+            let channel_events: Vec<(usize, usize)> = (0..tracks.len())
+                .flat_map(|t| (0..tracks[t].data.len()).map(move |e| (t, e)))
+                .filter(|&(t, e)| (0x80..0xF0).contains(&tracks[t].data[e].1.data[0]))
+                .collect();
+
+            if channel_events.is_empty() {
+                false
+            }
+            else {
+                let (t, e) = channel_events[random_index(rng, channel_events.len())];
+                let event = &mut tracks[t].data[e].1;
+
+                if Uniform::from(0..2).sample(rng) == 0 { // new message type, with data bytes added or dropped to suit
+                    let status = (0x80 + 0x10 * Uniform::from(0..7).sample(rng) as u8) | (event.data[0] & 0x0F);
+                    let length = match status & 0xF0 {
+                        0xC0 | 0xD0 => 1,
+                        _ => 2,
+                    };
+                    event.data[0] = status;
+                    event.data.resize(1 + length, Uniform::from(0..128).sample(rng) as u8);
+                }
+                else { // new channel
+                    event.data[0] = (event.data[0] & 0xF0) | Uniform::from(0..16).sample(rng) as u8;
+                }
+                true
+            }
+        },
+        Mutation::TweakDeltaTime => match random_track(rng, tracks, 1) {
+            Some(data) => {
+                let pair = random_index(rng, data.len());
+                let delta_time = &mut data[pair].0;
+                let ticks = delta_time.ticks() as i64;
+                let tweaked = match Uniform::from(0..5).sample(rng) {
+                    0 => ticks + Uniform::from(-16..17).sample(rng),
+                    1 => ticks * 2,
+                    2 => ticks / 2,
+                    3 => 0,
+                    _ => 0x0FFF_FFFF, // the largest delta time a 4 byte VLQ holds
+                };
+                *delta_time = DeltaTime::from_ticks(tweaked.clamp(0, 0x0FFF_FFFF) as u32);
+                true
+            },
+            None => false,
+        },
+        Mutation::SpliceTrack => {
+            if donors.is_empty() {
+                false
+            }
+            else {
+                let donor = donors[random_index(rng, donors.len())].clone();
+                if tracks.is_empty() || Uniform::from(0..2).sample(rng) == 0 {
+                    tracks.insert(random_index(rng, tracks.len() + 1), donor);
+                }
+                else {
+                    let index = random_index(rng, tracks.len());
+                    tracks[index] = donor;
+                }
+                header.ntracks = tracks.len() as u16;
+                true
+            }
+        },
+        Mutation::FlipHeaderField => {
+            match Uniform::from(0..3).sample(rng) {
+                0 => header.format = [0, 1, 2, Uniform::from(3..=0xFFFF).sample(rng)][random_index(rng, 4)],
+                1 => header.ntracks = [0, header.ntracks.wrapping_add(1), header.ntracks.wrapping_sub(1), 0xFFFF][random_index(rng, 4)],
+                _ => header.tickdiv ^= 1 << random_index(rng, 16),
+            }
+            true
+        },
+    };
+
+    // Keep every chunklen in step with the data, header fields are the only thing mutations are allowed to break
+    for track in tracks.iter_mut() {
+        *track = MTrk::new(std::mem::take(&mut track.data));
+    }
+
+    applied
+}
+
+/// Applies between 1 and `max_mutations` random mutations, returning the ones that took
+pub fn mutate(rng: &mut dyn RngCore, header: &mut MThd, tracks: &mut Vec<MTrk>, donors: &[MTrk], max_mutations: u32) -> Vec<Mutation> {
+    let count = Uniform::from(1..=max_mutations.max(1)).sample(rng);
+
+    let mut applied = Vec::new();
+    for _ in 0..count {
+        let mutation = Mutation::pick_random(rng);
+        if apply(rng, mutation, header, tracks, donors) {
+            applied.push(mutation);
+        }
+    }
+
+    applied
+}
+
+/// Collects the MIDI files named on the command line, looking inside any directories for .mid, .midi and .kar files
+fn collect_seeds(paths: &[String]) -> Result<Vec<String>, String> {
+    let mut seeds = Vec::new();
+
+    for path in paths {
+        if std::path::Path::new(path).is_dir() {
+            let entries = std::fs::read_dir(path).map_err(|error| format!("could not read {}: {}", path, error))?;
+            let mut files: Vec<String> = entries
+                .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .filter(|file| file.extension().and_then(|ext| ext.to_str()).is_some_and(|ext| ["mid", "midi", "kar"].contains(&ext.to_ascii_lowercase().as_str())))
+                .map(|file| file.to_string_lossy().into_owned())
+                .collect();
+            files.sort();
+            seeds.extend(files);
+        }
+        else {
+            seeds.push(path.clone());
+        }
+    }
+
+    Ok(seeds)
+}
+
+pub const USAGE: &str = "\
+usage: midi_generator mutate [options] <seed file or directory>...
+
+options:
+  -o, --output <dir>     write the mutants into <dir> (default mutants)
+  -n, --count <n>        number of mutants to write (default 1)
+  --mutations <n>        apply up to <n> mutations to each mutant (default 8)
+                         the mutations applied are listed one per line in <mutant>.mutations";
+
+/// Runs the mutate command, writing mutants of a seed corpus to a directory
+pub fn run(args: Vec<String>) -> Result<(), String> {
+    let mut output = String::from("mutants");
+    let mut count: u32 = 1;
+    let mut max_mutations: u32 = 8;
+    let mut paths = Vec::new();
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" | "--output" => output = args.next().ok_or(format!("{} needs a directory", arg))?,
+            "-n" | "--count" => count = args.next().and_then(|n| n.parse().ok()).ok_or(format!("{} needs a number", arg))?,
+            "--mutations" => max_mutations = args.next().and_then(|n| n.parse().ok()).ok_or(format!("{} needs a number", arg))?,
+            _ if arg.starts_with('-') => return Err(format!("unknown argument: {}\n{}", arg, USAGE)),
+            _ => paths.push(arg),
+        }
+    }
+
+    let mut corpus = Vec::new();
+    for seed in collect_seeds(&paths)? {
+        match reader::read_midi_file(&seed) {
+            Ok(file) => corpus.push(file),
+            Err(error) => eprintln!("skipping {}", error),
+        }
+    }
+    if corpus.is_empty() {
+        return Err(format!("no readable seed files\n{}", USAGE));
+    }
+
+    std::fs::create_dir_all(&output).map_err(|error| format!("could not create {}: {}", output, error))?;
+
+    let rng = &mut rand::thread_rng();
+    for index in 0..count {
+        let (mut header, mut tracks) = corpus[random_index(rng, corpus.len())].clone();
+        let donors: Vec<MTrk> = corpus[random_index(rng, corpus.len())].1.clone();
+
+        let mutations = mutate(rng, &mut header, &mut tracks, &donors, max_mutations);
+
+        let path = format!("{}/mutant_{:06}.mid", output, index);
+        std::fs::write(&path, encode_midi_file(&header, &tracks)).map_err(|error| format!("could not write {}: {}", path, error))?;
+
+        let record: String = mutations.iter().map(|mutation| format!("{}\n", mutation.name())).collect();
+        std::fs::write(format!("{}.mutations", path), record).map_err(|error| format!("could not write {}.mutations: {}", path, error))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{generate_midi_file, GeneratorOptions};
+
+    #[test]
+    fn mutants_keep_chunk_lengths_consistent() {
+        let rng = &mut rand::thread_rng();
+        for _ in 0..50 {
+            let (mut header, mut tracks) = generate_midi_file(rng, &GeneratorOptions::default());
+            let (_, donors) = generate_midi_file(rng, &GeneratorOptions::default());
+
+            mutate(rng, &mut header, &mut tracks, &donors, 8);
+
+            let bytes = encode_midi_file(&header, &tracks);
+            let (_, read_tracks) = reader::parse_midi_file(&bytes).unwrap();
+            assert_eq!(read_tracks.len(), tracks.len());
+        }
+    }
+
+    #[test]
+    fn change_status_keeps_event_lengths_valid() {
+        let rng = &mut rand::thread_rng();
+        let mut header = generate_midi_file(rng, &GeneratorOptions::default()).0;
+        let mut tracks = vec![MTrk::new(vec![(DeltaTime::zero(), Event::new_note_on(2, 60, 100))])];
+
+        for _ in 0..50 {
+            assert!(apply(rng, Mutation::ChangeStatus, &mut header, &mut tracks, &[]));
+            let event = &tracks[0].data[0].1.data;
+            let expected = match event[0] & 0xF0 {
+                0xC0 | 0xD0 => 2,
+                _ => 3,
+            };
+            assert_eq!(event.len(), expected);
+        }
+    }
+
+    #[test]
+    fn swap_always_moves_events() {
+        let rng = &mut rand::thread_rng();
+        let mut header = generate_midi_file(rng, &GeneratorOptions::default()).0;
+        let original = vec![(DeltaTime::zero(), Event::new_note_on(0, 60, 100)), (DeltaTime::zero(), Event::new_note_off(0, 60))];
+
+        for _ in 0..50 {
+            let mut tracks = vec![MTrk::new(original.clone())];
+            assert!(apply(rng, Mutation::SwapEvents, &mut header, &mut tracks, &[]));
+            assert_eq!(tracks[0].data[0].1.data, original[1].1.data);
+        }
+    }
+
+    #[test]
+    fn splice_brings_in_donor_tracks() {
+        let rng = &mut rand::thread_rng();
+        let (mut header, mut tracks) = generate_midi_file(rng, &GeneratorOptions::default());
+        let donor = MTrk::new(vec![(DeltaTime::zero(), Event::new_text_event(0x03, b"donor"))]);
+
+        assert!(apply(rng, Mutation::SpliceTrack, &mut header, &mut tracks, &[donor]));
+        assert!(tracks.iter().any(|track| track.data[0].1.data == [0xFF, 0x03, 0x05, b'd', b'o', b'n', b'o', b'r']));
+        assert_eq!(header.ntracks as usize, tracks.len());
+    }
+}
@@ -0,0 +1,586 @@
+// Notation
+// Quantizes the notes of a file into measures of notated values, the score that the MusicXML, ABC and LilyPond
+// exports are written from
+
+use crate::timing::{TempoMap, TickDiv};
+use crate::{MThd, MTrk};
+
+/// Number of divisions of a quarter note, making the 32nd note the shortest value a score holds
+pub const DIVISIONS: u32 = 8;
+
+/// Durations that can be written as a single note, from the dotted whole note down to the 32nd, in divisions
+const NOTE_VALUES: [u32; 11] = [48, 32, 24, 16, 12, 8, 6, 4, 3, 2, 1];
+
+/// Parts are cut off after this many measures, as long silences in generated tracks could otherwise run to millions
+pub const MAX_MEASURES: usize = 1_000;
+
+/// Notes that overlap are laid out in voices of their own up to this many, and are cut short past it
+pub const MAX_VOICES: usize = 4;
+
+#[derive(Debug, Clone, PartialEq)]
+/// A note, chord or rest within a measure
+pub struct Element {
+    pub pitches: Vec<u8>, // in ascending order, empty for a rest
+    pub duration: u32, // in divisions, always one of NOTE_VALUES
+    pub tie_start: bool, // tied to the next element
+    pub tie_stop: bool, // tied from the previous element
+    pub lyric: Option<String>, // the text of the Lyric events sung on the note, on the first element of a note only
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// A measure of a part
+pub struct Measure {
+    pub time: Option<(u32, u32)>, // numerator and denominator, where the time signature starts or changes
+    pub key: Option<(i8, bool)>, // sharps (negative for flats) and whether minor, where the key starts or changes
+    pub tempo: Option<f64>, // quarter notes per minute, where the tempo starts or changes
+    pub length: u32, // in divisions, less than the time signature gives where a change within the measure ends it
+    pub voices: Vec<Vec<Element>>, // the elements of each voice of the part, empty for a full measure of rest
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// The notes of one track laid out in measures
+pub struct Part {
+    pub track: usize, // number of the track the part comes from, counting from 1
+    pub name: String,
+    pub bass_clef: bool, // the notes mostly lie below middle C
+    pub measures: Vec<Measure>,
+    pub truncated: bool, // notes were left out past MAX_MEASURES
+    pub cut_short: bool, // overlapping notes were cut short or lengthened to fit in MAX_VOICES voices
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// The parts of a file, along with what a score shows at its head
+pub struct Score {
+    pub title: Option<String>,
+    pub bpm: f64, // quarter notes per minute at the start
+    pub parts: Vec<Part>,
+}
+
+/// Returns the base value and number of dots a duration is written with
+///
+/// The base value is given as the denominator of its fraction of a whole note, e.g. 4 for a quarter note.
+pub fn note_value(duration: u32) -> (u32, u8) {
+    let whole = 4 * DIVISIONS;
+    if whole.is_multiple_of(duration) {
+        (whole / duration, 0)
+    }
+    else {
+        (whole * 3 / (duration * 2), 1)
+    }
+}
+
+/// Spells a pitch in a key, returning its letter, its alteration in semitones and its octave
+///
+/// Black keys are spelled as sharps in sharp keys and in C, and as flats in flat keys. Octaves count from C4, middle C.
+pub fn spell(pitch: u8, key: i8) -> (char, i8, i32) {
+    const SHARPS: [(char, i8); 12] = [('C', 0), ('C', 1), ('D', 0), ('D', 1), ('E', 0), ('F', 0), ('F', 1), ('G', 0), ('G', 1), ('A', 0), ('A', 1), ('B', 0)];
+    const FLATS: [(char, i8); 12] = [('C', 0), ('D', -1), ('D', 0), ('E', -1), ('E', 0), ('F', 0), ('G', -1), ('G', 0), ('A', -1), ('A', 0), ('B', -1), ('B', 0)];
+
+    let (step, alter) = if key < 0 { FLATS[pitch as usize % 12] } else { SHARPS[pitch as usize % 12] };
+    (step, alter, pitch as i32 / 12 - 1)
+}
+
+/// Converts ticks to positions in divisions
+enum Grid<'a> {
+    Metrical(f64), // ticks per division
+    Timecode(&'a TempoMap), // ticks have a length in seconds only, and are placed on a grid of 120 BPM
+}
+
+impl Grid<'_> {
+    fn position(&self, tick: u32) -> u32 {
+        let position = match self {
+            Grid::Metrical(ticks_per_division) => tick as f64 / ticks_per_division,
+            Grid::Timecode(map) => map.tick_to_seconds(tick) * 2.0 * DIVISIONS as f64,
+        };
+        position.round().min(u32::MAX as f64) as u32
+    }
+}
+
+/// Returns the text of the first Meta event of the given type in a track
+fn meta_text(track: &MTrk, meta_type: u8) -> Option<String> {
+    track.data.iter().find_map(|(_, event)| {
+        if event.data.len() > 2 && event.data[..2] == [0xFF, meta_type] {
+            let text = crate::midicsv::split_length(event, 2)?;
+            Some(String::from_utf8_lossy(text).into_owned())
+        }
+        else {
+            None
+        }
+    })
+}
+
+/// Collects the notes of a track as (start, end, pitch) in divisions, in order of start
+///
+/// Each NoteOff, or NoteOn of velocity 0, ends the earliest sounding note of the same pitch and channel.
+/// Notes still sounding at the end of the track end with it.
+fn notes(track: &MTrk, grid: &Grid) -> Vec<(u32, u32, u8)> {
+    let mut sounding: Vec<(u8, u8, u32)> = Vec::new(); // channel, pitch and start of each sounding note
+    let mut notes = Vec::new();
+
+    for (tick, event) in track.timed_events() {
+        let data = &event.data;
+        if data.len() != 3 || !(0x80..0xA0).contains(&data[0]) {
+            continue;
+        }
+
+        let (channel, pitch) = (data[0] & 0x0F, data[1]);
+        if data[0] & 0xF0 == 0x90 && data[2] > 0 {
+            sounding.push((channel, pitch, grid.position(tick)));
+        }
+        else if let Some(index) = sounding.iter().position(|note| note.0 == channel && note.1 == pitch) {
+            let (_, _, start) = sounding.remove(index);
+            notes.push((start, grid.position(tick), pitch));
+        }
+    }
+
+    let end = grid.position(track.length());
+    notes.extend(sounding.into_iter().map(|(_, pitch, start)| (start, end, pitch)));
+    notes.sort_by_key(|(start, _, pitch)| (*start, *pitch));
+    notes
+}
+
+/// Collects the Lyric events of a track as (position, text), in order of position
+fn lyrics(track: &MTrk, grid: &Grid) -> Vec<(u32, String)> {
+    track.timed_events().into_iter()
+        .filter(|(_, event)| event.data.len() > 2 && event.data[..2] == [0xFF, 0x05])
+        .filter_map(|(tick, event)| {
+            let text = crate::midicsv::split_length(event, 2)?;
+            Some((grid.position(tick), String::from_utf8_lossy(text).into_owned()))
+        })
+        .collect()
+}
+
+/// A chord of one of the voices a part is laid out in
+struct Chord {
+    start: u32,
+    duration: u32,
+    pitches: Vec<u8>, // in ascending order
+    lyric: Option<String>,
+}
+
+/// Lays notes out in chords, in as few voices as let every note sound for its whole length, up to MAX_VOICES
+///
+/// Notes starting and ending together form a chord, and every note lasts at least one division. Going from the highest
+/// note at each position down, each goes to the first voice that is free by its start, so the tune lies in the first.
+/// Once MAX_VOICES are in use, a note that finds none free goes to the voice that frees up soonest, cutting short the
+/// chord sounding there, or joining it if they start together. Each Lyric is sung on the first chord of the first voice
+/// at or after it. Returns the voices, and whether any note was cut short or lengthened to fit.
+fn voices(notes: &[(u32, u32, u8)], lyrics: &[(u32, String)]) -> (Vec<Vec<Chord>>, bool) {
+    let mut notes = notes.to_vec();
+    notes.sort_by_key(|&(start, _, pitch)| (start, std::cmp::Reverse(pitch)));
+
+    let mut voices: Vec<Vec<Chord>> = Vec::new();
+    let mut changed = false;
+    let free_at = |chords: &Vec<Chord>| chords.last().map_or(0, |chord| chord.start + chord.duration);
+
+    for (start, end, pitch) in notes {
+        let duration = end.saturating_sub(start).max(1);
+        let chord = Chord { start, duration, pitches: vec![pitch], lyric: None };
+
+        if let Some(chord) = voices.iter_mut().filter_map(|chords| chords.last_mut()).find(|chord| chord.start == start && chord.duration == duration) {
+            if !chord.pitches.contains(&pitch) {
+                chord.pitches.push(pitch);
+                chord.pitches.sort_unstable();
+            }
+        }
+        else if let Some(chords) = voices.iter_mut().find(|chords| free_at(chords) <= start) {
+            chords.push(chord);
+        }
+        else if voices.len() < MAX_VOICES {
+            voices.push(vec![chord]);
+        }
+        else {
+            let chords = voices.iter_mut().min_by_key(|chords| free_at(chords)).unwrap();
+            let last = chords.last_mut().unwrap();
+            changed = true;
+
+            if last.start == start {
+                last.duration = last.duration.max(duration);
+                if !last.pitches.contains(&pitch) {
+                    last.pitches.push(pitch);
+                    last.pitches.sort_unstable();
+                }
+            }
+            else {
+                last.duration = start - last.start;
+                chords.push(chord);
+            }
+        }
+    }
+
+    if let Some(chords) = voices.first_mut() {
+        for (position, text) in lyrics {
+            let index = chords.partition_point(|chord| chord.start < *position);
+            if let Some(chord) = chords.get_mut(index) {
+                chord.lyric.get_or_insert_with(String::new).push_str(text);
+            }
+        }
+    }
+
+    (voices, changed)
+}
+
+/// The time signatures, keys and tempos in force over a part, each in order of position
+struct Signatures {
+    times: Vec<(u32, u32, u32)>, // position, numerator and denominator
+    keys: Vec<(u32, i8, bool)>, // position, sharps and whether minor
+    tempos: Vec<(u32, f64)>, // position and quarter notes per minute
+}
+
+/// Collects the TimeSignature, KeySignature and Tempo events of a set of tracks
+///
+/// Time signatures whose measures don't come to a whole number of divisions and keys beyond 7 sharps or flats
+/// can't be notated, and are left out. Tempo events are left out with timecode timing, which they don't apply to.
+fn signatures(tracks: &[&MTrk], grid: &Grid) -> Signatures {
+    let mut signatures = Signatures { times: Vec::new(), keys: Vec::new(), tempos: Vec::new() };
+
+    for track in tracks {
+        for (tick, event) in track.timed_events() {
+            match event.data.as_slice() {
+                [0xFF, 0x58, 0x04, numerator, denominator, _, _] if *numerator > 0 && *denominator <= 5 => {
+                    signatures.times.push((grid.position(tick), *numerator as u32, 1 << denominator));
+                },
+                [0xFF, 0x59, 0x02, sharps, mode] if (-7..=7).contains(&(*sharps as i8)) && *mode <= 1 => {
+                    signatures.keys.push((grid.position(tick), *sharps as i8, *mode == 1));
+                },
+                [0xFF, 0x51, 0x03, a, b, c] if matches!(grid, Grid::Metrical(_)) => {
+                    let tempo = (*a as u32) << 16 | (*b as u32) << 8 | *c as u32;
+                    if tempo > 0 {
+                        signatures.tempos.push((grid.position(tick), 60_000_000.0 / tempo as f64));
+                    }
+                },
+                _ => (),
+            }
+        }
+    }
+
+    signatures.times.sort_by_key(|time| time.0);
+    signatures.keys.sort_by_key(|key| key.0);
+    signatures.tempos.sort_by_key(|tempo| tempo.0);
+    signatures
+}
+
+/// Splits a duration into note values, longest first
+fn split_duration(mut duration: u32) -> Vec<u32> {
+    let mut values = Vec::new();
+    while duration > 0 {
+        let value = *NOTE_VALUES.iter().find(|value| **value <= duration).unwrap();
+        values.push(value);
+        duration -= value;
+    }
+    values
+}
+
+/// Lays the chords of each voice out in measures, filling the gaps with rests and tying notes across barlines
+///
+/// Time signatures and keys take effect where they change, starting from 4/4 in C major, and a change within a measure
+/// ends it early. Tempos take effect at the first barline at or after their position, starting from 120 BPM, and are
+/// only marked where they change by at least 1 BPM. Returns the measures, and whether they were cut off at MAX_MEASURES.
+fn measures(voices: &[Vec<Chord>], signatures: &Signatures) -> (Vec<Measure>, bool) {
+    let end = voices.iter().filter_map(|chords| chords.last()).map(|chord| chord.start + chord.duration).max().unwrap_or(0);
+
+    // the sounding pitches of each voice over spans of the timeline, as (start, end, pitches, lyric), with rests between chords
+    let spans: Vec<Vec<_>> = voices.iter().map(|chords| {
+        let mut spans = Vec::new();
+        let mut position = 0;
+        for chord in chords {
+            if chord.start > position {
+                spans.push((position, chord.start, Vec::new(), None));
+            }
+            spans.push((chord.start, chord.start + chord.duration, chord.pitches.clone(), chord.lyric.clone()));
+            position = chord.start + chord.duration;
+        }
+        spans
+    }).collect();
+
+    let time_at = |position: u32| signatures.times.iter().rev().find(|change| change.0 <= position).map(|change| (change.1, change.2));
+    let key_at = |position: u32| signatures.keys.iter().rev().find(|change| change.0 <= position).map(|change| (change.1, change.2));
+    let changes_at = |position: u32| time_at(position) != time_at(position - 1) || key_at(position) != key_at(position - 1);
+
+    let mut measures: Vec<Measure> = Vec::new();
+    let (mut time, mut key, mut tempo) = ((4, 4), (0, false), 120.0);
+    let mut start = 0;
+    let mut next_spans = vec![0; voices.len()]; // the span of each voice that the measure starts in
+
+    while start < end || measures.is_empty() {
+        if measures.len() == MAX_MEASURES {
+            return (measures, true);
+        }
+
+        let in_force = |changes: &[(u32, f64)]| changes.iter().rev().find(|change| change.0 <= start).map(|change| change.1);
+        let new_time = time_at(start).unwrap_or(time);
+        let new_key = key_at(start).unwrap_or(key);
+        let new_tempo = in_force(&signatures.tempos).unwrap_or(tempo);
+        let full_length = new_time.0 * 4 * DIVISIONS / new_time.1;
+        let length = signatures.times.iter().map(|change| change.0).chain(signatures.keys.iter().map(|change| change.0))
+            .filter(|position| *position > start && *position < start + full_length && changes_at(*position))
+            .min()
+            .map_or(full_length, |position| position - start);
+
+        let mut measure = Measure {
+            time: if measures.is_empty() || new_time != time { Some(new_time) } else { None },
+            key: if measures.is_empty() || new_key != key { Some(new_key) } else { None },
+            tempo: if measures.is_empty() || (new_tempo - tempo).abs() >= 1.0 { Some(new_tempo) } else { None },
+            length,
+            voices: Vec::new(),
+        };
+        time = new_time;
+        key = new_key;
+        if measure.tempo.is_some() {
+            tempo = new_tempo;
+        }
+
+        let measure_end = start + measure.length;
+        for (spans, span) in spans.iter().zip(&mut next_spans) {
+            let mut elements = Vec::new();
+            let mut position = start;
+
+            while position < measure_end {
+                while spans.get(*span).is_some_and(|(_, span_end, _, _)| *span_end <= position) {
+                    *span += 1;
+                }
+
+                let (piece_end, pitches, tied_after, tied_before, mut lyric) = match spans.get(*span) {
+                    Some((span_start, span_end, pitches, lyric)) if *span_start <= position => {
+                        let piece_end = (*span_end).min(measure_end);
+                        let note = !pitches.is_empty();
+                        let lyric = if position == *span_start { lyric.clone() } else { None };
+                        (piece_end, pitches.clone(), note && piece_end < *span_end, note && position > *span_start, lyric)
+                    },
+                    Some((span_start, _, _, _)) => ((*span_start).min(measure_end), Vec::new(), false, false, None),
+                    None => (measure_end, Vec::new(), false, false, None),
+                };
+
+                let values = split_duration(piece_end - position);
+                let count = values.len();
+                for (index, duration) in values.into_iter().enumerate() {
+                    let note = !pitches.is_empty();
+                    elements.push(Element {
+                        pitches: pitches.clone(),
+                        duration,
+                        tie_start: note && (index + 1 < count || tied_after),
+                        tie_stop: note && (index > 0 || tied_before),
+                        lyric: lyric.take(),
+                    });
+                }
+                position = piece_end;
+            }
+
+            if measure.length == full_length && elements.iter().all(|element| element.pitches.is_empty()) {
+                elements.clear();
+            }
+            measure.voices.push(elements);
+        }
+        measures.push(measure);
+        start = measure_end;
+    }
+
+    (measures, false)
+}
+
+/// Quantizes the notes of a file into a score of one part per track
+///
+/// Tracks without notes are left out. Parts are named after the InstrumentName of their track, or its
+/// SequenceORTrackName, or else their track number. The title is the SequenceORTrackName of the first track,
+/// and the tempo is that of the first Tempo event, or 120 BPM with timecode timing, which is placed on a grid of 120 BPM.
+pub fn score(header: &MThd, tracks: &[MTrk]) -> Score {
+    let mut parts = Vec::new();
+    let maps = TempoMap::for_file(header, tracks);
+
+    for (index, track) in tracks.iter().enumerate() {
+        let grid = match TickDiv::from_tickdiv(header.tickdiv) {
+            TickDiv::Metrical { ppqn } if ppqn > 0 => Grid::Metrical(ppqn as f64 / DIVISIONS as f64),
+            _ => Grid::Timecode(maps.track(index)),
+        };
+
+        let notes = notes(track, &grid);
+        if notes.is_empty() {
+            continue;
+        }
+
+        let signature_tracks: Vec<&MTrk> = if header.format == 2 { vec![track] } else { tracks.iter().collect() };
+        let signatures = signatures(&signature_tracks, &grid);
+        let average = notes.iter().map(|(_, _, pitch)| *pitch as f64).sum::<f64>() / notes.len() as f64;
+        let (voices, cut_short) = voices(&notes, &lyrics(track, &grid));
+        let (measures, truncated) = measures(&voices, &signatures);
+
+        parts.push(Part {
+            track: index + 1,
+            name: meta_text(track, 0x04).or_else(|| meta_text(track, 0x03)).unwrap_or(format!("Track {}", index + 1)),
+            bass_clef: average < 60.0,
+            measures,
+            truncated,
+            cut_short,
+        });
+    }
+
+    let first_tempo = tracks.iter()
+        .flat_map(|track| track.timed_events())
+        .filter(|(_, event)| event.data.len() == 6 && event.data[..3] == [0xFF, 0x51, 0x03])
+        .min_by_key(|(tick, _)| *tick)
+        .map(|(_, event)| (event.data[3] as u32) << 16 | (event.data[4] as u32) << 8 | event.data[5] as u32)
+        .filter(|tempo| *tempo > 0);
+    let bpm = match TickDiv::from_tickdiv(header.tickdiv) {
+        TickDiv::Metrical { ppqn } if ppqn > 0 => first_tempo.map_or(120.0, |tempo| 60_000_000.0 / tempo as f64),
+        _ => 120.0,
+    };
+
+    Score {
+        title: tracks.first().and_then(|track| meta_text(track, 0x03)),
+        bpm,
+        parts,
+    }
+}
+
+/// Reads a MIDI file and quantizes it into a score, for the notation export commands
+///
+/// Scores without a title are given the name of the file. Warns on stderr about every part that was cut off, or whose
+/// notes were cut short to fit in MAX_VOICES voices.
+pub fn read_score(path: &str) -> Result<Score, String> {
+    let (header, tracks) = crate::reader::read_midi_file(path)?;
+    let mut score = score(&header, &tracks);
+
+    if score.parts.is_empty() {
+        return Err(format!("{}: the file has no notes", path));
+    }
+    for part in score.parts.iter().filter(|part| part.truncated) {
+        eprintln!("warning: the part of track {} was cut off after {} measures", part.track, MAX_MEASURES);
+    }
+    for part in score.parts.iter().filter(|part| part.cut_short) {
+        eprintln!("warning: notes of track {} were cut short to fit in {} voices", part.track, MAX_VOICES);
+    }
+
+    let name = std::path::Path::new(path).file_stem().map(|stem| stem.to_string_lossy().into_owned());
+    score.title = score.title.or(name);
+    Ok(score)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{to_delta_times, Event};
+
+    #[test]
+    fn notes_are_quantized_and_tied_across_barlines() {
+        let header = MThd { identifier: *b"MThd", chunklen: 6, format: 0, ntracks: 1, tickdiv: 96 };
+        let track = MTrk::new(to_delta_times(vec![
+            (0, Event { data: vec![0xFF, 0x58, 0x04, 3, 2, 24, 8] }), // 3/4
+            (0, Event::new_text_event(0x03, b"Air")),
+            (0, Event::new_text_event(0x04, b"Flute")),
+            (0, Event::new_tempo(400_000)),
+            (96, Event::new_text_event(0x05, b"Hal-")),
+            (97, Event::new_note_on(0, 72, 100)), // a quarter note, a tick late
+            (192, Event::new_note_off(0, 72)),
+            (192, Event::new_text_event(0x05, b"lo ")),
+            (192, Event::new_note_on(0, 76, 100)), // a dotted half note crossing the barline
+            (192, Event::new_note_on(0, 79, 100)),
+            (288, Event::new_tempo(500_000)),
+            (480, Event::new_note_off(0, 76)),
+            (480, Event::new_note_off(0, 79)),
+        ]));
+
+        let score = score(&header, &[track]);
+        let parts = score.parts;
+        assert_eq!(score.title.as_deref(), Some("Air"));
+        assert_eq!(score.bpm, 150.0);
+        assert_eq!(parts.len(), 1);
+        assert_eq!(parts[0].name, "Flute");
+        assert!(!parts[0].bass_clef);
+
+        let measures = &parts[0].measures;
+        assert_eq!(measures.len(), 2);
+        assert_eq!(measures[0].time, Some((3, 4)));
+        assert_eq!(measures[1].time, None);
+        assert_eq!((measures[0].tempo, measures[1].tempo), (Some(150.0), Some(120.0)));
+        assert!(!parts[0].truncated);
+
+        let durations: Vec<(u32, usize, bool, bool)> = measures.iter().flat_map(|measure| &measure.voices[0])
+            .map(|element| (element.duration, element.pitches.len(), element.tie_start, element.tie_stop))
+            .collect();
+        assert_eq!(durations, vec![(8, 0, false, false), (8, 1, false, false), (8, 2, true, false), (16, 2, false, true), (8, 0, false, false)]);
+        let lyrics: Vec<Option<&str>> = measures.iter().flat_map(|measure| &measure.voices[0]).map(|element| element.lyric.as_deref()).collect();
+        assert_eq!(lyrics, vec![None, Some("Hal-"), Some("lo "), None, None]);
+    }
+
+    #[test]
+    fn empty_measures_are_whole_rests() {
+        let header = MThd { identifier: *b"MThd", chunklen: 6, format: 0, ntracks: 1, tickdiv: 8 };
+        let track = MTrk::new(to_delta_times(vec![
+            (0, Event { data: vec![0xFF, 0x58, 0x04, 6, 3, 24, 8] }), // 6/8
+            (48, Event::new_note_on(0, 40, 100)), // in the third measure
+            (52, Event::new_note_off(0, 40)),
+            (8 * 4 * 3 * 2_000, Event::new_note_on(0, 40, 100)),
+        ]));
+
+        let part = &score(&header, &[track]).parts[0];
+        assert!(part.bass_clef);
+        assert!(part.truncated);
+        assert_eq!(part.measures.len(), MAX_MEASURES);
+        assert!(part.measures[0].voices[0].is_empty() && part.measures[1].voices[0].is_empty());
+        assert_eq!(part.measures[0].length, 24);
+        assert_eq!(part.measures[2].voices[0].len(), 3); // an eighth note, then rests of 16 and 4 divisions
+    }
+
+    #[test]
+    fn signature_changes_within_a_measure_end_it() {
+        let header = MThd { identifier: *b"MThd", chunklen: 6, format: 0, ntracks: 1, tickdiv: 96 };
+        let track = MTrk::new(to_delta_times(vec![
+            (0, Event::new_note_on(0, 60, 100)), // a whole note
+            (0, Event { data: vec![0xFF, 0x59, 0x02, 0, 0] }), // C major again, which changes nothing
+            (96, Event { data: vec![0xFF, 0x59, 0x02, 0, 0] }),
+            (192, Event { data: vec![0xFF, 0x59, 0x02, 2, 0] }), // D major halfway through the first measure
+            (384, Event::new_note_off(0, 60)),
+            (480, Event { data: vec![0xFF, 0x58, 0x04, 3, 2, 24, 8] }), // 3/4 on the third beat of the next one
+            (480, Event::new_note_on(0, 62, 100)),
+            (576, Event::new_note_off(0, 62)),
+        ]));
+
+        let measures = &score(&header, &[track]).parts[0].measures;
+        let layout: Vec<_> = measures.iter().map(|measure| (measure.time, measure.key, measure.length)).collect();
+        assert_eq!(layout, vec![(Some((4, 4)), Some((0, false)), 16), (None, Some((2, false)), 24), (Some((3, 4)), None, 24)]);
+
+        let durations: Vec<Vec<(u32, bool, bool)>> = measures.iter()
+            .map(|measure| measure.voices[0].iter().map(|element| (element.duration, element.tie_start, element.tie_stop)).collect())
+            .collect();
+        assert_eq!(durations, vec![vec![(16, true, false)], vec![(16, false, true), (8, false, false)], vec![(8, false, false), (16, false, false)]]);
+    }
+
+    #[test]
+    fn overlapping_notes_keep_their_length_in_voices_of_their_own() {
+        let header = MThd { identifier: *b"MThd", chunklen: 6, format: 0, ntracks: 1, tickdiv: 96 };
+        let mut events = vec![(0, Event::new_note_on(0, 36, 100)), (384, Event::new_note_off(0, 36))]; // a whole note bass
+        for (index, pitch) in [72, 74, 76, 77].iter().enumerate() {
+            events.push((96 * index as u32, Event::new_note_on(0, *pitch, 100))); // under a tune of quarter notes
+            events.push((96 * index as u32 + 96, Event::new_note_off(0, *pitch)));
+        }
+        events.sort_by_key(|(tick, _)| *tick);
+
+        let part = &score(&header, &[MTrk::new(to_delta_times(events))]).parts[0];
+        let measure = &part.measures[0];
+        let durations = |elements: &Vec<Element>| elements.iter().map(|element| (element.duration, element.pitches.clone())).collect::<Vec<(u32, Vec<u8>)>>();
+        assert_eq!(durations(&measure.voices[0]), vec![(8, vec![72]), (8, vec![74]), (8, vec![76]), (8, vec![77])]);
+        assert_eq!(durations(&measure.voices[1]), vec![(32, vec![36])]);
+        assert!(!part.cut_short);
+
+        // past MAX_VOICES, the voice that frees up soonest is cut short
+        let notes: Vec<(u32, u32, u8)> = (0..=MAX_VOICES as u8).map(|index| (index as u32, 32, 60 + index)).collect();
+        let (voices, cut_short) = voices(&notes, &[]);
+        assert_eq!(voices.len(), MAX_VOICES);
+        assert!(cut_short);
+        assert_eq!(voices[0].iter().map(|chord| (chord.start, chord.duration)).collect::<Vec<(u32, u32)>>(), vec![(0, 4), (4, 28)]);
+    }
+
+    #[test]
+    fn durations_and_pitches_are_written_as_notation() {
+        assert_eq!(note_value(8), (4, 0));
+        assert_eq!(note_value(12), (4, 1));
+        assert_eq!(note_value(48), (1, 1));
+        assert_eq!(note_value(1), (32, 0));
+        assert_eq!(split_duration(27), vec![24, 3]);
+
+        assert_eq!(spell(60, 0), ('C', 0, 4));
+        assert_eq!(spell(61, 2), ('C', 1, 4));
+        assert_eq!(spell(61, -3), ('D', -1, 4));
+        assert_eq!(spell(21, 0), ('A', 0, 0));
+    }
+}
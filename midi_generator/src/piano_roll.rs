@@ -0,0 +1,403 @@
+// Piano roll
+// Draws a file as an SVG piano roll, with lanes for controllers, pitch bend and tempo beneath the notes
+
+use std::collections::{BTreeMap, VecDeque};
+
+use crate::midicsv::{parse_paths, split_length};
+use crate::musicxml::escape;
+use crate::reader::read_midi_file;
+use crate::timing::{duration, TempoMap, TickDiv};
+use crate::{MThd, MTrk};
+
+const MARGIN_LEFT: f64 = 56.0; // room for the pitch and lane labels
+const MARGIN_RIGHT: f64 = 16.0;
+const HEADER: f64 = 40.0; // the description of the file and the time labels
+const NOTE_HEIGHT: f64 = 6.0;
+const LANE_GAP: f64 = 12.0;
+const CONTROLLER_LANE: f64 = 80.0;
+const BEND_LANE: f64 = 60.0;
+const TEMPO_LANE: f64 = 60.0;
+const LEGEND_ROW: f64 = 18.0;
+const LEGEND_ENTRY: f64 = 220.0;
+const MIN_BAR_SPACING: f64 = 4.0; // closer bar lines would merge into a solid block
+const MIN_LABEL_SPACING: f64 = 80.0;
+
+/// A note as it is drawn, with the voice that picks its colour
+struct Note {
+    start: f64,
+    end: f64,
+    pitch: u8,
+    velocity: u8,
+    voice: usize,
+}
+
+/// What a file holds, timed in seconds from its start
+#[derive(Default)]
+struct Roll {
+    notes: Vec<Note>,
+    controllers: BTreeMap<(usize, u8), Vec<(f64, u8)>>, // by voice and controller number
+    bends: BTreeMap<usize, Vec<(f64, u16)>>, // by voice
+    tempos: Vec<(f64, f64)>, // in beats per minute
+    voices: Vec<(usize, u8)>, // track and channel of each voice, in order of first appearance
+    names: Vec<Option<String>>, // sequence name of each track
+}
+
+/// Returns the voice of a track and channel, adding it if it's new
+fn voice(voices: &mut Vec<(usize, u8)>, track: usize, channel: u8) -> usize {
+    voices.iter().position(|key| *key == (track, channel)).unwrap_or_else(|| {
+        voices.push((track, channel));
+        voices.len() - 1
+    })
+}
+
+/// Collects the notes, controllers, pitch bends and tempos of a file
+///
+/// Note Off ends the earliest sounding note of its pitch and channel, and notes still sounding at the end of
+/// their track end there. The tracks of format 2 files play one after another.
+fn collect(header: &MThd, tracks: &[MTrk]) -> Roll {
+    let metrical = matches!(TickDiv::from_tickdiv(header.tickdiv), TickDiv::Metrical { .. });
+    let mut roll = Roll::default();
+    let mut offset = 0.0;
+
+    let maps = TempoMap::for_file(header, tracks);
+    for (index, track) in tracks.iter().enumerate() {
+        let map = maps.track(index);
+        let mut sounding: BTreeMap<(u8, u8), VecDeque<(f64, u8)>> = BTreeMap::new();
+        let mut name = None;
+
+        if metrical && (index == 0 || header.format == 2) {
+            roll.tempos.push((offset, 120.0)); // until the first Tempo event
+        }
+
+        for (tick, event) in track.timed_events() {
+            let seconds = offset + map.tick_to_seconds(tick);
+            match event.data.as_slice() {
+                [status @ 0x80..=0x9F, pitch, velocity, ..] => {
+                    let channel = status & 0x0F;
+                    if status & 0xF0 == 0x90 && *velocity > 0 {
+                        sounding.entry((channel, *pitch)).or_default().push_back((seconds, *velocity));
+                    }
+                    else if let Some((start, velocity)) = sounding.get_mut(&(channel, *pitch)).and_then(VecDeque::pop_front) {
+                        let voice = voice(&mut roll.voices, index, channel);
+                        roll.notes.push(Note { start, end: seconds, pitch: *pitch, velocity, voice });
+                    }
+                },
+                [status @ 0xB0..=0xBF, controller, value, ..] => {
+                    let voice = voice(&mut roll.voices, index, status & 0x0F);
+                    roll.controllers.entry((voice, *controller)).or_default().push((seconds, *value));
+                },
+                [status @ 0xE0..=0xEF, lsb, msb, ..] => {
+                    let voice = voice(&mut roll.voices, index, status & 0x0F);
+                    roll.bends.entry(voice).or_default().push((seconds, (*msb as u16) << 7 | *lsb as u16));
+                },
+                [0xFF, 0x51, 0x03, a, b, c, ..] if metrical => {
+                    let tempo = ((*a as u32) << 16 | (*b as u32) << 8 | *c as u32).max(1);
+                    roll.tempos.push((seconds, 60_000_000.0 / tempo as f64));
+                },
+                [0xFF, 0x03, ..] if name.is_none() => {
+                    name = split_length(event, 2).map(|text| String::from_utf8_lossy(text).into_owned());
+                },
+                _ => (),
+            }
+        }
+
+        let end = offset + map.tick_to_seconds(track.length());
+        for ((channel, pitch), notes) in sounding {
+            for (start, velocity) in notes {
+                let voice = voice(&mut roll.voices, index, channel);
+                roll.notes.push(Note { start, end, pitch, velocity, voice });
+            }
+        }
+        roll.names.push(name);
+
+        if header.format == 2 {
+            offset = end;
+        }
+    }
+
+    roll.notes.sort_by(|a, b| a.start.total_cmp(&b.start));
+    roll.tempos.sort_by(|a, b| a.0.total_cmp(&b.0)); // stable, so the last Tempo event at a time wins
+    roll
+}
+
+/// Returns the times of the bar lines of a file, following its Time Signature events from 4/4
+///
+/// A time signature change starts a new bar even in the middle of one. Bars closer together than `min_gap`
+/// seconds are skipped, so files with huge gaps between events still draw a readable number of lines.
+/// Timecode files have no bars.
+fn bars(header: &MThd, tracks: &[MTrk], min_gap: f64) -> Vec<f64> {
+    let ppqn = match TickDiv::from_tickdiv(header.tickdiv) {
+        TickDiv::Metrical { ppqn } => ppqn.max(1) as u64,
+        TickDiv::Timecode { .. } => return Vec::new(),
+    };
+    if tracks.is_empty() {
+        return Vec::new();
+    }
+    let scopes: Vec<Vec<usize>> = if header.format == 2 { (0..tracks.len()).map(|index| vec![index]).collect() } else { vec![(0..tracks.len()).collect()] };
+
+    let maps = TempoMap::for_file(header, tracks);
+    let mut lines = Vec::new();
+    let mut offset = 0.0;
+    for scope in scopes {
+        let map = maps.track(scope[0]);
+        let end = scope.iter().map(|index| tracks[*index].length()).max().unwrap_or(0) as u64;
+
+        let mut signatures = vec![(0, 4 * ppqn)];
+        for index in &scope {
+            for (tick, event) in tracks[*index].timed_events() {
+                if let [0xFF, 0x58, 0x04, numerator, denominator, ..] = event.data.as_slice() {
+                    if *numerator > 0 && *denominator <= 16 {
+                        signatures.push((tick as u64, ((*numerator as u64 * 4 * ppqn) >> denominator).max(1)));
+                    }
+                }
+            }
+        }
+        signatures.sort_by_key(|(tick, _)| *tick); // stable, so the last one at a tick wins
+
+        let mut last: Option<f64> = None;
+        for (index, (start, bar)) in signatures.iter().enumerate() {
+            let stop = signatures.get(index + 1).map_or(end + 1, |(tick, _)| *tick).min(end + 1);
+            let mut tick = *start;
+            while tick < stop {
+                let seconds = offset + map.tick_to_seconds(tick.min(u32::MAX as u64) as u32);
+                if last.is_none_or(|last| seconds - last >= min_gap) {
+                    lines.push(seconds);
+                    last = Some(seconds);
+                }
+                let target = map.seconds_to_tick(last.unwrap() + min_gap - offset) as u64;
+                tick += bar * (target.saturating_sub(tick) / bar).max(1);
+            }
+        }
+
+        if header.format == 2 {
+            offset += map.tick_to_seconds(end as u32);
+        }
+    }
+
+    lines
+}
+
+/// Returns the colour of a voice, with hues spread by the golden angle so neighbouring voices differ
+fn colour(voice: usize) -> String {
+    format!("hsl({:.0}, 70%, 42%)", (voice as f64 * 137.508) % 360.0)
+}
+
+/// Returns the spacing of the time labels, 1, 2 or 5 times a power of ten seconds
+fn label_step(seconds_per_pixel: f64) -> f64 {
+    let wanted = seconds_per_pixel * MIN_LABEL_SPACING;
+    let power = 10f64.powf(wanted.log10().floor());
+    [1.0, 2.0, 5.0, 10.0].iter().map(|factor| factor * power).find(|step| *step >= wanted).unwrap_or(10.0 * power)
+}
+
+/// Writes values as a step line that holds each one until the next, to the right edge of the plot
+fn step_line(points: &[(f64, f64)], right: f64, stroke: &str) -> String {
+    let mut coordinates = Vec::new();
+    for (index, (x, y)) in points.iter().enumerate() {
+        if index > 0 {
+            coordinates.push(format!("{:.1},{:.1}", x, points[index - 1].1));
+        }
+        coordinates.push(format!("{:.1},{:.1}", x, y));
+    }
+    if let Some((_, y)) = points.last() {
+        coordinates.push(format!("{:.1},{:.1}", right, y));
+    }
+    format!("<polyline points=\"{}\" fill=\"none\" stroke=\"{}\" stroke-width=\"1\"/>\n", coordinates.join(" "), stroke)
+}
+
+/// Draws a file as an SVG piano roll of the given plot width
+///
+/// Time runs left to right in seconds with bar lines from the time signatures, and pitch bottom to top over the
+/// octaves the notes use. Notes are coloured by track and channel, with velocity as their opacity. Beneath them
+/// are lanes for controller values, pitch bend and tempo, and a legend of the colours.
+pub fn to_svg(header: &MThd, tracks: &[MTrk], width: f64) -> String {
+    let roll = collect(header, tracks);
+    let length = duration(header, tracks).max(roll.notes.iter().map(|note| note.end).fold(0.0, f64::max));
+    let length = if length > 0.0 { length } else { 1.0 };
+    let x = |seconds: f64| MARGIN_LEFT + seconds / length * width;
+    let right = MARGIN_LEFT + width;
+
+    let low = roll.notes.iter().map(|note| note.pitch).min().map_or(60, |pitch| pitch - pitch % 12);
+    let high = roll.notes.iter().map(|note| note.pitch).max().map_or(71, |pitch| (pitch - pitch % 12 + 11).min(127));
+    let row = |pitch: u8| HEADER + (high - pitch) as f64 * NOTE_HEIGHT;
+    let roll_bottom = HEADER + (high - low + 1) as f64 * NOTE_HEIGHT;
+    let controllers_top = roll_bottom + LANE_GAP;
+    let bends_top = controllers_top + CONTROLLER_LANE + LANE_GAP;
+    let tempos_top = bends_top + BEND_LANE + LANE_GAP;
+    let lanes_bottom = tempos_top + TEMPO_LANE;
+
+    let per_row = ((width + MARGIN_LEFT) / LEGEND_ENTRY).floor().max(1.0) as usize;
+    let legend_rows = roll.voices.len().div_ceil(per_row);
+    let total_width = right + MARGIN_RIGHT;
+    let total_height = lanes_bottom + LANE_GAP + legend_rows as f64 * LEGEND_ROW + 4.0;
+
+    let mut svg = format!("<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{0:.0}\" height=\"{1:.0}\" viewBox=\"0 0 {0:.0} {1:.0}\" font-family=\"sans-serif\" font-size=\"10\">\n",
+        total_width, total_height);
+    svg += &format!("<rect width=\"{:.0}\" height=\"{:.0}\" fill=\"white\"/>\n", total_width, total_height);
+
+    let timing = match TickDiv::from_tickdiv(header.tickdiv) {
+        TickDiv::Metrical { ppqn } => format!("{} ticks per quarter note", ppqn),
+        TickDiv::Timecode { fps, subframes } => format!("{} fps, {} subframes", fps, subframes),
+    };
+    svg += &format!("<text x=\"{:.1}\" y=\"14\" font-size=\"12\">format {}, {} tracks, {}, {:.3} seconds</text>\n",
+        MARGIN_LEFT, header.format, tracks.len(), timing, length);
+
+    // pitch rows, shaded where the black keys are, with the octaves labelled at their C
+    for pitch in low..=high {
+        if [1, 3, 6, 8, 10].contains(&(pitch % 12)) {
+            svg += &format!("<rect x=\"{:.1}\" y=\"{:.1}\" width=\"{:.1}\" height=\"{:.1}\" fill=\"#f0f0f0\"/>\n", MARGIN_LEFT, row(pitch), width, NOTE_HEIGHT);
+        }
+        if pitch % 12 == 0 {
+            svg += &format!("<text x=\"{:.1}\" y=\"{:.1}\" text-anchor=\"end\">C{}</text>\n", MARGIN_LEFT - 4.0, row(pitch) + NOTE_HEIGHT, pitch as i32 / 12 - 1);
+        }
+    }
+
+    // lane backgrounds, with the center of the pitch bend lane marked
+    for (top, height, label) in [(controllers_top, CONTROLLER_LANE, "controllers"), (bends_top, BEND_LANE, "pitch bend"), (tempos_top, TEMPO_LANE, "tempo")] {
+        svg += &format!("<rect x=\"{:.1}\" y=\"{:.1}\" width=\"{:.1}\" height=\"{:.1}\" fill=\"#f8f8f8\"/>\n", MARGIN_LEFT, top, width, height);
+        svg += &format!("<text x=\"{:.1}\" y=\"{:.1}\" text-anchor=\"end\">{}</text>\n", MARGIN_LEFT - 4.0, top + 10.0, label);
+    }
+    let center = bends_top + BEND_LANE / 2.0;
+    svg += &format!("<line x1=\"{:.1}\" y1=\"{:.1}\" x2=\"{:.1}\" y2=\"{:.1}\" stroke=\"#bbb\" stroke-dasharray=\"2,2\"/>\n", MARGIN_LEFT, center, right, center);
+
+    // bar lines through the roll and the lanes, and time labels above them
+    for bar in bars(header, tracks, length / width * MIN_BAR_SPACING) {
+        svg += &format!("<line x1=\"{0:.1}\" y1=\"{1:.1}\" x2=\"{0:.1}\" y2=\"{2:.1}\" stroke=\"#ccc\" stroke-width=\"0.5\"/>\n", x(bar), HEADER, lanes_bottom);
+    }
+    let step = label_step(length / width);
+    let mut label = 0.0;
+    while label <= length {
+        svg += &format!("<text x=\"{:.1}\" y=\"{:.1}\" text-anchor=\"middle\">{}s</text>\n", x(label), HEADER - 6.0, (label * 1000.0).round() / 1000.0);
+        label += step;
+    }
+
+    for note in &roll.notes {
+        svg += &format!("<rect x=\"{:.1}\" y=\"{:.1}\" width=\"{:.1}\" height=\"{:.1}\" fill=\"{}\" fill-opacity=\"{:.2}\"/>\n",
+            x(note.start), row(note.pitch), (x(note.end) - x(note.start)).max(1.0), NOTE_HEIGHT, colour(note.voice), 0.15 + 0.85 * note.velocity as f64 / 127.0);
+    }
+
+    // each controller labelled with its number where it starts, as the lines of one voice share a colour
+    for ((voice, controller), values) in &roll.controllers {
+        let points: Vec<(f64, f64)> = values.iter().map(|(seconds, value)| (x(*seconds), controllers_top + CONTROLLER_LANE - 2.0 - *value as f64 / 127.0 * (CONTROLLER_LANE - 4.0))).collect();
+        let (track, channel) = roll.voices[*voice];
+        svg += &format!("<g><title>track {}, channel {}, CC{}</title>\n", track + 1, channel + 1, controller);
+        svg += &step_line(&points, right, &colour(*voice));
+        svg += &format!("<text x=\"{:.1}\" y=\"{:.1}\" font-size=\"8\" fill=\"{}\">CC{}</text>\n</g>\n",
+            points[0].0 + 2.0, (points[0].1 - 2.0).max(controllers_top + 8.0), colour(*voice), controller);
+    }
+    for (voice, values) in &roll.bends {
+        let points: Vec<(f64, f64)> = values.iter().map(|(seconds, value)| (x(*seconds), bends_top + BEND_LANE - 2.0 - *value as f64 / 16383.0 * (BEND_LANE - 4.0))).collect();
+        svg += &step_line(&points, right, &colour(*voice));
+    }
+    if let Some(fastest) = roll.tempos.iter().map(|(_, bpm)| *bpm).reduce(f64::max) {
+        let points: Vec<(f64, f64)> = roll.tempos.iter().map(|(seconds, bpm)| (x(*seconds), tempos_top + TEMPO_LANE - 2.0 - bpm / fastest * (TEMPO_LANE - 14.0))).collect();
+        svg += &step_line(&points, right, "black");
+        svg += &format!("<text x=\"{:.1}\" y=\"{:.1}\">{:.1} BPM</text>\n", MARGIN_LEFT + 4.0, tempos_top + 10.0, fastest);
+    }
+
+    for (index, (track, channel)) in roll.voices.iter().enumerate() {
+        let left = MARGIN_LEFT + (index % per_row) as f64 * LEGEND_ENTRY;
+        let top = lanes_bottom + LANE_GAP + (index / per_row) as f64 * LEGEND_ROW;
+        let mut text = format!("track {}, channel {}", track + 1, channel + 1);
+        if let Some(name) = roll.names[*track].as_deref().filter(|name| !name.is_empty()) {
+            text += &format!(": {}", escape(&name.chars().take(24).collect::<String>()));
+        }
+        svg += &format!("<rect x=\"{:.1}\" y=\"{:.1}\" width=\"10\" height=\"10\" fill=\"{}\"/>\n", left, top, colour(index));
+        svg += &format!("<text x=\"{:.1}\" y=\"{:.1}\">{}</text>\n", left + 14.0, top + 9.0, text);
+    }
+
+    svg + "</svg>\n"
+}
+
+pub const USAGE: &str = "\
+usage: midi_generator piano-roll [options] <file>
+
+draws a MIDI file as an SVG piano roll, with lanes for controllers, pitch bend and tempo beneath the notes
+
+options:
+  -o, --output <file>    write the drawing to <file> (default <file>.svg)
+  --width <px>           width of the plot in pixels (default 1600)";
+
+/// Runs the piano-roll command, drawing a MIDI file as an SVG piano roll
+pub fn run(args: Vec<String>) -> Result<(), String> {
+    let mut width = 1600.0;
+    let mut rest = Vec::new();
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--width" => {
+                let pixels = args.next().ok_or(format!("{} needs a number of pixels", arg))?;
+                width = pixels.parse().ok().filter(|pixels| (100.0..=100_000.0).contains(pixels))
+                    .ok_or(format!("invalid width: {} (must be from 100 to 100000)", pixels))?;
+            },
+            _ => rest.push(arg),
+        }
+    }
+
+    let (input, output) = parse_paths(rest, "svg", USAGE)?;
+    let (header, tracks) = read_midi_file(&input)?;
+    std::fs::write(&output, to_svg(&header, &tracks, width)).map_err(|error| format!("could not write {}: {}", output, error))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{to_delta_times, Event};
+
+    fn header(format: u16) -> MThd {
+        MThd { identifier: *b"MThd", chunklen: 6, format, ntracks: 1, tickdiv: 96 }
+    }
+
+    #[test]
+    fn bars_follow_time_signatures_and_thin_out() {
+        let tracks = vec![MTrk::new(to_delta_times(vec![
+            (0, Event { data: vec![0xFF, 0x58, 0x04, 3, 2, 24, 8] }), // 3/4, half a second per beat
+            (576, Event { data: vec![0xFF, 0x58, 0x04, 6, 3, 24, 8] }), // 6/8 after two bars
+            (1_000_000, Event { data: vec![0xFF, 0x2F, 0x00] }),
+        ]))];
+
+        let lines = bars(&header(0), &tracks, 0.1);
+        assert_eq!(lines[..4], [0.0, 1.5, 3.0, 4.5]);
+        assert!(lines.windows(2).all(|pair| pair[1] - pair[0] >= 0.1));
+
+        let sparse = bars(&header(0), &tracks, 1000.0);
+        assert!(sparse.len() <= 6 && sparse.windows(2).all(|pair| pair[1] - pair[0] >= 1000.0), "{:?}", sparse);
+        assert!(bars(&MThd { tickdiv: 0xE728, ..header(0) }, &tracks, 0.1).is_empty());
+    }
+
+    #[test]
+    fn files_without_tracks_are_drawn() {
+        let header = MThd { ntracks: 0, ..header(1) };
+        assert!(bars(&header, &[], 0.1).is_empty());
+        assert!(to_svg(&header, &[], 1000.0).contains("format 1, 0 tracks"));
+    }
+
+    #[test]
+    fn notes_and_lanes_are_drawn() {
+        let tracks = vec![MTrk::new(to_delta_times(vec![
+            (0, Event::new_text_event(0x03, b"Lead <1>")),
+            (0, Event::new_tempo(250_000)),
+            (0, Event::new_note_on(1, 64, 127)),
+            (0, Event::new_note_on(1, 67, 0)), // ends nothing
+            (48, Event { data: vec![0xB1, 7, 100] }),
+            (96, Event::new_note_off(1, 64)),
+            (96, Event { data: vec![0xE1, 0x00, 0x40] }),
+            (96, Event::new_note_on(9, 36, 40)), // left sounding to the end of the track
+            (192, Event { data: vec![0xFF, 0x2F, 0x00] }),
+        ]))];
+
+        let svg = to_svg(&header(0), &tracks, 1000.0);
+        assert!(svg.starts_with("<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"1072\""));
+        assert!(svg.contains("format 0, 1 tracks, 96 ticks per quarter note, 0.500 seconds"));
+        assert!(svg.contains("<rect x=\"56.0\" y=\"82.0\" width=\"500.0\" height=\"6.0\" fill=\"hsl(0, 70%, 42%)\" fill-opacity=\"1.00\"/>"));
+        assert!(svg.contains("fill=\"hsl(138, 70%, 42%)\" fill-opacity=\"0.42\""));
+        assert_eq!(svg.matches("fill-opacity").count(), 2);
+        assert!(svg.contains(">C2</text>") && svg.contains(">C4</text>") && !svg.contains(">C5</text>"));
+        assert!(svg.contains(">240.0 BPM</text>"));
+        assert!(svg.contains(">track 1, channel 2: Lead &lt;1&gt;</text>"));
+        assert!(svg.contains(">track 1, channel 10: Lead &lt;1&gt;</text>"));
+        assert_eq!(svg.matches("<polyline").count(), 3);
+        assert!(svg.contains("<g><title>track 1, channel 2, CC7</title>") && svg.contains(">CC7</text>"));
+        assert!(svg.trim_end().ends_with("</svg>"));
+    }
+}
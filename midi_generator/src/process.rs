@@ -0,0 +1,65 @@
+// Local programs
+// Runs the programs that consume generated files, with a time limit and their output captured
+
+use std::io::Read;
+use std::process::{Command, ExitStatus, Stdio};
+use std::time::{Duration, Instant};
+
+/// What a finished run of a program left behind
+pub struct Run {
+    pub status: ExitStatus,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+}
+
+/// Fills in the file a command should work on, given by a {} argument or else added as the last argument
+pub fn command_for(command: &[String], path: &str) -> Vec<String> {
+    let mut args: Vec<String> = command.iter().map(|arg| arg.replace("{}", path)).collect();
+    if !command.iter().any(|arg| arg.contains("{}")) {
+        args.push(String::from(path));
+    }
+    args
+}
+
+/// Reads a pipe to the end on another thread, so a chatty program can't fill it and stall
+fn drain<R: Read + Send + 'static>(pipe: Option<R>) -> std::thread::JoinHandle<Vec<u8>> {
+    std::thread::spawn(move || {
+        let mut bytes = Vec::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut bytes);
+        }
+        bytes
+    })
+}
+
+/// Runs a program with no input, returning None if it was still running after `timeout` and had to be killed
+pub fn run_with_timeout(args: &[String], timeout: Duration) -> Result<Option<Run>, String> {
+    let mut child = Command::new(&args[0])
+        .args(&args[1..])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|error| format!("could not run {}: {}", args[0], error))?;
+
+    let stdout = drain(child.stdout.take());
+    let stderr = drain(child.stderr.take());
+
+    let start = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait().map_err(|error| format!("could not wait for {}: {}", args[0], error))? {
+            break Some(status);
+        }
+        if start.elapsed() > timeout {
+            let _ = child.kill();
+            let _ = child.wait();
+            break None;
+        }
+        std::thread::sleep(Duration::from_millis(5));
+    };
+
+    let stdout = stdout.join().unwrap_or_default();
+    let stderr = stderr.join().unwrap_or_default();
+
+    Ok(status.map(|status| Run { status, stdout, stderr }))
+}
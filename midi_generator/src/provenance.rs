@@ -0,0 +1,179 @@
+// Provenance
+// Records the version, seed and options a file was generated with in a Sequencer-Specific event,
+// and rebuilds the file from that record
+
+use crate::{encode_vlq, generate_file, parse_args, Event, GeneratorOptions};
+
+const MANUFACTURER_ID: u8 = 0x7D; // the ID the MIDI Manufacturers Association keeps for non-commercial use
+const SIGNATURE: &[u8] = b"midi_generator ";
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Returns the command line options that make the generator behave as `options` asks
+fn options_to_args(options: &GeneratorOptions) -> Vec<String> {
+    let mut args = Vec::new();
+
+    if options.spread_channels {
+        args.push(String::from("--spread-channels"));
+    }
+    if options.lyrics {
+        args.push(String::from("--lyrics"));
+    }
+    if options.karaoke {
+        args.push(String::from("--karaoke"));
+    }
+    if let Some(mode) = options.text_mode {
+        args.extend([String::from("--text-mode"), String::from(mode.name())]);
+    }
+    if let Some((lower, upper)) = options.bpm_range {
+        args.extend([String::from("--bpm"), format!("{}-{}", lower, upper)]);
+    }
+    if !options.faults.is_empty() {
+        let names: Vec<&str> = options.faults.iter().map(|fault| fault.name()).collect();
+        args.extend([String::from("--corrupt"), names.join(",")]);
+    }
+    if options.foreign_chunks {
+        args.push(String::from("--foreign-chunks"));
+    }
+    if options.rmid {
+        args.push(String::from("--rmid"));
+    }
+
+    args
+}
+
+/// Returns the Sequencer-Specific event recording how a file was generated
+///
+/// Its data is the non-commercial manufacturer ID followed by text such as
+/// "midi_generator 0.1.0 --seed 42 --lyrics --bpm 60-180", the command line options that rebuild the file.
+pub fn provenance_event(seed: u64, options: &GeneratorOptions) -> Event {
+    let mut record = format!("{}{} --seed {}", String::from_utf8_lossy(SIGNATURE), VERSION, seed);
+    for arg in options_to_args(options) {
+        record += " ";
+        record += &arg;
+    }
+
+    let mut data = vec![0xFF, 0x7F];
+    data.extend(encode_vlq(record.len() as u32 + 1));
+    data.push(MANUFACTURER_ID);
+    data.extend(record.as_bytes());
+
+    Event { data }
+}
+
+/// Finds the provenance record in the bytes of a file, returning its text
+///
+/// The bytes are searched rather than parsed, so that the record can still be found in files that were made malformed.
+fn find_provenance(bytes: &[u8]) -> Option<String> {
+    (0..bytes.len()).find_map(|pos| {
+        if !bytes[pos..].starts_with(&[0xFF, 0x7F]) {
+            return None;
+        }
+
+        let length_bytes = bytes[pos + 2..].iter().take(4).position(|byte| byte & 0x80 == 0)? + 1;
+        let length = bytes[pos + 2..pos + 2 + length_bytes].iter().fold(0, |value, byte| (value << 7) | (byte & 0x7F) as usize);
+        let data = bytes.get(pos + 2 + length_bytes..pos + 2 + length_bytes + length)?;
+
+        if data.first() == Some(&MANUFACTURER_ID) && data[1..].starts_with(SIGNATURE) {
+            String::from_utf8(data[1 + SIGNATURE.len()..].to_vec()).ok()
+        }
+        else {
+            None
+        }
+    })
+}
+
+/// Splits a provenance record into the version it was made by and the options that rebuild it
+fn parse_record(record: &str) -> Result<(String, Vec<String>), String> {
+    let mut words = record.split(' ').map(String::from);
+    let version = words.next().filter(|version| !version.is_empty()).ok_or("the provenance record is empty")?;
+    Ok((version, words.collect()))
+}
+
+/// Rebuilds a file from the bytes of a copy of it, returning the rebuilt bytes
+fn regenerate(bytes: &[u8]) -> Result<Vec<u8>, String> {
+    let record = find_provenance(bytes).ok_or("no provenance record found, the file wasn't made by midi_generator or was made with --no-provenance")?;
+    let (version, args) = parse_record(&record)?;
+
+    if version != VERSION {
+        return Err(format!("the file was made by midi_generator {}, which may generate differently from this version ({})", version, VERSION));
+    }
+
+    let args = parse_args(args.into_iter()).map_err(|error| format!("the provenance record doesn't parse: {}", error))?;
+    let seed = args.seed.ok_or("the provenance record has no seed")?;
+
+    Ok(generate_file(seed, &args.options).bytes)
+}
+
+pub const USAGE: &str = "\
+usage: midi_generator regenerate [options] <file>
+
+rebuilds <file> from the version, seed and options recorded in it when it was generated
+
+options:
+  -o, --output <file>    write the rebuilt file to <file> (default <file>.regenerated.mid)";
+
+/// Runs the regenerate command, rebuilding a generated file from its provenance record
+pub fn run(args: Vec<String>) -> Result<(), String> {
+    let mut output = None;
+    let mut path = None;
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" | "--output" => output = Some(args.next().ok_or(format!("{} needs a file name", arg))?),
+            _ if arg.starts_with('-') => return Err(format!("unknown argument: {}\n{}", arg, USAGE)),
+            _ if path.is_none() => path = Some(arg),
+            _ => return Err(format!("only one file can be given\n{}", USAGE)),
+        }
+    }
+
+    let path = path.ok_or(format!("no file given\n{}", USAGE))?;
+    let output = output.unwrap_or(format!("{}.regenerated.mid", path));
+
+    let original = std::fs::read(&path).map_err(|error| format!("could not read {}: {}", path, error))?;
+    let rebuilt = regenerate(&original).map_err(|error| format!("{}: {}", path, error))?;
+    std::fs::write(&output, &rebuilt).map_err(|error| format!("could not write {}: {}", output, error))?;
+
+    if rebuilt == original {
+        println!("{} is identical to {}", output, path);
+    }
+    else {
+        println!("{} differs from {}, which has been changed since it was generated", output, path);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::corrupt::Fault;
+
+    #[test]
+    fn files_are_rebuilt_byte_for_byte() {
+        let options = [
+            GeneratorOptions { provenance: true, ..Default::default() },
+            GeneratorOptions { provenance: true, lyrics: true, bpm_range: Some((60.5, 180.0)), spread_channels: true, ..Default::default() },
+            GeneratorOptions { provenance: true, karaoke: true, text_mode: Some(crate::TextMode::Words), ..Default::default() },
+            GeneratorOptions { provenance: true, faults: vec![Fault::LongVlq, Fault::WrongNtracks], foreign_chunks: true, ..Default::default() },
+            GeneratorOptions { provenance: true, lyrics: true, rmid: true, ..Default::default() },
+        ];
+
+        for options in &options {
+            for seed in 0..5 {
+                let bytes = generate_file(seed, options).bytes;
+                assert_eq!(regenerate(&bytes).unwrap(), bytes);
+            }
+        }
+    }
+
+    #[test]
+    fn record_lists_version_seed_and_options() {
+        let options = GeneratorOptions { lyrics: true, faults: vec![Fault::HighBitData, Fault::LongVlq], ..Default::default() };
+        let event = provenance_event(42, &options);
+
+        let record = find_provenance(&event.data).unwrap();
+        assert_eq!(record, format!("{} --seed 42 --lyrics --corrupt high-bit-data,long-vlq", VERSION));
+        assert!(regenerate(b"MThd").is_err());
+    }
+}
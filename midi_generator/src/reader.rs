@@ -0,0 +1,185 @@
+// MIDI file reader
+// Parses the bytes of a Standard MIDI File back into MThd, MTrk and Event values
+
+use crate::{DeltaTime, Event, MThd, MTrk};
+
+/// Reads a big-endian number of the given byte width at `pos`
+fn read_be(bytes: &[u8], pos: usize, width: usize) -> Result<u32, String> {
+    let field = bytes.get(pos..pos + width).ok_or(format!("file ends inside a field at byte {}", pos))?;
+    Ok(field.iter().fold(0, |value, byte| (value << 8) | *byte as u32))
+}
+
+/// Reads a variable-length quantity at `pos`, returning the raw bytes it took up and its value
+fn read_vlq(bytes: &[u8], pos: usize) -> Result<(Vec<u8>, u32), String> {
+    let mut raw = Vec::new();
+
+    loop {
+        let byte = *bytes.get(pos + raw.len()).ok_or(format!("track ends inside a variable-length quantity at byte {}", pos))?;
+        raw.push(byte);
+
+        if byte & 0x80 == 0 {
+            break;
+        }
+        if raw.len() == 4 {
+            return Err(format!("variable-length quantity longer than 4 bytes at byte {}", pos));
+        }
+    }
+
+    let value = DeltaTime { data: raw.clone() }.ticks();
+    Ok((raw, value))
+}
+
+/// Returns the number of data bytes that follow a channel event status byte
+fn channel_data_length(status: u8) -> usize {
+    match status & 0xF0 {
+        0xC0 | 0xD0 => 1,
+        _ => 2,
+    }
+}
+
+/// Parses the <DeltaTime, Event> pairs held in the data of an MTrk chunk
+///
+/// Events written with running status are stored with their status byte filled in,
+/// so every channel event in the result starts with its own status byte.
+fn parse_track_data(bytes: &[u8], offset: usize) -> Result<Vec<(DeltaTime, Event)>, String> {
+    let mut data = Vec::new();
+    let mut pos = 0;
+    let mut running_status: Option<u8> = None;
+
+    while pos < bytes.len() {
+        let (raw, _) = read_vlq(bytes, pos)?;
+        pos += raw.len();
+        let delta_time = DeltaTime { data: raw };
+
+        let first = *bytes.get(pos).ok_or(format!("track ends after a delta time at byte {}", offset + pos))?;
+        let mut event_bytes = Vec::new();
+
+        match first {
+            0x00..=0x7F => { // running status, the data bytes of another event like the last one
+                let status = running_status.ok_or(format!("data byte with no running status at byte {}", offset + pos))?;
+                let length = channel_data_length(status);
+                event_bytes.push(status);
+                event_bytes.extend_from_slice(bytes.get(pos..pos + length).ok_or(format!("track ends inside an event at byte {}", offset + pos))?);
+                pos += length;
+            },
+            0x80..=0xEF => {
+                let length = channel_data_length(first);
+                event_bytes.extend_from_slice(bytes.get(pos..pos + 1 + length).ok_or(format!("track ends inside an event at byte {}", offset + pos))?);
+                pos += 1 + length;
+                running_status = Some(first);
+            },
+            0xF0 | 0xF7 | 0xFF => { // SysEx and Meta events carry a length, and cancel running status
+                let header = if first == 0xFF { 2 } else { 1 };
+                let start = pos;
+                pos += header;
+                let (raw, length) = read_vlq(bytes, pos)?;
+                pos += raw.len() + length as usize;
+                event_bytes.extend_from_slice(bytes.get(start..pos).ok_or(format!("track ends inside an event at byte {}", offset + start))?);
+                running_status = None;
+            },
+            _ => return Err(format!("status byte {:02X} is not allowed in a MIDI file, at byte {}", first, offset + pos)),
+        }
+
+        if event_bytes[0] < 0xF0 && event_bytes[1..].iter().any(|byte| byte & 0x80 != 0) {
+            return Err(format!("channel event data byte with the high bit set at byte {}", offset + pos));
+        }
+
+        data.push((delta_time, Event { data: event_bytes }));
+    }
+
+    Ok(data)
+}
+
+/// Parses a Standard MIDI File into its header and track chunks
+///
+/// Chunks other than MTrk are skipped, as the spec asks readers to do.
+/// The ntracks field is kept as read even if the number of MTrk chunks differs.
+/// Files wrapped in a RIFF RMID container are unwrapped first, and byte positions in errors count from the
+/// start of the MIDI data.
+pub fn parse_midi_file(bytes: &[u8]) -> Result<(MThd, Vec<MTrk>), String> {
+    let bytes = crate::riff::unwrap_rmid(bytes)?;
+    if bytes.get(0..4) != Some(b"MThd") {
+        return Err(String::from("file doesn't start with an MThd chunk"));
+    }
+
+    let chunklen = read_be(bytes, 4, 4)?;
+    if chunklen < 6 {
+        return Err(format!("MThd chunklen of {} is too short", chunklen));
+    }
+
+    let header = MThd {
+        identifier: [b'M', b'T', b'h', b'd'],
+        chunklen: 6, // any bytes past the 6 we know are skipped
+        format: read_be(bytes, 8, 2)? as u16,
+        ntracks: read_be(bytes, 10, 2)? as u16,
+        tickdiv: read_be(bytes, 12, 2)? as u16,
+    };
+
+    let mut tracks = Vec::new();
+    let mut pos = 8 + chunklen as usize;
+
+    while pos < bytes.len() {
+        let identifier = bytes.get(pos..pos + 4).ok_or(format!("file ends inside a chunk header at byte {}", pos))?;
+        let length = read_be(bytes, pos + 4, 4)? as usize;
+        let start = pos + 8;
+        let body = bytes.get(start..start + length).ok_or(format!("chunk at byte {} is cut short", pos))?;
+
+        if identifier == b"MTrk" {
+            tracks.push(MTrk::new(parse_track_data(body, start)?));
+        }
+
+        pos = start + length;
+    }
+
+    Ok((header, tracks))
+}
+
+/// Reads and parses the MIDI file at `path`
+pub fn read_midi_file(path: &str) -> Result<(MThd, Vec<MTrk>), String> {
+    let bytes = std::fs::read(path).map_err(|error| format!("could not read {}: {}", path, error))?;
+    parse_midi_file(&bytes).map_err(|error| format!("{}: {}", path, error))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{encode_midi_file, generate_midi_file, GeneratorOptions};
+
+    #[test]
+    fn generated_files_read_back_the_same() {
+        let rng = &mut rand::thread_rng();
+        for _ in 0..20 {
+            let (header, tracks) = generate_midi_file(rng, &GeneratorOptions::default());
+            let bytes = encode_midi_file(&header, &tracks);
+
+            let (read_header, read_tracks) = parse_midi_file(&bytes).unwrap();
+            assert_eq!(encode_midi_file(&read_header, &read_tracks), bytes);
+        }
+    }
+
+    #[test]
+    fn running_status_is_filled_in() {
+        let bytes = [
+            b'M', b'T', b'h', b'd', 0, 0, 0, 6, 0, 0, 0, 1, 0, 96,
+            b'M', b'T', b'r', b'k', 0, 0, 0, 11,
+            0x00, 0x91, 60, 100,
+            0x10, 64, 100, // running status
+            0x00, 0xFF, 0x2F, 0x00,
+        ];
+
+        let (_, tracks) = parse_midi_file(&bytes).unwrap();
+        assert_eq!(tracks[0].data[1].1.data, vec![0x91, 64, 100]);
+        assert_eq!(tracks[0].data[1].0.ticks(), 0x10);
+    }
+
+    #[test]
+    fn broken_files_are_rejected() {
+        assert!(parse_midi_file(b"RIFF").is_err());
+
+        let truncated = [b'M', b'T', b'h', b'd', 0, 0, 0, 6, 0, 0, 0, 1, 0, 96, b'M', b'T', b'r', b'k', 0, 0, 0, 8, 0x00, 0x90];
+        assert!(parse_midi_file(&truncated).is_err());
+
+        let orphan = [b'M', b'T', b'h', b'd', 0, 0, 0, 6, 0, 0, 0, 1, 0, 96, b'M', b'T', b'r', b'k', 0, 0, 0, 3, 0x00, 60, 100];
+        assert!(parse_midi_file(&orphan).is_err());
+    }
+}
@@ -0,0 +1,479 @@
+// Test-case reduction
+// Shrinks a MIDI file that makes a target program fail down to a smaller file that still does, by delta debugging
+// at the MIDI level: whole tracks and events are dropped, text and SysEx payloads cut short and values simplified.
+// Files that don't parse, or only fail as they are encoded, are shrunk chunk by chunk and byte by byte instead
+
+use std::time::Duration;
+
+use crate::{encode_midi_file, encode_vlq, process, reader, DeltaTime, Event, MThd, MTrk};
+
+/// Decides whether the bytes of a candidate file still show the failure being reduced
+pub type Oracle<'a> = dyn FnMut(&[u8]) -> Result<bool, String> + 'a;
+
+/// Returns the items at the given indices, in order
+fn pick<T: Clone>(items: &[T], indices: &[usize]) -> Vec<T> {
+    indices.iter().map(|&index| items[index].clone()).collect()
+}
+
+/// Finds a small subset of the indices `0..len` for which `test` still holds, by delta debugging
+///
+/// Chunks of the kept indices are removed for as long as `test` holds for what is left, and the chunks are halved
+/// whenever none of them can go. The result is 1-minimal: removing any single index from it makes `test` fail.
+fn ddmin(len: usize, test: &mut dyn FnMut(&[usize]) -> Result<bool, String>) -> Result<Vec<usize>, String> {
+    let mut kept: Vec<usize> = (0..len).collect();
+    let mut nchunks = 2;
+
+    while !kept.is_empty() {
+        let chunk = kept.len().div_ceil(nchunks);
+        let mut removed = false;
+
+        for start in (0..kept.len()).step_by(chunk) {
+            let end = (start + chunk).min(kept.len());
+            let candidate: Vec<usize> = kept[..start].iter().chain(&kept[end..]).copied().collect();
+            if test(&candidate)? {
+                kept = candidate;
+                nchunks = (nchunks - 1).max(2);
+                removed = true;
+                break;
+            }
+        }
+
+        if !removed {
+            if chunk == 1 {
+                break;
+            }
+            nchunks = (nchunks * 2).min(kept.len());
+        }
+    }
+
+    Ok(kept)
+}
+
+/// Splits a text-type Meta event or a SysEx event into the bytes ahead of its length and its payload
+///
+/// A SysEx event keeps its closing F7 out of the payload, so that cutting the payload short leaves it terminated.
+/// Returns None for every other kind of event.
+fn split_payload(event: &Event) -> Option<(Vec<u8>, Vec<u8>, Vec<u8>)> {
+    let header = match event.data[0] {
+        0xFF if event.data.len() > 2 && (0x01..=0x0F).contains(&event.data[1]) => 2,
+        0xF0 | 0xF7 => 1,
+        _ => return None,
+    };
+
+    let length_bytes = event.data[header..].iter().position(|byte| byte & 0x80 == 0)? + 1;
+    let mut payload = event.data[header + length_bytes..].to_vec();
+    let terminator = if event.data[0] == 0xF0 && payload.last() == Some(&0xF7) { payload.split_off(payload.len() - 1) } else { Vec::new() };
+
+    Some((event.data[..header].to_vec(), payload, terminator))
+}
+
+/// Returns a copy of the <DeltaTime, Event> pair with its delta time and channel event data set to zero
+fn simplify(pair: &(DeltaTime, Event)) -> (DeltaTime, Event) {
+    let mut event = pair.1.clone();
+    if (0x80..0xF0).contains(&event.data[0]) {
+        event.data[1..].iter_mut().for_each(|byte| *byte = 0);
+    }
+
+    (DeltaTime::zero(), event)
+}
+
+/// Returns the tracks with the data of one of them replaced
+fn with_track(tracks: &[MTrk], index: usize, data: Vec<(DeltaTime, Event)>) -> Vec<MTrk> {
+    let mut tracks = tracks.to_vec();
+    tracks[index] = MTrk::new(data);
+    tracks
+}
+
+/// Runs the oracle on candidate files, keeping count of the runs
+struct Reducer<'a, 'b> {
+    oracle: &'a mut Oracle<'b>,
+    runs: u32,
+}
+
+impl Reducer<'_, '_> {
+    /// Asks the oracle about the bytes of a candidate
+    fn test_bytes(&mut self, bytes: &[u8]) -> Result<bool, String> {
+        self.runs += 1;
+        (self.oracle)(bytes)
+    }
+
+    /// Encodes a candidate with ntracks and every chunklen matching its tracks, and asks the oracle about it
+    fn test(&mut self, header: &MThd, tracks: &[MTrk]) -> Result<bool, String> {
+        self.test_bytes(&encode(header, tracks))
+    }
+
+    /// Drops as many whole chunks of a raw file as possible, then as many bytes from the body of each chunk
+    ///
+    /// Every pass is repeated until a whole round of them leaves the file unchanged.
+    fn drop_bytes(&mut self, bytes: &[u8]) -> Result<Vec<u8>, String> {
+        let mut chunks = raw_chunks(bytes);
+
+        loop {
+            let before = encode_raw(&chunks);
+
+            let kept = ddmin(chunks.len(), &mut |kept| self.test_bytes(&encode_raw(&pick(&chunks, kept))))?;
+            chunks = pick(&chunks, &kept);
+
+            for index in 0..chunks.len() {
+                let body = chunks[index].body.clone();
+                let with_body = |body: Vec<u8>| {
+                    let mut chunks = chunks.clone();
+                    chunks[index].body = body;
+                    chunks
+                };
+                let kept = ddmin(body.len(), &mut |kept| self.test_bytes(&encode_raw(&with_body(pick(&body, kept)))))?;
+                chunks[index].body = pick(&body, &kept);
+            }
+
+            let after = encode_raw(&chunks);
+            if after == before {
+                return Ok(after);
+            }
+        }
+    }
+
+    /// Drops as many whole tracks as possible
+    fn drop_tracks(&mut self, header: &MThd, tracks: &mut Vec<MTrk>) -> Result<(), String> {
+        let kept = ddmin(tracks.len(), &mut |kept| self.test(header, &pick(tracks, kept)))?;
+        *tracks = pick(tracks, &kept);
+        Ok(())
+    }
+
+    /// Drops as many events from each track as possible
+    fn drop_events(&mut self, header: &MThd, tracks: &mut [MTrk]) -> Result<(), String> {
+        for index in 0..tracks.len() {
+            let data = tracks[index].data.clone();
+            let kept = ddmin(data.len(), &mut |kept| self.test(header, &with_track(tracks, index, pick(&data, kept))))?;
+            tracks[index] = MTrk::new(pick(&data, &kept));
+        }
+        Ok(())
+    }
+
+    /// Cuts the payload of each text-type Meta event and SysEx event as short as possible
+    fn shorten_payloads(&mut self, header: &MThd, tracks: &mut [MTrk]) -> Result<(), String> {
+        for index in 0..tracks.len() {
+            for position in 0..tracks[index].data.len() {
+                let (prefix, payload, terminator) = match split_payload(&tracks[index].data[position].1) {
+                    Some(parts) => parts,
+                    None => continue,
+                };
+
+                let shortened = |length: usize| {
+                    let mut data = tracks[index].data.clone();
+                    let mut event = prefix.clone();
+                    event.extend(encode_vlq((length + terminator.len()) as u32));
+                    event.extend_from_slice(&payload[..length]);
+                    event.extend_from_slice(&terminator);
+                    data[position].1 = Event { data: event };
+                    data
+                };
+
+                // take off the biggest piece that keeps the failure, halving the piece whenever it doesn't
+                let mut length = payload.len();
+                let mut step = length;
+                while step > 0 {
+                    if step <= length && self.test(header, &with_track(tracks, index, shortened(length - step)))? {
+                        length -= step;
+                    }
+                    else {
+                        step /= 2;
+                    }
+                }
+
+                tracks[index] = MTrk::new(shortened(length));
+            }
+        }
+        Ok(())
+    }
+
+    /// Sets as many delta times and channel event data bytes to zero as possible
+    fn simplify_values(&mut self, header: &MThd, tracks: &mut [MTrk]) -> Result<(), String> {
+        for index in 0..tracks.len() {
+            let data = tracks[index].data.clone();
+            let simplified: Vec<(DeltaTime, Event)> = data.iter().map(simplify).collect();
+            let changeable: Vec<usize> = (0..data.len())
+                .filter(|&i| data[i].0.data != simplified[i].0.data || data[i].1.data != simplified[i].1.data)
+                .collect();
+
+            // the indices ddmin keeps are the events left as they were
+            let candidate = |kept: &[usize]| {
+                let mut candidate = simplified.clone();
+                for &i in kept {
+                    candidate[changeable[i]] = data[changeable[i]].clone();
+                }
+                candidate
+            };
+
+            let kept = ddmin(changeable.len(), &mut |kept| self.test(header, &with_track(tracks, index, candidate(kept))))?;
+            tracks[index] = MTrk::new(candidate(&kept));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+/// A chunk as it appears in a file, kept as raw bytes so that files which don't parse can still be shrunk
+struct RawChunk {
+    head: Vec<u8>, // identifier and length, or as much of them as the file holds
+    excess: usize, // how far the length runs past the end of the file, which stays the same as the body shrinks
+    body: Vec<u8>,
+}
+
+/// Splits a file into chunks by their length fields, without checking anything else
+fn raw_chunks(bytes: &[u8]) -> Vec<RawChunk> {
+    let mut chunks = Vec::new();
+    let mut pos = 0;
+
+    while pos < bytes.len() {
+        let head = bytes[pos..(pos + 8).min(bytes.len())].to_vec();
+        let length = if head.len() == 8 { u32::from_be_bytes([head[4], head[5], head[6], head[7]]) as usize } else { 0 };
+        let start = pos + head.len();
+        let end = start.saturating_add(length).min(bytes.len());
+
+        chunks.push(RawChunk { excess: length - (end - start), body: bytes[start..end].to_vec(), head });
+        pos = end;
+    }
+    chunks
+}
+
+/// Encodes raw chunks, with each length field changed by as much as its body was
+fn encode_raw(chunks: &[RawChunk]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    for chunk in chunks {
+        bytes.extend_from_slice(&chunk.head);
+        if chunk.head.len() == 8 {
+            let length = (chunk.body.len() + chunk.excess) as u32;
+            let end = bytes.len();
+            bytes[end - 4..].copy_from_slice(&length.to_be_bytes());
+        }
+        bytes.extend_from_slice(&chunk.body);
+    }
+    bytes
+}
+
+/// Encodes a file with ntracks matching the number of tracks
+fn encode(header: &MThd, tracks: &[MTrk]) -> Vec<u8> {
+    let mut header = header.clone();
+    header.ntracks = tracks.len() as u16;
+    encode_midi_file(&header, tracks)
+}
+
+/// Shrinks a file for as long as the oracle says it still fails, returning the bytes of the smallest failing file
+/// along with the number of times the oracle was run
+///
+/// A file that parses and still fails once it is encoded again, with ntracks and every chunklen matching its
+/// contents, is shrunk at the MIDI level. Any other file, such as one that only fails because of a broken chunk,
+/// padded delta times or running status, is shrunk byte by byte, with each chunklen following the size of its chunk
+/// so that one that was wrong stays wrong by the same amount.
+pub fn reduce(bytes: &[u8], oracle: &mut Oracle) -> Result<(Vec<u8>, u32), String> {
+    let mut reducer = Reducer { oracle, runs: 0 };
+    if !reducer.test_bytes(bytes)? {
+        return Err(String::from("the file doesn't fail to begin with"));
+    }
+
+    if let Ok((header, tracks)) = reader::parse_midi_file(bytes) {
+        let tracks: Vec<MTrk> = tracks.into_iter().map(|track| MTrk::new(track.data)).collect();
+        if reducer.test(&header, &tracks)? {
+            return reduce_tracks(reducer, header, tracks);
+        }
+        eprintln!("the file doesn't fail once it is encoded again, so it is reduced byte by byte");
+    }
+    else {
+        eprintln!("the file doesn't parse, so it is reduced byte by byte");
+    }
+
+    let reduced = reducer.drop_bytes(bytes)?;
+    Ok((reduced, reducer.runs))
+}
+
+/// Shrinks a parsed file at the MIDI level, repeating every pass until a whole round of them leaves it unchanged
+fn reduce_tracks(mut reducer: Reducer, header: MThd, mut tracks: Vec<MTrk>) -> Result<(Vec<u8>, u32), String> {
+    loop {
+        let before = encode(&header, &tracks);
+
+        reducer.drop_tracks(&header, &mut tracks)?;
+        reducer.drop_events(&header, &mut tracks)?;
+        reducer.shorten_payloads(&header, &mut tracks)?;
+        reducer.simplify_values(&header, &mut tracks)?;
+
+        let after = encode(&header, &tracks);
+        if after == before {
+            return Ok((after, reducer.runs));
+        }
+    }
+}
+
+/// A local command run on each candidate file, failing in the way being reduced
+struct ProcessOracle {
+    command: Vec<String>, // program and arguments, with {} standing for the candidate file
+    candidate: String, // where candidate files are written
+    exit_code: Option<i32>,
+    stderr: Option<String>,
+    timeout: Duration,
+}
+
+impl ProcessOracle {
+    /// Writes the candidate and runs the command on it, returning true if it fails the same way
+    ///
+    /// A run that outlasts the timeout is killed and counted as not failing, since a hang is a different failure.
+    fn fails(&self, bytes: &[u8]) -> Result<bool, String> {
+        std::fs::write(&self.candidate, bytes).map_err(|error| format!("could not write {}: {}", self.candidate, error))?;
+
+        let run = match process::run_with_timeout(&process::command_for(&self.command, &self.candidate), self.timeout)? {
+            Some(run) => run,
+            None => return Ok(false),
+        };
+        let stderr = String::from_utf8_lossy(&run.stderr);
+
+        let exit_matches = match self.exit_code {
+            Some(code) => run.status.code() == Some(code),
+            None => self.stderr.is_some() || !run.status.success(),
+        };
+        let stderr_matches = self.stderr.as_ref().is_none_or(|pattern| stderr.contains(pattern.as_str()));
+
+        Ok(exit_matches && stderr_matches)
+    }
+}
+
+pub const USAGE: &str = "\
+usage: midi_generator reduce [options] <file> <command> [arguments]...
+
+<command> is run on smaller and smaller versions of <file>, named by a {} argument or else added
+as the last argument, and each version that still makes it fail is kept; files that don't parse, or
+that only fail as they are encoded, are reduced byte by byte
+
+options:
+  -o, --output <file>    write the reduced file to <file> (default <file>.reduced.mid)
+  --exit-code <n>        the failure is <command> exiting with code <n> (default any unsuccessful exit)
+  --stderr <text>        the failure is <command> printing <text> to stderr
+  --timeout <seconds>    stop a run of <command> after <seconds>, counting it as not failing (default 10)";
+
+/// Runs the reduce command, shrinking a MIDI file that makes a local command fail
+pub fn run(args: Vec<String>) -> Result<(), String> {
+    let mut output = None;
+    let mut exit_code = None;
+    let mut stderr = None;
+    let mut timeout = 10.0;
+    let mut path = None;
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" | "--output" => output = Some(args.next().ok_or(format!("{} needs a file name", arg))?),
+            "--exit-code" => {
+                let code = args.next().ok_or(format!("{} needs a number", arg))?;
+                exit_code = Some(code.parse().map_err(|_| format!("invalid exit code: {}", code))?);
+            },
+            "--stderr" => stderr = Some(args.next().ok_or(format!("{} needs some text", arg))?),
+            "--timeout" => {
+                let seconds = args.next().ok_or(format!("{} needs a number of seconds", arg))?;
+                timeout = seconds.parse().ok().filter(|seconds: &f64| *seconds > 0.0).ok_or(format!("invalid timeout: {}", seconds))?;
+            },
+            _ if arg.starts_with('-') => return Err(format!("unknown argument: {}\n{}", arg, USAGE)),
+            _ => {
+                path = Some(arg);
+                break;
+            },
+        }
+    }
+
+    let path = path.ok_or(format!("no file given\n{}", USAGE))?;
+    let command: Vec<String> = args.collect();
+    if command.is_empty() {
+        return Err(format!("no command given\n{}", USAGE));
+    }
+    let output = output.unwrap_or(format!("{}.reduced.mid", path));
+
+    let original = std::fs::read(&path).map_err(|error| format!("could not read {}: {}", path, error))?;
+
+    let oracle = ProcessOracle {
+        command,
+        candidate: format!("{}.candidate.mid", output),
+        exit_code,
+        stderr,
+        timeout: Duration::from_secs_f64(timeout),
+    };
+
+    let result = reduce(&original, &mut |bytes| oracle.fails(bytes));
+    let _ = std::fs::remove_file(&oracle.candidate);
+    let (reduced, runs) = result?;
+
+    std::fs::write(&output, &reduced).map_err(|error| format!("could not write {}: {}", output, error))?;
+    println!("reduced {} bytes to {} bytes in {} runs, written to {}", original.len(), reduced.len(), runs, output);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::corrupt::Fault;
+    use crate::{generate_file, generate_midi_file, GeneratorOptions};
+
+    #[test]
+    fn ddmin_finds_the_needed_indices() {
+        let mut runs = 0;
+        let kept = ddmin(100, &mut |kept| {
+            runs += 1;
+            Ok(kept.contains(&17) && kept.contains(&83))
+        }).unwrap();
+
+        assert_eq!(kept, vec![17, 83]);
+        assert!(runs < 100);
+    }
+
+    #[test]
+    fn reduced_file_keeps_the_failure_and_stays_consistent() {
+        let rng = &mut rand::thread_rng();
+        let (header, mut tracks) = generate_midi_file(rng, &GeneratorOptions::default());
+        let last = tracks.len() - 1;
+        let mut data = tracks[last].data.clone();
+        data.insert(data.len() / 2, (DeltaTime::from_ticks(500), Event::new_text_event(0x01, b"CRASH and then some")));
+        tracks[last] = MTrk::new(data);
+
+        let mut oracle = |bytes: &[u8]| Ok(bytes.windows(5).any(|window| window == b"CRASH") && reader::parse_midi_file(bytes).is_ok());
+        let (bytes, _) = reduce(&encode(&header, &tracks), &mut oracle).unwrap();
+
+        let (header, tracks) = reader::parse_midi_file(&bytes).unwrap();
+        assert_eq!(header.ntracks, 1);
+        assert_eq!(tracks.len(), 1);
+        assert_eq!(tracks[0].data.len(), 1);
+        assert_eq!(tracks[0].data[0].0.ticks(), 0);
+        assert_eq!(tracks[0].data[0].1.data, [0xFF, 0x01, 5, b'C', b'R', b'A', b'S', b'H']);
+    }
+
+    #[test]
+    fn corrupt_files_are_reduced_byte_by_byte() {
+        let options = GeneratorOptions { faults: vec![Fault::LongVlq], ..Default::default() };
+        let original = generate_file(7, &options).bytes;
+        let fails = |bytes: &[u8]| reader::parse_midi_file(bytes).is_err_and(|error| error.contains("longer than 4 bytes"));
+        assert!(fails(&original));
+
+        let mut oracle = |bytes: &[u8]| Ok(fails(bytes));
+        let (bytes, runs) = reduce(&original, &mut oracle).unwrap();
+
+        assert!(fails(&bytes));
+        assert!(bytes.len() < 40, "{:02x?}", bytes);
+        assert!(runs > 1);
+    }
+
+    #[test]
+    fn raw_chunks_keep_lengths_that_are_wrong() {
+        let bytes = [b"MThd".as_slice(), &[0, 0, 0, 6, 0, 0, 0, 1, 0, 96], b"MTrk", &[0, 0, 0, 9, 0, 0xFF, 0x2F, 0]].concat();
+        let mut chunks = raw_chunks(&bytes);
+        assert_eq!(encode_raw(&chunks), bytes);
+        assert_eq!((chunks.len(), chunks[1].excess), (2, 5));
+
+        chunks[1].body.remove(0);
+        assert_eq!(&encode_raw(&chunks)[14..], [b"MTrk".as_slice(), &[0, 0, 0, 8, 0xFF, 0x2F, 0]].concat());
+    }
+
+    #[test]
+    fn sysex_payloads_stay_terminated() {
+        let event = Event { data: vec![0xF0, 4, 0x43, 0x12, 0x00, 0xF7] };
+        let (prefix, payload, terminator) = split_payload(&event).unwrap();
+
+        assert_eq!((prefix, payload, terminator), (vec![0xF0], vec![0x43, 0x12, 0x00], vec![0xF7]));
+        assert!(split_payload(&Event::new_tempo(500_000)).is_none());
+    }
+}
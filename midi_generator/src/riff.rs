@@ -0,0 +1,130 @@
+// RIFF
+// Reads and writes the chunks of RIFF files, and wraps MIDI files in the RIFF RMID container
+
+use std::convert::TryInto;
+
+use crate::MTrk;
+
+/// A chunk of a RIFF file, as its identifier and data
+pub type Chunk<'a> = ([u8; 4], &'a [u8]);
+
+/// Splits the data of a RIFF chunk into its subchunks
+///
+/// Subchunks are padded to an even length. A subchunk running past the end of the data is an error.
+pub fn subchunks(data: &[u8]) -> Result<Vec<Chunk<'_>>, String> {
+    let mut chunks = Vec::new();
+    let mut pos = 0;
+
+    while pos + 8 <= data.len() {
+        let identifier: [u8; 4] = data[pos..pos + 4].try_into().unwrap();
+        let length = u32::from_le_bytes(data[pos + 4..pos + 8].try_into().unwrap()) as usize;
+        let body = data.get(pos + 8..pos + 8 + length)
+            .ok_or(format!("the {} chunk runs past the end of its list", String::from_utf8_lossy(&identifier)))?;
+        chunks.push((identifier, body));
+        pos += 8 + length + length % 2;
+    }
+
+    Ok(chunks)
+}
+
+/// Returns the bytes of a chunk, padded to an even length
+pub fn chunk(identifier: &[u8; 4], data: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(9 + data.len());
+    bytes.extend_from_slice(identifier);
+    bytes.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(data);
+    if data.len() % 2 == 1 {
+        bytes.push(0);
+    }
+    bytes
+}
+
+/// Collects the INFO fields of an RMID file: the sequence name of the first track as its title, the copyright
+/// notice, and the software that wrote it
+///
+/// Text stops at the first zero byte, which would end the field early for readers anyway.
+pub fn rmid_info(tracks: &[MTrk]) -> Vec<([u8; 4], Vec<u8>)> {
+    let mut info = Vec::new();
+    let first_text = |meta_type: u8| {
+        let track = tracks.first()?;
+        let event = track.data.iter().map(|(_, event)| event).find(|event| event.data.len() > 2 && event.data[..2] == [0xFF, meta_type])?;
+        let text = crate::midicsv::split_length(event, 2)?;
+        Some(text.iter().copied().take_while(|byte| *byte != 0).collect::<Vec<u8>>())
+    };
+
+    if let Some(title) = first_text(0x03).filter(|title| !title.is_empty()) {
+        info.push((*b"INAM", title));
+    }
+    if let Some(copyright) = first_text(0x02).filter(|copyright| !copyright.is_empty()) {
+        info.push((*b"ICOP", copyright));
+    }
+    info.push((*b"ISFT", concat!("midi_generator ", env!("CARGO_PKG_VERSION")).as_bytes().to_vec()));
+    info
+}
+
+/// Wraps the bytes of a Standard MIDI File in a RIFF RMID container, with an INFO list after the data if there
+/// are any fields
+pub fn wrap_rmid(smf: &[u8], info: &[([u8; 4], Vec<u8>)]) -> Vec<u8> {
+    let mut body = b"RMID".to_vec();
+    body.extend(chunk(b"data", smf));
+
+    if !info.is_empty() {
+        let mut list = b"INFO".to_vec();
+        for (identifier, text) in info {
+            let mut text = text.clone();
+            text.push(0); // ZSTR
+            list.extend(chunk(identifier, &text));
+        }
+        body.extend(chunk(b"LIST", &list));
+    }
+
+    chunk(b"RIFF", &body)
+}
+
+/// Returns the Standard MIDI File held in the data chunk of an RMID file, or the bytes as they are if they
+/// aren't an RMID file
+pub fn unwrap_rmid(bytes: &[u8]) -> Result<&[u8], String> {
+    if bytes.len() < 12 || &bytes[..4] != b"RIFF" || &bytes[8..12] != b"RMID" {
+        return Ok(bytes);
+    }
+
+    let length = (u32::from_le_bytes(bytes[4..8].try_into().unwrap()) as usize).clamp(4, bytes.len() - 8);
+    let chunks = subchunks(&bytes[12..8 + length])?;
+    chunks.iter().find(|(identifier, _)| identifier == b"data")
+        .map(|(_, data)| *data)
+        .ok_or(String::from("the RMID file has no data chunk"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{encode_midi_file, to_delta_times, Event, MThd};
+
+    #[test]
+    fn rmid_files_wrap_and_unwrap() {
+        let header = MThd { identifier: *b"MThd", chunklen: 6, format: 0, ntracks: 1, tickdiv: 96 };
+        let tracks = vec![MTrk::new(to_delta_times(vec![
+            (0, Event::new_text_event(0x02, b"(c) 2024")),
+            (0, Event::new_text_event(0x03, b"Odd\0name")),
+            (0, Event::new_note_on(0, 60, 100)),
+        ]))];
+        let smf = encode_midi_file(&header, &tracks);
+
+        let info = rmid_info(&tracks);
+        assert_eq!(info[..2], [(*b"INAM", b"Odd".to_vec()), (*b"ICOP", b"(c) 2024".to_vec())]);
+        assert_eq!(info[2].0, *b"ISFT");
+
+        let rmid = wrap_rmid(&smf, &info);
+        assert_eq!(&rmid[..4], b"RIFF");
+        assert_eq!(u32::from_le_bytes(rmid[4..8].try_into().unwrap()) as usize, rmid.len() - 8);
+        assert_eq!(&rmid[8..16], b"RMIDdata");
+        assert!(rmid.windows(12).any(|window| window == b"INAM\x04\0\0\0Odd\0"));
+        assert!(rmid.windows(18).any(|window| window == b"ICOP\x09\0\0\0(c) 2024\0\0")); // padded to an even length
+
+        assert_eq!(unwrap_rmid(&rmid).unwrap(), &smf[..]);
+        assert_eq!(unwrap_rmid(&smf).unwrap(), &smf[..]);
+        assert!(unwrap_rmid(&wrap_rmid(&smf, &[])[..40]).is_err());
+        let (read_header, read_tracks) = crate::reader::parse_midi_file(&rmid).unwrap();
+        assert_eq!(encode_midi_file(&read_header, &read_tracks), smf);
+    }
+}
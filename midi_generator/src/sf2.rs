@@ -0,0 +1,733 @@
+// SoundFont
+// Reads SoundFont 2 files and plays their sampled instruments for the synthesizer
+
+use std::convert::TryInto;
+
+use crate::riff::{subchunks, Chunk};
+use crate::synth::Channel;
+
+// Generators, by their operator number
+const START_OFFSET: usize = 0;
+const END_OFFSET: usize = 1;
+const LOOP_START_OFFSET: usize = 2;
+const LOOP_END_OFFSET: usize = 3;
+const START_COARSE_OFFSET: usize = 4;
+const END_COARSE_OFFSET: usize = 12;
+const PAN: usize = 17;
+const DELAY_VOL_ENV: usize = 33;
+const ATTACK_VOL_ENV: usize = 34;
+const HOLD_VOL_ENV: usize = 35;
+const DECAY_VOL_ENV: usize = 36;
+const SUSTAIN_VOL_ENV: usize = 37;
+const RELEASE_VOL_ENV: usize = 38;
+const INSTRUMENT: u16 = 41;
+const KEY_RANGE: u16 = 43;
+const VEL_RANGE: u16 = 44;
+const LOOP_START_COARSE_OFFSET: usize = 45;
+const KEYNUM: usize = 46;
+const VELOCITY: usize = 47;
+const INITIAL_ATTENUATION: usize = 48;
+const LOOP_END_COARSE_OFFSET: usize = 50;
+const COARSE_TUNE: usize = 51;
+const FINE_TUNE: usize = 52;
+const SAMPLE_ID: u16 = 53;
+const SAMPLE_MODES: usize = 54;
+const SCALE_TUNING: usize = 56;
+const EXCLUSIVE_CLASS: usize = 57;
+const OVERRIDING_ROOT_KEY: usize = 58;
+const GENERATORS: usize = 61;
+
+/// Generators that only apply at instrument level, and are ignored in presets
+const INSTRUMENT_ONLY: [usize; 13] = [
+    START_OFFSET, END_OFFSET, LOOP_START_OFFSET, LOOP_END_OFFSET, START_COARSE_OFFSET, END_COARSE_OFFSET,
+    LOOP_START_COARSE_OFFSET, KEYNUM, VELOCITY, LOOP_END_COARSE_OFFSET, SAMPLE_MODES, EXCLUSIVE_CLASS, OVERRIDING_ROOT_KEY,
+];
+
+/// Values of the generators a zone doesn't set
+fn default_generators() -> [i32; GENERATORS] {
+    let mut generators = [0; GENERATORS];
+    generators[8] = 13500; // initial filter cutoff
+    for timecents in [21, 23, 25, 26, 27, 28, 30, 31, 32, DELAY_VOL_ENV, ATTACK_VOL_ENV, HOLD_VOL_ENV, DECAY_VOL_ENV, RELEASE_VOL_ENV] {
+        generators[timecents] = -12000;
+    }
+    generators[KEYNUM] = -1;
+    generators[VELOCITY] = -1;
+    generators[SCALE_TUNING] = 100;
+    generators[OVERRIDING_ROOT_KEY] = -1;
+    generators
+}
+
+/// A modulator, which turns a MIDI controller into a change of a generator
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Modulator {
+    source: u16,
+    destination: u16,
+    amount: i16,
+    amount_source: u16,
+    transform: u16,
+}
+
+impl Modulator {
+    /// Whether two modulators are the same, so that one overrides the other
+    fn same_as(&self, other: &Modulator) -> bool {
+        self.source == other.source && self.destination == other.destination && self.amount_source == other.amount_source
+    }
+
+    /// Returns how much the modulator adds to its destination for a note on a channel
+    fn value(&self, channel: &Channel, key: u8, velocity: u8) -> f64 {
+        let value = self.amount as f64 * source_value(self.source, channel, key, velocity)
+            * if self.amount_source == 0 { 1.0 } else { source_value(self.amount_source, channel, key, velocity) };
+        if self.transform == 2 { value.abs() } else { value }
+    }
+}
+
+/// The modulators every zone starts with, from the SoundFont 2.01 spec
+///
+/// Those for the filter, vibrato, reverb and chorus are left out, as the synthesizer has none of them.
+const DEFAULT_MODULATORS: [Modulator; 5] = [
+    Modulator { source: 0x0502, destination: INITIAL_ATTENUATION as u16, amount: 960, amount_source: 0, transform: 0 }, // velocity
+    Modulator { source: 0x0587, destination: INITIAL_ATTENUATION as u16, amount: 960, amount_source: 0, transform: 0 }, // CC7 volume
+    Modulator { source: 0x058B, destination: INITIAL_ATTENUATION as u16, amount: 960, amount_source: 0, transform: 0 }, // CC11 expression
+    Modulator { source: 0x028A, destination: PAN as u16, amount: 1000, amount_source: 0, transform: 0 }, // CC10 pan
+    Modulator { source: 0x020E, destination: FINE_TUNE as u16, amount: 12700, amount_source: 0x0010, transform: 0 }, // pitch wheel
+];
+
+/// Returns the value of a modulator source, mapped through its curve to between 0 and 1, or -1 and 1 if bipolar
+fn source_value(source: u16, channel: &Channel, key: u8, velocity: u8) -> f64 {
+    let index = (source & 0x7F) as usize;
+    let x = if source & 0x80 != 0 {
+        channel.controllers[index] as f64 / 128.0
+    }
+    else {
+        match index {
+            0 => return 0.0, // no controller
+            2 => velocity as f64 / 128.0,
+            3 => key as f64 / 128.0,
+            13 => channel.pressure as f64 / 128.0,
+            14 => channel.wheel as f64 / 16384.0,
+            16 => channel.bend_range / 127.0, // pitch wheel sensitivity, in semitones
+            _ => return 0.0, // polyphonic pressure and links aren't followed
+        }
+    };
+
+    let x = if source & 0x100 != 0 { 1.0 - x } else { x };
+    let curve = |x: f64| match source >> 10 {
+        1 => if x >= 1.0 { 1.0 } else { (-40.0 / 96.0 * (1.0 - x).log10()).min(1.0) }, // concave
+        2 => if x <= 0.0 { 0.0 } else { 1.0 - (-40.0 / 96.0 * x.log10()).min(1.0) }, // convex
+        3 => if x >= 0.5 { 1.0 } else { 0.0 }, // switch
+        _ => x, // linear
+    };
+
+    if source & 0x200 != 0 {
+        if x >= 0.5 { curve(2.0 * x - 1.0) } else { -curve(1.0 - 2.0 * x) }
+    }
+    else {
+        curve(x)
+    }
+}
+
+/// A zone of a preset or instrument, applying to a range of keys and velocities
+#[derive(Debug, Default)]
+struct Zone {
+    generators: Vec<(u16, i16)>,
+    modulators: Vec<Modulator>,
+    keys: (u8, u8),
+    velocities: (u8, u8),
+    link: Option<usize>, // the instrument of a preset zone or the sample of an instrument zone
+}
+
+impl Zone {
+    fn applies_to(&self, key: u8, velocity: u8) -> bool {
+        (self.keys.0..=self.keys.1).contains(&key) && (self.velocities.0..=self.velocities.1).contains(&velocity)
+    }
+
+    fn apply(&self, generators: &mut [i32; GENERATORS]) {
+        for &(operator, amount) in &self.generators {
+            if let Some(generator) = generators.get_mut(operator as usize) {
+                *generator = amount as i32;
+            }
+        }
+    }
+}
+
+/// A preset or instrument: an optional global zone holding defaults for the others, and the zones themselves
+#[derive(Debug, Default)]
+struct ZoneList {
+    global: Option<Zone>,
+    zones: Vec<Zone>,
+}
+
+/// Puts the modulators of a zone over those before it, overriding any that are the same
+fn merge_modulators(modulators: &mut Vec<Modulator>, zone: Option<&Zone>) {
+    for modulator in zone.map_or(&[][..], |zone| &zone.modulators) {
+        match modulators.iter_mut().find(|existing| existing.same_as(modulator)) {
+            Some(existing) => *existing = *modulator,
+            None => modulators.push(*modulator),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct SampleHeader {
+    start: u32,
+    end: u32,
+    loop_start: u32,
+    loop_end: u32,
+    rate: u32,
+    root: u8,
+    correction: i8, // cents
+}
+
+#[derive(Debug)]
+struct Preset {
+    bank: u16,
+    program: u16,
+    zones: ZoneList,
+}
+
+/// A parsed SoundFont 2 file
+#[derive(Debug)]
+pub struct SoundFont {
+    presets: Vec<Preset>,
+    instruments: Vec<ZoneList>,
+    samples: Vec<SampleHeader>,
+    data: Vec<i16>, // every sample, one after another
+}
+
+/// Returns the subchunks of the LIST chunk of the given type
+fn list<'a>(chunks: &[Chunk<'a>], list_type: &[u8; 4]) -> Result<Vec<Chunk<'a>>, String> {
+    let (_, body) = chunks.iter().find(|(identifier, body)| identifier == b"LIST" && body.starts_with(list_type))
+        .ok_or(format!("the file has no {} list", String::from_utf8_lossy(list_type)))?;
+    subchunks(&body[4..])
+}
+
+/// Returns the records of a subchunk of the pdta list, checking that they fill it exactly
+fn records<'a>(chunks: &[Chunk<'a>], identifier: &[u8; 4], size: usize) -> Result<Vec<&'a [u8]>, String> {
+    let name = String::from_utf8_lossy(identifier);
+    let (_, body) = chunks.iter().find(|(chunk, _)| chunk == identifier).ok_or(format!("the file has no {} chunk", name))?;
+    if body.len() % size != 0 || body.len() < size {
+        return Err(format!("the {} chunk isn't a whole number of {} byte records", name, size));
+    }
+    Ok(body.chunks(size).collect())
+}
+
+fn u16_at(record: &[u8], pos: usize) -> u16 {
+    u16::from_le_bytes([record[pos], record[pos + 1]])
+}
+
+fn u32_at(record: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes(record[pos..pos + 4].try_into().unwrap())
+}
+
+/// Reads the zones of the presets or instruments in the hydra
+///
+/// `bags` gives the index of the first bag of each preset or instrument, ending with the terminal record.
+/// The first zone is global if it doesn't end with the generator linking it to an instrument or sample.
+fn zone_lists(bags: &[usize], bag_records: &[&[u8]], generators: &[&[u8]], modulators: &[&[u8]], link: u16) -> Result<Vec<ZoneList>, String> {
+    let index = |record: &[u8], pos: usize, limit: usize, what: &str| {
+        let index = u16_at(record, pos) as usize;
+        if index > limit { Err(format!("a zone points past the end of the {} list", what)) } else { Ok(index) }
+    };
+
+    let mut lists = Vec::new();
+    for pair in bags.windows(2) {
+        if pair[0] > pair[1] || pair[1] >= bag_records.len() {
+            return Err(String::from("the zones of a preset or instrument are out of order"));
+        }
+
+        let mut list = ZoneList::default();
+        for bag in pair[0]..pair[1] {
+            let (first_generator, end_generator) = (index(bag_records[bag], 0, generators.len(), "generator")?, index(bag_records[bag + 1], 0, generators.len(), "generator")?);
+            let (first_modulator, end_modulator) = (index(bag_records[bag], 2, modulators.len(), "modulator")?, index(bag_records[bag + 1], 2, modulators.len(), "modulator")?);
+
+            let mut zone = Zone { keys: (0, 127), velocities: (0, 127), ..Default::default() };
+            for record in generators.get(first_generator..end_generator).unwrap_or(&[]) {
+                let (operator, amount) = (u16_at(record, 0), [record[2], record[3]]);
+                match operator {
+                    KEY_RANGE => zone.keys = (amount[0], amount[1]),
+                    VEL_RANGE => zone.velocities = (amount[0], amount[1]),
+                    _ if operator == link => {
+                        zone.link = Some(u16::from_le_bytes(amount) as usize);
+                        break; // generators after the link are ignored
+                    },
+                    _ => zone.generators.push((operator, i16::from_le_bytes(amount))),
+                }
+            }
+            for record in modulators.get(first_modulator..end_modulator).unwrap_or(&[]) {
+                zone.modulators.push(Modulator {
+                    source: u16_at(record, 0),
+                    destination: u16_at(record, 2),
+                    amount: u16_at(record, 4) as i16,
+                    amount_source: u16_at(record, 6),
+                    transform: u16_at(record, 8),
+                });
+            }
+
+            if zone.link.is_some() {
+                list.zones.push(zone);
+            }
+            else if bag == pair[0] {
+                list.global = Some(zone);
+            }
+        }
+        lists.push(list);
+    }
+
+    Ok(lists)
+}
+
+/// Parses the bytes of a SoundFont 2 file
+pub fn parse_soundfont(bytes: &[u8]) -> Result<SoundFont, String> {
+    if bytes.len() < 12 || &bytes[..4] != b"RIFF" || &bytes[8..12] != b"sfbk" {
+        return Err(String::from("not a SoundFont 2 file"));
+    }
+    let length = (u32_at(bytes, 4) as usize).clamp(4, bytes.len() - 8);
+    let chunks = subchunks(&bytes[12..8 + length])?;
+
+    let sdta = list(&chunks, b"sdta")?;
+    let data: Vec<i16> = sdta.iter().find(|(identifier, _)| identifier == b"smpl")
+        .map_or(Vec::new(), |(_, body)| body.chunks_exact(2).map(|pair| i16::from_le_bytes([pair[0], pair[1]])).collect());
+
+    let pdta = list(&chunks, b"pdta")?;
+    let phdr = records(&pdta, b"phdr", 38)?;
+    let pbag = records(&pdta, b"pbag", 4)?;
+    let pmod = records(&pdta, b"pmod", 10)?;
+    let pgen = records(&pdta, b"pgen", 4)?;
+    let inst = records(&pdta, b"inst", 22)?;
+    let ibag = records(&pdta, b"ibag", 4)?;
+    let imod = records(&pdta, b"imod", 10)?;
+    let igen = records(&pdta, b"igen", 4)?;
+    let shdr = records(&pdta, b"shdr", 46)?;
+
+    let preset_bags: Vec<usize> = phdr.iter().map(|record| u16_at(record, 24) as usize).collect();
+    let preset_zones = zone_lists(&preset_bags, &pbag, &pgen, &pmod, INSTRUMENT)?;
+    let presets = phdr.iter().zip(preset_zones)
+        .map(|(record, zones)| Preset { program: u16_at(record, 20), bank: u16_at(record, 22), zones })
+        .collect();
+
+    let instrument_bags: Vec<usize> = inst.iter().map(|record| u16_at(record, 20) as usize).collect();
+    let instruments = zone_lists(&instrument_bags, &ibag, &igen, &imod, SAMPLE_ID)?;
+
+    let samples = shdr[..shdr.len() - 1].iter().map(|record| SampleHeader {
+        start: u32_at(record, 20),
+        end: u32_at(record, 24),
+        loop_start: u32_at(record, 28),
+        loop_end: u32_at(record, 32),
+        rate: u32_at(record, 36),
+        root: record[40],
+        correction: record[41] as i8,
+    }).collect();
+
+    Ok(SoundFont { presets, instruments, samples, data })
+}
+
+/// Reads and parses the SoundFont at `path`
+pub fn read_soundfont(path: &str) -> Result<SoundFont, String> {
+    let bytes = std::fs::read(path).map_err(|error| format!("could not read {}: {}", path, error))?;
+    parse_soundfont(&bytes).map_err(|error| format!("{}: {}", path, error))
+}
+
+/// The generators and modulators of one sample played for a note, with those of the preset added in
+struct Region {
+    generators: [i32; GENERATORS],
+    modulators: Vec<Modulator>,
+    sample: SampleHeader,
+}
+
+impl SoundFont {
+    /// Finds the preset for a bank and program
+    ///
+    /// A missing program falls back to the same program in bank 0, or for percussion to the standard kit.
+    fn preset(&self, bank: u16, program: u8) -> Option<&Preset> {
+        let find = |bank: u16, program: u8| self.presets.iter().find(|preset| preset.bank == bank && preset.program == program as u16);
+        find(bank, program).or_else(|| if bank == 128 { find(128, 0) } else { find(0, program) })
+    }
+
+    /// Returns the regions sounding for a note of a preset
+    fn regions(&self, bank: u16, program: u8, key: u8, velocity: u8) -> Vec<Region> {
+        let mut regions = Vec::new();
+        let preset = match self.preset(bank, program) {
+            Some(preset) => preset,
+            None => return regions,
+        };
+
+        for preset_zone in preset.zones.zones.iter().filter(|zone| zone.applies_to(key, velocity)) {
+            let instrument = match preset_zone.link.and_then(|index| self.instruments.get(index)) {
+                Some(instrument) => instrument,
+                None => continue,
+            };
+
+            for zone in instrument.zones.iter().filter(|zone| zone.applies_to(key, velocity)) {
+                let sample = match zone.link.and_then(|index| self.samples.get(index)) {
+                    Some(sample) => *sample,
+                    None => continue,
+                };
+
+                let mut generators = default_generators();
+                if let Some(global) = &instrument.global {
+                    global.apply(&mut generators);
+                }
+                zone.apply(&mut generators);
+
+                let mut preset_generators = [0; GENERATORS];
+                if let Some(global) = &preset.zones.global {
+                    global.apply(&mut preset_generators);
+                }
+                preset_zone.apply(&mut preset_generators);
+                for (index, generator) in generators.iter_mut().enumerate().filter(|(index, _)| !INSTRUMENT_ONLY.contains(index)) {
+                    *generator += preset_generators[index];
+                }
+
+                let mut modulators = DEFAULT_MODULATORS.to_vec();
+                merge_modulators(&mut modulators, instrument.global.as_ref());
+                merge_modulators(&mut modulators, Some(zone));
+                let mut preset_modulators = Vec::new();
+                merge_modulators(&mut preset_modulators, preset.zones.global.as_ref());
+                merge_modulators(&mut preset_modulators, Some(preset_zone));
+                modulators.extend(preset_modulators); // added to the instrument's, not replacing them
+
+                regions.push(Region { generators, modulators, sample });
+            }
+        }
+
+        regions
+    }
+
+    /// Starts the voices that play a note, for a channel at its current bank and program
+    pub fn voices(&self, channel: &Channel, percussion: bool, key: u8, velocity: u8, sample_rate: f64) -> Vec<SampleVoice> {
+        self.regions(channel.bank(percussion), channel.program, key, velocity).into_iter()
+            .filter_map(|region| SampleVoice::new(region, key, velocity, self.data.len(), sample_rate))
+            .collect()
+    }
+
+    /// Returns the sample data that voices play from
+    pub fn data(&self) -> &[i16] {
+        &self.data
+    }
+}
+
+/// Converts timecents to seconds
+fn seconds(timecents: i32) -> f64 {
+    2f64.powf(timecents.min(8000) as f64 / 1200.0)
+}
+
+/// Samples between updates of the modulated attenuation, pan and pitch of a voice
+const CONTROL_PERIOD: u32 = 64;
+
+/// A sample played for a note, following its loop and volume envelope
+pub struct SampleVoice {
+    region: Region,
+    key: u8, // as it sounds, after any key number override
+    velocity: u8,
+    position: f64, // in the sample data
+    end: usize,
+    looped: Option<(usize, usize)>, // loop start and end, while the loop is followed
+    loop_until_release: bool,
+    base_step: f64, // sample positions per output sample, at the pitch of the key
+    counter: u32, // samples until the next control update
+    gains: (f64, f64), // of the left and right output, from attenuation and pan
+    step: f64,
+    pub exclusive_class: i32,
+}
+
+impl SampleVoice {
+    /// Starts playing a region, or returns None if its sample is empty
+    fn new(region: Region, key: u8, velocity: u8, data_length: usize, sample_rate: f64) -> Option<SampleVoice> {
+        let generators = &region.generators;
+        let sample = region.sample;
+        let address = |base: u32, fine: usize, coarse: usize| {
+            (base as i64 + generators[fine] as i64 + generators[coarse] as i64 * 32768).clamp(0, data_length as i64) as usize
+        };
+
+        let start = address(sample.start, START_OFFSET, START_COARSE_OFFSET);
+        let end = address(sample.end, END_OFFSET, END_COARSE_OFFSET);
+        let loop_start = address(sample.loop_start, LOOP_START_OFFSET, LOOP_START_COARSE_OFFSET);
+        let loop_end = address(sample.loop_end, LOOP_END_OFFSET, LOOP_END_COARSE_OFFSET);
+        if start + 1 >= end {
+            return None;
+        }
+
+        let mode = generators[SAMPLE_MODES] & 3;
+        let looped = if (mode == 1 || mode == 3) && start <= loop_start && loop_start + 1 < loop_end && loop_end <= end {
+            Some((loop_start, loop_end))
+        }
+        else {
+            None
+        };
+
+        let key = if generators[KEYNUM] >= 0 { generators[KEYNUM].min(127) as u8 } else { key };
+        let velocity = if generators[VELOCITY] >= 0 { generators[VELOCITY].min(127) as u8 } else { velocity };
+        let root = if generators[OVERRIDING_ROOT_KEY] >= 0 { generators[OVERRIDING_ROOT_KEY] } else if sample.root <= 127 { sample.root as i32 } else { 60 };
+        let cents = (key as i32 - root) * generators[SCALE_TUNING].clamp(0, 1200) + sample.correction as i32;
+        let base_step = 2f64.powf(cents as f64 / 1200.0) * sample.rate.max(1) as f64 / sample_rate;
+
+        Some(SampleVoice {
+            exclusive_class: generators[EXCLUSIVE_CLASS],
+            key,
+            velocity,
+            position: start as f64,
+            end,
+            looped,
+            loop_until_release: mode == 3,
+            base_step,
+            counter: 0,
+            gains: (0.0, 0.0),
+            step: base_step,
+            region,
+        })
+    }
+
+    /// Updates the attenuation, pan and pitch of the voice from its generators and modulators
+    fn update(&mut self, channel: &Channel) {
+        let generators = &self.region.generators;
+        let mut attenuation = generators[INITIAL_ATTENUATION] as f64;
+        let mut pan = generators[PAN] as f64;
+        let mut cents = (generators[COARSE_TUNE].clamp(-120, 120) * 100 + generators[FINE_TUNE].clamp(-99, 99)) as f64;
+
+        for modulator in &self.region.modulators {
+            let value = modulator.value(channel, self.key, self.velocity);
+            match modulator.destination as usize {
+                INITIAL_ATTENUATION => attenuation += value,
+                PAN => pan += value,
+                FINE_TUNE => cents += value,
+                COARSE_TUNE => cents += value * 100.0,
+                _ => (), // the synthesizer has no filter, LFOs or effects to modulate
+            }
+        }
+
+        let gain = 10f64.powf(-attenuation.clamp(0.0, 1440.0) / 200.0);
+        let angle = (pan.clamp(-500.0, 500.0) + 500.0) / 1000.0 * std::f64::consts::FRAC_PI_2;
+        self.gains = (gain * angle.cos(), gain * angle.sin());
+        self.step = self.base_step * 2f64.powf(cents / 1200.0);
+    }
+
+    /// Returns the level of the volume envelope a time after the note started, and after it was released if it has been
+    ///
+    /// The attack rises linearly, while decay and release fall by 100 dB over their time, as the spec lays out.
+    fn envelope(&self, time: f64, released: Option<f64>) -> f64 {
+        let generators = &self.region.generators;
+        let delay = seconds(generators[DELAY_VOL_ENV]);
+        let attack = seconds(generators[ATTACK_VOL_ENV]);
+        let hold = seconds(generators[HOLD_VOL_ENV]);
+        let decay = seconds(generators[DECAY_VOL_ENV]);
+        let sustain = generators[SUSTAIN_VOL_ENV].clamp(0, 1440) as f64;
+
+        let held = |time: f64| {
+            let time = time - delay;
+            if time < 0.0 {
+                0.0
+            }
+            else if time < attack {
+                time / attack
+            }
+            else {
+                let decayed = (time - attack - hold).max(0.0) / decay * 1000.0;
+                10f64.powf(-decayed.min(sustain) / 200.0)
+            }
+        };
+
+        match released {
+            Some(released) => {
+                let faded = (time - released) / seconds(generators[RELEASE_VOL_ENV]) * 1000.0;
+                held(released) * 10f64.powf(-faded / 200.0)
+            },
+            None => held(time),
+        }
+    }
+
+    /// Whether the voice has played to the end of its sample or been released for long enough to be silent
+    ///
+    /// A voice that modulators have pitched beyond any finite step is finished too.
+    pub fn finished(&self, time: f64, released: Option<f64>) -> bool {
+        let faded = released.is_some_and(|released| (time - released) / seconds(self.region.generators[RELEASE_VOL_ENV]) >= 0.96);
+        faded || !self.step.is_finite() || (self.looped.is_none() && self.position as usize + 1 >= self.end)
+    }
+
+    /// Returns the next stereo frame of the voice and moves it on by one sample
+    pub fn next(&mut self, data: &[i16], channel: &Channel, time: f64, released: Option<f64>) -> [f64; 2] {
+        if self.counter == 0 {
+            self.update(channel);
+            self.counter = CONTROL_PERIOD;
+        }
+        self.counter -= 1;
+        if !self.step.is_finite() {
+            return [0.0, 0.0];
+        }
+
+        if released.is_some() && self.loop_until_release {
+            self.looped = None; // plays on from the loop to the end of the sample
+        }
+
+        let index = self.position as usize;
+        let following = match self.looped {
+            Some((loop_start, loop_end)) if index + 1 >= loop_end => loop_start,
+            _ => index + 1,
+        };
+        let (a, b) = (data[index] as f64, data[following.min(self.end - 1)] as f64);
+        let value = (a + (b - a) * self.position.fract()) / 32768.0 * self.envelope(time, released);
+
+        self.position += self.step;
+        match self.looped {
+            Some((loop_start, loop_end)) if self.position >= loop_end as f64 => {
+                self.position = loop_start as f64 + (self.position - loop_start as f64).rem_euclid((loop_end - loop_start) as f64);
+            },
+            Some(_) => (),
+            None => self.position = self.position.min((self.end - 1) as f64),
+        }
+
+        [value * self.gains.0, value * self.gains.1]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::riff::chunk;
+
+    fn record(name: &str, fields: &[u32], sizes: &[usize]) -> Vec<u8> {
+        let mut bytes = name.as_bytes().to_vec();
+        bytes.resize(20, 0);
+        for (field, size) in fields.iter().zip(sizes) {
+            bytes.extend_from_slice(&field.to_le_bytes()[..*size]);
+        }
+        bytes
+    }
+
+    fn generator(operator: u16, amount: i16) -> Vec<u8> {
+        [operator.to_le_bytes(), amount.to_le_bytes()].concat()
+    }
+
+    fn range(operator: u16, low: u8, high: u8) -> Vec<u8> {
+        [operator.to_le_bytes(), [low, high]].concat()
+    }
+
+    /// Builds a SoundFont with a piano preset and a bank 8 variation of it, whose instrument splits the keyboard
+    /// between a looped square wave below middle C and a short unlooped ramp above it
+    fn soundfont_bytes() -> Vec<u8> {
+        let mut samples: Vec<i16> = (0..100).map(|index| if index % 10 < 5 { 16000 } else { -16000 }).collect();
+        samples.extend((0..50).map(|index| index * 100));
+        samples.extend([0; 46]); // the padding the spec asks for after each sample
+        let smpl: Vec<u8> = samples.iter().flat_map(|sample| sample.to_le_bytes()).collect();
+
+        let shdr = [
+            record("square", &[0, 100, 20, 100, 44100, 69, 0, 0, 1], &[4, 4, 4, 4, 4, 1, 1, 2, 2]),
+            record("ramp", &[100, 150, 0, 0, 22050, 72, 0, 0, 1], &[4, 4, 4, 4, 4, 1, 1, 2, 2]),
+            record("EOS", &[0; 9], &[4, 4, 4, 4, 4, 1, 1, 2, 2]),
+        ].concat();
+        let igen = [
+            generator(RELEASE_VOL_ENV as u16, 0), // global zone: 1 second release
+            range(KEY_RANGE, 0, 59), generator(SAMPLE_MODES as u16, 1), generator(SAMPLE_ID, 0),
+            range(KEY_RANGE, 60, 127), generator(INITIAL_ATTENUATION as u16, 100), generator(SAMPLE_ID, 1),
+            vec![0; 4],
+        ].concat();
+        let ibag = [[0u16, 0], [1, 0], [4, 0], [7, 0]].iter().flat_map(|[generator, modulator]| [generator.to_le_bytes(), modulator.to_le_bytes()].concat()).collect::<Vec<u8>>();
+        let inst = [record("split", &[0], &[2]), record("EOI", &[3], &[2])].concat();
+        let pgen = [
+            generator(INSTRUMENT, 0),
+            generator(COARSE_TUNE as u16, 12), generator(INITIAL_ATTENUATION as u16, 20), generator(SAMPLE_MODES as u16, 0), generator(INSTRUMENT, 0),
+            vec![0; 4],
+        ].concat();
+        let pbag = [[0u16, 0], [1, 0], [5, 0]].iter().flat_map(|[generator, modulator]| [generator.to_le_bytes(), modulator.to_le_bytes()].concat()).collect::<Vec<u8>>();
+        let phdr = [
+            record("Piano", &[0, 0, 0, 0, 0, 0], &[2, 2, 2, 4, 4, 4]),
+            record("Piano an octave up", &[0, 8, 1, 0, 0, 0], &[2, 2, 2, 4, 4, 4]),
+            record("EOP", &[0, 0, 2, 0, 0, 0], &[2, 2, 2, 4, 4, 4]),
+        ].concat();
+
+        let pdta = [
+            &b"pdta"[..],
+            &chunk(b"phdr", &phdr), &chunk(b"pbag", &pbag), &chunk(b"pmod", &[0; 10]), &chunk(b"pgen", &pgen),
+            &chunk(b"inst", &inst), &chunk(b"ibag", &ibag), &chunk(b"imod", &[0; 10]), &chunk(b"igen", &igen),
+            &chunk(b"shdr", &shdr),
+        ].concat();
+        let body = [
+            &b"sfbk"[..],
+            &chunk(b"LIST", &[&b"INFO"[..], &chunk(b"ifil", &[2, 0, 1, 0])].concat()),
+            &chunk(b"LIST", &[&b"sdta"[..], &chunk(b"smpl", &smpl)].concat()),
+            &chunk(b"LIST", &pdta),
+        ].concat();
+        chunk(b"RIFF", &body)
+    }
+
+    #[test]
+    fn zones_are_picked_by_bank_program_key_and_velocity() {
+        let soundfont = parse_soundfont(&soundfont_bytes()).unwrap();
+        assert_eq!(soundfont.presets.len(), 2);
+        assert_eq!(soundfont.samples.len(), 2);
+
+        let low = soundfont.regions(0, 0, 57, 100);
+        assert_eq!(low.len(), 1);
+        assert_eq!(low[0].sample.root, 69);
+        assert_eq!(low[0].generators[SAMPLE_MODES], 1);
+        assert_eq!(low[0].generators[RELEASE_VOL_ENV], 0); // from the global zone
+        assert_eq!(low[0].modulators, DEFAULT_MODULATORS.to_vec());
+
+        let high = soundfont.regions(8, 0, 72, 100);
+        assert_eq!(high[0].sample.root, 72);
+        assert_eq!(high[0].generators[INITIAL_ATTENUATION], 120); // the preset adds to the instrument
+        assert_eq!(high[0].generators[COARSE_TUNE], 12);
+        assert_eq!(soundfont.regions(8, 0, 57, 100)[0].generators[SAMPLE_MODES], 1); // instrument only
+
+        assert_eq!(soundfont.regions(5, 0, 60, 100)[0].generators[COARSE_TUNE], 0); // falls back to bank 0
+        assert!(soundfont.regions(0, 1, 60, 100).is_empty());
+        assert!(parse_soundfont(&soundfont_bytes()[..200]).is_err());
+        assert!(parse_soundfont(b"RIFF\0\0\0\0sfbk").is_err());
+    }
+
+    #[test]
+    fn samples_loop_until_released_and_are_pitched_by_key() {
+        let soundfont = parse_soundfont(&soundfont_bytes()).unwrap();
+        let channel = Channel::default();
+
+        let mut voices = soundfont.voices(&channel, false, 57, 127, 44100.0);
+        let voice = &mut voices[0];
+        let frames: Vec<[f64; 2]> = (0..1000).map(|index| voice.next(soundfont.data(), &channel, index as f64 / 44100.0, None)).collect();
+        assert!(!voice.finished(1000.0 / 44100.0, None)); // held by the loop, well past the end of the sample
+        assert!(frames[990][0].abs() > 0.1);
+        assert!((frames[990][0] - frames[990][1]).abs() < 1e-9); // pan in the centre
+
+        // an octave above the root of the ramp, at half the output rate, reads it at one sample a frame
+        let mut voices = soundfont.voices(&channel, false, 84, 127, 44100.0);
+        assert!((voices[0].step - 1.0).abs() < 1e-9);
+        for index in 0..49 {
+            voices[0].next(soundfont.data(), &channel, index as f64 / 44100.0, None);
+        }
+        assert!(voices[0].finished(49.0 / 44100.0, None));
+
+        assert!(voice.finished(2.0, Some(1.0)));
+        assert!(voice.envelope(1.5, Some(1.0)) < voice.envelope(1.0, None) / 100.0);
+    }
+
+    #[test]
+    fn tuning_out_of_range_is_clamped() {
+        let soundfont = parse_soundfont(&soundfont_bytes()).unwrap();
+        let channel = Channel::default();
+
+        let mut region = soundfont.regions(0, 0, 57, 127).remove(0);
+        region.generators[SCALE_TUNING] = 32767;
+        region.generators[COARSE_TUNE] = 32767;
+        region.generators[FINE_TUNE] = -32768;
+        let mut voice = SampleVoice::new(region, 127, 127, soundfont.data().len(), 44100.0).unwrap();
+        for index in 0..100 {
+            voice.next(soundfont.data(), &channel, index as f64 / 44100.0, None);
+        }
+
+        // 58 keys above the root at 1200 cents a key, then 120 semitones and 99 cents up
+        let cents = 58.0 * 1200.0 + 12_000.0 - 99.0;
+        assert!((voice.step / 2f64.powf(cents / 1200.0) - 1.0).abs() < 1e-9); // the sample is at the output rate
+        assert!((20.0..100.0).contains(&voice.position)); // wrapped into the loop
+    }
+
+    #[test]
+    fn modulator_sources_follow_their_curves() {
+        let mut channel = Channel::default();
+        assert_eq!(source_value(0x0502, &channel, 60, 0), 1.0); // no velocity, fully attenuated
+        assert!(source_value(0x0502, &channel, 60, 127) < 0.01);
+        assert!((source_value(0x028A, &channel, 60, 0)).abs() < 1e-9); // CC10 at the centre
+        assert_eq!(source_value(0x0010, &channel, 60, 0), 2.0 / 127.0);
+
+        channel.wheel = 0;
+        let bend = DEFAULT_MODULATORS[4].value(&channel, 60, 100);
+        assert!((bend + 200.0).abs() < 1e-9); // 2 semitones down
+    }
+}
@@ -0,0 +1,484 @@
+// Synthesizer
+// Renders the notes of a file to audio with a small built-in synthesizer, so files can be listened to without a
+// MIDI device
+
+use crate::midicsv::parse_paths;
+use crate::sf2::{read_soundfont, SampleVoice, SoundFont};
+use crate::timing::TempoMap;
+use crate::wav::{encode_wav, SampleFormat};
+use crate::{MThd, MTrk};
+
+/// The most notes that sound at once, past which the oldest is cut off
+const MAX_VOICES: usize = 64;
+
+/// Seconds rendered after the last event, for the notes still sounding to die away
+const TAIL: f64 = 1.5;
+
+/// Gain applied to the mix, leaving headroom for many notes at once
+const MASTER_GAIN: f64 = 0.25;
+
+/// Oscillator waveforms
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Waveform {
+    Sine,
+    Square,
+    Saw,
+}
+
+impl Waveform {
+    pub fn from_name(name: &str) -> Option<Waveform> {
+        match name {
+            "sine" => Some(Waveform::Sine),
+            "square" => Some(Waveform::Square),
+            "saw" => Some(Waveform::Saw),
+            _ => None,
+        }
+    }
+
+    /// Picks a waveform for a General MIDI program by the family it belongs to
+    fn for_program(program: u8) -> Waveform {
+        match program / 8 {
+            2 | 10 => Waveform::Square, // organs and synth leads
+            5..=8 | 11 => Waveform::Saw, // strings, ensembles, brass, reeds and synth pads
+            _ => Waveform::Sine,
+        }
+    }
+
+    /// Returns the value of the waveform at a phase between 0 and 1, scaled to sound about as loud as a sine
+    fn sample(self, phase: f64) -> f64 {
+        match self {
+            Waveform::Sine => (phase * std::f64::consts::TAU).sin(),
+            Waveform::Square => if phase < 0.5 { 0.4 } else { -0.4 },
+            Waveform::Saw => 0.5 * (2.0 * phase - 1.0),
+        }
+    }
+}
+
+/// An ADSR envelope, with times in seconds and the sustain level between 0 and 1
+struct Envelope {
+    attack: f64,
+    decay: f64,
+    sustain: f64,
+    release: f64,
+}
+
+const TONE_ENVELOPE: Envelope = Envelope { attack: 0.005, decay: 0.1, sustain: 0.7, release: 0.15 };
+
+impl Envelope {
+    /// Returns the level of the envelope a time after the note started, and after it was released if it has been
+    fn level(&self, time: f64, released: Option<f64>) -> f64 {
+        let held = |time: f64| {
+            if time < self.attack {
+                time / self.attack
+            }
+            else if time < self.attack + self.decay {
+                1.0 - (1.0 - self.sustain) * (time - self.attack) / self.decay
+            }
+            else {
+                self.sustain
+            }
+        };
+
+        match released {
+            Some(released) => held(released) * (1.0 - (time - released) / self.release).max(0.0),
+            None => held(time),
+        }
+    }
+}
+
+/// A drum sound, made of a sine sweeping down to a pitch mixed with noise and dying away
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Drum {
+    from: f64, // frequency the sweep starts at, in Hz
+    to: f64, // frequency the sweep settles at
+    noise: f64, // share of noise in the mix
+    decay: f64, // seconds until the sound has died away
+}
+
+impl Drum {
+    /// Approximates the sound of a note of the General MIDI percussion key map
+    fn for_note(note: u8) -> Drum {
+        let drum = |from, to, noise, decay| Drum { from, to, noise, decay };
+        match note {
+            35 | 36 => drum(150.0, 50.0, 0.0, 0.35), // bass drums
+            38 | 40 => drum(250.0, 180.0, 0.7, 0.2), // snares
+            37 | 39 => drum(500.0, 400.0, 0.9, 0.1), // side stick and hand clap
+            41 | 43 | 45 | 47 | 48 | 50 => {
+                let pitch = 70.0 + (note - 41) as f64 * 15.0;
+                drum(pitch * 1.5, pitch, 0.1, 0.3) // toms, from low to high
+            },
+            42 | 44 => drum(8000.0, 8000.0, 1.0, 0.05), // closed and pedal hi-hats
+            46 => drum(8000.0, 8000.0, 1.0, 0.3), // open hi-hat
+            49 | 52 | 55 | 57 => drum(5000.0, 5000.0, 1.0, 1.2), // crash, Chinese and splash cymbals
+            51 | 53 | 59 => drum(600.0, 500.0, 0.8, 0.8), // ride cymbals and bell
+            _ => drum(400.0, 300.0, 0.5, 0.15),
+        }
+    }
+}
+
+/// What a voice plays
+enum Sound {
+    Tone(Waveform),
+    Drum(Drum),
+    Sample(Box<SampleVoice>), // from a SoundFont
+}
+
+/// A note being played
+struct Voice {
+    channel: usize,
+    note: u8,
+    gain: f64, // from the velocity, for tones and drums
+    sound: Sound,
+    phase: f64, // of the oscillator, between 0 and 1
+    time: f64, // seconds since the note started
+    released: Option<f64>, // time the note was released at
+}
+
+impl Voice {
+    /// Whether the voice has died away and can be dropped
+    fn finished(&self) -> bool {
+        match &self.sound {
+            Sound::Tone(_) => self.released.is_some_and(|released| self.time - released >= TONE_ENVELOPE.release),
+            Sound::Drum(drum) => self.time >= drum.decay,
+            Sound::Sample(sample) => sample.finished(self.time, self.released),
+        }
+    }
+
+    /// Returns the next stereo frame of the voice and moves it on by one sample
+    ///
+    /// Samples take their volume, pan and pitch bend from the modulators of the SoundFont, and the other sounds
+    /// from the controllers of the channel.
+    fn next(&mut self, sample_rate: f64, channel: &Channel, noise: &mut Noise, data: &[i16]) -> [f64; 2] {
+        let (frequency, value) = match &mut self.sound {
+            Sound::Tone(waveform) => {
+                let frequency = 440.0 * 2f64.powf((self.note as f64 - 69.0 + channel.bend()) / 12.0);
+                (frequency, waveform.sample(self.phase) * TONE_ENVELOPE.level(self.time, self.released))
+            },
+            Sound::Drum(drum) => {
+                let frequency = drum.to + (drum.from - drum.to) * (-30.0 * self.time).exp();
+                let tone = (self.phase * std::f64::consts::TAU).sin();
+                let level = (-5.0 * self.time / drum.decay).exp();
+                (frequency, (drum.noise * noise.next() + (1.0 - drum.noise) * tone) * level)
+            },
+            Sound::Sample(sample) => {
+                let frame = sample.next(data, channel, self.time, self.released);
+                self.time += 1.0 / sample_rate;
+                return frame;
+            },
+        };
+
+        self.phase = (self.phase + frequency / sample_rate).fract();
+        self.time += 1.0 / sample_rate;
+        let (left, right) = channel.gains();
+        [value * self.gain * left, value * self.gain * right]
+    }
+}
+
+/// White noise from a xorshift generator, so that renders of the same file are identical
+struct Noise(u32);
+
+impl Noise {
+    fn next(&mut self) -> f64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        self.0 as f64 / u32::MAX as f64 * 2.0 - 1.0
+    }
+}
+
+/// The state of a channel that affects its sound
+#[derive(Clone, Copy)]
+pub struct Channel {
+    pub program: u8,
+    pub controllers: [u8; 128],
+    pub pressure: u8, // channel aftertouch
+    pub wheel: u16, // pitch wheel position, 8192 is the centre
+    pub bend_range: f64, // in semitones either way, set with RPN 0
+}
+
+impl Default for Channel {
+    fn default() -> Channel {
+        let mut controllers = [0; 128];
+        controllers[7] = 100; // volume
+        controllers[10] = 64; // pan
+        controllers[11] = 127; // expression
+        controllers[100] = 127; // no RPN selected
+        controllers[101] = 127;
+        Channel { program: 0, controllers, pressure: 0, wheel: 8192, bend_range: 2.0 }
+    }
+}
+
+impl Channel {
+    /// Returns the bank selected with CC0, with channel 10 playing the percussion bank 128 of SoundFonts
+    pub fn bank(&self, percussion: bool) -> u16 {
+        if percussion { 128 } else { self.controllers[0] as u16 }
+    }
+
+    /// Returns how far the pitch wheel bends notes, in semitones
+    fn bend(&self) -> f64 {
+        (self.wheel as f64 - 8192.0) / 8192.0 * self.bend_range
+    }
+
+    /// Returns the gains of the left and right outputs
+    ///
+    /// Volume and expression follow the square law of the General MIDI guidelines, and pan keeps the power
+    /// constant, with both 0 and 1 hard left.
+    fn gains(&self) -> (f64, f64) {
+        let volume = (self.controllers[7] as f64 / 127.0 * self.controllers[11] as f64 / 127.0).powi(2);
+        let angle = (self.controllers[10].max(1) - 1) as f64 / 126.0 * std::f64::consts::FRAC_PI_2;
+        (volume * angle.cos(), volume * angle.sin())
+    }
+
+    /// Sets a controller, following the data entry of the pitch bend range
+    fn set_controller(&mut self, controller: u8, value: u8) {
+        self.controllers[controller as usize & 0x7F] = value;
+        if self.controllers[101] == 0 && self.controllers[100] == 0 {
+            match controller {
+                6 => self.bend_range = value as f64,
+                38 => self.bend_range = self.bend_range.trunc() + value as f64 / 100.0,
+                _ => (),
+            }
+        }
+    }
+}
+
+/// Settings of a render
+pub struct RenderOptions {
+    pub sample_rate: u32,
+    pub max_seconds: f64, // the render is cut off after this long
+    pub waveform: Option<Waveform>, // plays every channel but the drums with this waveform instead of by program
+    pub soundfont: Option<SoundFont>, // plays the presets of a SoundFont instead of the built-in sounds
+}
+
+impl Default for RenderOptions {
+    fn default() -> RenderOptions {
+        RenderOptions { sample_rate: 44_100, max_seconds: 600.0, waveform: None, soundfont: None }
+    }
+}
+
+/// Collects the channel events of a file with their time in seconds, in order of time
+///
+/// Format 2 tracks are played one after another, as their lengths add up to the duration of the file.
+fn channel_events<'a>(header: &MThd, tracks: &'a [MTrk]) -> Vec<(f64, &'a [u8])> {
+    let mut events = Vec::new();
+    let mut offset = 0.0;
+
+    let maps = TempoMap::for_file(header, tracks);
+    for (index, track) in tracks.iter().enumerate() {
+        let map = maps.track(index);
+        for (tick, event) in track.timed_events() {
+            if matches!(event.data.first(), Some(0x80..=0xEF)) {
+                events.push((offset + map.tick_to_seconds(tick), event.data.as_slice()));
+            }
+        }
+        if header.format == 2 {
+            offset += map.tick_to_seconds(track.length());
+        }
+    }
+
+    events.sort_by(|a, b| a.0.total_cmp(&b.0)); // stable, keeping track order at the same time
+    events
+}
+
+/// Renders a file to stereo frames, returning them and whether the render was cut off at the longest allowed
+///
+/// Channel 10 plays drums, and the others play a tone with an ADSR envelope whose waveform is picked by program.
+/// Velocity scales the gain of a note with the same square law as volume.
+///
+/// With a SoundFont every note plays the zones of the preset for the bank and program of its channel instead,
+/// with notes of the same exclusive class cutting each other off, such as open and closed hi-hats.
+pub fn render(header: &MThd, tracks: &[MTrk], options: &RenderOptions) -> (Vec<[f32; 2]>, bool) {
+    let events = channel_events(header, tracks);
+    let last = events.last().map_or(0.0, |(seconds, _)| *seconds);
+    let truncated = last > options.max_seconds;
+    let sample_rate = options.sample_rate as f64;
+    let length = ((last + TAIL).min(options.max_seconds) * sample_rate).ceil() as usize;
+
+    let mut frames = Vec::with_capacity(length);
+    let mut channels = [Channel::default(); 16];
+    let mut voices: Vec<Voice> = Vec::new();
+    let mut noise = Noise(0x9E37_79B9);
+    let mut next = 0;
+
+    for index in 0..length {
+        let now = index as f64 / sample_rate;
+        while let Some((_, data)) = events.get(next).filter(|(seconds, _)| *seconds <= now) {
+            let channel = (data[0] & 0x0F) as usize;
+            match (data[0] & 0xF0, data.get(1), data.get(2)) {
+                (0x90, Some(&note), Some(&velocity)) if velocity > 0 => {
+                    let sounds = match &options.soundfont {
+                        Some(soundfont) => {
+                            let samples = soundfont.voices(&channels[channel], channel == 9, note, velocity, sample_rate);
+                            for class in samples.iter().map(|sample| sample.exclusive_class).filter(|class| *class != 0) {
+                                voices.retain(|voice| !(voice.channel == channel && matches!(&voice.sound, Sound::Sample(sample) if sample.exclusive_class == class)));
+                            }
+                            samples.into_iter().map(|sample| Sound::Sample(Box::new(sample))).collect()
+                        },
+                        None if channel == 9 => vec![Sound::Drum(Drum::for_note(note))],
+                        None => vec![Sound::Tone(options.waveform.unwrap_or_else(|| Waveform::for_program(channels[channel].program)))],
+                    };
+
+                    for sound in sounds {
+                        if voices.len() == MAX_VOICES {
+                            voices.remove(0);
+                        }
+                        let gain = (velocity as f64 / 127.0).powi(2);
+                        voices.push(Voice { channel, note, gain, sound, phase: 0.0, time: 0.0, released: None });
+                    }
+                },
+                (0x80, Some(&note), _) | (0x90, Some(&note), Some(0)) => {
+                    for voice in voices.iter_mut().filter(|voice| voice.channel == channel && voice.note == note && voice.released.is_none()) {
+                        voice.released = Some(voice.time);
+                    }
+                },
+                (0xB0, Some(120), _) => voices.retain(|voice| voice.channel != channel), // all sound off
+                (0xB0, Some(123), _) => { // all notes off
+                    for voice in voices.iter_mut().filter(|voice| voice.channel == channel && voice.released.is_none()) {
+                        voice.released = Some(voice.time);
+                    }
+                },
+                (0xB0, Some(&controller), Some(&value)) => channels[channel].set_controller(controller, value),
+                (0xC0, Some(&program), _) => channels[channel].program = program,
+                (0xD0, Some(&pressure), _) => channels[channel].pressure = pressure,
+                (0xE0, Some(&lsb), Some(&msb)) => channels[channel].wheel = (msb as u16) << 7 | lsb as u16,
+                _ => (),
+            }
+            next += 1;
+        }
+
+        let data = options.soundfont.as_ref().map_or(&[][..], SoundFont::data);
+        let mut frame = [0.0; 2];
+        for voice in &mut voices {
+            let [left, right] = voice.next(sample_rate, &channels[voice.channel], &mut noise, data);
+            frame[0] += left * MASTER_GAIN;
+            frame[1] += right * MASTER_GAIN;
+        }
+        voices.retain(|voice| !voice.finished());
+        frames.push([frame[0] as f32, frame[1] as f32]);
+    }
+
+    (frames, truncated)
+}
+
+pub const USAGE: &str = "\
+usage: midi_generator render [options] <file>
+
+plays a MIDI file through a built-in synthesizer and writes the audio as a stereo WAV file
+
+options:
+  -o, --output <file>      write the audio to <file> (default <file>.wav)
+  -r, --sample-rate <hz>   samples per second (default 44100)
+  --float                  write 32-bit float samples instead of 16-bit integers
+  --waveform <name>        play every channel but the drums as sine, square or saw waves,
+                           instead of picking a waveform by program
+  -s, --soundfont <file>   play the presets of a SoundFont 2 file instead of the built-in sounds,
+                           picked by bank select and program change
+  --max-duration <secs>    cut the render off after this many seconds (default 600)";
+
+/// Runs the render command, writing a MIDI file as a WAV file
+pub fn run(args: Vec<String>) -> Result<(), String> {
+    let mut options = RenderOptions::default();
+    let mut format = SampleFormat::Int16;
+    let mut rest = Vec::new();
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-r" | "--sample-rate" => {
+                let rate = args.next().ok_or(format!("{} needs a number", arg))?;
+                options.sample_rate = rate.parse().ok().filter(|rate| (8_000..=192_000).contains(rate))
+                    .ok_or(format!("invalid sample rate: {} (must be from 8000 to 192000)", rate))?;
+            },
+            "--float" => format = SampleFormat::Float32,
+            "-s" | "--soundfont" => {
+                let path = args.next().ok_or(format!("{} needs a file name", arg))?;
+                options.soundfont = Some(read_soundfont(&path)?);
+            },
+            "--waveform" => {
+                let name = args.next().ok_or(format!("{} needs a waveform", arg))?;
+                options.waveform = Some(Waveform::from_name(&name).ok_or(format!("unknown waveform: {}", name))?);
+            },
+            "--max-duration" => {
+                let seconds = args.next().ok_or(format!("{} needs a number of seconds", arg))?;
+                options.max_seconds = seconds.parse().ok().filter(|seconds: &f64| *seconds > 0.0 && seconds.is_finite())
+                    .ok_or(format!("invalid duration: {}", seconds))?;
+            },
+            _ => rest.push(arg),
+        }
+    }
+
+    let (input, output) = parse_paths(rest, "wav", USAGE)?;
+    let (header, tracks) = crate::reader::read_midi_file(&input)?;
+    let (frames, truncated) = render(&header, &tracks, &options);
+    if truncated {
+        eprintln!("warning: the render was cut off after {} seconds", options.max_seconds);
+    }
+
+    let bytes = encode_wav(&frames, options.sample_rate, format)?;
+    std::fs::write(&output, bytes).map_err(|error| format!("could not write {}: {}", output, error))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{to_delta_times, Event};
+
+    fn header(format: u16) -> MThd {
+        MThd { identifier: *b"MThd", chunklen: 6, format, ntracks: 1, tickdiv: 96 }
+    }
+
+    #[test]
+    fn notes_sound_for_their_length_and_die_away() {
+        let track = MTrk::new(to_delta_times(vec![
+            (0, Event { data: vec![0xB0, 10, 0] }), // hard left
+            (0, Event { data: vec![0xB9, 10, 0] }),
+            (0, Event::new_note_on(0, 69, 127)),
+            (96, Event::new_note_off(0, 69)), // half a second at 120 BPM
+            (192, Event::new_note_on(9, 36, 100)),
+        ]));
+        let options = RenderOptions { sample_rate: 8_000, ..Default::default() };
+        let (frames, truncated) = render(&header(0), &[track], &options);
+
+        assert!(!truncated);
+        assert_eq!(frames.len(), ((1.0 + TAIL) * 8_000.0) as usize);
+        assert!(frames.iter().all(|frame| frame[1].abs() < 1e-6)); // nothing on the right
+        let peak = |range: std::ops::Range<usize>| frames[range].iter().map(|frame| frame[0].abs()).fold(0.0, f32::max);
+        assert!(peak(1_000..3_000) > 0.05);
+        assert!(peak(6_000..8_000) < 1e-6); // released, with the drum yet to play
+        assert!(peak(8_000..9_000) > 0.01);
+        assert!(peak(11_000..12_000) < 1e-3);
+    }
+
+    #[test]
+    fn controllers_and_pitch_bend_change_the_sound() {
+        let mut channel = Channel::default();
+        let (left, right) = channel.gains();
+        assert!((left - right).abs() < 0.01);
+        channel.set_controller(10, 1);
+        assert_eq!(channel.gains().1, 0.0);
+        channel.set_controller(7, 127);
+        channel.set_controller(10, 127);
+        let (left, right) = channel.gains();
+        assert!(left.abs() < 1e-9 && (right - 1.0).abs() < 1e-9);
+
+        for (controller, value) in [(101, 0), (100, 0), (6, 12), (38, 50)] { // RPN 0, the pitch bend range
+            channel.set_controller(controller, value);
+        }
+        channel.wheel = 0;
+        assert_eq!(channel.bend(), -12.5);
+
+        let track = MTrk::new(to_delta_times(vec![
+            (0, Event { data: vec![0xE0, 0x7F, 0x7F] }), // bent up 2 semitones
+            (0, Event::new_note_on(0, 57, 127)), // A3, sounding as B3
+            (960, Event::new_note_off(0, 57)),
+        ]));
+        let options = RenderOptions { sample_rate: 8_000, max_seconds: 1.0, waveform: Some(Waveform::Square), ..Default::default() };
+        let (frames, truncated) = render(&header(0), &[track], &options);
+        assert!(truncated);
+        assert_eq!(frames.len(), 8_000);
+
+        // a square wave changes sign twice a cycle
+        let crossings = frames.windows(2).filter(|pair| (pair[0][0] > 0.0) != (pair[1][0] > 0.0)).count();
+        assert!((crossings as f64 / 2.0 - 246.9).abs() < 2.0, "{} crossings", crossings);
+    }
+}
@@ -0,0 +1,269 @@
+// Timing
+// Converts between ticks and wall-clock time using the tickdiv of the header and the Tempo events of the tracks
+
+use crate::{MThd, MTrk};
+
+const DEFAULT_TEMPO: u32 = 500_000; // 120 BPM, in force until the first Tempo event
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+/// The timing scheme held in the tickdiv field of MThd
+pub enum TickDiv {
+    Metrical { ppqn: u16 }, // bit 15 clear, ticks per quarter note
+    Timecode { fps: u8, subframes: u8 }, // bit 15 set, fps is 24, 25, 29 (29.97 drop-frame) or 30
+}
+
+impl TickDiv {
+    /// Splits a tickdiv value into its timing scheme
+    pub fn from_tickdiv(tickdiv: u16) -> TickDiv {
+        if tickdiv & 0x8000 == 0 {
+            TickDiv::Metrical { ppqn: tickdiv & 0x7FFF }
+        }
+        else {
+            TickDiv::Timecode {
+                fps: ((tickdiv >> 8) as u8 as i8).unsigned_abs(), // stored as a negative number
+                subframes: (tickdiv & 0xFF) as u8,
+            }
+        }
+    }
+}
+
+/// Returns the real frame rate for an fps value from tickdiv
+///
+/// 29 stands for the NTSC drop-frame rate of 30000/1001 (29.97) frames per second, the others are exact.
+pub fn frames_per_second(fps: u8) -> f64 {
+    match fps {
+        29 => 30_000.0 / 1_001.0,
+        _ => fps as f64,
+    }
+}
+
+#[derive(Debug)]
+/// A stretch of the tempo map where the tempo doesn't change
+struct TempoSegment {
+    tick: u32, // where the segment starts
+    seconds: f64, // time elapsed at the start of the segment
+    tempo: u32, // microseconds per quarter note
+}
+
+#[derive(Debug)]
+/// Maps ticks to seconds and back for a set of tracks sharing the same tempo
+///
+/// With metrical timing the map follows every Tempo event in the tracks.
+/// With timecode timing ticks have a fixed length and Tempo events are ignored, as the spec requires.
+pub struct TempoMap {
+    tickdiv: TickDiv,
+    segments: Vec<TempoSegment>, // always at least one, in order of tick
+}
+
+impl TempoMap {
+    /// Builds the tempo map from the Tempo events of the given tracks
+    ///
+    /// Tempo events on the same tick are applied in track order, so the last one wins.
+    pub fn new(tickdiv: u16, tracks: &[&MTrk]) -> TempoMap {
+        let mut changes: Vec<(u32, u32)> = Vec::new();
+
+        for track in tracks {
+            for (tick, event) in track.timed_events() {
+                if event.data.len() >= 6 && event.data[..3] == [0xFF, 0x51, 0x03] {
+                    let tempo = ((event.data[3] as u32) << 16) | ((event.data[4] as u32) << 8) | event.data[5] as u32;
+                    changes.push((tick, tempo));
+                }
+            }
+        }
+        changes.sort_by_key(|(tick, _)| *tick); // stable, keeping track order within a tick
+
+        let tickdiv = TickDiv::from_tickdiv(tickdiv);
+        let mut segments = vec![TempoSegment { tick: 0, seconds: 0.0, tempo: DEFAULT_TEMPO }];
+
+        for (tick, tempo) in changes {
+            let seconds = seconds_in_segment(tickdiv, segments.last().unwrap(), tick);
+            segments.push(TempoSegment { tick, seconds, tempo });
+        }
+
+        TempoMap {
+            tickdiv,
+            segments,
+        }
+    }
+
+    /// Builds the tempo maps of every track of a file at once
+    ///
+    /// Format 2 tracks are independent sequences with tempo maps of their own.
+    /// In format 0 and 1 files every track follows the Tempo events of the whole file, so they share one map.
+    pub fn for_file(header: &MThd, tracks: &[MTrk]) -> TrackTempoMaps {
+        if header.format == 2 {
+            TrackTempoMaps {
+                maps: tracks.iter().map(|track| TempoMap::new(header.tickdiv, &[track])).collect(),
+                shared: false,
+            }
+        }
+        else {
+            TrackTempoMaps {
+                maps: vec![TempoMap::new(header.tickdiv, &tracks.iter().collect::<Vec<&MTrk>>())],
+                shared: true,
+            }
+        }
+    }
+
+    /// Returns the number of seconds from the start of the track to the given tick
+    pub fn tick_to_seconds(&self, tick: u32) -> f64 {
+        let index = self.segments.partition_point(|segment| segment.tick <= tick) - 1;
+        seconds_in_segment(self.tickdiv, &self.segments[index], tick)
+    }
+
+    /// Returns the tick nearest to the given number of seconds from the start of the track
+    pub fn seconds_to_tick(&self, seconds: f64) -> u32 {
+        let seconds = seconds.max(0.0);
+        let segment = match self.tickdiv {
+            TickDiv::Metrical { .. } => &self.segments[self.segments.partition_point(|segment| segment.seconds <= seconds) - 1],
+            TickDiv::Timecode { .. } => &self.segments[0],
+        };
+
+        let ticks = (seconds - segment.seconds) * ticks_per_second(self.tickdiv, segment.tempo);
+        (segment.tick as f64 + ticks).round().min(u32::MAX as f64) as u32
+    }
+}
+
+/// The tempo maps of the tracks of a file, built once rather than for every track
+pub struct TrackTempoMaps {
+    maps: Vec<TempoMap>,
+    shared: bool, // one map for every track, as in format 0 and 1 files
+}
+
+impl TrackTempoMaps {
+    /// Returns the tempo map that applies to a track
+    pub fn track(&self, index: usize) -> &TempoMap {
+        if self.shared { &self.maps[0] } else { &self.maps[index] }
+    }
+}
+
+/// Returns how many ticks pass per second at the given tempo
+///
+/// A ppqn, frame rate or sub-frame resolution of 0 is treated as 1 so that broken headers still give a finite answer.
+fn ticks_per_second(tickdiv: TickDiv, tempo: u32) -> f64 {
+    match tickdiv {
+        TickDiv::Metrical { ppqn } => ppqn.max(1) as f64 * 1_000_000.0 / tempo.max(1) as f64,
+        TickDiv::Timecode { fps, subframes } => frames_per_second(fps.max(1)) * subframes.max(1) as f64,
+    }
+}
+
+/// Returns the time of a tick at or after the start of a segment
+fn seconds_in_segment(tickdiv: TickDiv, segment: &TempoSegment, tick: u32) -> f64 {
+    match tickdiv {
+        TickDiv::Metrical { .. } => segment.seconds + (tick - segment.tick) as f64 / ticks_per_second(tickdiv, segment.tempo),
+        TickDiv::Timecode { .. } => tick as f64 / ticks_per_second(tickdiv, segment.tempo),
+    }
+}
+
+/// Returns how long the file plays for, in seconds
+///
+/// Format 0 and 1 tracks play together, so the file lasts as long as its longest track.
+/// Format 2 tracks are independent sequences played one after another, so their lengths add up.
+pub fn duration(header: &MThd, tracks: &[MTrk]) -> f64 {
+    let maps = TempoMap::for_file(header, tracks);
+    let lengths = tracks.iter().enumerate().map(|(index, track)| maps.track(index).tick_to_seconds(track.length()));
+
+    if header.format == 2 {
+        lengths.sum()
+    }
+    else {
+        lengths.fold(0.0, f64::max)
+    }
+}
+
+/// Formats a time as an SMPTE timecode, HH:MM:SS:FF
+///
+/// At 29 fps frames are numbered with drop-frame counting: frame numbers 0 and 1 are skipped at the start
+/// of every minute except each tenth minute, and the last separator becomes ';' as is customary.
+pub fn timecode(seconds: f64, fps: u8) -> String {
+    let mut frame = (seconds.max(0.0) * frames_per_second(fps)) as u64;
+    let nominal = match fps {
+        29 => 30,
+        _ => fps.max(1) as u64,
+    };
+
+    if fps == 29 {
+        // 17982 real frames make up ten minutes, in which 18 frame numbers are dropped
+        let tens = frame / 17_982;
+        let rest = frame % 17_982;
+        frame += 18 * tens + if rest < 2 { 0 } else { 2 * ((rest - 2) / 1_798) };
+    }
+
+    format!("{:02}:{:02}:{:02}{}{:02}",
+        frame / (nominal * 3_600),
+        frame / (nominal * 60) % 60,
+        frame / nominal % 60,
+        if fps == 29 { ';' } else { ':' },
+        frame % nominal)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{to_delta_times, Event};
+
+    fn header(format: u16, tickdiv: u16) -> MThd {
+        MThd {
+            identifier: [b'M', b'T', b'h', b'd'],
+            chunklen: 6,
+            format,
+            ntracks: 1,
+            tickdiv,
+        }
+    }
+
+    #[test]
+    fn metrical_ticks_follow_tempo_changes() {
+        let track = MTrk::new(to_delta_times(vec![
+            (0, Event::new_tempo(500_000)),
+            (192, Event::new_tempo(1_000_000)),
+            (288, Event::new_note_on(0, 60, 100)),
+        ]));
+        let map = TempoMap::new(96, &[&track]);
+
+        assert_eq!(map.tick_to_seconds(96), 0.5);
+        assert_eq!(map.tick_to_seconds(192), 1.0);
+        assert_eq!(map.tick_to_seconds(288), 2.0);
+        assert_eq!(map.seconds_to_tick(2.0), 288);
+        assert_eq!(map.seconds_to_tick(0.75), 144);
+        assert_eq!(duration(&header(0, 96), &[track]), 2.0);
+    }
+
+    #[test]
+    fn timecode_ticks_ignore_tempo() {
+        let track = MTrk::new(to_delta_times(vec![
+            (0, Event::new_tempo(2_000_000)),
+            (1_500, Event::new_note_on(0, 60, 100)),
+        ]));
+
+        let map = TempoMap::new(0xE728, &[&track]); // 25 fps, 40 sub-frames, 1 ms ticks
+        assert_eq!(map.tick_to_seconds(1_500), 1.5);
+        assert_eq!(map.seconds_to_tick(1.5), 1_500);
+
+        let map = TempoMap::new(0xE304, &[&track]); // 29.97 fps, 4 sub-frames, so 3000 frames last 100.1 seconds
+        assert!((map.tick_to_seconds(12_000) - 100.1).abs() < 1e-9);
+        assert_eq!(map.seconds_to_tick(100.1), 12_000);
+    }
+
+    #[test]
+    fn format_2_tracks_play_in_sequence() {
+        let first = MTrk::new(to_delta_times(vec![(0, Event::new_tempo(250_000)), (96, Event::new_note_on(0, 60, 100))]));
+        let second = MTrk::new(to_delta_times(vec![(96, Event::new_note_on(0, 60, 100))]));
+
+        let tracks = [first, second];
+        assert_eq!(duration(&header(2, 96), &tracks), 0.25 + 0.5);
+
+        let maps = TempoMap::for_file(&header(2, 96), &tracks);
+        assert_eq!(maps.track(1).tick_to_seconds(96), 0.5); // the tempo of the first track doesn't apply
+        let maps = TempoMap::for_file(&header(1, 96), &tracks);
+        assert_eq!(maps.track(1).tick_to_seconds(96), 0.25);
+    }
+
+    #[test]
+    fn drop_frame_timecode_skips_frame_numbers() {
+        assert_eq!(timecode(1_799.0 * 1_001.0 / 30_000.0, 29), "00:00:59;29");
+        assert_eq!(timecode(1_800.0 * 1_001.0 / 30_000.0, 29), "00:01:00;02");
+        assert_eq!(timecode(17_982.0 * 1_001.0 / 30_000.0, 29), "00:10:00;00");
+        assert_eq!(timecode(90.5, 25), "00:01:30:12");
+    }
+}
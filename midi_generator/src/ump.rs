@@ -0,0 +1,766 @@
+// Universal MIDI Packets
+// Writes files as MIDI 2.0 Universal MIDI Packets, either as a stream of packets or as a MIDI Clip File, and turns
+// clip files back into Standard MIDI Files
+
+use std::collections::BTreeMap;
+use std::convert::{TryFrom, TryInto};
+
+use crate::midicsv::{parse_paths, split_length};
+use crate::reader::read_midi_file;
+use crate::timing::{frames_per_second, TempoMap, TickDiv};
+use crate::{encode_midi_file, encode_vlq, to_delta_times, Event, MThd, MTrk};
+
+/// The signature at the start of a MIDI Clip File, which is followed by big-endian packets
+const CLIP_SIGNATURE: &[u8; 8] = b"SMF2CLIP";
+
+/// The largest number of ticks one Delta Clockstamp can carry, longer gaps take several
+const MAX_DELTA_CLOCKSTAMP: u64 = 0xF_FFFF;
+
+/// Ticks of JR Timestamps per second
+const JR_TICKS_PER_SECOND: f64 = 31_250.0;
+
+/// Status banks and statuses of the Flex Data messages standing in for Meta events
+const FLEX_TEMPO: (u8, u8) = (0x00, 0x00);
+const FLEX_TIME_SIGNATURE: (u8, u8) = (0x00, 0x01);
+const FLEX_KEY_SIGNATURE: (u8, u8) = (0x00, 0x05);
+const FLEX_METADATA_TEXT: u8 = 0x01;
+const FLEX_PERFORMANCE_TEXT: u8 = 0x02;
+
+/// How channel events are carried
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Protocol {
+    Midi1, // MIDI 1.0 Channel Voice messages (message type 2), the bytes as they are
+    Midi2, // MIDI 2.0 Channel Voice messages (message type 4), with 16-bit velocities and 32-bit controllers
+}
+
+/// How a stream of packets says when they happen
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Timestamps {
+    DeltaClockstamps, // ticks since the previous packet, after a Ticks Per Quarter Note message
+    Jr, // JR Timestamps of the sender clock in 1/31250 seconds, which wrap around every 2.1 seconds
+}
+
+/// Scales a value up to more bits with the Min-Center-Max scaling of the MIDI 2.0 spec
+///
+/// The minimum, center and maximum of the source stay the minimum, center and maximum of the result, and the low
+/// bits above the center are filled in by repeating the bits of the source.
+pub fn scale_up(value: u32, source_bits: u32, destination_bits: u32) -> u32 {
+    let scale_bits = destination_bits - source_bits;
+    let shifted = value << scale_bits;
+    if value <= 1 << (source_bits - 1) {
+        return shifted;
+    }
+
+    let repeat_bits = source_bits - 1;
+    let mut repeat = value & ((1 << repeat_bits) - 1);
+    repeat = if scale_bits > repeat_bits { repeat << (scale_bits - repeat_bits) } else { repeat >> (repeat_bits - scale_bits) };
+
+    let mut scaled = shifted;
+    while repeat != 0 {
+        scaled |= repeat;
+        repeat >>= repeat_bits;
+    }
+    scaled
+}
+
+/// Scales a value down to fewer bits by dropping its low bits
+fn scale_down(value: u32, source_bits: u32, destination_bits: u32) -> u32 {
+    value >> (source_bits - destination_bits)
+}
+
+/// Returns the number of 32-bit words in a packet of the given message type
+fn packet_words(message_type: u32) -> usize {
+    match message_type {
+        0x0..=0x2 | 0x6 | 0x7 => 1,
+        0x3 | 0x4 | 0x8..=0xA => 2,
+        0xB | 0xC => 3,
+        _ => 4,
+    }
+}
+
+/// Returns a Delta Clockstamp Ticks Per Quarter Note message
+fn ticks_per_quarter_note(ticks: u16) -> u32 {
+    0x0030_0000 | ticks as u32
+}
+
+/// Returns the Delta Clockstamps for a gap of the given number of ticks, one per 2^20 - 1 ticks
+///
+/// Every gap gets at least one, as every message of a clip follows a Delta Clockstamp.
+fn delta_clockstamps(mut ticks: u64) -> Vec<u32> {
+    let mut words = Vec::new();
+    while ticks > MAX_DELTA_CLOCKSTAMP {
+        words.push(0x0040_0000 | MAX_DELTA_CLOCKSTAMP as u32);
+        ticks -= MAX_DELTA_CLOCKSTAMP;
+    }
+    words.push(0x0040_0000 | ticks as u32);
+    words
+}
+
+/// Returns a Flex Data message addressed to a whole group
+fn flex_data(group: u8, form: u8, (bank, status): (u8, u8), data: [u32; 3]) -> Vec<u32> {
+    let first = 0xD000_0000 | (group as u32) << 24 | (form as u32) << 22 | 1 << 20 | (bank as u32) << 8 | status as u32;
+    vec![first, data[0], data[1], data[2]]
+}
+
+/// Splits text into Flex Data messages of up to 12 bytes each
+fn flex_text(group: u8, kind: (u8, u8), text: &[u8]) -> Vec<Vec<u32>> {
+    let text: Vec<u8> = text.iter().copied().filter(|byte| *byte != 0).collect(); // zero bytes pad the last message
+    let pieces: Vec<&[u8]> = if text.is_empty() { vec![&[]] } else { text.chunks(12).collect() };
+
+    pieces.iter().enumerate().map(|(index, piece)| {
+        let form = match (index, pieces.len()) {
+            (_, 1) => 0,
+            (0, _) => 1,
+            (index, count) if index + 1 == count => 3,
+            _ => 2,
+        };
+        let mut bytes = [0; 12];
+        bytes[..piece.len()].copy_from_slice(piece);
+        let data = [0, 4, 8].map(|start| u32::from_be_bytes(bytes[start..start + 4].try_into().unwrap()));
+        flex_data(group, form, kind, data)
+    }).collect()
+}
+
+/// Returns the Flex Data status of a text Meta event, and back
+fn text_kind(meta_type: u8) -> (u8, u8) {
+    match meta_type {
+        0x02 => (FLEX_METADATA_TEXT, 0x04), // Copyright Notice
+        0x03 => (FLEX_METADATA_TEXT, 0x03), // MIDI Clip Name
+        0x05 => (FLEX_PERFORMANCE_TEXT, 0x01), // Lyrics
+        _ => (FLEX_METADATA_TEXT, 0x00), // Unknown
+    }
+}
+
+fn text_meta_type(kind: (u8, u8)) -> u8 {
+    match kind {
+        (FLEX_METADATA_TEXT, 0x04) => 0x02,
+        (FLEX_METADATA_TEXT, 0x02) | (FLEX_METADATA_TEXT, 0x03) => 0x03,
+        (FLEX_PERFORMANCE_TEXT, 0x01) => 0x05,
+        _ => 0x01,
+    }
+}
+
+/// Returns the tonic of a key as the letter number of a Set Key Signature message, A being 1
+fn tonic(sharps: i8, minor: bool) -> u8 {
+    const MAJOR: &[u8; 15] = b"CGDAEBFCGDAEBFC";
+    const MINOR: &[u8; 15] = b"AEBFCGDAEBFCGDA";
+    let letters = if minor { MINOR } else { MAJOR };
+    letters[(sharps.clamp(-7, 7) + 7) as usize] - b'A' + 1
+}
+
+/// Splits SysEx data into 7-bit System Exclusive messages of up to 6 bytes each
+///
+/// Data bytes with bit 7 set can't be carried and lose it.
+fn sysex7(group: u8, data: &[u8]) -> Vec<Vec<u32>> {
+    let pieces: Vec<&[u8]> = if data.is_empty() { vec![&[]] } else { data.chunks(6).collect() };
+
+    pieces.iter().enumerate().map(|(index, piece)| {
+        let status: u32 = match (index, pieces.len()) {
+            (_, 1) => 0,
+            (0, _) => 1,
+            (index, count) if index + 1 == count => 3,
+            _ => 2,
+        };
+        let mut bytes = [0u32; 6];
+        for (slot, byte) in bytes.iter_mut().zip(piece.iter()) {
+            *slot = (byte & 0x7F) as u32;
+        }
+        vec![
+            0x3000_0000 | (group as u32) << 24 | status << 20 | (piece.len() as u32) << 16 | bytes[0] << 8 | bytes[1],
+            bytes[2] << 24 | bytes[3] << 16 | bytes[4] << 8 | bytes[5],
+        ]
+    }).collect()
+}
+
+/// What a channel has been sent that later MIDI 2.0 messages depend on
+#[derive(Clone, Copy)]
+struct ChannelState {
+    bank: Option<(u8, u8)>, // Bank Select MSB and LSB waiting for a Program Change
+    registered: (u8, u8), // RPN MSB and LSB
+    assignable: (u8, u8), // NRPN MSB and LSB
+    selected: u8, // opcode of the parameter kind last selected, 0x2 for RPN or 0x3 for NRPN, 0 for none
+    data_msb: u8,
+}
+
+impl Default for ChannelState {
+    fn default() -> Self {
+        ChannelState { bank: None, registered: (0x7F, 0x7F), assignable: (0x7F, 0x7F), selected: 0, data_msb: 0 }
+    }
+}
+
+/// Translates the MIDI 1.0 channel events of a track into MIDI 2.0 Channel Voice messages
+///
+/// Bank Select is held back and sent in the Program Change that follows it, and RPN and NRPN Data Entry become
+/// Registered and Assignable Controller messages, as the MIDI 2.0 translation rules ask.
+#[derive(Default)]
+struct Translator {
+    channels: [ChannelState; 16],
+}
+
+impl Translator {
+    fn translate(&mut self, group: u8, bytes: &[u8]) -> Option<Vec<u32>> {
+        let status = bytes[0];
+        let channel = &mut self.channels[(status & 0x0F) as usize];
+        let data = |index: usize| bytes.get(index).map_or(0, |byte| (byte & 0x7F) as u32);
+        let first = |status: u8, index: u32, low: u32| 0x4000_0000 | (group as u32) << 24 | (status as u32) << 16 | index << 8 | low;
+
+        match status & 0xF0 {
+            0x80 => Some(vec![first(status, data(1), 0), scale_up(data(2), 7, 16) << 16]),
+            0x90 if data(2) == 0 => Some(vec![first(0x80 | (status & 0x0F), data(1), 0), scale_up(64, 7, 16) << 16]),
+            0x90 => Some(vec![first(status, data(1), 0), scale_up(data(2), 7, 16) << 16]),
+            0xA0 => Some(vec![first(status, data(1), 0), scale_up(data(2), 7, 32)]),
+            0xB0 => {
+                let value = data(2) as u8;
+                match data(1) {
+                    0 => channel.bank = Some((value, channel.bank.map_or(0, |(_, lsb)| lsb))),
+                    32 => channel.bank = Some((channel.bank.map_or(0, |(msb, _)| msb), value)),
+                    101 => (channel.registered.0, channel.selected) = (value, 0x2),
+                    100 => (channel.registered.1, channel.selected) = (value, 0x2),
+                    99 => (channel.assignable.0, channel.selected) = (value, 0x3),
+                    98 => (channel.assignable.1, channel.selected) = (value, 0x3),
+                    6 | 38 => {
+                        let lsb = if data(1) == 6 {
+                            channel.data_msb = value;
+                            0
+                        }
+                        else {
+                            value
+                        };
+                        let (msb_index, lsb_index) = match channel.selected {
+                            0x2 => channel.registered,
+                            0x3 => channel.assignable,
+                            _ => return None,
+                        };
+                        if (msb_index, lsb_index) == (0x7F, 0x7F) {
+                            return None; // the null parameter
+                        }
+                        let opcode = channel.selected << 4 | (status & 0x0F);
+                        let entry = (channel.data_msb as u32) << 7 | lsb as u32;
+                        return Some(vec![first(opcode, msb_index as u32, lsb_index as u32), scale_up(entry, 14, 32)]);
+                    },
+                    index => return Some(vec![first(status, index, 0), scale_up(value as u32, 7, 32)]),
+                }
+                None
+            },
+            0xC0 => {
+                let (flags, bank) = match channel.bank.take() {
+                    Some((msb, lsb)) => (1, (msb as u32) << 8 | lsb as u32),
+                    None => (0, 0),
+                };
+                Some(vec![first(status, 0, flags), data(1) << 24 | bank])
+            },
+            0xD0 => Some(vec![first(status, 0, 0), scale_up(data(1), 7, 32)]),
+            0xE0 => Some(vec![first(status, 0, 0), scale_up(data(2) << 7 | data(1), 14, 32)]),
+            _ => None,
+        }
+    }
+}
+
+/// A packet and when it happens, in ticks from the start of the clip and in seconds
+struct TimedPacket {
+    tick: u64,
+    seconds: f64,
+    words: Vec<u32>,
+}
+
+/// Returns the number of ticks per quarter note that the packets are timed in
+///
+/// Timecode files have no quarter notes, so their packets are laid out at the default tempo of 120 BPM, where a
+/// quarter note lasts half a second, with as many ticks in it as there are in a second of the nominal frame rate.
+fn clip_ticks_per_quarter_note(tickdiv: u16) -> u16 {
+    match TickDiv::from_tickdiv(tickdiv) {
+        TickDiv::Metrical { ppqn } => ppqn.max(1),
+        TickDiv::Timecode { fps, subframes } => ((frames_per_second(fps).round() * subframes as f64) as u16).max(1),
+    }
+}
+
+/// Turns the events of a file into packets, in order of time, and returns them with the length of the file in ticks
+///
+/// Metrical files keep their ticks. The ticks of timecode files are worked out again from the time of each packet,
+/// so a frame rate that isn't a whole number of ticks per quarter note doesn't make the clip drift.
+///
+/// Tracks play on the group given by their MIDI Port event, or group 1 if they have none. Tempo, time signature, key
+/// signature and text Meta events become Flex Data messages and SysEx events 7-bit System Exclusive messages.
+/// The tracks of format 2 files play one after another.
+fn packets(header: &MThd, tracks: &[MTrk], protocol: Protocol) -> (Vec<TimedPacket>, u64) {
+    let metrical = matches!(TickDiv::from_tickdiv(header.tickdiv), TickDiv::Metrical { .. });
+    let mut packets = Vec::new();
+    let (mut tick_offset, mut seconds_offset, mut end) = (0, 0.0, 0);
+    let ticks_per_second = 2.0 * clip_ticks_per_quarter_note(header.tickdiv) as f64;
+    let clip_tick = |tick: u64, seconds: f64| if metrical { tick } else { (seconds * ticks_per_second).round() as u64 };
+
+    let maps = TempoMap::for_file(header, tracks);
+    for (index, track) in tracks.iter().enumerate() {
+        let map = maps.track(index);
+        let mut translator = Translator::default();
+        let mut group = 0;
+
+        for (tick, event) in track.timed_events() {
+            let data = event.data.as_slice();
+            let words: Vec<Vec<u32>> = match data {
+                [0xFF, 0x21, 0x01, port, ..] => {
+                    group = port & 0x0F;
+                    continue;
+                },
+                [0xFF, 0x51, 0x03, a, b, c, ..] if metrical => {
+                    let tempo = (*a as u32) << 16 | (*b as u32) << 8 | *c as u32;
+                    vec![flex_data(group, 0, FLEX_TEMPO, [tempo * 100, 0, 0])] // in units of 10 nanoseconds
+                },
+                [0xFF, 0x58, 0x04, numerator, denominator, _, thirty_seconds, ..] => {
+                    let signature = (*numerator as u32) << 24 | (*denominator as u32) << 16 | (*thirty_seconds as u32) << 8;
+                    vec![flex_data(group, 0, FLEX_TIME_SIGNATURE, [signature, 0, 0])]
+                },
+                [0xFF, 0x59, 0x02, sharps, mode, ..] if (-7..=7).contains(&(*sharps as i8)) => {
+                    let key = ((*sharps as i8 as u8 & 0x0F) as u32) << 28 | (tonic(*sharps as i8, *mode == 1) as u32) << 24;
+                    vec![flex_data(group, 0, FLEX_KEY_SIGNATURE, [key, 0, 0])]
+                },
+                [0xFF, 0x01..=0x07, ..] => match split_length(event, 2) {
+                    Some(text) => flex_text(group, text_kind(data[1]), text),
+                    None => continue,
+                },
+                [0xF0, ..] => match split_length(event, 1) {
+                    Some(sysex) => sysex7(group, sysex.strip_suffix(&[0xF7]).unwrap_or(sysex)),
+                    None => continue,
+                },
+                [0x80..=0xEF, ..] => match protocol {
+                    Protocol::Midi1 => {
+                        let byte = |index: usize| data.get(index).map_or(0, |byte| (byte & 0x7F) as u32);
+                        vec![vec![0x2000_0000 | (group as u32) << 24 | (data[0] as u32) << 16 | byte(1) << 8 | byte(2)]]
+                    },
+                    Protocol::Midi2 => translator.translate(group, data).into_iter().collect(),
+                },
+                _ => continue,
+            };
+
+            let seconds = seconds_offset + map.tick_to_seconds(tick);
+            let tick = clip_tick(tick_offset + tick as u64, seconds);
+            packets.extend(words.into_iter().map(|words| TimedPacket { tick, seconds, words }));
+        }
+
+        let length = track.length();
+        end = end.max(clip_tick(tick_offset + length as u64, seconds_offset + map.tick_to_seconds(length)));
+        if header.format == 2 {
+            tick_offset += length as u64;
+            seconds_offset += map.tick_to_seconds(length);
+        }
+    }
+
+    packets.sort_by_key(|packet| packet.tick); // stable, so tracks keep their order within a tick
+    (packets, end)
+}
+
+/// Returns the bytes of packets as they are stored, big-endian
+fn words_to_bytes(words: &[u32]) -> Vec<u8> {
+    words.iter().flat_map(|word| word.to_be_bytes()).collect()
+}
+
+/// Writes a file as a stream of Universal MIDI Packets, each one after the timestamp it happens at
+pub fn to_ump_stream(header: &MThd, tracks: &[MTrk], protocol: Protocol, timestamps: Timestamps) -> Vec<u8> {
+    let (packets, _) = packets(header, tracks, protocol);
+    let mut words = Vec::new();
+
+    match timestamps {
+        Timestamps::DeltaClockstamps => {
+            words.push(ticks_per_quarter_note(clip_ticks_per_quarter_note(header.tickdiv)));
+            let mut previous = 0;
+            for packet in packets {
+                words.extend(delta_clockstamps(packet.tick - previous));
+                words.extend(packet.words);
+                previous = packet.tick;
+            }
+        },
+        Timestamps::Jr => {
+            let mut previous = None;
+            for packet in packets {
+                let timestamp = ((packet.seconds * JR_TICKS_PER_SECOND).round() as u64 & 0xFFFF) as u32;
+                if previous != Some(packet.tick) {
+                    words.push(0x0020_0000 | timestamp); // only where the time moves on
+                    previous = Some(packet.tick);
+                }
+                words.extend(packet.words);
+            }
+        },
+    }
+
+    words_to_bytes(&words)
+}
+
+/// Writes a file as a MIDI Clip File
+///
+/// The clip configuration header gives the ticks per quarter note, then the sequence runs from Start of Clip to an
+/// End of Clip at the end of the longest track, with a Delta Clockstamp before every message.
+pub fn to_clip(header: &MThd, tracks: &[MTrk], protocol: Protocol) -> Vec<u8> {
+    let (packets, end) = packets(header, tracks, protocol);
+    let mut words = delta_clockstamps(0);
+    words.push(ticks_per_quarter_note(clip_ticks_per_quarter_note(header.tickdiv)));
+    words.extend(delta_clockstamps(0));
+    words.extend([0xF020_0000, 0, 0, 0]); // Start of Clip
+
+    let mut previous = 0;
+    for packet in packets {
+        words.extend(delta_clockstamps(packet.tick - previous));
+        words.extend(packet.words);
+        previous = packet.tick;
+    }
+    words.extend(delta_clockstamps(end - previous));
+    words.extend([0xF021_0000, 0, 0, 0]); // End of Clip
+
+    let mut bytes = CLIP_SIGNATURE.to_vec();
+    bytes.extend(words_to_bytes(&words));
+    bytes
+}
+
+/// Turns MIDI 2.0 Channel Voice messages back into MIDI 1.0 channel events
+///
+/// Program Change with a bank is preceded by Bank Select, and Registered and Assignable Controller messages become
+/// the four controllers that select an RPN or NRPN and set its data. Per-note and relative messages are left out.
+fn midi1_events(words: &[u32]) -> Vec<Vec<u8>> {
+    let status = (words[0] >> 16) as u8;
+    let channel = status & 0x0F;
+    let (index, low) = ((words[0] >> 8) as u8 & 0x7F, words[0] as u8);
+    let controller = |number: u8, value: u32| vec![0xB0 | channel, number, value as u8];
+
+    match status & 0xF0 {
+        0x80 => vec![vec![status, index, scale_down(words[1] >> 16, 16, 7) as u8]],
+        0x90 => vec![vec![status, index, scale_down(words[1] >> 16, 16, 7).max(1) as u8]], // 0 would end the note
+        0xA0 => vec![vec![status, index, scale_down(words[1], 32, 7) as u8]],
+        0xB0 => vec![controller(index, scale_down(words[1], 32, 7))],
+        0x20 | 0x30 => {
+            let (msb, lsb) = if status & 0xF0 == 0x20 { (101, 100) } else { (99, 98) };
+            let entry = scale_down(words[1], 32, 14);
+            vec![controller(msb, index as u32), controller(lsb, (low & 0x7F) as u32), controller(6, entry >> 7), controller(38, entry & 0x7F)]
+        },
+        0xC0 => {
+            let mut events = Vec::new();
+            if low & 0x01 != 0 {
+                events.push(controller(0, (words[1] >> 8) & 0x7F));
+                events.push(controller(32, words[1] & 0x7F));
+            }
+            events.push(vec![status, (words[1] >> 24) as u8 & 0x7F]);
+            events
+        },
+        0xD0 => vec![vec![status, scale_down(words[1], 32, 7) as u8]],
+        0xE0 => {
+            let bend = scale_down(words[1], 32, 14);
+            vec![vec![status, (bend & 0x7F) as u8, (bend >> 7) as u8]]
+        },
+        _ => Vec::new(),
+    }
+}
+
+/// Returns the bytes carried in the data words of a message, most significant byte first
+fn word_bytes(words: &[u32]) -> impl Iterator<Item = u8> + '_ {
+    words.iter().flat_map(|word| word.to_be_bytes())
+}
+
+/// Reads a MIDI Clip File as a format 1 Standard MIDI File
+///
+/// The first track holds what came from Flex Data messages: tempo, time and key signatures and text. Each group
+/// that carries channel or System Exclusive messages gets a track of its own, starting with a MIDI Port event.
+pub fn from_clip(bytes: &[u8]) -> Result<(MThd, Vec<MTrk>), String> {
+    let body = bytes.strip_prefix(&CLIP_SIGNATURE[..]).ok_or("not a MIDI Clip File, it doesn't start with SMF2CLIP")?;
+    if body.len() % 4 != 0 {
+        return Err(String::from("the clip ends in the middle of a packet"));
+    }
+    let words: Vec<u32> = body.chunks(4).map(|word| u32::from_be_bytes(word.try_into().unwrap())).collect();
+
+    let mut ticks_per_quarter_note = None;
+    let (mut tick, mut end) = (0u64, None);
+    let mut conductor: Vec<(u64, Vec<u8>)> = Vec::new();
+    let mut groups: BTreeMap<u8, Vec<(u64, Vec<u8>)>> = BTreeMap::new();
+    let mut texts: BTreeMap<(u8, u8, u8), Vec<u8>> = BTreeMap::new();
+    let mut sysex: BTreeMap<u8, Vec<u8>> = BTreeMap::new();
+
+    let mut pos = 0;
+    while pos < words.len() && end.is_none() {
+        let message_type = words[pos] >> 28;
+        let packet = words.get(pos..pos + packet_words(message_type)).ok_or("the clip ends in the middle of a packet")?;
+        pos += packet.len();
+        let group = (packet[0] >> 24) as u8 & 0x0F;
+
+        match (message_type, (packet[0] >> 20) & 0x0F) {
+            (0x0, 0x3) => ticks_per_quarter_note = Some(packet[0] & 0xFFFF),
+            (0x0, 0x4) => tick += (packet[0] & 0xF_FFFF) as u64,
+            (0xF, _) if (packet[0] >> 16) & 0x3FF == 0x21 => end = Some(tick),
+            (0x2, _) => {
+                let status = (packet[0] >> 16) as u8;
+                let length = if (0xC0..=0xDF).contains(&status) { 2 } else { 3 };
+                if (0x80..0xF0).contains(&status) {
+                    let event = word_bytes(packet).skip(1).take(length).collect();
+                    groups.entry(group).or_default().push((tick, event));
+                }
+            },
+            (0x4, _) => {
+                let events = groups.entry(group).or_default();
+                events.extend(midi1_events(packet).into_iter().map(|event| (tick, event)));
+            },
+            (0x3, status) => {
+                let count = ((packet[0] >> 16) & 0x0F).min(6) as usize;
+                let data = word_bytes(packet).skip(2).take(count);
+                let buffer = sysex.entry(group).or_default();
+                if status <= 1 {
+                    buffer.clear();
+                }
+                buffer.extend(data);
+                if status == 0 || status == 3 {
+                    let data = std::mem::take(buffer);
+                    let mut event = vec![0xF0];
+                    event.extend(encode_vlq(data.len() as u32 + 1));
+                    event.extend(data);
+                    event.push(0xF7);
+                    groups.entry(group).or_default().push((tick, event));
+                }
+            },
+            (0xD, _) => {
+                let form = (packet[0] >> 22) as u8 & 0x03;
+                let kind = ((packet[0] >> 8) as u8, packet[0] as u8);
+                let data = packet[1];
+                match kind {
+                    FLEX_TEMPO => {
+                        let tempo = (data / 100).clamp(1, 0xFF_FFFF);
+                        conductor.push((tick, Event::new_tempo(tempo).data));
+                    },
+                    FLEX_TIME_SIGNATURE => {
+                        let thirty_seconds = match (data >> 8) as u8 { 0 => 8, value => value };
+                        conductor.push((tick, vec![0xFF, 0x58, 0x04, (data >> 24) as u8, (data >> 16) as u8, 24, thirty_seconds]));
+                    },
+                    FLEX_KEY_SIGNATURE => {
+                        let sharps = (data >> 24) as i8 >> 4;
+                        let minor = (data >> 24) as u8 & 0x0F == tonic(sharps, true);
+                        conductor.push((tick, vec![0xFF, 0x59, 0x02, sharps as u8, minor as u8]));
+                    },
+                    (FLEX_METADATA_TEXT, _) | (FLEX_PERFORMANCE_TEXT, _) => {
+                        let buffer = texts.entry((group, kind.0, kind.1)).or_default();
+                        if form <= 1 {
+                            buffer.clear();
+                        }
+                        buffer.extend(word_bytes(&packet[1..]).filter(|byte| *byte != 0));
+                        if form == 0 || form == 3 {
+                            let text = std::mem::take(buffer);
+                            conductor.push((tick, Event::new_text_event(text_meta_type(kind), &text).data));
+                        }
+                    },
+                    _ => (),
+                }
+            },
+            _ => (), // JR Timestamps, Start of Clip and messages with no Standard MIDI File counterpart
+        }
+    }
+
+    let ticks_per_quarter_note = ticks_per_quarter_note
+        .ok_or("the clip has no Delta Clockstamp Ticks Per Quarter Note message")?;
+    let tickdiv = u16::try_from(ticks_per_quarter_note).ok().filter(|ticks| (1..=0x7FFF).contains(ticks))
+        .ok_or(format!("{} ticks per quarter note don't fit in a MIDI file header", ticks_per_quarter_note))?;
+    let end = u32::try_from(end.unwrap_or(tick)).map_err(|_| String::from("the clip is too long for a MIDI file"))?;
+
+    let track = |mut events: Vec<(u64, Vec<u8>)>| -> MTrk {
+        events.push((end as u64, vec![0xFF, 0x2F, 0x00]));
+        MTrk::new(to_delta_times(events.into_iter().map(|(tick, data)| (tick.min(end as u64) as u32, Event { data })).collect()))
+    };
+    let mut tracks = vec![track(conductor)];
+    for (group, mut events) in groups {
+        events.insert(0, (0, vec![0xFF, 0x21, 0x01, group]));
+        tracks.push(track(events));
+    }
+
+    let header = MThd { identifier: *b"MThd", chunklen: 6, format: 1, ntracks: tracks.len() as u16, tickdiv };
+    Ok((header, tracks))
+}
+
+/// Parses the --protocol option of the commands
+fn parse_protocol(value: Option<String>) -> Result<Protocol, String> {
+    match value.as_deref() {
+        Some("1") => Ok(Protocol::Midi1),
+        Some("2") => Ok(Protocol::Midi2),
+        Some(other) => Err(format!("unknown protocol: {} (must be 1 or 2)", other)),
+        None => Err(String::from("--protocol needs 1 or 2")),
+    }
+}
+
+pub const TO_UMP_USAGE: &str = "\
+usage: midi_generator to-ump [options] <file>
+
+writes a MIDI file as a stream of MIDI 2.0 Universal MIDI Packets, big-endian
+
+options:
+  -o, --output <file>      write the packets to <file> (default <file>.ump)
+  --protocol <1|2>         carry channel events as MIDI 1.0 (message type 2) or MIDI 2.0 (message type 4)
+                           Channel Voice messages (default 2)
+  --jr-timestamps          time the packets with JR Timestamps instead of Delta Clockstamps";
+
+pub const TO_CLIP_USAGE: &str = "\
+usage: midi_generator to-clip [options] <file>
+
+writes a MIDI file as a MIDI Clip File (SMF2CLIP) of Universal MIDI Packets
+
+options:
+  -o, --output <file>      write the clip to <file> (default <file>.midi2)
+  --protocol <1|2>         carry channel events as MIDI 1.0 (message type 2) or MIDI 2.0 (message type 4)
+                           Channel Voice messages (default 2)";
+
+pub const FROM_CLIP_USAGE: &str = "\
+usage: midi_generator from-clip [options] <file>
+
+turns a MIDI Clip File (SMF2CLIP) into a format 1 MIDI file with a track per group
+
+options:
+  -o, --output <file>      write the MIDI file to <file> (default <file>.mid)";
+
+/// Runs the to-ump command, writing a MIDI file as a stream of Universal MIDI Packets
+pub fn run_to_ump(args: Vec<String>) -> Result<(), String> {
+    let mut protocol = Protocol::Midi2;
+    let mut timestamps = Timestamps::DeltaClockstamps;
+    let mut rest = Vec::new();
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--protocol" => protocol = parse_protocol(args.next())?,
+            "--jr-timestamps" => timestamps = Timestamps::Jr,
+            _ => rest.push(arg),
+        }
+    }
+
+    let (input, output) = parse_paths(rest, "ump", TO_UMP_USAGE)?;
+    let (header, tracks) = read_midi_file(&input)?;
+    std::fs::write(&output, to_ump_stream(&header, &tracks, protocol, timestamps))
+        .map_err(|error| format!("could not write {}: {}", output, error))
+}
+
+/// Runs the to-clip command, writing a MIDI file as a MIDI Clip File
+pub fn run_to_clip(args: Vec<String>) -> Result<(), String> {
+    let mut protocol = Protocol::Midi2;
+    let mut rest = Vec::new();
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--protocol" => protocol = parse_protocol(args.next())?,
+            _ => rest.push(arg),
+        }
+    }
+
+    let (input, output) = parse_paths(rest, "midi2", TO_CLIP_USAGE)?;
+    let (header, tracks) = read_midi_file(&input)?;
+    std::fs::write(&output, to_clip(&header, &tracks, protocol)).map_err(|error| format!("could not write {}: {}", output, error))
+}
+
+/// Runs the from-clip command, turning a MIDI Clip File into a MIDI file
+pub fn run_from_clip(args: Vec<String>) -> Result<(), String> {
+    let (input, output) = parse_paths(args, "mid", FROM_CLIP_USAGE)?;
+    let bytes = std::fs::read(&input).map_err(|error| format!("could not read {}: {}", input, error))?;
+    let (header, tracks) = from_clip(&bytes).map_err(|error| format!("{}: {}", input, error))?;
+    std::fs::write(&output, encode_midi_file(&header, &tracks)).map_err(|error| format!("could not write {}: {}", output, error))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(bytes: &[u8]) -> Vec<u32> {
+        bytes.chunks(4).map(|word| u32::from_be_bytes(word.try_into().unwrap())).collect()
+    }
+
+    fn file() -> (MThd, Vec<MTrk>) {
+        let header = MThd { identifier: *b"MThd", chunklen: 6, format: 0, ntracks: 1, tickdiv: 96 };
+        let tracks = vec![MTrk::new(to_delta_times(vec![
+            (0, Event::new_tempo(500_000)),
+            (0, Event::new_text_event(0x03, b"A clip name of 25 letters")),
+            (0, Event { data: vec![0xFF, 0x59, 0x02, 0xFD, 0x01] }), // C minor
+            (0, Event { data: vec![0xB0, 0, 1] }),
+            (0, Event { data: vec![0xB0, 32, 2] }),
+            (0, Event { data: vec![0xC0, 5] }),
+            (0, Event::new_note_on(0, 60, 127)),
+            (48, Event { data: vec![0xE0, 0x7F, 0x7F] }),
+            (48, Event { data: vec![0xF0, 0x04, 0x7E, 0x7F, 0x09, 0xF7] }),
+            (2_000_000, Event::new_note_on(0, 60, 0)),
+            (2_000_000, Event { data: vec![0xFF, 0x2F, 0x00] }),
+        ]))];
+        (header, tracks)
+    }
+
+    #[test]
+    fn values_are_scaled_with_min_center_max() {
+        assert_eq!(scale_up(0, 7, 16), 0);
+        assert_eq!(scale_up(64, 7, 16), 0x8000);
+        assert_eq!(scale_up(127, 7, 16), 0xFFFF);
+        assert_eq!(scale_up(100, 7, 16), 0xC924);
+        assert_eq!(scale_up(127, 7, 32), 0xFFFF_FFFF);
+        assert_eq!(scale_up(0x2000, 14, 32), 0x8000_0000);
+        assert_eq!(scale_up(0x3FFF, 14, 32), 0xFFFF_FFFF);
+        for value in 0..128 {
+            assert_eq!(scale_down(scale_up(value, 7, 32), 32, 7), value);
+        }
+    }
+
+    #[test]
+    fn files_are_written_as_packets() {
+        let (header, tracks) = file();
+
+        let midi2 = words(&to_ump_stream(&header, &tracks, Protocol::Midi2, Timestamps::DeltaClockstamps));
+        assert_eq!(midi2[..2], [0x0030_0060, 0x0040_0000]);
+        assert_eq!(midi2[2..6], [0xD010_0000, 50_000_000, 0, 0]);
+        assert_eq!(midi2[7..11], [0xD050_0103, u32::from_be_bytes(*b"A cl"), u32::from_be_bytes(*b"ip n"), u32::from_be_bytes(*b"ame ")]);
+        assert_eq!(midi2[17] >> 22 & 0x03, 3); // the last piece of the text
+        assert_eq!(midi2[21..24], [0x0040_0000, 0xD010_0005, 0xD300_0000]); // three flats, C
+        assert_eq!(midi2[26..32], [0x0040_0000, 0x40C0_0001, 0x0500_0102, 0x0040_0000, 0x4090_3C00, 0xFFFF_0000]); // bank select held for the program change
+        assert_eq!(midi2[32..35], [0x0040_0030, 0x40E0_0000, 0xFFFF_FFFF]);
+        assert_eq!(midi2[35..38], [0x0040_0000, 0x3003_7E7F, 0x0900_0000]);
+        assert_eq!(midi2[38..], [0x004F_FFFF, 0x0040_0000 | (2_000_000 - 48 - 0xF_FFFF), 0x4080_3C00, 0x8000_0000]);
+
+        let midi1 = words(&to_ump_stream(&header, &tracks, Protocol::Midi1, Timestamps::Jr));
+        assert_eq!(midi1[0], 0x0020_0000);
+        assert!(midi1.contains(&0x20B0_0001) && midi1.contains(&0x20C0_0500) && midi1.contains(&0x2090_3C7F));
+        assert!(midi1.contains(&(0x0020_0000 | 7_813))); // half a quarter note at 120 BPM
+    }
+
+    #[test]
+    fn timecode_clips_keep_time() {
+        let track = |tick| vec![MTrk::new(to_delta_times(vec![(0, Event::new_note_on(0, 60, 100)), (tick, Event::new_note_off(0, 60))]))];
+        let timecode = |tickdiv| MThd { identifier: *b"MThd", chunklen: 6, format: 0, ntracks: 1, tickdiv };
+
+        let clip = words(&to_clip(&timecode(0xE701), &track(25), Protocol::Midi1)[8..]); // 25 fps, 1 sub-frame
+        assert_eq!(clip[1], 0x0030_0000 | 25); // a quarter note at 120 BPM lasts half a second
+        assert_eq!(clip[9], 0x0040_0000 | 50);
+
+        // 29.97 fps, 4 sub-frames, so 12000 ticks last 100.1 seconds
+        let clip = words(&to_clip(&timecode(0xE304), &track(12_000), Protocol::Midi1)[8..]);
+        assert_eq!(clip[1], 0x0030_0000 | 120);
+        assert_eq!(clip[9], 0x0040_0000 | 24_024);
+    }
+
+    #[test]
+    fn clips_convert_back_to_midi_files() {
+        let (header, tracks) = file();
+        let clip = to_clip(&header, &tracks, Protocol::Midi2);
+        assert_eq!(&clip[..8], b"SMF2CLIP");
+        let clip_words = words(&clip[8..]);
+        assert_eq!(clip_words[..4], [0x0040_0000, 0x0030_0060, 0x0040_0000, 0xF020_0000]);
+        assert_eq!(clip_words[clip_words.len() - 4..], [0xF021_0000, 0, 0, 0]);
+
+        let (read_header, read_tracks) = from_clip(&clip).unwrap();
+        assert_eq!((read_header.format, read_header.ntracks, read_header.tickdiv), (1, 2, 96));
+        let events = |track: &MTrk| track.timed_events().iter().map(|(tick, event)| (*tick, event.data.clone())).collect::<Vec<_>>();
+        assert_eq!(events(&read_tracks[0]), [
+            (0, vec![0xFF, 0x51, 0x03, 0x07, 0xA1, 0x20]),
+            (0, Event::new_text_event(0x03, b"A clip name of 25 letters").data),
+            (0, vec![0xFF, 0x59, 0x02, 0xFD, 0x01]),
+            (2_000_000, vec![0xFF, 0x2F, 0x00]),
+        ]);
+        assert_eq!(events(&read_tracks[1]), [
+            (0, vec![0xFF, 0x21, 0x01, 0x00]),
+            (0, vec![0xB0, 0, 1]),
+            (0, vec![0xB0, 32, 2]),
+            (0, vec![0xC0, 5]),
+            (0, vec![0x90, 60, 127]),
+            (48, vec![0xE0, 0x7F, 0x7F]),
+            (48, vec![0xF0, 0x04, 0x7E, 0x7F, 0x09, 0xF7]),
+            (2_000_000, vec![0x80, 60, 64]),
+            (2_000_000, vec![0xFF, 0x2F, 0x00]),
+        ]);
+
+        assert!(from_clip(b"SMF2CLIP\x00\x40\x00\x00").is_err()); // no ticks per quarter note
+        assert!(from_clip(&clip[..clip.len() - 2]).is_err());
+        assert!(from_clip(b"MThd").is_err());
+    }
+}
@@ -0,0 +1,88 @@
+// WAV output
+// Writes stereo audio as a RIFF WAVE file
+
+use std::convert::TryFrom;
+
+/// How the samples of a WAV file are stored
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SampleFormat {
+    Int16, // signed 16-bit PCM, clipped to full scale
+    Float32, // IEEE floats, which keep peaks above full scale
+}
+
+/// Encodes stereo frames, as [left, right] between -1 and 1, as the bytes of a WAV file
+///
+/// Float files carry the fact chunk that the format asks for with samples other than PCM.
+pub fn encode_wav(frames: &[[f32; 2]], sample_rate: u32, format: SampleFormat) -> Result<Vec<u8>, String> {
+    let (format_tag, bytes_per_sample): (u16, u32) = match format {
+        SampleFormat::Int16 => (1, 2),
+        SampleFormat::Float32 => (3, 4),
+    };
+    let block_align = 2 * bytes_per_sample;
+    let data_length = u32::try_from(frames.len() as u64 * block_align as u64)
+        .ok()
+        .filter(|length| *length <= u32::MAX - 58)
+        .ok_or("the audio is too long for a WAV file")?;
+
+    let mut fmt = Vec::new();
+    fmt.extend_from_slice(&format_tag.to_le_bytes());
+    fmt.extend_from_slice(&2u16.to_le_bytes()); // channels
+    fmt.extend_from_slice(&sample_rate.to_le_bytes());
+    fmt.extend_from_slice(&(sample_rate * block_align).to_le_bytes());
+    fmt.extend_from_slice(&(block_align as u16).to_le_bytes());
+    fmt.extend_from_slice(&(8 * bytes_per_sample as u16).to_le_bytes());
+    if format == SampleFormat::Float32 {
+        fmt.extend_from_slice(&0u16.to_le_bytes()); // no extension
+    }
+
+    let mut body = Vec::with_capacity(58 + data_length as usize);
+    body.extend_from_slice(b"WAVE");
+    body.extend_from_slice(b"fmt ");
+    body.extend_from_slice(&(fmt.len() as u32).to_le_bytes());
+    body.extend_from_slice(&fmt);
+    if format == SampleFormat::Float32 {
+        body.extend_from_slice(b"fact");
+        body.extend_from_slice(&4u32.to_le_bytes());
+        body.extend_from_slice(&(frames.len() as u32).to_le_bytes());
+    }
+    body.extend_from_slice(b"data");
+    body.extend_from_slice(&data_length.to_le_bytes());
+    for sample in frames.iter().flatten() {
+        match format {
+            SampleFormat::Int16 => body.extend_from_slice(&((sample.clamp(-1.0, 1.0) * 32767.0).round() as i16).to_le_bytes()),
+            SampleFormat::Float32 => body.extend_from_slice(&sample.to_le_bytes()),
+        }
+    }
+
+    let mut bytes = Vec::with_capacity(8 + body.len());
+    bytes.extend_from_slice(b"RIFF");
+    bytes.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(&body);
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryInto;
+
+    #[test]
+    fn pcm_and_float_files_have_the_right_layout() {
+        let frames = [[0.5, -1.5], [0.0, 1.0]];
+
+        let pcm = encode_wav(&frames, 22050, SampleFormat::Int16).unwrap();
+        assert_eq!(pcm.len(), 44 + 8);
+        assert_eq!(&pcm[..4], b"RIFF");
+        assert_eq!(u32::from_le_bytes(pcm[4..8].try_into().unwrap()), 44 + 8 - 8);
+        assert_eq!(&pcm[8..16], b"WAVEfmt ");
+        assert_eq!(pcm[16..36], [16, 0, 0, 0, 1, 0, 2, 0, 0x22, 0x56, 0, 0, 0x88, 0x58, 1, 0, 4, 0, 16, 0]);
+        assert_eq!(&pcm[36..44], b"data\x08\0\0\0");
+        assert_eq!(pcm[44..], [0x00, 0x40, 0x01, 0x80, 0, 0, 0xFF, 0x7F]); // 16384, clipped to -32767, 0, 32767
+
+        let float = encode_wav(&frames, 48000, SampleFormat::Float32).unwrap();
+        assert_eq!(float.len(), 58 + 16);
+        assert_eq!(float[20..22], [3, 0]);
+        assert_eq!(&float[38..50], b"fact\x04\0\0\0\x02\0\0\0");
+        assert_eq!(float[62..66], (-1.5f32).to_le_bytes()); // left as it is
+    }
+}